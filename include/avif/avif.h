@@ -168,6 +168,14 @@ enum avifRGBFormat {
     AVIF_RGB_FORMAT_GRAY,
     AVIF_RGB_FORMAT_GRAY_A,
     AVIF_RGB_FORMAT_A_GRAY,
+    AVIF_RGB_FORMAT_AR30,
+    AVIF_RGB_FORMAT_AB30,
+    AVIF_RGB_FORMAT_RGBA_F32,
+    AVIF_RGB_FORMAT_GBR_PLANAR,
+    AVIF_RGB_FORMAT_BGR565,
+    AVIF_RGB_FORMAT_Y410,
+    AVIF_RGB_FORMAT_Y416,
+    AVIF_RGB_FORMAT_YUY2,
 };
 
 enum avifMatrixCoefficients : uint16_t {
@@ -310,6 +318,8 @@ struct Decoder;
 
 struct Encoder;
 
+struct EncoderOutput;
+
 using avifBool = int;
 
 using avifStrictFlags = uint32_t;
@@ -551,6 +561,7 @@ struct avifEncoder {
     Box<Encoder> rust_encoder;
     bool rust_encoder_initialized;
     Box<CodecSpecificOptions> codec_specific_options;
+    Box<EncoderOutput> output;
 };
 
 using avifAddImageFlags = uint32_t;
@@ -750,10 +761,30 @@ avifResult crabby_avifEncoderAddImageGrid(avifEncoder *encoder,
                                           const avifImage *const *cellImages,
                                           avifAddImageFlags addImageFlags);
 
+/// # Safety
+/// Used by the C API with the following pre-conditions:
+/// - if encoder is not null, it has to point to a valid avifEncoder object.
+/// - if io is not null, it has to point to a valid avifIO object whose write function is not
+///   null.
+///
+/// The encoded file is written to io instead of being returned by avifEncoderFinish(). Must be
+/// called before adding any image. The encoder takes ownership of io.
+avifResult crabby_avifEncoderSetIO(avifEncoder *encoder, avifIO *io);
+
+/// # Safety
+/// Used by the C API with the following pre-conditions:
+/// - if encoder is not null, it has to point to a valid avifEncoder object.
+/// - if filename is not null, it has to point to a valid C-style string.
+///
+/// Same as avifEncoderSetIO() with a file created (or truncated) at filename.
+avifResult crabby_avifEncoderSetIOFile(avifEncoder *encoder, const char *filename);
+
 /// # Safety
 /// Used by the C API with the following pre-conditions:
 /// - if encoder is not null, it has to point to a valid avifEncoder object.
 /// - if output is not null, it has to point to a valid avifRWData object.
+///
+/// If an output was set with avifEncoderSetIO() or avifEncoderSetIOFile(), output is left empty.
 avifResult crabby_avifEncoderFinish(avifEncoder *encoder, avifRWData *output);
 
 /// # Safety
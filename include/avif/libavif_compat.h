@@ -49,6 +49,8 @@
 #define avifEncoderFinish crabby_avifEncoderFinish
 #define avifEncoderSetCodecSpecificOption \
   crabby_avifEncoderSetCodecSpecificOption
+#define avifEncoderSetIO crabby_avifEncoderSetIO
+#define avifEncoderSetIOFile crabby_avifEncoderSetIOFile
 #define avifEncoderWrite crabby_avifEncoderWrite
 #define avifFree crabby_avifFree
 #define avifGainMapCreate crabby_avifGainMapCreate
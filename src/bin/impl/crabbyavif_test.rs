@@ -24,3 +24,54 @@ fn crabbyavif_test() -> AvifResult<()> {
     );
     Ok(())
 }
+
+#[cfg(feature = "encoder")]
+#[test]
+fn compare_test() -> AvifResult<()> {
+    use crabby_avif::image::*;
+    use crabby_avif::utils::writer::y4m::Y4MWriter;
+    use crabby_avif::utils::writer::Writer;
+
+    let write_y4m = |filename: &str, offset: u8| -> AvifResult<String> {
+        let mut image = Image {
+            width: 32,
+            height: 24,
+            depth: 8,
+            yuv_format: PixelFormat::Yuv420,
+            ..Default::default()
+        };
+        image.allocate_planes(Category::Color)?;
+        for plane in YUV_PLANES {
+            let plane_data = image.plane_data(plane).unwrap();
+            for y in 0..plane_data.height {
+                for (x, value) in image.row_mut(plane, y)?.iter_mut().enumerate() {
+                    *value = (x as u8 * 4 + y as u8 * 2).saturating_add(offset);
+                }
+            }
+        }
+        let path = std::env::temp_dir().join(filename);
+        let mut file = std::fs::File::create(&path).unwrap();
+        Y4MWriter::create(false).write_frame(&mut file, &image)?;
+        Ok(path.to_str().unwrap().to_string())
+    };
+    let reference = write_y4m("crabbyavif_compare_reference.y4m", 0)?;
+    let distorted = write_y4m("crabbyavif_compare_distorted.y4m", 3)?;
+    assert!(main_impl(vec!["crabbyavif", "--compare", &reference, &distorted].iter()).is_ok());
+    assert!(main_impl(
+        vec![
+            "crabbyavif",
+            "--compare",
+            &reference,
+            "--compare-linear",
+            &distorted
+        ]
+        .iter()
+    )
+    .is_ok());
+    assert!(main_impl(vec!["crabbyavif", "--compare", &reference].iter()).is_err());
+    assert!(
+        main_impl(vec!["crabbyavif", "--compare", &reference, &distorted, "out.png"].iter())
+            .is_err()
+    );
+    Ok(())
+}
@@ -21,8 +21,10 @@ use clap::Parser;
 use crabby_avif::decoder::*;
 #[cfg(feature = "encoder")]
 use crabby_avif::encoder::*;
+use crabby_avif::reformat::rgb;
 use crabby_avif::utils::clap::CleanAperture;
 use crabby_avif::utils::clap::CropRect;
+use crabby_avif::utils::metrics;
 use crabby_avif::utils::IFraction;
 use crabby_avif::utils::UFraction;
 use crabby_avif::*;
@@ -173,6 +175,24 @@ fn codec_choice_parser(s: &str) -> Result<CodecChoice, String> {
     }
 }
 
+fn dithering_parser(s: &str) -> Result<rgb::Dithering, String> {
+    match s {
+        "none" => Ok(rgb::Dithering::None),
+        "ordered" => Ok(rgb::Dithering::Ordered),
+        "fs" | "floyd-steinberg" => Ok(rgb::Dithering::FloydSteinberg),
+        _ => Err(format!("Invalid dithering mode: {s}")),
+    }
+}
+
+fn alpha_mode_parser(s: &str) -> Result<metrics::AlphaMode, String> {
+    match s {
+        "ignore" => Ok(metrics::AlphaMode::Ignore),
+        "separate" => Ok(metrics::AlphaMode::Separate),
+        "premultiply" => Ok(metrics::AlphaMode::Premultiply),
+        _ => Err("Invalid alpha mode".into()),
+    }
+}
+
 fn header_format_parser(s: &str) -> Result<HeaderFormat, String> {
     match s {
         "meta" | "default" => Ok(HeaderFormat::Default),
@@ -201,6 +221,19 @@ struct CommandLineArgs {
     #[arg(short = 'i', long, default_value = "false")]
     info: bool,
 
+    /// Compare input_file to the given reference image file and print quality metrics instead of
+    /// saving to disk. Both files can be AVIF, PNG, JPEG or Y4M
+    #[arg(long)]
+    compare: Option<String>,
+
+    /// Compare only: Compute the metrics on linear light instead of gamma-encoded values
+    #[arg(long, default_value = "false")]
+    compare_linear: bool,
+
+    /// Compare only: How the alpha channel is compared, one of ignore, separate or premultiply
+    #[arg(long, value_parser = alpha_mode_parser, default_value = "separate")]
+    compare_alpha: metrics::AlphaMode,
+
     /// Number of threads to use for AVIF encoding/decoding
     #[arg(long)]
     jobs: Option<u32>,
@@ -227,6 +260,11 @@ struct CommandLineArgs {
     #[arg(long, value_parser = value_parser!(f32))]
     quality_gainmap: Option<f32>,
 
+    /// AVIF Decode only: Dithering used when the output has a lower bit depth than the AVIF image,
+    /// one of none, ordered or floyd-steinberg (fs). (PNG/JPEG only, default: none)
+    #[arg(long, value_parser = dithering_parser)]
+    dither: Option<rgb::Dithering>,
+
     /// PNG output compression level in 0..9 (default: 5).
     #[arg(long, value_parser = value_parser!(i32).range(0..=9))]
     png_compress: Option<i32>,
@@ -692,10 +730,12 @@ fn decode(args: &CommandLineArgs, input_file: &String) -> AvifResult<()> {
         "png" => Box::new(PngWriter {
             depth: args.depth,
             compression_level: args.png_compress,
+            dithering: args.dither.unwrap_or_default(),
         }),
         #[cfg(feature = "jpeg")]
         "jpg" | "jpeg" => Box::new(JpegWriter {
             quality: args.quality.map(|quality| quality as u8),
+            dithering: args.dither.unwrap_or_default(),
         }),
         _ => {
             return Err(AvifError::UnknownError(format!(
@@ -731,6 +771,102 @@ fn decode(args: &CommandLineArgs, input_file: &String) -> AvifResult<()> {
     Ok(())
 }
 
+#[cfg(feature = "encoder")]
+fn read_image_file(filename: &str) -> AvifResult<image::Image> {
+    let extension = get_extension(filename);
+    let mut reader: Box<dyn Reader> = match extension.as_str() {
+        "y4m" => Box::new(Y4MReader::create(filename)?),
+        #[cfg(feature = "jpeg")]
+        "jpg" | "jpeg" => Box::new(JpegReader::create(filename)?),
+        #[cfg(feature = "png")]
+        "png" => Box::new(PngReader::create(filename)?),
+        _ => {
+            return Err(AvifError::UnknownError(format!(
+                "Unknown input file extension ({extension})"
+            )));
+        }
+    };
+    // Store the RGB samples losslessly so that they can be compared as is.
+    let config = Config {
+        yuv_format: if extension == "y4m" { None } else { Some(PixelFormat::Yuv444) },
+        matrix_coefficients: Some(MatrixCoefficients::Identity),
+        ..Default::default()
+    };
+    Ok(reader.read_frame(&config)?.0)
+}
+
+#[cfg(not(feature = "encoder"))]
+fn read_image_file(_filename: &str) -> AvifResult<image::Image> {
+    Err(AvifError::UnknownError(
+        "Only AVIF files can be compared without the encoder feature".into(),
+    ))
+}
+
+// Returns the image at --index of an AVIF file, or the first frame of any other file, converted to
+// RGB at its own bit depth.
+fn read_rgb_image(args: &CommandLineArgs, filename: &String) -> AvifResult<rgb::Image> {
+    let decoder;
+    let file_image;
+    let image = if can_decode(filename) {
+        decoder = {
+            let mut decoder = create_decoder_and_parse(args, filename)?;
+            decoder.nth_image(args.index.unwrap_or(0))?;
+            decoder
+        };
+        decoder.image().unwrap()
+    } else {
+        file_image = read_image_file(filename)?;
+        &file_image
+    };
+    let mut rgb = rgb::Image::create_from_yuv(image);
+    rgb.format = if image.alpha_present { rgb::Format::Rgba } else { rgb::Format::Rgb };
+    rgb.allocate()?;
+    rgb.convert_from_yuv(image)?;
+    Ok(rgb)
+}
+
+fn compare(args: &CommandLineArgs, reference_file: &String, input_file: &String) -> AvifResult<()> {
+    let reference = read_rgb_image(args, reference_file)?;
+    let image = read_rgb_image(args, input_file)?;
+    if reference.width != image.width || reference.height != image.height {
+        return Err(AvifError::UnknownError(format!(
+            "Image dimensions differ: {}x{} vs {}x{}",
+            reference.width, reference.height, image.width, image.height
+        )));
+    }
+    let options = metrics::CompareOptions {
+        space: if args.compare_linear {
+            metrics::MetricSpace::Linear
+        } else {
+            metrics::MetricSpace::Gamma
+        },
+        alpha: args.compare_alpha,
+    };
+    let comparison = metrics::compare_rgb(&reference, &image, &options)?;
+    println!("Comparing {input_file} to reference {reference_file}");
+    let precision = if args.compare_linear { 6 } else { 0 };
+    let print_metrics = |name: String, metrics: &metrics::Metrics| {
+        println!(" * {name}");
+        print_data_as_columns(&[
+            (1, "PSNR", format!("{:.2} dB", metrics.psnr)),
+            (1, "SSIM", format!("{:.6}", metrics.ssim)),
+            (1, "MS-SSIM", format!("{:.6}", metrics.ms_ssim)),
+            (
+                1,
+                "Max Abs Error",
+                // Errors are integer sample differences unless computed on linear values.
+                format!("{:.*}", precision, metrics.max_abs_error),
+            ),
+            (1, "Mean Error", format!("{:.6}", metrics.mean_error)),
+        ]);
+    };
+    for channel in &comparison.channels {
+        print_metrics(format!("Channel {:?}", channel.channel), &channel.metrics);
+    }
+    print_metrics("Overall".into(), &comparison.overall);
+    Ok(())
+}
+
 #[cfg(feature = "encoder")]
 fn read_file(filepath: &String) -> io::Result<Vec<u8>> {
     let mut file = File::open(filepath)?;
@@ -919,6 +1055,17 @@ fn can_encode(filename: &str) -> bool {
 }
 
 fn validate_args(args: &CommandLineArgs) -> AvifResult<()> {
+    if args.compare.is_some() {
+        if args.input_file.is_none() {
+            return Err(AvifError::UnknownError("input_file is required".into()));
+        }
+        if args.info || args.output_file.is_some() {
+            return Err(AvifError::UnknownError(
+                "--compare contains unsupported extra arguments".into(),
+            ));
+        }
+        return Ok(());
+    }
     if let Some(input_file) = &args.input_file {
         if can_decode(input_file) {
             if args.info {
@@ -926,6 +1073,7 @@ fn validate_args(args: &CommandLineArgs) -> AvifResult<()> {
                     || args.quality.is_some()
                     || args.depth.is_some()
                     || args.index.is_some()
+                    || args.dither.is_some()
                     || args.extract_gainmap
                 {
                     return Err(AvifError::UnknownError(
@@ -960,6 +1108,11 @@ fn validate_args(args: &CommandLineArgs) -> AvifResult<()> {
                         "png-compress-level is only supported for png output".into(),
                     ));
                 }
+                if args.dither.is_some() && !matches!(extension.as_str(), "png" | "jpg" | "jpeg") {
+                    return Err(AvifError::UnknownError(
+                        "dither is only supported for png and jpeg output".into(),
+                    ));
+                }
             }
         } else {
             // TODO: b/403090413 - validate encoding args.
@@ -1007,6 +1160,9 @@ where
         println!("  {}", codec_versions());
     }
     validate_args(&args)?;
+    if let (Some(reference_file), Some(input_file)) = (&args.compare, &args.input_file) {
+        return compare(&args, reference_file, input_file);
+    }
     if let Some(input_file) = &args.input_file {
         if can_decode(input_file) {
             if args.info {
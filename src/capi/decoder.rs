@@ -223,12 +223,15 @@ impl From<&avifDecoder> for Settings {
                 // Silently treat all other choices the same as Auto.
                 _ => CodecChoice::Auto,
             },
+            codec_plugins: Vec::new(),
             image_size_limit: NonZero::new(decoder.imageSizeLimit),
             image_dimension_limit: NonZero::new(decoder.imageDimensionLimit),
             image_count_limit: NonZero::new(decoder.imageCountLimit),
             max_threads: u32::try_from(decoder.maxThreads).unwrap_or(0),
             android_mediacodec_output_color_format: decoder.androidMediaCodecOutputColorFormat,
             allow_sample_transform: decoder.allowSampleTransform == AVIF_TRUE,
+            semi_planar_output_format: None,
+            apply_transformations: false,
         }
     }
 }
@@ -55,8 +55,14 @@ pub struct avifEncoder {
     rust_encoder: Box<Encoder>,
     rust_encoder_initialized: bool,
     codec_specific_options: Box<CodecSpecificOptions>,
+    output: Box<EncoderOutput>,
 }
 
+// Output set with avifEncoderSetIO() or avifEncoderSetIOFile(). Passed to the Rust encoder when it
+// is created.
+#[derive(Default)]
+struct EncoderOutput(Option<GenericOutput>);
+
 impl Default for avifEncoder {
     fn default() -> Self {
         let settings = Settings::default();
@@ -86,6 +92,7 @@ impl Default for avifEncoder {
             modificationTime: 0,
             rust_encoder_initialized: false,
             codec_specific_options: Default::default(),
+            output: Default::default(),
         }
     }
 }
@@ -132,6 +139,7 @@ impl From<&avifEncoder> for Settings {
                 _ => CodecChoice::Auto,
             },
             threads: encoder.maxThreads as u32,
+            concurrent_cell_count: 1,
             speed: if encoder.speed >= 0 && encoder.speed <= 10 {
                 Some(encoder.speed as u32)
             } else {
@@ -143,6 +151,10 @@ impl From<&avifEncoder> for Settings {
             repetition_count: RepetitionCount::create_from(encoder.repetitionCount),
             extra_layer_count: encoder.extraLayerCount,
             recipe: Recipe::None,
+            rate_control: RateControl::Quality,
+            two_pass: None,
+            aom_options: Default::default(),
+            input_optimizations: Default::default(),
             force_write_extended_pixi: false,
             creation_time: if encoder.creationTime == 0 {
                 None
@@ -186,6 +198,13 @@ impl avifEncoder {
                 );
             }
             self.codec_specific_options.clear();
+            if let Some(output) = self.output.0.take() {
+                let res = self.rust_encoder.set_output(output);
+                self.diag.set_from_result(&res);
+                if let Err(err) = res {
+                    return (&err).into();
+                }
+            }
             avifResult::Ok
         }
     }
@@ -264,7 +283,15 @@ pub unsafe extern "C" fn crabby_avifEncoderAddImage(
                 None => rust_encoder(encoder).add_image(&image),
             }
         } else {
-            rust_encoder(encoder).add_image_for_sequence(&image, durationInTimescales)
+            let frame_options = FrameOptions {
+                force_keyframe: (addImageFlags & AVIF_ADD_IMAGE_FLAG_FORCE_KEYFRAME) != 0,
+                ..Default::default()
+            };
+            rust_encoder(encoder).add_image_for_sequence_with_options(
+                &image,
+                durationInTimescales,
+                &frame_options,
+            )
         };
     encoder_ref.diag.set_from_result(&res);
     res.into()
@@ -334,10 +361,67 @@ pub unsafe extern "C" fn crabby_avifEncoderAddImageGrid(
     res.into()
 }
 
+/// # Safety
+/// Used by the C API with the following pre-conditions:
+/// - if encoder is not null, it has to point to a valid avifEncoder object.
+/// - if io is not null, it has to point to a valid avifIO object whose write function is not
+///   null.
+///
+/// The encoded file is written to io instead of being returned by avifEncoderFinish(). Must be
+/// called before adding any image. The encoder takes ownership of io.
+#[no_mangle]
+pub unsafe extern "C" fn crabby_avifEncoderSetIO(
+    encoder: *mut avifEncoder,
+    io: *mut avifIO,
+) -> avifResult {
+    check_pointer!(encoder);
+    check_pointer!(io);
+    let encoder_ref = deref_mut!(encoder);
+    if encoder_ref.rust_encoder_initialized {
+        return avifResult::InvalidArgument;
+    }
+    encoder_ref.output.0 = Some(Box::new(avifEncoderIOWrapper::create(io)));
+    avifResult::Ok
+}
+
+/// # Safety
+/// Used by the C API with the following pre-conditions:
+/// - if encoder is not null, it has to point to a valid avifEncoder object.
+/// - if filename is not null, it has to point to a valid C-style string.
+///
+/// Same as avifEncoderSetIO() with a file created (or truncated) at filename.
+#[no_mangle]
+pub unsafe extern "C" fn crabby_avifEncoderSetIOFile(
+    encoder: *mut avifEncoder,
+    filename: *const c_char,
+) -> avifResult {
+    check_pointer!(encoder);
+    check_pointer!(filename);
+    let encoder_ref = deref_mut!(encoder);
+    if encoder_ref.rust_encoder_initialized {
+        return avifResult::InvalidArgument;
+    }
+    // SAFETY: filename is guaranteed to be not-null and contain a valid C-string as per the
+    // pre-conditions of this function.
+    let filename = match unsafe { CStr::from_ptr(filename) }.to_str() {
+        Ok(filename) => filename,
+        Err(_) => return avifResult::InvalidArgument,
+    };
+    match std::fs::File::create(filename) {
+        Ok(file) => {
+            encoder_ref.output.0 = Some(Box::new(std::io::BufWriter::new(file)));
+            avifResult::Ok
+        }
+        Err(_) => avifResult::IoError,
+    }
+}
+
 /// # Safety
 /// Used by the C API with the following pre-conditions:
 /// - if encoder is not null, it has to point to a valid avifEncoder object.
 /// - if output is not null, it has to point to a valid avifRWData object.
+///
+/// If an output was set with avifEncoderSetIO() or avifEncoderSetIOFile(), output is left empty.
 #[no_mangle]
 pub unsafe extern "C" fn crabby_avifEncoderFinish(
     encoder: *mut avifEncoder,
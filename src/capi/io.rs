@@ -234,6 +234,65 @@ impl crate::decoder::IO for avifIOWrapper {
     }
 }
 
+// Adapts an avifIO to the encoder output, which is written sequentially except for a few patches
+// of already written bytes.
+pub struct avifEncoderIOWrapper {
+    io: *mut avifIO,
+    offset: u64,
+    size: u64,
+}
+
+impl avifEncoderIOWrapper {
+    pub fn create(io: *mut avifIO) -> Self {
+        Self {
+            io,
+            offset: 0,
+            size: 0,
+        }
+    }
+}
+
+impl Drop for avifEncoderIOWrapper {
+    fn drop(&mut self) {
+        if let Some(destroy) = deref_const!(self.io).destroy {
+            // SAFETY: Calling into a C function.
+            unsafe {
+                destroy(self.io);
+            }
+        }
+    }
+}
+
+impl std::io::Write for avifEncoderIOWrapper {
+    #[cfg_attr(feature = "disable_cfi", sanitize(cfi = "off"))]
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // SAFETY: Calling into a C function.
+        let res = unsafe { ((*self.io).write)(self.io, 0, self.offset, buf.as_ptr(), buf.len()) };
+        if res != avifResult::Ok {
+            return Err(std::io::Error::other("avifIO write failed"));
+        }
+        self.offset += buf.len() as u64;
+        self.size = self.size.max(self.offset);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl std::io::Seek for avifEncoderIOWrapper {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let offset = match pos {
+            std::io::SeekFrom::Start(offset) => Some(offset),
+            std::io::SeekFrom::End(delta) => self.size.checked_add_signed(delta),
+            std::io::SeekFrom::Current(delta) => self.offset.checked_add_signed(delta),
+        };
+        self.offset = offset.ok_or(std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+        Ok(self.offset)
+    }
+}
+
 pub struct avifCIOWrapper {
     io: GenericIO,
     buf: Vec<u8>,
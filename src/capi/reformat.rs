@@ -68,8 +68,15 @@ impl From<&avifRGBImage> for rgb::Image {
             chroma_downsampling: rgb.chroma_downsampling,
             premultiply_alpha: rgb.alpha_premultiplied,
             is_float: rgb.is_float,
+            dithering: rgb::Dithering::None,
             max_threads: rgb.max_threads,
-            pixels: Pixels::from_raw_pointer(rgb.pixels, rgb.depth, rgb.height, rgb.row_bytes).ok(),
+            pixels: Pixels::from_raw_pointer(
+                rgb.pixels,
+                rgb.depth,
+                rgb.height.saturating_mul(rgb.format.plane_count()),
+                rgb.row_bytes,
+            )
+            .ok(),
             row_bytes: rgb.row_bytes,
         };
         let format = match (rgb.format, rgb.ignore_alpha) {
@@ -90,6 +97,14 @@ impl From<&avifRGBImage> for rgb::Image {
             (rgb::Format::GrayA, false) => rgb::Format::GrayA,
             (rgb::Format::AGray, true) => rgb::Format::Gray,
             (rgb::Format::AGray, false) => rgb::Format::AGray,
+            (rgb::Format::Ar30, _) => rgb::Format::Ar30,
+            (rgb::Format::Ab30, _) => rgb::Format::Ab30,
+            (rgb::Format::RgbaF32, _) => rgb::Format::RgbaF32,
+            (rgb::Format::GbrPlanar, _) => rgb::Format::GbrPlanar,
+            (rgb::Format::Bgr565, _) => rgb::Format::Bgr565,
+            (rgb::Format::Y410, _) => rgb::Format::Y410,
+            (rgb::Format::Y416, _) => rgb::Format::Y416,
+            (rgb::Format::Yuy2, _) => rgb::Format::Yuy2,
         };
         dst.shuffle_channels_to(format).unwrap()
     }
@@ -319,7 +334,13 @@ pub unsafe extern "C" fn crabby_avifRGBImageAllocatePixels(rgb: *mut avifRGBImag
     }
     let rgb = deref_mut!(rgb);
     let pixel_size = rgb.format.pixel_size(rgb.depth);
-    let row_bytes = match checked_mul!(rgb.width, pixel_size) {
+    // Yuy2 stores two horizontally adjacent pixels together. So the width has to be even.
+    let width = if rgb.format == rgb::Format::Yuy2 {
+        rgb.width.saturating_add(rgb.width & 1)
+    } else {
+        rgb.width
+    };
+    let row_bytes = match checked_mul!(width, pixel_size) {
         Ok(value) => value,
         Err(_) => return avifResult::InvalidArgument,
     };
@@ -327,7 +348,8 @@ pub unsafe extern "C" fn crabby_avifRGBImageAllocatePixels(rgb: *mut avifRGBImag
         Ok(value) => value,
         Err(_) => return avifResult::InvalidArgument,
     };
-    let alloc_size = match checked_mul!(row_bytes, rgb.height as usize) {
+    let row_count = rgb.height as usize * rgb.format.plane_count() as usize;
+    let alloc_size = match checked_mul!(row_bytes, row_count) {
         Ok(value) => round2_usize(value),
         Err(_) => return avifResult::InvalidArgument,
     };
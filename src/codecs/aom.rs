@@ -15,10 +15,14 @@
 #![allow(non_upper_case_globals)]
 
 use crate::codecs::*;
+use crate::encoder::AomTune;
+use crate::encoder::AomTuneContent;
 use crate::encoder::Sample;
 use crate::encoder::ScalingMode;
+use crate::encoder::TwoPassRateControl;
 use crate::image::Image;
 use crate::image::YuvRange;
+use crate::internal_utils::*;
 use crate::parser::obu::Av1SequenceHeader;
 use crate::utils::IFraction;
 use crate::*;
@@ -36,8 +40,19 @@ pub struct Aom {
     aom_config: Option<aom_codec_enc_cfg>,
     config: Option<EncoderConfig>,
     current_layer: u32,
+    // Output of the first pass, read by the last pass.
+    stats: Vec<u8>,
+    pts: i64,
+    // Kept alive for libaom, which does not copy the path.
+    film_grain_table: Option<CString>,
+    roi_map_enabled: bool,
 }
 
+// SAFETY: The libaom context and configuration hold raw pointers, which makes this struct !Send.
+// They are owned by this instance, only used through &mut self, and libaom does not tie an
+// encoder context to the thread that created it.
+unsafe impl Send for Aom {}
+
 fn aom_format(image: &Image, category: Category) -> AvifResult<aom_img_fmt_t> {
     let format = match category {
         Category::Alpha => aom_img_fmt_AOM_IMG_FMT_I420,
@@ -80,6 +95,21 @@ fn aom_seq_profile(image: &Image, category: Category) -> AvifResult<u32> {
     }
 }
 
+fn set_min_max_quantizers(aom_config: &mut aom_codec_enc_cfg, config: &EncoderConfig) {
+    if aom_config.rc_end_usage == aom_rc_mode_AOM_VBR
+        || aom_config.rc_end_usage == aom_rc_mode_AOM_CBR
+    {
+        // cq-level is unused in these modes, so set the min and max quantizer instead.
+        (aom_config.rc_min_quantizer, aom_config.rc_max_quantizer) = config.min_max_quantizers();
+    }
+    if let Some(min_quantizer) = config.aom_options.min_quantizer {
+        aom_config.rc_min_quantizer = min_quantizer;
+    }
+    if let Some(max_quantizer) = config.aom_options.max_quantizer {
+        aom_config.rc_max_quantizer = max_quantizer;
+    }
+}
+
 fn get_aom_scaling_mode_1d(mut fraction: IFraction) -> AvifResult<aom_scaling_mode_1d> {
     fraction.is_valid()?;
     fraction.simplify();
@@ -141,6 +171,149 @@ fn add_aom_pkt_to_output_samples(
     Ok(true)
 }
 
+impl Aom {
+    fn add_pkt(
+        &mut self,
+        pkt: &aom_codec_cx_pkt,
+        output_samples: &mut Vec<Sample>,
+    ) -> AvifResult<bool> {
+        if pkt.kind == aom_codec_cx_pkt_kind_AOM_CODEC_STATS_PKT {
+            // # Safety: buf and sz are guaranteed to be valid as per libaom API contract.
+            let stats = unsafe {
+                std::slice::from_raw_parts(
+                    pkt.data.twopass_stats.buf as *const u8,
+                    pkt.data.twopass_stats.sz,
+                )
+            };
+            self.stats.extend_from_slice(stats);
+            return Ok(true);
+        }
+        add_aom_pkt_to_output_samples(pkt, output_samples)
+    }
+
+    // Applies the typed options other than the tuning, tiling and quantizers.
+    fn set_aom_options(&mut self, config: &EncoderConfig, category: Category) -> AvifResult<()> {
+        let options = &config.aom_options;
+        if let Some(sharpness) = options.sharpness {
+            codec_control!(self, aome_enc_control_id_AOME_SET_SHARPNESS, sharpness);
+        }
+        if let Some(deltaq_mode) = options.deltaq_mode {
+            codec_control!(self, aome_enc_control_id_AV1E_SET_DELTAQ_MODE, deltaq_mode);
+        }
+        if let Some(aq_mode) = options.aq_mode {
+            codec_control!(self, aome_enc_control_id_AV1E_SET_AQ_MODE, aq_mode);
+        }
+        if let Some(enable_chroma_deltaq) = options.enable_chroma_deltaq {
+            codec_control!(
+                self,
+                aome_enc_control_id_AV1E_SET_ENABLE_CHROMA_DELTAQ,
+                enable_chroma_deltaq as i32
+            );
+        }
+        if let Some(tune_content) = options.tune_content {
+            let tune_content = match tune_content {
+                AomTuneContent::Default => aom_tune_content_AOM_CONTENT_DEFAULT,
+                AomTuneContent::Screen => aom_tune_content_AOM_CONTENT_SCREEN,
+                AomTuneContent::Film => aom_tune_content_AOM_CONTENT_FILM,
+            };
+            codec_control!(
+                self,
+                aome_enc_control_id_AV1E_SET_TUNE_CONTENT,
+                tune_content
+            );
+        }
+        if let Some(enable_cdef) = options.enable_cdef {
+            codec_control!(
+                self,
+                aome_enc_control_id_AV1E_SET_ENABLE_CDEF,
+                enable_cdef as i32
+            );
+        }
+        if category != Category::Color {
+            // Film grain is not meaningful for alpha and gain map samples.
+            return Ok(());
+        }
+        if let Some(denoise_noise_level) = options.denoise_noise_level {
+            codec_control!(
+                self,
+                aome_enc_control_id_AV1E_SET_DENOISE_NOISE_LEVEL,
+                denoise_noise_level
+            );
+        }
+        if let Some(film_grain_table) = options.film_grain_table {
+            // The absence of nul characters was checked by Encoder::create_with_settings().
+            self.film_grain_table = Some(CString::new(film_grain_table).unwrap());
+            codec_control!(
+                self,
+                aome_enc_control_id_AV1E_SET_FILM_GRAIN_TABLE,
+                self.film_grain_table.unwrap_ref().as_ptr()
+            );
+        }
+        Ok(())
+    }
+
+    // Signals the quality map of the frame, if any, as segments with a quantizer index delta each.
+    // libaom expects one segment index per 4x4 block of the frame with its dimensions rounded up
+    // to multiples of 8.
+    fn set_roi_map(&mut self, image: &Image, config: &EncoderConfig) -> AvifResult<()> {
+        // # Safety: Zero initializing a C-struct, which disables the ROI map. The relevant fields
+        // are populated in the lines below.
+        let mut roi_map: aom_roi_map_t = unsafe { std::mem::zeroed() };
+        let mut segment_ids: Vec<u8> = Vec::new();
+        if let Some(quality_map) = &config.quality_map {
+            let offsets = quality_map.distinct_offsets();
+            let rows = image.height.div_ceil(8) * 2;
+            let columns = image.width.div_ceil(8) * 2;
+            segment_ids.reserve(usize_from_u32(checked_mul!(rows, columns)?)?);
+            for row in 0..rows {
+                for column in 0..columns {
+                    let offset = quality_map.offset_at(column * 4 + 2, row * 4 + 2, image.width);
+                    let segment_id = offsets.iter().position(|x| *x == offset).unwrap_or(0);
+                    segment_ids.push(segment_id as u8);
+                }
+            }
+            for (segment_id, offset) in offsets.iter().enumerate() {
+                // A quality offset of 100 maps to the largest delta accepted by libaom.
+                roi_map.delta_q[segment_id] = -offset * 63 / 100;
+            }
+            // Do not restrict the reference frames of the segments.
+            roi_map.ref_frame.fill(-1);
+            roi_map.enabled = 1;
+            roi_map.roi_map = segment_ids.as_mut_ptr();
+            roi_map.rows = rows;
+            roi_map.cols = columns;
+        }
+        codec_control!(
+            self,
+            aome_enc_control_id_AOME_SET_ROI_MAP,
+            &mut roi_map as *mut _
+        );
+        self.roi_map_enabled = config.quality_map.is_some();
+        Ok(())
+    }
+
+    fn set_codec_specific_options(&mut self, options: &[(String, String)]) -> AvifResult<()> {
+        for (key, value) in options {
+            if key == "end-usage" {
+                // This key is already processed before initialization of the encoder.
+                continue;
+            }
+            c_str!(key_str, key_str_tmp, key.clone());
+            c_str!(value_str, value_str_tmp, value.clone());
+            // # Safety: Calling a C function with valid parameters.
+            if unsafe {
+                aom_codec_set_option(self.encoder.unwrap_mut() as *mut _, key_str, value_str)
+            } != aom_codec_err_t_AOM_CODEC_OK
+            {
+                return AvifError::unknown_error(format!(
+                    "Unable to set codec specific option: {key} to {value}"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
 impl Encoder for Aom {
     fn encode_image(
         &mut self,
@@ -154,7 +327,8 @@ impl Encoder for Aom {
             let encoder_iface = unsafe { aom_codec_av1_cx() };
             let aom_usage = if config.is_single_image {
                 AOM_USAGE_ALL_INTRA
-            } else if config.speed.unwrap_or(0) >= 7 {
+            } else if config.two_pass.is_none() && config.speed.unwrap_or(0) >= 7 {
+                // The realtime usage does not support two-pass encoding.
                 AOM_USAGE_REALTIME
             } else {
                 AOM_USAGE_GOOD_QUALITY
@@ -221,13 +395,40 @@ impl Encoder for Aom {
                     }
                 };
             }
-            if aom_config.rc_end_usage == aom_rc_mode_AOM_VBR
-                || aom_config.rc_end_usage == aom_rc_mode_AOM_CBR
-            {
-                // cq-level is unused in these modes, so set the min and max quantizer instead.
-                (aom_config.rc_min_quantizer, aom_config.rc_max_quantizer) =
-                    config.min_max_quantizers();
+            if let Some(two_pass) = &config.two_pass {
+                if config.pass == EncoderPass::First {
+                    aom_config.g_pass = aom_enc_pass_AOM_RC_FIRST_PASS;
+                    self.stats.clear();
+                } else {
+                    aom_config.g_pass = aom_enc_pass_AOM_RC_LAST_PASS;
+                    // self.stats is not modified until the encoder is destroyed.
+                    aom_config.rc_twopass_stats_in = aom_fixed_buf_t {
+                        buf: self.stats.as_mut_ptr() as *mut _,
+                        sz: self.stats.len(),
+                    };
+                }
+                aom_config.g_timebase = aom_rational {
+                    num: 1,
+                    den: config.timescale as _,
+                };
+                match two_pass.rate_control {
+                    TwoPassRateControl::TargetBitrate(bitrate) => {
+                        aom_config.rc_end_usage = aom_rc_mode_AOM_VBR;
+                        aom_config.rc_target_bitrate = bitrate;
+                    }
+                    TwoPassRateControl::ConstrainedQuality { max_bitrate } => {
+                        aom_config.rc_end_usage = aom_rc_mode_AOM_CQ;
+                        aom_config.rc_target_bitrate = max_bitrate;
+                    }
+                }
+                if let Some(lookahead) = two_pass.lookahead {
+                    if !config.disable_lagged_output {
+                        aom_config.g_lag_in_frames = lookahead;
+                    }
+                }
             }
+            set_min_max_quantizers(&mut aom_config, config);
+            self.pts = 0;
 
             let mut encoder_uninit: MaybeUninit<aom_codec_ctx_t> = MaybeUninit::uninit();
             // # Safety: Calling a C function with valid parameters.
@@ -258,18 +459,22 @@ impl Encoder for Aom {
             if config.quantizer() == 0 {
                 codec_control!(self, aome_enc_control_id_AV1E_SET_LOSSLESS, 1);
             }
-            if config.tile_rows_log2 != 0 {
-                codec_control!(
-                    self,
-                    aome_enc_control_id_AV1E_SET_TILE_ROWS,
-                    config.tile_rows_log2
-                );
+            let tile_rows_log2 = config
+                .aom_options
+                .tile_rows_log2
+                .map_or(config.tile_rows_log2, |value| value as i32);
+            if tile_rows_log2 != 0 {
+                codec_control!(self, aome_enc_control_id_AV1E_SET_TILE_ROWS, tile_rows_log2);
             }
-            if config.tile_columns_log2 != 0 {
+            let tile_columns_log2 = config
+                .aom_options
+                .tile_columns_log2
+                .map_or(config.tile_columns_log2, |value| value as i32);
+            if tile_columns_log2 != 0 {
                 codec_control!(
                     self,
                     aome_enc_control_id_AV1E_SET_TILE_COLUMNS,
-                    config.tile_columns_log2
+                    tile_columns_log2
                 );
             }
             if config.extra_layer_count > 0 {
@@ -329,30 +534,16 @@ impl Encoder for Aom {
                     1
                 );
             }
+            self.set_aom_options(config, category)?;
             let codec_specific_options = config.codec_specific_options(category);
-            for (key, value) in &codec_specific_options {
-                if key == "end-usage" {
-                    // This key is already processed before initialization of the encoder.
-                    continue;
-                }
-                c_str!(key_str, key_str_tmp, key.clone());
-                c_str!(value_str, value_str_tmp, value.clone());
-                // # Safety: Calling a C function with valid parameters.
-                if unsafe {
-                    aom_codec_set_option(self.encoder.unwrap_mut() as *mut _, key_str, value_str)
-                } != aom_codec_err_t_AOM_CODEC_OK
-                {
-                    return AvifError::unknown_error(format!(
-                        "Unable to set codec specific option: {key} to {value}"
-                    ));
-                }
-            }
+            self.set_codec_specific_options(&codec_specific_options)?;
             if !codec_specific_options.iter().any(|(key, _)| key == "tune") {
-                codec_control!(
-                    self,
-                    aome_enc_control_id_AOME_SET_TUNING,
-                    aom_tune_metric_AOM_TUNE_SSIM
-                );
+                let tune = match config.aom_options.tune {
+                    Some(AomTune::Psnr) => aom_tune_metric_AOM_TUNE_PSNR,
+                    Some(AomTune::Iq) => aom_tune_metric_AOM_TUNE_IQ,
+                    Some(AomTune::Ssim) | None => aom_tune_metric_AOM_TUNE_SSIM,
+                };
+                codec_control!(self, aome_enc_control_id_AOME_SET_TUNING, tune);
             }
             if image.depth == 12 {
                 // libaom may produce integer overflows with 12-bit input when loop restoration is
@@ -373,8 +564,7 @@ impl Encoder for Aom {
                 if aom_config.rc_end_usage == aom_rc_mode_AOM_VBR
                     || aom_config.rc_end_usage == aom_rc_mode_AOM_CBR
                 {
-                    (aom_config.rc_min_quantizer, aom_config.rc_max_quantizer) =
-                        config.min_max_quantizers();
+                    set_min_max_quantizers(aom_config, config);
                     // # Safety: Calling a C function with valid parameters.
                     let err = unsafe {
                         aom_codec_enc_config_set(
@@ -416,6 +606,11 @@ impl Encoder for Aom {
                     config.tile_columns_log2
                 );
             }
+            // Only the options that were added or changed since the previous frame are applied.
+            // This includes the encoder-wide values restored after a frame replaced them.
+            let changed_options =
+                config.changed_codec_specific_options(self.config.unwrap_ref(), category);
+            self.set_codec_specific_options(&changed_options)?;
             self.config = Some(config.clone());
         }
         if self.current_layer > config.extra_layer_count {
@@ -428,6 +623,9 @@ impl Encoder for Aom {
                 self.current_layer
             );
         }
+        if config.quality_map.is_some() || self.roi_map_enabled {
+            self.set_roi_map(image, config)?;
+        }
         let scaling_mode = aom_scaling_mode(&self.config.unwrap_ref().scaling_mode)?;
         if scaling_mode.h_scaling_mode != aom_scaling_mode_1d_AOME_NORMAL
             || scaling_mode.v_scaling_mode != aom_scaling_mode_1d_AOME_NORMAL
@@ -479,8 +677,10 @@ impl Encoder for Aom {
         aom_image.cp = image.color_primaries as u32;
         aom_image.tc = image.transfer_characteristics as u32;
         aom_image.mc = image.matrix_coefficients as u32;
-        // TODO: b/392112497 - force keyframes when necessary.
         let mut encode_flags = 0i64;
+        if config.force_keyframe {
+            encode_flags |= AOM_EFLAG_FORCE_KF as i64;
+        }
         if self.current_layer > 0 {
             encode_flags |= AOM_EFLAG_NO_REF_GF as i64
                 | AOM_EFLAG_NO_REF_ARF as i64
@@ -489,13 +689,19 @@ impl Encoder for Aom {
                 | AOM_EFLAG_NO_UPD_GF as i64
                 | AOM_EFLAG_NO_UPD_ARF as i64;
         }
+        // The timing only matters for bitrate targets.
+        let (pts, duration) = match config.two_pass {
+            Some(_) => (self.pts, config.duration),
+            None => (0, 1),
+        };
+        self.pts = checked_add!(self.pts, i64_from_u64(duration)?)?;
         // # Safety: Calling a C function with valid parameters.
         let err = unsafe {
             aom_codec_encode(
                 self.encoder.unwrap_mut() as *mut _,
                 &aom_image as *const _,
-                0,
-                1,
+                pts,
+                duration as _,
                 encode_flags as _,
             )
         };
@@ -513,7 +719,7 @@ impl Encoder for Aom {
             }
             // # Safety: pkt is guaranteed to be valid and not null (libaom API contract).
             let pkt = unsafe { *pkt };
-            add_aom_pkt_to_output_samples(&pkt, output_samples)?;
+            self.add_pkt(&pkt, output_samples)?;
         }
         if config.is_single_image
             || (config.extra_layer_count > 0 && config.extra_layer_count == self.current_layer)
@@ -562,12 +768,20 @@ impl Encoder for Aom {
                 }
                 // # Safety: pkt is guaranteed to be valid and not null (libaom API contract).
                 let pkt = unsafe { *pkt };
-                got_packet = add_aom_pkt_to_output_samples(&pkt, output_samples)?;
+                got_packet = self.add_pkt(&pkt, output_samples)?;
             }
             if !got_packet {
                 break;
             }
         }
+        if self.config.unwrap_ref().pass == EncoderPass::First {
+            // The last pass starts with a new encoder instance.
+            // # Safety: Calling a C function with valid parameters.
+            unsafe {
+                aom_codec_destroy(self.encoder.unwrap_mut() as *mut _);
+            }
+            self.encoder = None;
+        }
         Ok(())
     }
 
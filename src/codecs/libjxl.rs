@@ -49,6 +49,11 @@ pub struct Libjxl {
     reconstructed_jxl: Option<Vec<u8>>,
 }
 
+// SAFETY: The libjxl encoder and decoder are raw pointers, which makes this struct !Send. They
+// are created and destroyed by this instance and only used through &mut self. libjxl allows
+// these objects to be used from any thread as long as the calls are not concurrent.
+unsafe impl Send for Libjxl {}
+
 // Convenient error mapping.
 trait JxlEncoderStatusTrait {
     fn map_enc_err(self, encoder: *mut JxlEncoder) -> Result<(), AvifError>;
@@ -30,6 +30,8 @@ pub mod libjxl;
 use crate::decoder::item::Item;
 use crate::decoder::GridImageHelper;
 use crate::image::Image;
+#[cfg(feature = "encoder")]
+use crate::image::QualityMap;
 use crate::parser::mp4box::CodecConfiguration;
 use crate::AndroidMediaCodecOutputColorFormat;
 use crate::AvifResult;
@@ -96,6 +98,25 @@ pub(crate) struct EncoderConfig {
     pub threads: u32,
     pub scaling_mode: ScalingMode,
     pub codec_specific_options: CodecSpecificOptions,
+    pub force_keyframe: bool,
+    pub two_pass: Option<TwoPass>,
+    pub aom_options: AomOptions,
+    pub quality_map: Option<QualityMap>,
+    pub pass: EncoderPass,
+    // Timing of the encoded frame, only used with two_pass.
+    pub timescale: u64,
+    pub duration: u64,
+}
+
+#[cfg(feature = "encoder")]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum EncoderPass {
+    #[default]
+    Single,
+    // Collects statistics without producing samples. Calling Encoder::finish() ends the pass and
+    // the next call to Encoder::encode_image() starts the last pass.
+    First,
+    Last,
 }
 
 #[cfg(feature = "encoder")]
@@ -133,6 +154,20 @@ impl EncoderConfig {
         options
     }
 
+    // Returns the options of |category| that were added or changed since |previous|. The options
+    // of |previous| that are no longer set are not returned because they cannot be reverted.
+    pub(crate) fn changed_codec_specific_options(
+        &self,
+        previous: &EncoderConfig,
+        category: Category,
+    ) -> Vec<(String, String)> {
+        let previous_options = previous.codec_specific_options(category);
+        self.codec_specific_options(category)
+            .into_iter()
+            .filter(|option| !previous_options.contains(option))
+            .collect()
+    }
+
     pub(crate) fn quantizer(&self) -> i32 {
         ((100 - (self.quality.clamp(0.0, 100.0) as i32)) * 63 + 50) / 100
     }
@@ -151,7 +186,7 @@ impl EncoderConfig {
 }
 
 #[cfg(feature = "encoder")]
-pub(crate) trait Encoder {
+pub(crate) trait Encoder: Send {
     fn encode_image(
         &mut self,
         image: &Image,
@@ -231,4 +266,32 @@ mod tests {
         expected.sort();
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn changed_codec_specific_options() {
+        let encoder_options = HashMap::from([
+            ((None, String::from("abcd")), String::from("value1")),
+            ((None, String::from("efgh")), String::from("value2")),
+        ]);
+        let mut frame_options = encoder_options.clone();
+        frame_options.insert((None, String::from("abcd")), String::from("frame_value"));
+        let frame_config = EncoderConfig {
+            codec_specific_options: frame_options,
+            ..Default::default()
+        };
+        let next_config = EncoderConfig {
+            codec_specific_options: encoder_options,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            frame_config.changed_codec_specific_options(&next_config, Category::Color),
+            vec![(String::from("abcd"), String::from("frame_value"))]
+        );
+        // The following frame does not inherit the option of the previous frame.
+        assert_eq!(
+            next_config.changed_codec_specific_options(&frame_config, Category::Color),
+            vec![(String::from("abcd"), String::from("value1"))]
+        );
+    }
 }
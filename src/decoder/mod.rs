@@ -41,6 +41,9 @@ use crate::parser::exif;
 use crate::parser::mp4box;
 use crate::parser::mp4box::*;
 use crate::parser::obu::Av1SequenceHeader;
+use crate::plugin::*;
+use crate::utils::clap::CleanAperture;
+use crate::utils::clap::CropRect;
 use crate::utils::pixels::ChannelIdc;
 use crate::utils::pixels::Pixels;
 use crate::*;
@@ -71,7 +74,16 @@ pub type GenericIO = Box<dyn IO>;
 pub(crate) type Codec = Box<dyn crate::codecs::Decoder>;
 
 impl CodecChoice {
-    fn get_decoder_codec(&self, compression_format: CompressionFormat) -> Option<Codec> {
+    pub(crate) fn get_decoder_codec(
+        &self,
+        compression_format: CompressionFormat,
+        plugins: &[DecoderPluginFactory],
+    ) -> Option<Codec> {
+        self.get_builtin_decoder_codec(compression_format)
+            .or_else(|| find_decoder_plugin(*self, compression_format, plugins))
+    }
+
+    fn get_builtin_decoder_codec(&self, compression_format: CompressionFormat) -> Option<Codec> {
         match compression_format {
             CompressionFormat::Avif => {
                 if matches!(self, CodecChoice::Aom) {
@@ -154,12 +166,21 @@ pub struct Settings {
     pub allow_incremental: bool,
     pub image_content_to_decode: ImageContentType,
     pub codec_choice: CodecChoice,
+    // Decoders that can be selected with CodecChoice::Plugin.
+    pub codec_plugins: Vec<DecoderPluginFactory>,
     pub image_size_limit: Option<NonZero<u32>>,
     pub image_dimension_limit: Option<NonZero<u32>>,
     pub image_count_limit: Option<NonZero<u32>>,
     pub max_threads: u32,
     pub android_mediacodec_output_color_format: AndroidMediaCodecOutputColorFormat,
     pub allow_sample_transform: bool,
+    // If set, the color planes of the decoded image are converted into this semi-planar format
+    // (AndroidNv12, AndroidNv21 or AndroidP010) before being returned by image().
+    pub semi_planar_output_format: Option<PixelFormat>,
+    // If true, the clap, irot and imir properties are applied to the pixels of the decoded image
+    // and gain map before they are returned by image() and gainmap(). The dimensions reported
+    // after parse() are the ones before the transformations.
+    pub apply_transformations: bool,
 }
 
 impl Default for Settings {
@@ -173,12 +194,15 @@ impl Default for Settings {
             allow_incremental: false,
             image_content_to_decode: ImageContentType::ColorAndAlpha,
             codec_choice: Default::default(),
+            codec_plugins: Vec::new(),
             image_size_limit: NonZero::new(DEFAULT_IMAGE_SIZE_LIMIT),
             image_dimension_limit: NonZero::new(DEFAULT_IMAGE_DIMENSION_LIMIT),
             image_count_limit: NonZero::new(DEFAULT_IMAGE_COUNT_LIMIT),
             max_threads: 1,
             android_mediacodec_output_color_format: AndroidMediaCodecOutputColorFormat::default(),
             allow_sample_transform: false,
+            semi_planar_output_format: None,
+            apply_transformations: false,
         }
     }
 }
@@ -352,6 +376,10 @@ pub struct Decoder {
     gainmap: GainMap,
     gainmap_present: bool,
     image: Image,
+    // Holds the decoded image after the conversions requested in Settings, if any.
+    output_image: Option<Image>,
+    // Holds the gain map with the transformations of the decoded image applied, if requested.
+    output_gainmap: Option<GainMap>,
     extra_inputs: [Image; DecodingItem::MAX_EXTRA_INPUTS],
     source: Source,
     tile_info: [TileInfo; DecodingItem::COUNT],
@@ -469,7 +497,7 @@ impl Decoder {
         self.repetition_count
     }
     pub fn gainmap(&self) -> &GainMap {
-        &self.gainmap
+        self.output_gainmap.as_ref().unwrap_or(&self.gainmap)
     }
     pub fn gainmap_present(&self) -> bool {
         self.gainmap_present
@@ -984,10 +1012,19 @@ impl Decoder {
         if self.settings.codec_choice == CodecChoice::Libgav1 {
             return Err(AvifError::NotImplemented);
         }
+        if self
+            .settings
+            .semi_planar_output_format
+            .is_some_and(|x| !x.is_semi_planar())
+        {
+            return AvifError::invalid_argument();
+        }
         if self.parsing_complete() {
             // Parse was called again. Reset the data and start over.
             self.parse_state = ParseState::None;
         }
+        self.output_image = None;
+        self.output_gainmap = None;
         if self.io.is_none() {
             return AvifError::io_not_set();
         }
@@ -1533,11 +1570,10 @@ impl Decoder {
 
     fn create_codec(&mut self, decoding_item: DecodingItem, tile_index: usize) -> AvifResult<()> {
         let tile = &self.tiles[decoding_item.usize()][tile_index];
-        let mut codec: Codec = match self
-            .settings
-            .codec_choice
-            .get_decoder_codec(tile.codec_config.compression_format())
-        {
+        let mut codec: Codec = match self.settings.codec_choice.get_decoder_codec(
+            tile.codec_config.compression_format(),
+            &self.settings.codec_plugins,
+        ) {
             None => return AvifError::no_codec_available(),
             Some(codec) => codec,
         };
@@ -1818,10 +1854,9 @@ impl Decoder {
                 }
             }
             if !tiles_slice1.is_empty() {
+                // The layers of an overlay may have different dimensions.
                 let first_tile_image = &tiles_slice1[0].image;
-                if tile.image.width != first_tile_image.width
-                    || tile.image.height != first_tile_image.height
-                    || tile.image.depth != first_tile_image.depth
+                if tile.image.depth != first_tile_image.depth
                     || tile.image.yuv_format != first_tile_image.yuv_format
                     || tile.image.yuv_range != first_tile_image.yuv_range
                     || tile.image.color_primaries != first_tile_image.color_primaries
@@ -1997,7 +2032,10 @@ impl Decoder {
         // Has to be a grid.
         self.tile_info[decoding_item.usize()].is_grid()
             // Has to be one of the supported codecs.
-            && matches!(codec, CodecChoice::MediaCodec | CodecChoice::Dav1d)
+            && matches!(
+                codec,
+                CodecChoice::MediaCodec | CodecChoice::Dav1d | CodecChoice::Plugin(_)
+            )
             // All the tiles must use the same codec instance.
             && self.tiles[decoding_item.usize()][1..]
                 .iter()
@@ -2074,6 +2112,8 @@ impl Decoder {
             (_, Ok(_)) | (true, Err(AvifError::WaitingOnIo)) => {}
             (_, Err(err)) => return Err(err),
         }
+        self.output_image = None;
+        self.output_gainmap = None;
         self.decode_tiles(next_image_index as usize)?;
 
         if !self.tile_info[DecodingItem::COLOR.usize()]
@@ -2091,11 +2131,82 @@ impl Decoder {
                 .allocate_planes_and_apply(&self.extra_inputs, &mut self.image)?;
         }
 
+        self.create_output_image()?;
+
         self.image_index = next_image_index;
         self.image_timing = self.nth_image_timing(self.image_index as u32)?;
         Ok(())
     }
 
+    // Returns the clean aperture of the color image scaled to the dimensions of the gain map, which
+    // may be smaller than the color image.
+    fn gainmap_clap(&self) -> AvifResult<Option<CleanAperture>> {
+        let Some(clap) = &self.image.clap else {
+            return Ok(None);
+        };
+        let gainmap = &self.gainmap.image;
+        // Only the region matters here, so do not restrict the offsets of the color image.
+        let rect = CropRect::create_from(
+            clap,
+            self.image.width,
+            self.image.height,
+            PixelFormat::Yuv444,
+        )?
+        .scaled(
+            self.image.width,
+            self.image.height,
+            gainmap.width,
+            gainmap.height,
+            gainmap.yuv_format,
+        )?;
+        Ok(Some(CleanAperture::create_from(
+            &rect,
+            gainmap.width,
+            gainmap.height,
+            gainmap.yuv_format,
+        )?))
+    }
+
+    fn create_output_image(&mut self) -> AvifResult<()> {
+        let mut output_image = None;
+        if self.settings.apply_transformations
+            && (self.image.clap.is_some()
+                || self.image.irot_angle.is_some()
+                || self.image.imir_axis.is_some())
+        {
+            output_image = Some(self.image.transformed_image()?);
+            if self.gainmap.image.has_plane(Plane::Y) {
+                // The transformative properties of the color item also apply to the gain map so
+                // that it stays aligned with the transformed image.
+                let gainmap_image = self.gainmap.image.transformed_image_with(
+                    self.gainmap_clap()?.as_ref(),
+                    self.image.irot_angle,
+                    self.image.imir_axis,
+                )?;
+                self.output_gainmap = Some(self.gainmap.try_clone_with_image(gainmap_image)?);
+            }
+        }
+        if let Some(yuv_format) = self.settings.semi_planar_output_format {
+            let image = output_image.as_ref().unwrap_or(&self.image);
+            if image.yuv_format != yuv_format {
+                output_image = Some(if image.yuv_format.is_semi_planar() {
+                    image
+                        .convert_to_planar()?
+                        .convert_to_semi_planar(yuv_format)?
+                } else {
+                    image.convert_to_semi_planar(yuv_format)?
+                });
+            }
+        }
+        if let Some(image) = &mut output_image {
+            image.exif = self.image.exif.try_clone()?;
+            image.xmp = self.image.xmp.try_clone()?;
+            image.icc = self.image.icc.try_clone()?;
+        }
+        self.output_image = output_image;
+        Ok(())
+    }
+
     fn is_current_frame_fully_decoded(&self) -> bool {
         if !self.parsing_complete() {
             return false;
@@ -2141,7 +2252,7 @@ impl Decoder {
 
     pub fn image(&self) -> Option<&Image> {
         if self.parsing_complete() {
-            Some(&self.image)
+            Some(self.output_image.as_ref().unwrap_or(&self.image))
         } else {
             None
         }
@@ -0,0 +1,77 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::encoder::*;
+
+impl Encoder {
+    // Encodes the cells of the image with each codec given to add_alternative_codec().
+    pub(crate) fn encode_alternatives(
+        &mut self,
+        grid_columns: u32,
+        grid_rows: u32,
+        cell_images: &[&Image],
+    ) -> AvifResult<()> {
+        for codec_choice in self.alternative_codec_choices.clone() {
+            let settings = Settings {
+                codec_choice,
+                header_format: HeaderFormat::Default,
+                recipe: Recipe::None,
+                two_pass: None,
+                // The cells were already optimized, if enabled.
+                input_optimizations: InputOptimizations::default(),
+                aom_options: if codec_choice == CodecChoice::Aom {
+                    self.settings.aom_options
+                } else {
+                    AomOptions::default()
+                },
+                ..self.settings
+            };
+            let mut encoder = Encoder::create_with_settings(&settings)?;
+            encoder.codec_plugins = self.codec_plugins.clone();
+            encoder.add_image_impl(
+                grid_columns,
+                grid_rows,
+                cell_images,
+                /*duration=*/ 0,
+                /*is_single_image=*/ true,
+                None,
+                &FrameOptions::default(),
+            )?;
+            for warning in &encoder.warnings {
+                self.add_warning(warning);
+            }
+            self.alternative_encoders.push(encoder);
+        }
+        Ok(())
+    }
+
+    // Moves the items of the alternative renditions to this encoder and groups their primary
+    // items with the primary item. Must be called once the codecs are finished.
+    pub(crate) fn finish_alternatives(&mut self) -> AvifResult<()> {
+        if self.alternative_encoders.is_empty() {
+            return Ok(());
+        }
+        for mut encoder in std::mem::take(&mut self.alternative_encoders) {
+            encoder.finish_codecs()?;
+            let first_item_index = self.items.len();
+            let primary_item_id = self.append_items_of(&mut encoder)?;
+            for item in &mut self.items[first_item_index..] {
+                item.codec_choice = Some(encoder.settings.codec_choice);
+            }
+            self.alternative_item_ids.push(primary_item_id);
+        }
+        self.alternative_item_ids.push(self.primary_item_id);
+        Ok(())
+    }
+}
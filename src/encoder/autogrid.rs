@@ -0,0 +1,386 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::encoder::*;
+use crate::gainmap::GainMap;
+use crate::utils::clap::CleanAperture;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct FrameSizeLimits {
+    pub width: u32,
+    pub height: u32,
+    pub area: u64,
+}
+
+impl CodecChoice {
+    pub(crate) fn frame_size_limits(&self) -> FrameSizeLimits {
+        match self.actual() {
+            // JPEG XL codestream level 5 (ISO/IEC 18181-2:2024, Section 6.2).
+            #[cfg(feature = "jpegxl")]
+            Self::Libjxl => FrameSizeLimits {
+                width: 1 << 18,
+                height: 1 << 18,
+                area: 1 << 28,
+            },
+            // AV1 level 6.x (AV1 specification, Annex A.3), which is the highest level allowed by
+            // the AVIF Advanced profile.
+            _ => FrameSizeLimits {
+                width: 16384,
+                height: 8704,
+                area: 35651584,
+            },
+        }
+    }
+}
+
+// Returns the size of each cell along a dimension of |size| samples split into |count| cells.
+fn cell_size(size: u32, count: u32, is_subsampled: bool) -> u32 {
+    let cell_size = size.div_ceil(count);
+    if is_subsampled {
+        round_up_to_even(cell_size)
+    } else {
+        cell_size
+    }
+}
+
+fn round_up_to_even(value: u32) -> u32 {
+    value + (value & 1)
+}
+
+// Returns the number of columns and rows of the grid that an image of |width|x|height| samples
+// has to be split into for each cell to fit in |limits|, or None if no split is needed.
+pub(crate) fn grid_dimensions(
+    width: u32,
+    height: u32,
+    yuv_format: PixelFormat,
+    limits: &FrameSizeLimits,
+) -> AvifResult<Option<(u32, u32)>> {
+    if width <= limits.width
+        && height <= limits.height
+        && width as u64 * height as u64 <= limits.area
+    {
+        return Ok(None);
+    }
+    let is_subsampled_x = yuv_format.chroma_shift_x().0 != 0;
+    let is_subsampled_y = yuv_format.chroma_shift_y() != 0;
+    let mut columns = width.div_ceil(limits.width);
+    let mut rows = height.div_ceil(limits.height);
+    loop {
+        let cell_width = cell_size(width, columns, is_subsampled_x);
+        let cell_height = cell_size(height, rows, is_subsampled_y);
+        if cell_width as u64 * cell_height as u64 <= limits.area {
+            break;
+        }
+        // Split along the longest cell dimension to keep the cells as square as possible.
+        if cell_width >= cell_height {
+            columns += 1;
+        } else {
+            rows += 1;
+        }
+    }
+    if columns > 256 || rows > 256 {
+        return AvifError::invalid_image_grid(format!(
+            "{width}x{height} image cannot be split into at most 256x256 cells"
+        ));
+    }
+    Ok(Some((columns, rows)))
+}
+
+// Splits |image| into |columns|x|rows| cells of equal dimensions, except for the right-most and
+// bottom-most cells which may be smaller. The cells point to the pixels of |image|, except when
+// the image dimensions are odd in a subsampled direction. In that case the last cells are copied
+// and padded by one sample, because the grid dimensions have to be even, and the first cell gets
+// a clean aperture cropping the padding out. The first cell also carries the metadata of |image|.
+fn split_image(image: &Image, columns: u32, rows: u32, is_gainmap: bool) -> AvifResult<Vec<Image>> {
+    let is_subsampled_x = image.yuv_format.chroma_shift_x().0 != 0;
+    let is_subsampled_y = image.yuv_format.chroma_shift_y() != 0;
+    let cell_width = cell_size(image.width, columns, is_subsampled_x);
+    let cell_height = cell_size(image.height, rows, is_subsampled_y);
+    let grid_width = if is_subsampled_x { round_up_to_even(image.width) } else { image.width };
+    let grid_height = if is_subsampled_y { round_up_to_even(image.height) } else { image.height };
+    if checked_mul!(cell_width, columns - 1)? >= image.width
+        || checked_mul!(cell_height, rows - 1)? >= image.height
+    {
+        return AvifError::invalid_image_grid(format!(
+            "{}x{} image cannot be split into {columns}x{rows} cells",
+            image.width, image.height
+        ));
+    }
+    let mut cells = Vec::new();
+    for row in 0..rows {
+        for column in 0..columns {
+            let rect = CropRect {
+                x: column * cell_width,
+                y: row * cell_height,
+                width: cell_width.min(image.width - column * cell_width),
+                height: cell_height.min(image.height - row * cell_height),
+            };
+            let mut cell = image.sub_image(&rect)?;
+            let padded_width = cell_width.min(grid_width - rect.x);
+            let padded_height = cell_height.min(grid_height - rect.y);
+            if padded_width != rect.width || padded_height != rect.height {
+                let mut padded_cell = cell.shallow_clone();
+                padded_cell.width = padded_width;
+                padded_cell.height = padded_height;
+                padded_cell.copy_and_pad(&cell)?;
+                padded_cell.quality_map = cell.quality_map.take();
+                cell = padded_cell;
+            }
+            cells.push(cell);
+        }
+    }
+    if !is_gainmap {
+        let first_cell = &mut cells[0];
+        first_cell.exif = image.exif.try_clone()?;
+        first_cell.xmp = image.xmp.try_clone()?;
+        first_cell.icc = image.icc.try_clone()?;
+        if grid_width != image.width || grid_height != image.height || image.clap.is_some() {
+            let crop_rect = match &image.clap {
+                Some(clap) => {
+                    CropRect::create_from(clap, image.width, image.height, image.yuv_format)?
+                }
+                None => CropRect {
+                    x: 0,
+                    y: 0,
+                    width: image.width,
+                    height: image.height,
+                },
+            };
+            first_cell.clap = Some(CleanAperture::create_from(
+                &crop_rect,
+                grid_width,
+                grid_height,
+                image.yuv_format,
+            )?);
+        }
+    }
+    Ok(cells)
+}
+
+impl Encoder {
+    // Encodes |image| and |gainmap| as grids of equal cells if |image| exceeds the frame size
+    // limits of the codec, or as single images otherwise.
+    pub(crate) fn add_image_or_auto_grid(
+        &mut self,
+        image: &Image,
+        gainmap: Option<&GainMap>,
+        is_single_image: bool,
+    ) -> AvifResult<()> {
+        let limits = self.settings.codec_choice.frame_size_limits();
+        let (columns, rows) =
+            match grid_dimensions(image.width, image.height, image.yuv_format, &limits)? {
+                Some(grid_dimensions) => grid_dimensions,
+                None => {
+                    return self.add_image_impl(
+                        1,
+                        1,
+                        &[image],
+                        0,
+                        is_single_image,
+                        gainmap.as_ref().map(std::slice::from_ref),
+                        &FrameOptions::default(),
+                    )
+                }
+            };
+        let cells = split_image(image, columns, rows, /*is_gainmap=*/ false)?;
+        let cell_refs: Vec<&Image> = cells.iter().collect();
+        let gainmap = match gainmap {
+            Some(gainmap) => gainmap,
+            None => {
+                return self.add_image_impl(
+                    columns,
+                    rows,
+                    &cell_refs,
+                    0,
+                    is_single_image,
+                    None,
+                    &FrameOptions::default(),
+                )
+            }
+        };
+        let mut gainmap_cells = Vec::new();
+        for cell in split_image(&gainmap.image, columns, rows, /*is_gainmap=*/ true)? {
+            gainmap_cells.push(GainMap {
+                image: cell,
+                metadata: gainmap.metadata.clone(),
+                alt_icc: gainmap.alt_icc.try_clone()?,
+                alt_color_primaries: gainmap.alt_color_primaries,
+                alt_transfer_characteristics: gainmap.alt_transfer_characteristics,
+                alt_matrix_coefficients: gainmap.alt_matrix_coefficients,
+                alt_yuv_range: gainmap.alt_yuv_range,
+                alt_plane_count: gainmap.alt_plane_count,
+                alt_plane_depth: gainmap.alt_plane_depth,
+                alt_clli: gainmap.alt_clli,
+            });
+        }
+        let gainmap_refs: Vec<&GainMap> = gainmap_cells.iter().collect();
+        self.add_image_impl(
+            columns,
+            rows,
+            &cell_refs,
+            0,
+            is_single_image,
+            Some(&gainmap_refs),
+            &FrameOptions::default(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    const AV1_LIMITS: FrameSizeLimits = FrameSizeLimits {
+        width: 16384,
+        height: 8704,
+        area: 35651584,
+    };
+
+    #[test_case(16384, 2176, PixelFormat::Yuv420, None ; "max width")]
+    #[test_case(4096, 8704, PixelFormat::Yuv420, None ; "max height")]
+    #[test_case(5968, 5968, PixelFormat::Yuv420, None ; "max area")]
+    #[test_case(16385, 64, PixelFormat::Yuv420, Some((2, 1)) ; "too wide")]
+    #[test_case(64, 8705, PixelFormat::Yuv444, Some((1, 2)) ; "too tall")]
+    #[test_case(5971, 5971, PixelFormat::Yuv444, Some((2, 1)) ; "too large")]
+    #[test_case(65536, 4000, PixelFormat::Yuv420, Some((8, 1)) ; "panorama")]
+    #[test_case(20000, 30000, PixelFormat::Yuv400, Some((4, 5)) ; "scan")]
+    fn grid_dims(width: u32, height: u32, yuv_format: PixelFormat, expected: Option<(u32, u32)>) {
+        let result = grid_dimensions(width, height, yuv_format, &AV1_LIMITS).unwrap();
+        assert_eq!(result, expected);
+        if let Some((columns, rows)) = result {
+            let cell_width = cell_size(width, columns, yuv_format == PixelFormat::Yuv420);
+            let cell_height = cell_size(height, rows, yuv_format == PixelFormat::Yuv420);
+            assert!(cell_width <= AV1_LIMITS.width);
+            assert!(cell_height <= AV1_LIMITS.height);
+            assert!(cell_width as u64 * cell_height as u64 <= AV1_LIMITS.area);
+        }
+    }
+
+    #[test]
+    fn too_many_cells() {
+        assert!(grid_dimensions(16384 * 256 + 1, 64, PixelFormat::Yuv420, &AV1_LIMITS).is_err());
+        assert!(grid_dimensions(64, 8704 * 256 + 1, PixelFormat::Yuv420, &AV1_LIMITS).is_err());
+    }
+
+    #[test_case(256, 128, PixelFormat::Yuv420, 2, 1, false ; "even")]
+    #[test_case(255, 129, PixelFormat::Yuv420, 2, 2, true ; "odd 420")]
+    #[test_case(255, 129, PixelFormat::Yuv422, 2, 2, true ; "odd 422")]
+    #[test_case(255, 129, PixelFormat::Yuv444, 2, 2, false ; "odd 444")]
+    fn split(
+        width: u32,
+        height: u32,
+        yuv_format: PixelFormat,
+        columns: u32,
+        rows: u32,
+        padded: bool,
+    ) -> AvifResult<()> {
+        let mut image = Image {
+            width,
+            height,
+            depth: 8,
+            yuv_format,
+            exif: vec![1, 2, 3],
+            ..Default::default()
+        };
+        image.allocate_planes(Category::Color)?;
+        image.allocate_planes(Category::Alpha)?;
+        for plane in ALL_PLANES {
+            for y in 0..image.height(plane) {
+                for (x, value) in image.row_mut(plane, y as u32)?.iter_mut().enumerate() {
+                    *value = (x * 3 + y * 7) as u8;
+                }
+            }
+        }
+        let cells = split_image(&image, columns, rows, false)?;
+        assert_eq!(cells.len(), (columns * rows) as usize);
+        assert_eq!(cells[0].exif, image.exif);
+        assert_eq!(cells[0].clap.is_some(), padded);
+        if let Some(clap) = &cells[0].clap {
+            let grid_width = cells[0].width * (columns - 1) + cells.last().unwrap().width;
+            let grid_height = cells[0].height * (rows - 1) + cells.last().unwrap().height;
+            let crop_rect = CropRect::create_from(clap, grid_width, grid_height, yuv_format)?;
+            assert_eq!(
+                (crop_rect.x, crop_rect.y, crop_rect.width, crop_rect.height),
+                (0, 0, width, height)
+            );
+        }
+        // Check that the cells cover the image. The padding is not checked as it is cropped out.
+        for (index, cell) in cells.iter().enumerate() {
+            let x = (index as u32 % columns) * cells[0].width;
+            let y = (index as u32 / columns) * cells[0].height;
+            for plane in ALL_PLANES {
+                let (cell_x, cell_y) = if plane == Plane::Y || plane == Plane::A {
+                    (x as usize, y as usize)
+                } else {
+                    (
+                        yuv_format.apply_chroma_shift_x(x) as usize,
+                        yuv_format.apply_chroma_shift_y(y) as usize,
+                    )
+                };
+                let width = cell.width(plane).min(image.width(plane) - cell_x);
+                let height = cell.height(plane).min(image.height(plane) - cell_y);
+                for cell_row in 0..height {
+                    let expected = image.row(plane, (cell_y + cell_row) as u32)?;
+                    assert_eq!(
+                        &cell.row(plane, cell_row as u32)?[..width],
+                        &expected[cell_x..cell_x + width]
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test_case(QualityMap::Blocks {
+        block_size: 32,
+        offsets: (0..24).map(|index| index % 5 - 2).collect(),
+    } ; "blocks")]
+    #[test_case(QualityMap::Regions(vec![
+        QualityRegion {
+            rect: CropRect { x: 100, y: 10, width: 60, height: 50 },
+            quality_offset: 20,
+        },
+        QualityRegion {
+            rect: CropRect { x: 0, y: 0, width: 10, height: 96 },
+            quality_offset: -30,
+        },
+    ]) ; "regions")]
+    fn split_quality_map(quality_map: QualityMap) -> AvifResult<()> {
+        let mut image = Image {
+            width: 255,
+            height: 96,
+            depth: 8,
+            yuv_format: PixelFormat::Yuv444,
+            quality_map: Some(quality_map.clone()),
+            ..Default::default()
+        };
+        image.allocate_planes(Category::Color)?;
+        assert!(quality_map.is_valid(image.width, image.height));
+        let cells = split_image(&image, 2, 1, false)?;
+        for (index, cell) in cells.iter().enumerate() {
+            let cell_map = cell.quality_map.as_ref().unwrap();
+            assert!(cell_map.is_valid(cell.width, cell.height));
+            for y in 0..cell.height {
+                for x in 0..cell.width {
+                    assert_eq!(
+                        cell_map.offset_at(x, y, cell.width),
+                        quality_map.offset_at(index as u32 * 128 + x, y, image.width)
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::encoder::*;
+
+impl Encoder {
+    // Makes the frame chosen with FrameOptions::cover, or the image given to add_cover_image(),
+    // the primary item. Must be called once the codecs are finished.
+    pub(crate) fn finish_cover(&mut self) -> AvifResult<()> {
+        if let Some(frame_index) = self.cover_frame_index {
+            for item in &mut self.items {
+                if item.samples.len() > frame_index {
+                    item.iloc_sample_index = frame_index;
+                }
+            }
+            return Ok(());
+        }
+        let mut cover = match self.cover.take() {
+            Some(cover) => cover,
+            None => return Ok(()),
+        };
+        if !self.is_sequence() {
+            return AvifError::invalid_argument();
+        }
+        cover.finish_codecs()?;
+        for item in &mut self.items {
+            if !item.samples.is_empty() {
+                item.is_track_only = true;
+            }
+        }
+        let first_cover_item_index = self.items.len();
+        let primary_item_id = self.append_items_of(&mut cover)?;
+        for item in &mut self.items[first_cover_item_index..] {
+            item.is_cover = true;
+        }
+        // The Exif and XMP items describe the primary item.
+        for item in &mut self.items {
+            if item.iref_type.as_deref() == Some("cdsc") {
+                item.iref_to_id = Some(primary_item_id);
+            }
+        }
+        self.primary_item_id = primary_item_id;
+        self.cover_image_metadata = std::mem::take(&mut cover.image_metadata);
+        Ok(())
+    }
+}
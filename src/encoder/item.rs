@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::decoder::tile::Overlay;
+use crate::decoder::Extent;
 use crate::encoder::*;
 use crate::internal_utils::stream::*;
 use crate::utils::clap::CleanAperture;
@@ -28,21 +30,38 @@ pub(crate) struct Item {
     pub is_sato_least_significant_input: bool,
     pub codec: Option<Codec>,
     pub samples: Vec<Sample>,
+    // Sample referenced by the iloc extent of an item with several samples (see
+    // FrameOptions::cover).
+    pub iloc_sample_index: usize,
     pub codec_configuration: Option<CodecConfiguration>,
     pub cell_index: usize,
     pub hidden_image: bool,
     pub infe_name: String,
     pub infe_content_type: String,
     pub mdat_offset_locations: Vec<usize>,
+    // Position in the output of the samples (or of the metadata payload) already written by a
+    // streaming encoder. See Encoder::set_output().
+    pub written_samples: Vec<Extent>,
     pub iref_to_id: Option<u16>, // If some, then make an iref from this id to iref_to_id.
     pub iref_type: Option<String>,
     pub grid: Option<Grid>,
+    pub overlay: Option<Overlay>,
+    pub dimensions: Option<(u32, u32)>, // If some, used instead of the image metadata dimensions.
+    pub depth: Option<u8>,              // If some, used instead of the image metadata depth.
     pub associations: Vec<(
         u8,   // 1-based property_index
         bool, // essential
     )>,
     pub extra_layer_count: u32,
     pub dimg_from_id: Option<u16>, // If some, then make an iref from dimg_from_id to this id.
+    pub is_thumbnail: bool,
+    // True if the item belongs to the image given to Encoder::add_cover_image(). Such items are
+    // only written in the meta box.
+    pub is_cover: bool,
+    // True if the item is an image sequence track replaced by a cover image in the meta box.
+    pub is_track_only: bool,
+    pub codec_choice: Option<CodecChoice>, // If some, used instead of Settings::codec_choice.
+    pub thmb_to_id: Option<u16>, // If some, then make a 'thmb' iref from this id to thmb_to_id.
     pub metadata_payload: Vec<u8>,
 }
 
@@ -59,8 +78,19 @@ impl fmt::Debug for Item {
 }
 
 impl Item {
+    // Discards the property associations and offset locations recorded while writing the boxes
+    // of this item, so that the boxes can be written again.
+    pub(crate) fn clear_written_boxes(&mut self) {
+        self.associations.clear();
+        self.mdat_offset_locations.clear();
+    }
+
     pub(crate) fn has_ipma(&self) -> bool {
-        self.grid.is_some() || self.codec.is_some() || self.is_tmap() || self.is_sato()
+        self.grid.is_some()
+            || self.overlay.is_some()
+            || self.codec.is_some()
+            || self.is_tmap()
+            || self.is_sato()
     }
 
     pub(crate) fn is_metadata(&self) -> bool {
@@ -87,15 +117,19 @@ impl Item {
         image_metadata: &Image,
     ) -> AvifResult<()> {
         stream.start_full_box("ispe", (0, 0))?;
-        let width = match self.grid {
-            Some(grid) => grid.width,
-            None => image_metadata.width,
+        let width = match (self.grid, &self.overlay, self.dimensions) {
+            (Some(grid), _, _) => grid.width,
+            (None, Some(overlay), _) => overlay.width,
+            (None, None, Some((width, _))) => width,
+            (None, None, None) => image_metadata.width,
         };
         // unsigned int(32) image_width;
         stream.write_u32(width)?;
-        let height = match self.grid {
-            Some(grid) => grid.height,
-            None => image_metadata.height,
+        let height = match (self.grid, &self.overlay, self.dimensions) {
+            (Some(grid), _, _) => grid.height,
+            (None, Some(overlay), _) => overlay.height,
+            (None, None, Some((_, height))) => height,
+            (None, None, None) => image_metadata.height,
         };
         // unsigned int(32) image_height;
         stream.write_u32(height)?;
@@ -121,9 +155,10 @@ impl Item {
         let num_channels = num_color_channels + if has_native_alpha_channel { 1 } else { 0 };
         // unsigned int (8) num_channels;
         stream.write_u8(num_channels)?;
+        let depth = self.depth.unwrap_or(image_metadata.depth);
         for _ in 0..num_channels {
             // unsigned int (8) bits_per_channel;
-            stream.write_u8(image_metadata.depth)?;
+            stream.write_u8(depth)?;
         }
         if force_write_extended_pixi {
             // See ISO/IEC 23008-12 DAM 2.
@@ -500,7 +535,7 @@ impl Item {
                 // Color properties.
                 // Note the 'tmap' item when a gain map is present also has category set to
                 // Category::Color.
-                // Note a derived 'grid' or 'sato' item can have any category.
+                // Note a derived 'grid', 'iovl' or 'sato' item can have any category.
                 if !item_metadata.icc.is_empty() {
                     streams.push(OStream::default());
                     self.write_icc(streams.last_mut().unwrap(), item_metadata)?;
@@ -825,14 +860,29 @@ impl Item {
         stream.finish_box()
     }
 
+    // The data of written samples may have been released.
+    pub(crate) fn sample_size(&self, index: usize) -> usize {
+        match self.written_samples.get(index) {
+            Some(extent) => extent.size,
+            None => self.samples[index].data.len(),
+        }
+    }
+
     pub(crate) fn write_stsc(&self, stream: &mut OStream) -> AvifResult<()> {
         stream.start_full_box("stsc", (0, 0))?;
         // unsigned int(32) entry_count;
         stream.write_u32(1)?;
         // unsigned int(32) first_chunk;
         stream.write_u32(1)?;
+        // The samples of a streaming encoder are interleaved with the samples of other tracks, so
+        // each sample is its own chunk. Otherwise all samples are in a single chunk.
+        let samples_per_chunk = if self.written_samples.is_empty() {
+            u32_from_usize(self.samples.len())?
+        } else {
+            1
+        };
         // unsigned int(32) samples_per_chunk;
-        stream.write_u32(u32_from_usize(self.samples.len())?)?;
+        stream.write_u32(samples_per_chunk)?;
         // unsigned int(32) sample_description_index;
         stream.write_u32(1)?;
         stream.finish_box()
@@ -844,20 +894,40 @@ impl Item {
         stream.write_u32(0)?;
         // unsigned int(32) sample_count;
         stream.write_u32(u32_from_usize(self.samples.len())?)?;
-        for sample in &self.samples {
+        for index in 0..self.samples.len() {
             // unsigned int(32) entry_size;
-            stream.write_u32(u32_from_usize(sample.data.len())?)?;
+            stream.write_u32(u32_from_usize(self.sample_size(index))?)?;
         }
         stream.finish_box()
     }
 
-    pub(crate) fn write_stco(&mut self, stream: &mut OStream) -> AvifResult<()> {
-        stream.start_full_box("stco", (0, 0))?;
+    pub(crate) fn write_stco(
+        &mut self,
+        stream: &mut OStream,
+        large_offsets: bool,
+    ) -> AvifResult<()> {
+        stream.start_full_box(if large_offsets { "co64" } else { "stco" }, (0, 0))?;
+        let write_chunk_offset = |stream: &mut OStream, offset: u64| {
+            if large_offsets {
+                // unsigned int(64) chunk_offset;
+                stream.write_u64(offset)
+            } else {
+                // unsigned int(32) chunk_offset;
+                stream.write_u32(u32_from_u64(offset)?)
+            }
+        };
+        if !self.written_samples.is_empty() {
+            // unsigned int(32) entry_count;
+            stream.write_u32(u32_from_usize(self.written_samples.len())?)?;
+            for extent in &self.written_samples {
+                write_chunk_offset(stream, extent.offset)?;
+            }
+            return stream.finish_box();
+        }
         // unsigned int(32) entry_count;
         stream.write_u32(1)?;
-        // unsigned int(32) chunk_offset;
         self.mdat_offset_locations.push(stream.offset());
-        stream.write_u32(0)?;
+        write_chunk_offset(stream, 0)?;
         stream.finish_box()
     }
 
@@ -886,13 +956,14 @@ impl Item {
         stream: &mut OStream,
         image_metadata: &Image,
         duration_in_timescales: &Vec<u64>,
+        large_offsets: bool,
     ) -> AvifResult<()> {
         stream.start_box("stbl")?;
         self.write_stsd(stream, image_metadata)?;
         self.write_stts(stream, duration_in_timescales)?;
         self.write_stsc(stream)?;
         self.write_stsz(stream)?;
-        self.write_stco(stream)?;
+        self.write_stco(stream, large_offsets)?;
         self.write_stss(stream)?;
         stream.finish_box()
     }
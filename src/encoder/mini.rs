@@ -25,11 +25,19 @@ pub fn is_mini_compatible(enc: &Encoder) -> bool {
     }
 
     // TODO: b/456440247 - Implement with JPEG XL.
-    if enc.settings.codec_choice.actual() != CodecChoice::Aom {
+    if enc.compression_format(enc.settings.codec_choice) != Some(CompressionFormat::Avif) {
         return false;
     }
 
-    // TODO: b/434944440 - Return false if there is any sample transform recipe.
+    // The MinimizedImageBox cannot signal alternative renditions.
+    if enc.items.iter().any(|item| item.codec_choice.is_some()) {
+        return false;
+    }
+
+    // The MinimizedImageBox cannot signal Sample Transform derived image items.
+    if enc.items.iter().any(|item| item.is_sato()) {
+        return false;
+    }
 
     // Check for maximum field values and maximum chunk sizes.
 
@@ -108,8 +116,8 @@ pub fn is_mini_compatible(enc: &Encoder) -> bool {
 
     let mut color_item = None;
     for item in &enc.items {
-        // Grids are not supported by a MinimizedImageBox.
-        if item.grid.is_some() {
+        // Grids and overlays are not supported by a MinimizedImageBox.
+        if item.grid.is_some() || item.overlay.is_some() {
             return false;
         }
 
@@ -12,15 +12,31 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod alternative;
+mod autogrid;
+mod cover;
 pub mod item;
 pub mod mini;
 pub mod mp4box;
+mod optimize;
+mod parallel;
+mod ratecontrol;
+mod rgbinput;
 mod sampletransform;
+mod streaming;
+mod thumbnail;
+mod twopass;
 
 use crate::encoder::item::*;
 use crate::encoder::mp4box::*;
+use crate::encoder::parallel::*;
+use crate::encoder::streaming::StreamingOutput;
+use crate::encoder::twopass::TwoPassFrame;
 
 use crate::codecs::EncoderConfig;
+use crate::codecs::EncoderPass;
+use crate::decoder::tile::Overlay;
+use crate::decoder::CompressionFormat;
 use crate::gainmap::GainMap;
 use crate::image::*;
 use crate::internal_utils::stream::IStream;
@@ -28,7 +44,10 @@ use crate::internal_utils::stream::OStream;
 use crate::internal_utils::*;
 use crate::parser::exif;
 use crate::parser::mp4box::*;
+use crate::plugin::*;
+use crate::reformat::rgb;
 use crate::utils::clap::CropRect;
+use crate::utils::metrics::*;
 use crate::utils::IFraction;
 use crate::*;
 
@@ -41,6 +60,11 @@ use crate::codecs::avm::Avm;
 #[cfg(feature = "jpegxl")]
 use crate::codecs::libjxl::Libjxl;
 
+pub use crate::internal_utils::sampletransform::SampleTransform;
+pub use crate::internal_utils::sampletransform::SampleTransformBinaryOp;
+pub use crate::internal_utils::sampletransform::SampleTransformToken;
+pub use crate::internal_utils::sampletransform::SampleTransformUnaryOp;
+
 use std::collections::HashMap;
 use std::fmt;
 use std::time::SystemTime;
@@ -145,6 +169,309 @@ pub enum Recipe {
     // precision loss (16-bit samples truncated to the 12 most significant
     // bits).
     BitDepthExtension12b4b,
+    // Encode the 12 most significant bits of each input image sample lossily
+    // into a base image. The difference between the input image and the
+    // decoded base image, offset by 128 and clamped to 8 bits, is encoded
+    // losslessly in a separate hidden image item. The two are summed at
+    // decoding. The result is lossless wherever the base image error fits in
+    // the 4 bits overlapping the residual, and close to the input elsewhere.
+    // It is backward compatible like BitDepthExtension12b4b but much smaller
+    // when the quality is below 100. A decoder must be available at encoding
+    // to reconstruct the base image. Only supported for single images.
+    BitDepthExtension12b8bOverlap4b,
+    // Same as BitDepthExtension12b8bOverlap4b with a 10-bit base image and a
+    // 10-bit residual offset by 512.
+    BitDepthExtension10b10bOverlap4b,
+}
+
+// How the quality settings are chosen for an encoded image.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RateControl {
+    // Use the quality values of MutableSettings as is.
+    #[default]
+    Quality,
+    // Search for the color quality for which the size of the encoded file in bytes is the closest
+    // to |target_size|, without exceeding |max_size| if set. The alpha and gain map qualities are
+    // scaled proportionally to the color quality, relative to the values in MutableSettings.
+    // Only supported for single images (including grids and gain maps), not for sequences or
+    // layered images.
+    TargetSize {
+        target_size: usize,
+        max_size: Option<usize>,
+    },
+    // Search for the lowest color quality for which |metric|, computed in |space| between the
+    // input image and the output of the crate's own decoder, reaches at least |target|. The alpha
+    // and gain map qualities are scaled as for TargetSize. For grids, every cell must reach the
+    // target. Same restrictions as TargetSize, and a decoder must be available.
+    TargetMetric {
+        metric: Metric,
+        space: MetricSpace,
+        target: f64,
+    },
+}
+
+// Rate control of an image sequence encoded in two passes (see Settings::two_pass).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TwoPassRateControl {
+    // Average bitrate in kilobits per second over the durations of the frames.
+    TargetBitrate(u32),
+    // Encode the frames at the quality of MutableSettings unless it exceeds |max_bitrate| in
+    // kilobits per second.
+    ConstrainedQuality { max_bitrate: u32 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TwoPass {
+    pub rate_control: TwoPassRateControl,
+    // Number of frames the codec may look ahead of the encoded frame, instead of its default
+    // value. Ignored if the image has an alpha channel.
+    pub lookahead: Option<u32>,
+}
+
+impl TwoPass {
+    pub(crate) fn is_valid(&self) -> bool {
+        match self.rate_control {
+            TwoPassRateControl::TargetBitrate(bitrate) => bitrate > 0,
+            TwoPassRateControl::ConstrainedQuality { max_bitrate } => max_bitrate > 0,
+        }
+    }
+}
+
+// Metric the libaom encoder optimizes for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AomTune {
+    Ssim,
+    Psnr,
+    // Image quality, tuned for still images.
+    Iq,
+}
+
+// Kind of content given to the libaom encoder.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AomTuneContent {
+    Default,
+    // Enables the coding tools for screen captures, such as intra block copy and palettes.
+    Screen,
+    Film,
+}
+
+// Typed libaom encoder settings, only used with CodecChoice::Aom. Each field left to None keeps
+// the default value of the crate or of libaom. These are applied before the options set with
+// Encoder::set_codec_specific_option(), which take precedence for the same libaom setting.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AomOptions {
+    // Defaults to AomTune::Ssim.
+    pub tune: Option<AomTune>,
+    // Loop filter sharpness, from 0 to 7 inclusive.
+    pub sharpness: Option<u32>,
+    // Delta quantization mode, from 0 (off) to 5 inclusive, see AV1E_SET_DELTAQ_MODE.
+    pub deltaq_mode: Option<u32>,
+    // Adaptive quantization mode, from 0 (off) to 3 inclusive, see AV1E_SET_AQ_MODE.
+    pub aq_mode: Option<u32>,
+    pub enable_chroma_deltaq: Option<bool>,
+    // Strength of the denoising applied before encoding, from 0 (off) to 50 inclusive. The
+    // removed noise is signaled as film grain parameters. Color only.
+    pub denoise_noise_level: Option<u32>,
+    // Path to a film grain table file whose parameters are signaled instead of estimated ones.
+    // Color only.
+    pub film_grain_table: Option<&'static str>,
+    pub tune_content: Option<AomTuneContent>,
+    pub enable_cdef: Option<bool>,
+    // Replace the values given by MutableSettings::tiling_mode, from 0 to 6 inclusive.
+    pub tile_rows_log2: Option<u32>,
+    pub tile_columns_log2: Option<u32>,
+    // Bounds of the quantizer chosen by the encoder, from 0 to 63 inclusive.
+    pub min_quantizer: Option<u32>,
+    pub max_quantizer: Option<u32>,
+}
+
+impl AomOptions {
+    // Returns an error naming the first field that is out of its allowed range.
+    pub(crate) fn is_valid(&self) -> AvifResult<()> {
+        for (name, value, max) in [
+            ("sharpness", self.sharpness, 7),
+            ("deltaq_mode", self.deltaq_mode, 5),
+            ("aq_mode", self.aq_mode, 3),
+            ("denoise_noise_level", self.denoise_noise_level, 50),
+            ("tile_rows_log2", self.tile_rows_log2, 6),
+            ("tile_columns_log2", self.tile_columns_log2, 6),
+            ("min_quantizer", self.min_quantizer, 63),
+            ("max_quantizer", self.max_quantizer, 63),
+        ] {
+            if let Some(value) = value {
+                if value > max {
+                    return AvifError::unknown_error(format!(
+                        "AomOptions::{name} is {value}, must be in [0:{max}]"
+                    ));
+                }
+            }
+        }
+        if let (Some(min_quantizer), Some(max_quantizer)) = (self.min_quantizer, self.max_quantizer)
+        {
+            if min_quantizer > max_quantizer {
+                return AvifError::unknown_error(format!(
+                    "AomOptions::min_quantizer is {min_quantizer}, must be at most \
+                     AomOptions::max_quantizer ({max_quantizer})"
+                ));
+            }
+        }
+        if let Some(path) = self.film_grain_table {
+            if path.is_empty() || path.contains('\0') {
+                return AvifError::unknown_error(
+                    "AomOptions::film_grain_table must be a non-empty path without NUL characters",
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+// Changes made to the input of Encoder::add_image() and Encoder::add_image_grid() before encoding,
+// to reduce the size of the output without losing information. Image sequences and layered images
+// are encoded as is.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct InputOptimizations {
+    // Do not encode the alpha plane if all its samples are opaque. This is always done for single
+    // images, and this field only reports it.
+    pub drop_opaque_alpha: bool,
+    // Encode the image as PixelFormat::Yuv400 if all its chroma samples are neutral.
+    pub grayscale: bool,
+    // Encode the image with the lowest bit depth of 8, 10 or 12 for which the samples are
+    // unchanged once shifted, if the least significant bits of all samples are zero. Only done
+    // with Recipe::Auto or Recipe::None.
+    pub reduce_depth: bool,
+}
+
+// How the RGB samples given to Encoder::add_rgb_image() are stored when encoded losslessly.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RgbLosslessMode {
+    // The R, G and B samples are stored as is, with MatrixCoefficients::Identity.
+    #[default]
+    Identity,
+    // The samples are stored with MatrixCoefficients::YcgcoRe, which usually compresses better
+    // but needs two more bits per sample. Identity is used for RGB depths other than 8 and 10.
+    YcgcoR,
+}
+
+// Conversion of the input of Encoder::add_rgb_image() and its variants to YUV. When
+// MutableSettings::quality is 100, the samples are converted losslessly as described by
+// lossless_mode, and yuv_format, yuv_range, matrix_coefficients, depth and sharp_yuv are
+// ignored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RgbPolicy {
+    pub yuv_format: PixelFormat,
+    pub yuv_range: YuvRange,
+    pub matrix_coefficients: MatrixCoefficients,
+    pub color_primaries: ColorPrimaries,
+    pub transfer_characteristics: TransferCharacteristics,
+    // Depth of the YUV samples. Defaults to the depth of the RGB image.
+    pub depth: Option<u8>,
+    // If true, the chroma samples are downsampled with libsharpyuv instead of following
+    // rgb::Image::chroma_downsampling. Requires the sharpyuv feature.
+    pub sharp_yuv: bool,
+    pub lossless_mode: RgbLosslessMode,
+}
+
+impl Default for RgbPolicy {
+    fn default() -> Self {
+        Self {
+            yuv_format: PixelFormat::Yuv420,
+            yuv_range: YuvRange::Full,
+            matrix_coefficients: MatrixCoefficients::Bt601,
+            color_primaries: ColorPrimaries::default(),
+            transfer_characteristics: TransferCharacteristics::default(),
+            depth: None,
+            sharp_yuv: false,
+            lossless_mode: RgbLosslessMode::default(),
+        }
+    }
+}
+
+// Outcome of the rate control search, see Encoder::rate_control_result().
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RateControlResult {
+    pub quality: f32,
+    pub quality_alpha: f32,
+    pub quality_gainmap: f32,
+    // Size in bytes of the encoded file.
+    pub size: usize,
+    // Number of times the image was encoded during the search. The output of the chosen attempt
+    // is kept as is.
+    pub attempt_count: u32,
+    // Value of the metric for RateControl::TargetMetric. This is the lowest value among the grid
+    // cells, and may be below the target if the highest quality did not reach it.
+    pub metric: Option<f64>,
+}
+
+// An image placed on the canvas of an overlay (see Encoder::add_image_overlay()).
+#[derive(Clone, Copy)]
+pub struct OverlaySubImage<'a> {
+    pub image: &'a Image,
+    // Position of the top-left corner of the image on the canvas. The image may extend past the
+    // edges of the canvas, in which case it is clipped.
+    pub horizontal_offset: i32,
+    pub vertical_offset: i32,
+}
+
+// Options of a single frame of an image sequence (see Encoder::add_image_for_sequence_with_options()).
+#[derive(Clone, Debug, Default)]
+pub struct FrameOptions {
+    // Encodes the frame as a keyframe (sync sample), in addition to the frames chosen by
+    // Settings::keyframe_interval.
+    pub force_keyframe: bool,
+    // Replace MutableSettings::quality and quality_alpha for this frame only.
+    pub quality: Option<f32>,
+    pub quality_alpha: Option<f32>,
+    // Replacing the options set with Encoder::set_codec_specific_option(), for this frame only.
+    // Each key must already be set for all categories, or for the same category, with
+    // Encoder::set_codec_specific_option() so that its value can be restored for the following
+    // frames.
+    pub codec_specific_options: CodecSpecificOptions,
+    // Uses this frame as the primary image item, shown by readers that do not support image
+    // sequences, instead of the first frame. Implies force_keyframe. At most one frame can be the
+    // cover. See also Encoder::add_cover_image().
+    pub cover: bool,
+}
+
+impl FrameOptions {
+    fn is_valid(&self) -> bool {
+        [self.quality, self.quality_alpha]
+            .iter()
+            .flatten()
+            .all(|quality| (0.0..=100.0).contains(quality))
+    }
+
+    // Returns true if the codec_specific_options can be reverted to the values in
+    // |encoder_options| after this frame.
+    fn are_codec_specific_options_revertible(
+        &self,
+        encoder_options: &CodecSpecificOptions,
+    ) -> bool {
+        self.codec_specific_options.keys().all(|(category, key)| {
+            encoder_options.contains_key(&(None, key.clone()))
+                || (category.is_some() && encoder_options.contains_key(&(*category, key.clone())))
+        })
+    }
+}
+
+// A downscaled version of the primary image stored alongside it (see Encoder::add_thumbnail()).
+#[derive(Clone, Copy, Debug)]
+pub struct ThumbnailSettings {
+    pub width: u32,
+    pub height: u32,
+    pub quality: f32,
+    pub quality_alpha: f32,
+}
+
+impl Default for ThumbnailSettings {
+    fn default() -> Self {
+        Self {
+            width: 256,
+            height: 256,
+            quality: 60.0,
+            quality_alpha: 60.0,
+        }
+    }
 }
 
 impl CodecChoice {
@@ -156,9 +483,41 @@ impl CodecChoice {
         }
     }
 
-    fn get_item_type_and_encoder_codec(&self) -> Result<(&str, Codec), AvifError> {
+    pub(crate) fn must_write_extended_pixi(self) -> bool {
+        #[cfg(feature = "jpegxl")]
+        if self == Self::Libjxl {
+            return true;
+        }
+        false
+    }
+
+    pub(crate) fn supports_native_alpha_channel(self) -> bool {
+        #[cfg(feature = "jpegxl")]
+        if self == Self::Libjxl {
+            return true;
+        }
+        false
+    }
+
+    fn get_item_type_and_encoder_codec(
+        &self,
+        plugins: &[EncoderPluginFactory],
+    ) -> Result<(&str, Codec), AvifError> {
         match self.actual() {
             Self::Auto => unreachable!(),
+            Self::Plugin(name) => {
+                let (compression_format, codec) = find_encoder_plugin(name, plugins)?;
+                let item_type = match compression_format {
+                    CompressionFormat::Avif => "av01",
+                    #[cfg(feature = "avm")]
+                    CompressionFormat::Avif2 => "av02",
+                    #[cfg(feature = "jpegxl")]
+                    CompressionFormat::JpegXl => "hxlI",
+                    // Writing HEVC codec configurations is not supported.
+                    CompressionFormat::Heic => return AvifError::not_implemented(),
+                };
+                Ok((item_type, codec))
+            }
             #[cfg(feature = "aom")]
             Self::Aom => Ok(("av01", Box::<Aom>::default())),
             #[cfg(feature = "avm")]
@@ -174,6 +533,9 @@ impl CodecChoice {
 pub struct Settings {
     pub codec_choice: CodecChoice,
     pub threads: u32,
+    // Maximum number of items (grid cells, alpha, gain map) encoded at the same time, each by its
+    // own codec instance using |threads| threads. The output does not depend on this value.
+    pub concurrent_cell_count: u32,
     pub speed: Option<u32>,
     pub header_format: HeaderFormat,
     pub keyframe_interval: i32,
@@ -181,6 +543,13 @@ pub struct Settings {
     pub repetition_count: RepetitionCount,
     pub extra_layer_count: u32,
     pub recipe: Recipe,
+    pub rate_control: RateControl,
+    // If set, the frames given to Encoder::add_image_for_sequence() are buffered and encoded
+    // twice by Encoder::finish(): once to collect statistics and once to produce the file. Only
+    // supported by CodecChoice::Aom, for image sequences.
+    pub two_pass: Option<TwoPass>,
+    pub aom_options: AomOptions,
+    pub input_optimizations: InputOptimizations,
     pub force_write_extended_pixi: bool,
     pub creation_time: Option<u64>,
     pub modification_time: Option<u64>,
@@ -192,6 +561,7 @@ impl Default for Settings {
         Settings {
             codec_choice: CodecChoice::default(),
             threads: 1,
+            concurrent_cell_count: 1,
             speed: None,
             header_format: HeaderFormat::default(),
             keyframe_interval: 0,
@@ -199,6 +569,10 @@ impl Default for Settings {
             repetition_count: RepetitionCount::Infinite,
             extra_layer_count: 0,
             recipe: Recipe::None,
+            rate_control: RateControl::Quality,
+            two_pass: None,
+            aom_options: AomOptions::default(),
+            input_optimizations: InputOptimizations::default(),
             force_write_extended_pixi: false,
             creation_time: None,
             modification_time: None,
@@ -209,27 +583,29 @@ impl Default for Settings {
 
 impl Settings {
     pub(crate) fn is_valid(&self) -> bool {
-        self.extra_layer_count < MAX_AV1_LAYER_COUNT as u32 && self.timescale > 0
+        self.extra_layer_count < MAX_AV1_LAYER_COUNT as u32
+            && self.timescale > 0
+            && self.rate_control.is_valid()
+            && match self.two_pass {
+                Some(two_pass) => {
+                    two_pass.is_valid()
+                        && self.codec_choice.actual() == CodecChoice::Aom
+                        && self.extra_layer_count == 0
+                        && self.rate_control == RateControl::Quality
+                        && self.timescale <= i32::MAX as u64
+                }
+                None => true,
+            }
     }
 
-    pub(crate) fn must_write_extended_pixi(&self) -> bool {
-        #[cfg(feature = "jpegxl")]
-        if self.codec_choice == CodecChoice::Libjxl {
-            return true;
-        }
-        self.force_write_extended_pixi
-    }
     pub(crate) fn codec_supports_native_alpha_channel(&self) -> bool {
-        #[cfg(feature = "jpegxl")]
-        if self.codec_choice == CodecChoice::Libjxl {
-            return true;
-        }
-        false
+        self.codec_choice.supports_native_alpha_channel()
     }
 }
 
+// An encoded image, as produced by a codec.
 #[derive(Debug, Default)]
-pub(crate) struct Sample {
+pub struct Sample {
     pub data: Vec<u8>,
     pub sync: bool,
 }
@@ -249,9 +625,14 @@ impl Sample {
 
 pub(crate) type Codec = Box<dyn crate::codecs::Encoder>;
 
+// Destination of the encoded file, see Encoder::set_output().
+pub trait Output: std::io::Write + std::io::Seek {}
+impl<T: std::io::Write + std::io::Seek> Output for T {}
+pub type GenericOutput = Box<dyn Output>;
+
 // If Category is None, the option applies to all categories. If Category is some, it only
 // applies to that category.
-pub(crate) type CodecSpecificOptions = HashMap<(Option<Category>, String), String>;
+pub type CodecSpecificOptions = HashMap<(Option<Category>, String), String>;
 
 #[derive(Default)]
 pub struct Encoder {
@@ -265,6 +646,27 @@ pub struct Encoder {
     alpha_present: bool,
     duration_in_timescales: Vec<u64>,
     codec_specific_options: CodecSpecificOptions,
+    // Encoders that can be selected with CodecChoice::Plugin, see add_codec_plugin().
+    codec_plugins: Vec<EncoderPluginFactory>,
+    thumbnails: Vec<ThumbnailSettings>,
+    thumbnail_image_metadata: Image,
+    // Encoder of the image given to add_cover_image(), whose items are moved to this encoder by
+    // finish().
+    cover: Option<Box<Encoder>>,
+    cover_image_metadata: Image,
+    cover_frame_index: Option<usize>,
+    alternative_codec_choices: Vec<CodecChoice>,
+    // Encoders of the alternative renditions, whose items are moved to this encoder by finish().
+    alternative_encoders: Vec<Encoder>,
+    rate_control_result: Option<RateControlResult>,
+    streaming_output: Option<StreamingOutput>,
+    // Whether iloc, stco and mdat use 64-bit offsets and sizes. Decided when writing the output.
+    large_offsets: bool,
+    // Frames encoded by finish() if Settings::two_pass is set.
+    two_pass_frames: Vec<TwoPassFrame>,
+    pass: EncoderPass,
+    warnings: Vec<String>,
+    applied_input_optimizations: InputOptimizations,
     final_recipe: Option<Recipe>, // Decided when the first image is added.
                                   // Guaranteed not to be Recipe::Auto.
 }
@@ -274,12 +676,57 @@ impl Encoder {
         if !settings.is_valid() {
             return AvifError::invalid_argument();
         }
+        if settings.aom_options != AomOptions::default() {
+            // The typed options are specific to libaom.
+            if settings.codec_choice.actual() != CodecChoice::Aom {
+                return AvifError::invalid_codec_specific_option();
+            }
+            settings.aom_options.is_valid()?;
+        }
         Ok(Self {
             settings: *settings,
             ..Default::default()
         })
     }
 
+    // Returns the messages about the inputs that were ignored, such as an Image::quality_map that
+    // the codec does not support.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    // Returns the optimizations of Settings::input_optimizations that were applied to the image.
+    pub fn applied_input_optimizations(&self) -> InputOptimizations {
+        self.applied_input_optimizations
+    }
+
+    // Returns the format of the samples produced with |codec_choice|, if it is an available
+    // encoder.
+    pub(crate) fn compression_format(
+        &self,
+        codec_choice: CodecChoice,
+    ) -> Option<CompressionFormat> {
+        match codec_choice.actual() {
+            CodecChoice::Aom => Some(CompressionFormat::Avif),
+            #[cfg(feature = "avm")]
+            CodecChoice::Avm => Some(CompressionFormat::Avif2),
+            #[cfg(feature = "jpegxl")]
+            CodecChoice::Libjxl => Some(CompressionFormat::JpegXl),
+            CodecChoice::Plugin(name) => self
+                .codec_plugins
+                .iter()
+                .find(|plugin| plugin.name == name)
+                .map(|plugin| plugin.compression_format),
+            _ => None,
+        }
+    }
+
+    fn add_warning(&mut self, warning: &str) {
+        if !self.warnings.iter().any(|existing| existing == warning) {
+            self.warnings.push(warning.into());
+        }
+    }
+
     pub fn update_settings(&mut self, mutable: &MutableSettings) -> AvifResult<()> {
         self.settings.mutable = *mutable;
         Ok(())
@@ -294,6 +741,91 @@ impl Encoder {
         self.codec_specific_options.insert((category, key), value);
     }
 
+    // Registers an encoder that can be selected with CodecChoice::Plugin(plugin.name), in
+    // Settings::codec_choice or with add_alternative_codec(). Must be called before adding the
+    // image. The names of the registered plugins must be unique.
+    pub fn add_codec_plugin(&mut self, plugin: EncoderPluginFactory) -> AvifResult<()> {
+        if !self.items.is_empty()
+            || self
+                .codec_plugins
+                .iter()
+                .any(|registered| registered.name == plugin.name)
+        {
+            return AvifError::invalid_argument();
+        }
+        self.codec_plugins.push(plugin);
+        Ok(())
+    }
+
+    // Requests the image to also be encoded with |codec_choice|, without the codec specific
+    // options. The primary item encoded with Settings::codec_choice and the alternative
+    // renditions are grouped in an 'altr' entity group, the alternatives first in the order they
+    // were requested, so that readers pick the first rendition they support and readers ignoring
+    // the group use the primary item. Must be called before adding the image. Only supported for
+    // single images without gain map, recipe or thumbnail, and with RateControl::Quality since the
+    // rate control search does not account for the alternative renditions.
+    pub fn add_alternative_codec(&mut self, codec_choice: CodecChoice) -> AvifResult<()> {
+        if !self.items.is_empty()
+            || codec_choice.actual() == self.settings.codec_choice.actual()
+            || self
+                .alternative_codec_choices
+                .contains(&codec_choice.actual())
+        {
+            return AvifError::invalid_argument();
+        }
+        if self.settings.extra_layer_count != 0
+            || self.settings.rate_control != RateControl::Quality
+            || self.streaming_output.is_some()
+        {
+            return AvifError::not_implemented();
+        }
+        self.alternative_codec_choices.push(codec_choice.actual());
+        Ok(())
+    }
+
+    // Requests a thumbnail of the primary image to be encoded. The visible region of the image
+    // (after applying the clean aperture, if any) is downscaled to the given dimensions, so the
+    // caller is responsible for preserving the aspect ratio. Must be called before adding the
+    // image. Only supported for single images that are not grids. Requires libyuv for the
+    // downscaling, otherwise NotImplemented is returned.
+    pub fn add_thumbnail(&mut self, thumbnail: &ThumbnailSettings) -> AvifResult<()> {
+        if !self.items.is_empty() || !thumbnail.is_valid() {
+            return AvifError::invalid_argument();
+        }
+        if !cfg!(feature = "libyuv") {
+            return AvifError::not_implemented();
+        }
+        self.thumbnails.push(*thumbnail);
+        Ok(())
+    }
+
+    // Makes the encoder write the file to |output|, starting at offset 0, instead of returning it
+    // from finish(). The encoded samples are written as soon as they are produced and their data
+    // is released, so that memory usage only grows with the sample tables. The boxes describing
+    // the samples are written by finish(), after the samples. Must be called before adding any
+    // image. Not supported for layered images or with a RateControl other than Quality.
+    // HeaderFormat::Mini is ignored.
+    pub fn set_output(&mut self, output: GenericOutput) -> AvifResult<()> {
+        if !self.items.is_empty() {
+            return AvifError::invalid_argument();
+        }
+        if self.settings.extra_layer_count != 0
+            || self.settings.rate_control != RateControl::Quality
+            || self.cover.is_some()
+            || !self.alternative_codec_choices.is_empty()
+        {
+            return AvifError::not_implemented();
+        }
+        self.streaming_output = Some(StreamingOutput::create(output));
+        Ok(())
+    }
+
+    // Returns the qualities chosen by the rate control search, if Settings::rate_control is not
+    // RateControl::Quality and an image was added.
+    pub fn rate_control_result(&self) -> Option<RateControlResult> {
+        self.rate_control_result
+    }
+
     pub(crate) fn is_sequence(&self) -> bool {
         self.settings.extra_layer_count == 0 && self.duration_in_timescales.len() > 1
     }
@@ -335,7 +867,7 @@ impl Encoder {
             let (item_type, codec) = self
                 .settings
                 .codec_choice
-                .get_item_type_and_encoder_codec()?;
+                .get_item_type_and_encoder_codec(&self.codec_plugins)?;
             let item = Item {
                 id: u16_from_usize(self.items.len() + 1)?,
                 item_type: item_type.into(),
@@ -356,6 +888,58 @@ impl Encoder {
         Ok(top_level_item_id)
     }
 
+    fn add_overlay_items(
+        &mut self,
+        width: u32,
+        height: u32,
+        canvas_fill_value: [u16; 4],
+        sub_images: &[OverlaySubImage],
+        category: Category,
+    ) -> AvifResult<u16> {
+        let overlay = Overlay {
+            canvas_fill_value,
+            width,
+            height,
+            horizontal_offsets: sub_images.iter().map(|x| x.horizontal_offset).collect(),
+            vertical_offsets: sub_images.iter().map(|x| x.vertical_offset).collect(),
+        };
+        let mut stream = OStream::default();
+        write_iovl(&mut stream, &overlay)?;
+        let overlay_item = Item {
+            id: u16_from_usize(self.items.len() + 1)?,
+            item_type: "iovl".into(),
+            infe_name: category.infe_name(),
+            category,
+            overlay: Some(overlay),
+            metadata_payload: stream.data,
+            ..Default::default()
+        };
+        let overlay_item_id = overlay_item.id;
+        self.items.push(overlay_item);
+        // The order of the 'dimg' references matches the order of the offsets in the 'iovl'
+        // payload since the items are created in that order.
+        for (cell_index, sub_image) in sub_images.iter().enumerate() {
+            let (item_type, codec) = self
+                .settings
+                .codec_choice
+                .get_item_type_and_encoder_codec(&self.codec_plugins)?;
+            let item = Item {
+                id: u16_from_usize(self.items.len() + 1)?,
+                item_type: item_type.into(),
+                infe_name: category.infe_name(),
+                cell_index,
+                category,
+                dimg_from_id: Some(overlay_item_id),
+                hidden_image: true,
+                dimensions: Some((sub_image.image.width, sub_image.image.height)),
+                codec: Some(codec),
+                ..Default::default()
+            };
+            self.items.push(item);
+        }
+        Ok(overlay_item_id)
+    }
+
     fn add_exif_item(&mut self) -> AvifResult<()> {
         if self.image_metadata.exif.is_empty() {
             return Ok(());
@@ -394,6 +978,25 @@ impl Encoder {
         Ok(())
     }
 
+    // Moves the finished items of |encoder| after the items of this encoder, except its Exif and
+    // XMP items. Returns the new id of the primary item of |encoder|.
+    fn append_items_of(&mut self, encoder: &mut Encoder) -> AvifResult<u16> {
+        // The Exif and XMP items are added last, so removing them keeps the other ids valid.
+        encoder
+            .items
+            .retain(|item| !matches!(item.item_type.as_str(), "Exif" | "mime"));
+        let id_offset = u16_from_usize(self.items.len())?;
+        let shift_id = |id: u16| checked_add!(id, id_offset);
+        for mut item in std::mem::take(&mut encoder.items) {
+            item.id = shift_id(item.id)?;
+            item.iref_to_id = item.iref_to_id.map(shift_id).transpose()?;
+            item.dimg_from_id = item.dimg_from_id.map(shift_id).transpose()?;
+            item.thmb_to_id = item.thmb_to_id.map(shift_id).transpose()?;
+            self.items.push(item);
+        }
+        shift_id(encoder.primary_item_id)
+    }
+
     fn copy_alt_image_metadata(&mut self, gainmap: &GainMap, grid: &Grid) -> AvifResult<()> {
         self.alt_image_metadata.width = grid.width;
         self.alt_image_metadata.height = grid.height;
@@ -425,6 +1028,8 @@ impl Encoder {
                 (8 | 10 | 12, Recipe::None)
                     | (16, Recipe::BitDepthExtension8b8b)
                     | (16, Recipe::BitDepthExtension12b4b)
+                    | (16, Recipe::BitDepthExtension12b8bOverlap4b)
+                    | (16, Recipe::BitDepthExtension10b10bOverlap4b)
             ) {
                 return AvifError::invalid_argument();
             }
@@ -471,6 +1076,54 @@ impl Encoder {
         Ok(())
     }
 
+    fn validate_image_overlay(
+        width: u32,
+        height: u32,
+        sub_images: &[OverlaySubImage],
+    ) -> AvifResult<()> {
+        if width == 0 || height == 0 || sub_images.is_empty() || sub_images.len() > 0xffff {
+            return AvifError::invalid_argument();
+        }
+        let first_image = sub_images[0].image;
+        for sub_image in sub_images {
+            let image = sub_image.image;
+            if !matches!(image.depth, 8 | 10 | 12) {
+                return AvifError::invalid_argument();
+            }
+            if !image.has_same_cicp(first_image)
+                || image.has_alpha() != first_image.has_alpha()
+                || image.alpha_premultiplied != first_image.alpha_premultiplied
+            {
+                return AvifError::invalid_argument();
+            }
+            if image.matrix_coefficients == MatrixCoefficients::Identity
+                && image.yuv_format != PixelFormat::Yuv444
+            {
+                return AvifError::invalid_argument();
+            }
+            if !image.has_plane(Plane::Y) {
+                return AvifError::no_content();
+            }
+            // The chroma samples of the sub-images have to be aligned with the chroma samples of
+            // the canvas.
+            if (image.yuv_format.chroma_shift_x().0 == 1 && sub_image.horizontal_offset % 2 != 0)
+                || (image.yuv_format.chroma_shift_y() == 1 && sub_image.vertical_offset % 2 != 0)
+            {
+                return AvifError::invalid_argument();
+            }
+        }
+        if let Some(clap) = &first_image.clap {
+            if !CropRect::create_from(clap, width, height, first_image.yuv_format)?.is_valid(
+                width,
+                height,
+                first_image.yuv_format,
+            ) {
+                return AvifError::invalid_argument();
+            }
+        }
+        Ok(())
+    }
+
     fn validate_gainmap_grid(grid: &Grid, gainmaps: &[&GainMap]) -> AvifResult<()> {
         for gainmap in &gainmaps[1..] {
             if gainmaps[0] != *gainmap {
@@ -495,6 +1148,7 @@ impl Encoder {
         Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn add_image_impl(
         &mut self,
         grid_columns: u32,
@@ -503,19 +1157,54 @@ impl Encoder {
         mut duration: u64,
         is_single_image: bool,
         gainmaps: Option<&[&GainMap]>,
+        frame_options: &FrameOptions,
     ) -> AvifResult<()> {
         let cell_count: usize = usize_from_u32(grid_rows * grid_columns)?;
         if cell_count == 0 || cell_images.len() != cell_count {
             return AvifError::invalid_argument();
         }
+        if self.settings.two_pass.is_some()
+            && (is_single_image || cell_count != 1 || gainmaps.is_some())
+        {
+            return AvifError::not_implemented();
+        }
         if duration == 0 {
             duration = 1;
         }
+        let optimized_images = if is_single_image
+            && self.items.is_empty()
+            && self.settings.input_optimizations != InputOptimizations::default()
+        {
+            self.optimize_input(cell_images)?
+        } else {
+            None
+        };
+        let optimized_image_refs: Vec<&Image>;
+        let cell_images = match &optimized_images {
+            Some(images) => {
+                optimized_image_refs = images.iter().collect();
+                &optimized_image_refs[..]
+            }
+            None => cell_images,
+        };
+        for image in cell_images {
+            if let Some(quality_map) = &image.quality_map {
+                if !quality_map.is_valid(image.width, image.height) {
+                    return AvifError::invalid_argument();
+                }
+                if self.settings.codec_choice.actual() != CodecChoice::Aom {
+                    self.add_warning("Image::quality_map is not supported by the codec");
+                }
+            }
+        }
         let first_image = cell_images[0];
-        let final_recipe = self
-            .settings
-            .recipe
-            .self_or_auto_choose_depending_on(first_image);
+        let final_recipe = self.settings.recipe.self_or_auto_choose_depending_on(
+            first_image,
+            is_single_image,
+            self.settings.extra_layer_count,
+            self,
+        );
+        let mut thumbnail_images = Vec::new();
         if self.items.is_empty() {
             assert!(self.final_recipe.is_none());
             self.final_recipe = Some(final_recipe);
@@ -590,17 +1279,41 @@ impl Encoder {
             match final_recipe {
                 Recipe::Auto => unreachable!(),
                 Recipe::None => {}
-                Recipe::BitDepthExtension8b8b | Recipe::BitDepthExtension12b4b => {
+                Recipe::BitDepthExtension8b8b
+                | Recipe::BitDepthExtension12b4b
+                | Recipe::BitDepthExtension12b8bOverlap4b
+                | Recipe::BitDepthExtension10b10bOverlap4b => {
                     if first_image.depth != 16 {
                         return AvifError::invalid_argument();
                     }
-                    if gainmaps.is_some() {
+                    if gainmaps.is_some()
+                        || (final_recipe.residual_depths().is_some()
+                            && (!is_single_image || self.settings.extra_layer_count != 0))
+                    {
                         return AvifError::not_implemented();
                     }
                     self.create_bit_depth_extension_items(&grid)?;
                 }
             }
 
+            if !self.thumbnails.is_empty() {
+                if cell_count != 1 || !is_single_image || final_recipe != Recipe::None {
+                    return AvifError::not_implemented();
+                }
+                thumbnail_images = self.add_thumbnail_items(first_image)?;
+            }
+
+            if !self.alternative_codec_choices.is_empty() {
+                if !is_single_image
+                    || gainmaps.is_some()
+                    || final_recipe != Recipe::None
+                    || !self.thumbnails.is_empty()
+                {
+                    return AvifError::not_implemented();
+                }
+                self.encode_alternatives(grid_columns, grid_rows, cell_images)?;
+            }
+
             self.add_exif_item()?;
             self.add_xmp_item()?;
         } else {
@@ -623,39 +1336,155 @@ impl Encoder {
             }
         }
 
-        let (tile_rows_log2, tile_columns_log2) = self
-            .settings
-            .mutable
-            .tiling_mode
-            .log2(cell_images[0].width, cell_images[0].height);
-        // Encode the AV1 OBUs.
-        for item in &mut self.items {
+        if self.settings.two_pass.is_some() {
+            return self.buffer_two_pass_frame(cell_images[0], duration, frame_options);
+        }
+        self.encode_items_with_rate_control(
+            cell_images,
+            gainmaps,
+            &thumbnail_images,
+            final_recipe,
+            is_single_image,
+            frame_options,
+            duration,
+        )?;
+        self.write_pending_samples()?;
+        self.duration_in_timescales.push(duration);
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn encode_items(
+        &mut self,
+        cell_images: &[&Image],
+        gainmaps: Option<&[&GainMap]>,
+        thumbnail_images: &[Image],
+        final_recipe: Recipe,
+        is_single_image: bool,
+        pad_cells: bool,
+        frame_options: &FrameOptions,
+        duration: u64,
+    ) -> AvifResult<()> {
+        let frame_index = self.duration_in_timescales.len();
+        let keyframe_interval = self.settings.keyframe_interval;
+        let force_keyframe = frame_options.force_keyframe
+            || frame_options.cover
+            || (!is_single_image
+                && self.settings.extra_layer_count == 0
+                && keyframe_interval > 0
+                && frame_index % keyframe_interval as usize == 0);
+        let mut codec_specific_options = self.codec_specific_options.clone();
+        codec_specific_options.extend(frame_options.codec_specific_options.clone());
+        // With a residual recipe, the residual items are encoded after the base items, from the
+        // decoded samples of the base items.
+        let has_residual = final_recipe.residual_depths().is_some();
+        let mut decoded_base_images = Vec::new();
+        let mut item_indices: Vec<usize> = (0..self.items.len()).collect();
+        if has_residual {
+            item_indices
+                .sort_by_key(|item_index| self.items[*item_index].is_sato_least_significant_input);
+        }
+        // Prepare the input of the codec of each item. Without concurrency, each item is encoded as
+        // soon as its input is ready so that at most one padded cell or recipe image is allocated
+        // at a time.
+        let sequential = self.settings.concurrent_cell_count <= 1;
+        let mut jobs = Vec::new();
+        for item_index in item_indices {
+            let item = &self.items[item_index];
             if item.codec.is_none() {
                 continue;
             }
-            let mut image = match item.category {
+            if has_residual
+                && item.is_sato_least_significant_input
+                && decoded_base_images.is_empty()
+            {
+                // All the base items were given to their codecs. Compute the residual from the
+                // samples they produced.
+                encode_items_in_parallel(
+                    &mut self.items,
+                    std::mem::take(&mut jobs),
+                    self.settings.concurrent_cell_count,
+                )?;
+                for base_item_index in 0..self.items.len() {
+                    if !self.items[base_item_index].is_sato_least_significant_input {
+                        self.finish_codec(base_item_index)?;
+                    }
+                }
+                decoded_base_images = self.decode_base_items(cell_images, final_recipe)?;
+            }
+            let item = &self.items[item_index];
+            let image = match item.category {
+                _ if item.is_thumbnail => &thumbnail_images[item.cell_index],
                 Category::Gainmap => &gainmaps.unwrap()[item.cell_index].image,
                 _ => cell_images[item.cell_index],
             };
+            let mut input = EncodeInput::Borrowed(image);
             let first_image = match item.category {
                 Category::Gainmap => &gainmaps.unwrap()[0].image,
                 _ => cell_images[0],
             };
-            let mut padded_image;
-            if image.width != first_image.width || image.height != first_image.height {
+            let is_grid_cell = pad_cells && !item.is_thumbnail;
+            // The quality map is not meaningful for the parts of a split sample.
+            let quality_map = match &image.quality_map {
+                Some(quality_map)
+                    if item.category == Category::Color
+                        && !item.is_thumbnail
+                        && final_recipe == Recipe::None =>
+                {
+                    if is_grid_cell
+                        && (image.width != first_image.width || image.height != first_image.height)
+                    {
+                        // Cover the padding below.
+                        Some(quality_map.crop(
+                            &CropRect {
+                                x: 0,
+                                y: 0,
+                                width: first_image.width,
+                                height: first_image.height,
+                            },
+                            image.width,
+                        )?)
+                    } else {
+                        Some(quality_map.clone())
+                    }
+                }
+                _ => None,
+            };
+            if is_grid_cell
+                && (image.width != first_image.width || image.height != first_image.height)
+            {
                 // Pad the right-most and/or bottom-most tiles so that all tiles share the same dimensions.
-                padded_image = first_image.shallow_clone();
+                let mut padded_image = first_image.shallow_clone();
                 padded_image.copy_and_pad(image)?;
-                image = &padded_image;
+                input = EncodeInput::Owned(Box::new(padded_image));
             }
+            let image = input.image();
+            // Grid cells are all encoded with the dimensions of the first cell.
+            let tiling_image = if is_grid_cell { cell_images[0] } else { image };
+            let (tile_rows_log2, tile_columns_log2) = self
+                .settings
+                .mutable
+                .tiling_mode
+                .log2(tiling_image.width, tiling_image.height);
             let mut quality = match item.category {
-                Category::Color => self.settings.mutable.quality,
-                Category::Alpha => self.settings.mutable.quality_alpha,
+                _ if item.is_thumbnail => {
+                    let thumbnail = &self.thumbnails[item.cell_index];
+                    if item.category == Category::Alpha {
+                        thumbnail.quality_alpha
+                    } else {
+                        thumbnail.quality
+                    }
+                }
+                Category::Color => frame_options
+                    .quality
+                    .unwrap_or(self.settings.mutable.quality),
+                Category::Alpha => frame_options
+                    .quality_alpha
+                    .unwrap_or(self.settings.mutable.quality_alpha),
                 Category::Gainmap => self.settings.mutable.quality_gainmap,
             };
 
             // If used, contains the most or least significiant bits of the image.
-            let bit_depth_extension_image;
             match final_recipe {
                 Recipe::Auto => unreachable!(),
                 Recipe::None => assert!(!item.is_sato_least_significant_input),
@@ -666,9 +1495,9 @@ impl Encoder {
                         // Encode the most significant bits losslessly.
                         quality = 100.0;
                     }
-                    bit_depth_extension_image =
-                        Self::create_bit_depth_extension_8b8b_image(image, item)?;
-                    image = &bit_depth_extension_image;
+                    input = EncodeInput::Owned(Box::new(
+                        Self::create_bit_depth_extension_8b8b_image(image, item)?,
+                    ));
                 }
                 Recipe::BitDepthExtension12b4b => {
                     if !item.is_sato_least_significant_input {
@@ -678,55 +1507,98 @@ impl Encoder {
                         quality = 100.0;
                     }
                     let item_will_be_encoded_losslessly = quality == 100.0;
-                    bit_depth_extension_image = Self::create_bit_depth_extension_12b4b_image(
+                    input =
+                        EncodeInput::Owned(Box::new(Self::create_bit_depth_extension_12b4b_image(
+                            image,
+                            item,
+                            item_will_be_encoded_losslessly,
+                        )?));
+                }
+                Recipe::BitDepthExtension12b8bOverlap4b
+                | Recipe::BitDepthExtension10b10bOverlap4b => {
+                    if item.is_sato_least_significant_input {
+                        // The residual compensates for the loss of the base image.
+                        quality = 100.0;
+                    }
+                    input = EncodeInput::Owned(Box::new(Self::create_residual_recipe_image(
                         image,
+                        &decoded_base_images[item.cell_index],
                         item,
-                        item_will_be_encoded_losslessly,
-                    )?;
-                    image = &bit_depth_extension_image;
+                        final_recipe,
+                    )?));
                 }
             }
 
-            let encoder_config = EncoderConfig {
-                tile_rows_log2,
-                tile_columns_log2,
-                quality,
-                disable_lagged_output: self.alpha_present,
-                is_single_image,
-                speed: self.settings.speed,
-                extra_layer_count: self.settings.extra_layer_count,
-                threads: self.settings.threads,
-                scaling_mode: self.settings.mutable.scaling_mode,
-                codec_specific_options: self.codec_specific_options.clone(),
+            let job = EncodeJob {
+                item_index,
+                input,
+                config: EncoderConfig {
+                    tile_rows_log2,
+                    tile_columns_log2,
+                    quality,
+                    disable_lagged_output: self.alpha_present,
+                    is_single_image,
+                    speed: self.settings.speed,
+                    extra_layer_count: self.settings.extra_layer_count,
+                    threads: self.settings.threads,
+                    scaling_mode: self.settings.mutable.scaling_mode,
+                    codec_specific_options: codec_specific_options.clone(),
+                    force_keyframe,
+                    two_pass: self.settings.two_pass,
+                    aom_options: self.settings.aom_options,
+                    quality_map,
+                    pass: self.pass,
+                    timescale: self.settings.timescale,
+                    duration,
+                },
             };
-            item.codec.unwrap_mut().encode_image(
-                image,
-                item.category,
-                &encoder_config,
-                &mut item.samples,
-            )?;
+            if sequential {
+                encode_item(&mut self.items[item_index], &job)?;
+            } else {
+                jobs.push(job);
+            }
         }
-        self.duration_in_timescales.push(duration);
-        Ok(())
+        // Encode the AV1 OBUs of the items that were not encoded above.
+        encode_items_in_parallel(&mut self.items, jobs, self.settings.concurrent_cell_count)
     }
 
+    // Images exceeding the frame size limits of the codec are split into a grid.
     pub fn add_image(&mut self, image: &Image) -> AvifResult<()> {
-        self.add_image_impl(
-            1,
-            1,
-            &[image],
-            0,
-            self.settings.extra_layer_count == 0,
-            None,
-        )
+        self.add_image_or_auto_grid(image, None, self.settings.extra_layer_count == 0)
     }
 
     pub fn add_image_for_sequence(&mut self, image: &Image, duration: u64) -> AvifResult<()> {
-        if self.settings.extra_layer_count != 0 {
+        self.add_image_for_sequence_with_options(image, duration, &FrameOptions::default())
+    }
+
+    pub fn add_image_for_sequence_with_options(
+        &mut self,
+        image: &Image,
+        duration: u64,
+        frame_options: &FrameOptions,
+    ) -> AvifResult<()> {
+        if self.settings.extra_layer_count != 0
+            || !frame_options.is_valid()
+            || !frame_options.are_codec_specific_options_revertible(&self.codec_specific_options)
+        {
             return AvifError::invalid_argument();
         }
+        let frame_index = self.duration_in_timescales.len() + self.two_pass_frames.len();
+        if frame_options.cover {
+            if self.cover.is_some() || self.cover_frame_index.is_some() {
+                return AvifError::invalid_argument();
+            }
+            // The data of the samples written to the output is released.
+            if self.streaming_output.is_some() {
+                return AvifError::not_implemented();
+            }
+        }
         // TODO: this and add_image cannot be used on the same instance.
-        self.add_image_impl(1, 1, &[image], duration, false, None)
+        self.add_image_impl(1, 1, &[image], duration, false, None, frame_options)?;
+        if frame_options.cover {
+            self.cover_frame_index = Some(frame_index);
+        }
+        Ok(())
     }
 
     pub fn add_image_grid(
@@ -745,14 +1617,81 @@ impl Encoder {
             0,
             self.settings.extra_layer_count == 0,
             None,
+            &FrameOptions::default(),
         )
     }
 
+    // Converts |rgb| to YUV as described by |policy| and encodes it like add_image().
+    pub fn add_rgb_image(&mut self, rgb: &rgb::Image, policy: &RgbPolicy) -> AvifResult<()> {
+        let image = policy.convert(rgb, self.settings.mutable.quality == 100.0)?;
+        self.add_image(&image)
+    }
+
+    pub fn add_rgb_image_for_sequence(
+        &mut self,
+        rgb: &rgb::Image,
+        duration: u64,
+        policy: &RgbPolicy,
+    ) -> AvifResult<()> {
+        let image = policy.convert(rgb, self.settings.mutable.quality == 100.0)?;
+        self.add_image_for_sequence(&image, duration)
+    }
+
+    pub fn add_rgb_image_grid(
+        &mut self,
+        grid_columns: u32,
+        grid_rows: u32,
+        rgb_images: &[&rgb::Image],
+        policy: &RgbPolicy,
+    ) -> AvifResult<()> {
+        let lossless = self.settings.mutable.quality == 100.0;
+        let images = rgb_images
+            .iter()
+            .map(|rgb| policy.convert(rgb, lossless))
+            .collect::<AvifResult<Vec<_>>>()?;
+        let image_refs: Vec<&Image> = images.iter().collect();
+        self.add_image_grid(grid_columns, grid_rows, &image_refs)
+    }
+
+    // Encodes |image| as a still image with |mutable| instead of Settings::mutable, and makes it
+    // the primary image item of the image sequence added with add_image_for_sequence(), instead of
+    // its first frame. This is the image shown by readers that do not support image sequences, so
+    // it can be of higher quality than the frames. The image is encoded immediately, with the
+    // codec specific options set so far. Not supported for layered images or with set_output().
+    pub fn add_cover_image(&mut self, image: &Image, mutable: &MutableSettings) -> AvifResult<()> {
+        if self.cover.is_some() || self.cover_frame_index.is_some() {
+            return AvifError::invalid_argument();
+        }
+        if self.settings.extra_layer_count != 0 || self.streaming_output.is_some() {
+            return AvifError::not_implemented();
+        }
+        let settings = Settings {
+            header_format: HeaderFormat::Default,
+            recipe: Recipe::None,
+            rate_control: RateControl::Quality,
+            two_pass: None,
+            input_optimizations: InputOptimizations::default(),
+            mutable: *mutable,
+            ..self.settings
+        };
+        let mut cover = Encoder::create_with_settings(&settings)?;
+        cover.codec_specific_options = self.codec_specific_options.clone();
+        cover.codec_plugins = self.codec_plugins.clone();
+        cover.add_image(image)?;
+        for warning in &cover.warnings {
+            self.add_warning(warning);
+        }
+        self.cover = Some(Box::new(cover));
+        Ok(())
+    }
+
+    // Images exceeding the frame size limits of the codec are split into a grid, and so is the
+    // gain map, with the same number of cells.
     pub fn add_image_gainmap(&mut self, image: &Image, gainmap: &GainMap) -> AvifResult<()> {
         if self.settings.extra_layer_count != 0 {
             return AvifError::not_implemented();
         }
-        self.add_image_impl(1, 1, &[image], 0, true, Some(&[gainmap]))
+        self.add_image_or_auto_grid(image, Some(gainmap), true)
     }
 
     pub fn add_image_gainmap_grid(
@@ -768,37 +1707,211 @@ impl Encoder {
         if self.settings.extra_layer_count != 0 {
             return AvifError::not_implemented();
         }
-        self.add_image_impl(grid_columns, grid_rows, images, 0, true, Some(gainmaps))
+        self.add_image_impl(
+            grid_columns,
+            grid_rows,
+            images,
+            0,
+            true,
+            Some(gainmaps),
+            &FrameOptions::default(),
+        )
     }
 
+    // Encodes a canvas of |width|x|height| pixels filled with |canvas_fill_value| (RGBA, 16-bit
+    // per channel) on which each of the |sub_images| is placed at its offset, in order. Each
+    // sub-image is encoded as a hidden image item referenced by an 'iovl' derived image item.
+    // All the sub-images must share the same depth, pixel format and color properties but may
+    // have different dimensions. The alpha value of |canvas_fill_value| is only meaningful if
+    // the sub-images have an alpha channel.
+    pub fn add_image_overlay(
+        &mut self,
+        width: u32,
+        height: u32,
+        canvas_fill_value: [u16; 4],
+        sub_images: &[OverlaySubImage],
+    ) -> AvifResult<()> {
+        if !self.items.is_empty()
+            || self.settings.extra_layer_count != 0
+            || !self.thumbnails.is_empty()
+            || !self.alternative_codec_choices.is_empty()
+        {
+            return AvifError::not_implemented();
+        }
+        Self::validate_image_overlay(width, height, sub_images)?;
+        let cell_images: Vec<_> = sub_images.iter().map(|sub_image| sub_image.image).collect();
+        let first_image = cell_images[0];
+        let final_recipe = self.settings.recipe.self_or_auto_choose_depending_on(
+            first_image,
+            /*is_single_image=*/ true,
+            self.settings.extra_layer_count,
+            self,
+        );
+        if final_recipe != Recipe::None {
+            return AvifError::not_implemented();
+        }
+        self.final_recipe = Some(final_recipe);
+        self.image_metadata = first_image.shallow_clone();
+        self.image_metadata.exif = first_image.exif.try_clone()?;
+        self.image_metadata.xmp = first_image.xmp.try_clone()?;
+        self.image_metadata.icc = first_image.icc.try_clone()?;
+
+        let color_item_id = self.add_overlay_items(
+            width,
+            height,
+            canvas_fill_value,
+            sub_images,
+            Category::Color,
+        )?;
+        self.primary_item_id = color_item_id;
+        // The canvas may be transparent even if all the sub-images are opaque.
+        self.alpha_present = first_image.has_alpha()
+            && (canvas_fill_value[3] != u16::MAX
+                || !cell_images.iter().all(|image| image.is_opaque()));
+        if self.alpha_present && !self.settings.codec_supports_native_alpha_channel() {
+            let alpha_item_id = self.add_overlay_items(
+                width,
+                height,
+                canvas_fill_value,
+                sub_images,
+                Category::Alpha,
+            )?;
+            let alpha_item = &mut self.items[alpha_item_id as usize - 1];
+            alpha_item.iref_type = Some(String::from("auxl"));
+            alpha_item.iref_to_id = Some(color_item_id);
+            if self.image_metadata.alpha_premultiplied {
+                let color_item = &mut self.items[color_item_id as usize - 1];
+                color_item.iref_type = Some(String::from("prem"));
+                color_item.iref_to_id = Some(alpha_item_id);
+            }
+        }
+        self.add_exif_item()?;
+        self.add_xmp_item()?;
+
+        self.encode_items(
+            &cell_images,
+            None,
+            &[],
+            final_recipe,
+            /*is_single_image=*/ true,
+            /*pad_cells=*/ false,
+            &FrameOptions::default(),
+            /*duration=*/ 1,
+        )?;
+        self.write_pending_samples()?;
+        self.duration_in_timescales.push(1);
+        Ok(())
+    }
+
+    // Encodes each of the |inputs| as a hidden image item, and a primary 'sato' (Sample
+    // Transform) derived image item whose samples are computed from the inputs by
+    // |sample_transform|, SampleTransformToken::ImageItem(i) referring to inputs[i]. The derived
+    // image has the properties of the first input, with |depth| bits per sample. All the inputs
+    // must share the same dimensions, pixel format, color properties and alpha channel presence.
+    // The derived image is only output by decoders with
+    // decoder::Settings::allow_sample_transform set, and the decoder of this crate supports at most
+    // 3 inputs.
+    pub fn add_sample_transform_image(
+        &mut self,
+        sample_transform: &SampleTransform,
+        inputs: &[&Image],
+        depth: u8,
+    ) -> AvifResult<()> {
+        if !self.items.is_empty()
+            || self.settings.extra_layer_count != 0
+            || !self.thumbnails.is_empty()
+            || !self.alternative_codec_choices.is_empty()
+        {
+            return AvifError::not_implemented();
+        }
+        Self::validate_sample_transform(sample_transform, inputs, depth)?;
+        let first_image = inputs[0];
+        let final_recipe = self.settings.recipe.self_or_auto_choose_depending_on(
+            first_image,
+            /*is_single_image=*/ true,
+            self.settings.extra_layer_count,
+            self,
+        );
+        if final_recipe != Recipe::None {
+            return AvifError::not_implemented();
+        }
+        self.final_recipe = Some(final_recipe);
+        self.image_metadata = first_image.shallow_clone();
+        self.image_metadata.depth = depth;
+        self.image_metadata.exif = first_image.exif.try_clone()?;
+        self.image_metadata.xmp = first_image.xmp.try_clone()?;
+        self.image_metadata.icc = first_image.icc.try_clone()?;
+        self.alpha_present = first_image.has_alpha();
+        self.add_sample_transform_items(sample_transform, inputs)?;
+        self.add_exif_item()?;
+        self.add_xmp_item()?;
+
+        self.encode_items(
+            inputs,
+            None,
+            &[],
+            final_recipe,
+            /*is_single_image=*/ true,
+            /*pad_cells=*/ false,
+            &FrameOptions::default(),
+            /*duration=*/ 1,
+        )?;
+        self.write_pending_samples()?;
+        self.duration_in_timescales.push(1);
+        Ok(())
+    }
+
+    // Returns the encoded file, or an empty vector if an output was set with set_output().
     pub fn finish(&mut self) -> AvifResult<Vec<u8>> {
         if self.items.is_empty() {
             return AvifError::no_content();
         }
-        for item in &mut self.items {
-            if item.codec.is_none() {
-                continue;
-            }
-            item.codec.unwrap_mut().finish(&mut item.samples)?;
-            if item.extra_layer_count > 0
-                && item.samples.len() != 1 + item.extra_layer_count as usize
-            {
-                return AvifError::invalid_argument();
-            }
-            // TODO: check if sample count == duration count.
+        if self.streaming_output.is_some() {
+            self.finish_streaming()?;
+            return Ok(Vec::new());
+        }
+        self.finish_codecs()?;
+        self.finish_cover()?;
+        self.finish_alternatives()?;
+        self.write_output()
+    }
 
-            if !item.samples.is_empty() {
-                assert_eq!(item.codec_configuration, None);
-                let is_single_image = self.duration_in_timescales.len() < 2;
-                let is_lossless = self.settings.mutable.quality == 100.0;
-                item.codec_configuration = Some(item.codec.unwrap_ref().get_codec_config(
-                    &self.image_metadata,
-                    is_single_image,
-                    is_lossless,
-                    &item.samples,
-                )?);
-            }
+    fn finish_codecs(&mut self) -> AvifResult<()> {
+        self.encode_two_pass_frames()?;
+        for item_index in 0..self.items.len() {
+            self.finish_codec(item_index)?;
         }
+        Ok(())
+    }
+
+    // Outputs the remaining samples of the codec of the item and sets its codec configuration.
+    // Items with a codec configuration were already finalized by the rate control search or, for
+    // the base items of a residual recipe, by encode_items().
+    fn finish_codec(&mut self, item_index: usize) -> AvifResult<()> {
+        let item = &mut self.items[item_index];
+        if item.codec.is_none() || item.codec_configuration.is_some() {
+            return Ok(());
+        }
+        item.codec.unwrap_mut().finish(&mut item.samples)?;
+        if item.extra_layer_count > 0 && item.samples.len() != 1 + item.extra_layer_count as usize {
+            return AvifError::invalid_argument();
+        }
+        // TODO: check if sample count == duration count.
+
+        if !item.samples.is_empty() {
+            let is_single_image = self.duration_in_timescales.len() < 2;
+            let is_lossless = self.settings.mutable.quality == 100.0;
+            item.codec_configuration = Some(item.codec.unwrap_ref().get_codec_config(
+                &self.image_metadata,
+                is_single_image,
+                is_lossless,
+                &item.samples,
+            )?);
+        }
+        Ok(())
+    }
+
+    fn write_output(&mut self) -> AvifResult<Vec<u8>> {
         let mut stream = OStream::default();
 
         if self.settings.header_format == HeaderFormat::Mini && mini::is_mini_compatible(self) {
@@ -813,16 +1926,63 @@ impl Encoder {
             self.settings.creation_time,
             self.settings.modification_time,
         )?;
+        let mdat_payload_size: usize = self
+            .items
+            .iter()
+            .map(|item| {
+                if item.samples.is_empty() {
+                    item.metadata_payload.len()
+                } else {
+                    item.samples.iter().map(|sample| sample.data.len()).sum()
+                }
+            })
+            .sum();
+        if !self.large_offsets
+            && requires_large_offsets(stream.offset(), mdat_payload_size, u32::MAX as usize)
+        {
+            // Switch to 64-bit offsets and write the boxes again.
+            self.large_offsets = true;
+            for item in &mut self.items {
+                item.clear_written_boxes();
+            }
+            return self.write_output();
+        }
         self.write_mdat(&mut stream)?;
         Ok(stream.data)
     }
 }
 
+// Returns true if the mdat box starting at |mdat_offset| with a payload of |mdat_payload_size|
+// bytes may end past |max_offset| when written with 32-bit offsets and sizes. Such a mdat box has
+// an 8-byte header.
+fn requires_large_offsets(mdat_offset: usize, mdat_payload_size: usize, max_offset: usize) -> bool {
+    mdat_offset
+        .checked_add(8)
+        .and_then(|offset| offset.checked_add(mdat_payload_size))
+        .is_none_or(|end| end > max_offset)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use test_case::test_case;
 
+    #[test_case(0, 0, 8, false ; "empty")]
+    #[test_case(100, 92, 200, false ; "ends at max offset")]
+    #[test_case(100, 93, 200, true ; "ends past max offset")]
+    #[test_case(100, usize::MAX, usize::MAX, true ; "overflow")]
+    fn large_offsets_decision(
+        mdat_offset: usize,
+        mdat_payload_size: usize,
+        max_offset: usize,
+        expected: bool,
+    ) {
+        assert_eq!(
+            requires_large_offsets(mdat_offset, mdat_payload_size, max_offset),
+            expected
+        );
+    }
+
     #[test_case(256, 144, 0, 0 ; "144p")]
     #[test_case(426, 240, 0, 0 ; "240p")]
     #[test_case(640, 360, 0, 0 ; "360p")]
@@ -850,4 +2010,54 @@ mod tests {
             (expected_tile_columns_log2, expected_tile_rows_log2)
         );
     }
+
+    #[cfg(feature = "aom")]
+    pub(crate) fn gradient_image(width: u32, height: u32) -> AvifResult<Image> {
+        let mut image = Image {
+            width,
+            height,
+            depth: 8,
+            yuv_format: PixelFormat::Yuv420,
+            ..Default::default()
+        };
+        image.allocate_planes(Category::Color)?;
+        for plane in YUV_PLANES {
+            for y in 0..image.height(plane) {
+                for (x, value) in image.row_mut(plane, y as u32)?.iter_mut().enumerate() {
+                    *value = (x + y) as u8;
+                }
+            }
+        }
+        Ok(image)
+    }
+
+    #[cfg(feature = "aom")]
+    #[test]
+    fn large_offsets() -> AvifResult<()> {
+        // Files past 4 GiB are too large to be built in memory for a test, so force the 64-bit
+        // boxes used for them.
+        let image = gradient_image(64, 48)?;
+        let mut encoder = Encoder::create_with_settings(&Settings {
+            speed: Some(10),
+            ..Default::default()
+        })?;
+        for _ in 0..2 {
+            encoder.add_image_for_sequence(&image, 1)?;
+        }
+        encoder.large_offsets = true;
+        let edata = encoder.finish()?;
+        assert!(edata.windows(4).any(|window| window == b"co64"));
+        assert!(!edata.windows(4).any(|window| window == b"stco"));
+
+        let mut decoder = crate::decoder::Decoder::default();
+        decoder.set_io_vec(edata);
+        decoder.parse()?;
+        assert_eq!(decoder.image_count(), 2);
+        if cfg!(feature = "dav1d") {
+            for _ in 0..2 {
+                decoder.next_image()?;
+            }
+        }
+        Ok(())
+    }
 }
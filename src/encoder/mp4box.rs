@@ -14,6 +14,7 @@
 
 use crate::encoder::*;
 
+use crate::decoder::tile::Overlay;
 use crate::gainmap::GainMapMetadata;
 use crate::internal_utils::stream::OStream;
 use crate::internal_utils::*;
@@ -53,6 +54,30 @@ pub(crate) fn write_pitm(stream: &mut OStream, item_id: u16) -> AvifResult<()> {
     stream.finish_box()
 }
 
+// Writes |value| on |size| bytes, which must be 4 or 8.
+fn write_uxx(stream: &mut OStream, value: u64, size: u8) -> AvifResult<()> {
+    match size {
+        4 => stream.write_u32(u32_from_u64(value)?),
+        8 => stream.write_u64(value),
+        _ => AvifError::unknown_error(""),
+    }
+}
+
+// Fills a placeholder written by write_iloc() or write_stco() with the position of the data in
+// the file.
+pub(crate) fn write_offset_at(
+    stream: &mut OStream,
+    offset: u64,
+    location: usize,
+    large_offsets: bool,
+) -> AvifResult<()> {
+    if large_offsets {
+        stream.write_u64_at_offset(offset, location)
+    } else {
+        stream.write_u32_at_offset(u32_from_u64(offset)?, location)
+    }
+}
+
 pub(crate) fn write_grid(stream: &mut OStream, grid: &Grid) -> AvifResult<()> {
     // ISO/IEC 23008-12 6.6.2.3.2
     // aligned(8) class ImageGrid {
@@ -85,6 +110,67 @@ pub(crate) fn write_grid(stream: &mut OStream, grid: &Grid) -> AvifResult<()> {
     Ok(())
 }
 
+pub(crate) fn write_iovl(stream: &mut OStream, overlay: &Overlay) -> AvifResult<()> {
+    // ISO/IEC 23008-12 6.6.2.2.2
+    // aligned(8) class ImageOverlay {
+    //     unsigned int(8) version = 0;
+    //     unsigned int(8) flags;
+    //     for (j=0; j<4; j++) {
+    //         unsigned int(16) canvas_fill_value;
+    //     }
+    //     FieldLength = ((flags & 1) + 1) * 16;
+    //     unsigned int(FieldLength) output_width;
+    //     unsigned int(FieldLength) output_height;
+    //     for (i=0; i<reference_count; i++) {
+    //         signed int(FieldLength) horizontal_offset;
+    //         signed int(FieldLength) vertical_offset;
+    //     }
+    // }
+    let offset_fits_in_i16 = |offset: &i32| i16::try_from(*offset).is_ok();
+    let flags = if overlay.width > 65535
+        || overlay.height > 65535
+        || !overlay.horizontal_offsets.iter().all(offset_fits_in_i16)
+        || !overlay.vertical_offsets.iter().all(offset_fits_in_i16)
+    {
+        1
+    } else {
+        0
+    };
+    // unsigned int(8) version = 0;
+    stream.write_u8(0)?;
+    // unsigned int(8) flags;
+    stream.write_u8(flags)?;
+    for value in overlay.canvas_fill_value {
+        // unsigned int(16) canvas_fill_value;
+        stream.write_u16(value)?;
+    }
+    // unsigned int(FieldLength) output_width;
+    // unsigned int(FieldLength) output_height;
+    if flags == 1 {
+        stream.write_u32(overlay.width)?;
+        stream.write_u32(overlay.height)?;
+    } else {
+        stream.write_u16(overlay.width as u16)?;
+        stream.write_u16(overlay.height as u16)?;
+    }
+    for (horizontal_offset, vertical_offset) in overlay
+        .horizontal_offsets
+        .iter()
+        .zip(overlay.vertical_offsets.iter())
+    {
+        // signed int(FieldLength) horizontal_offset;
+        // signed int(FieldLength) vertical_offset;
+        if flags == 1 {
+            stream.write_i32(*horizontal_offset)?;
+            stream.write_i32(*vertical_offset)?;
+        } else {
+            stream.write_i16(*horizontal_offset as i16)?;
+            stream.write_i16(*vertical_offset as i16)?;
+        }
+    }
+    Ok(())
+}
+
 pub(crate) fn write_tmap(metadata: &GainMapMetadata) -> AvifResult<Vec<u8>> {
     let mut stream = OStream::default();
     // ToneMapImage syntax as per section 6.6.2.4.2 of ISO/IEC 23008-12:2024
@@ -129,6 +215,26 @@ pub(crate) fn write_tmap(metadata: &GainMapMetadata) -> AvifResult<Vec<u8>> {
 }
 
 impl Encoder {
+    // Returns the brands of the codecs of the alternative renditions (see
+    // Encoder::add_alternative_codec()) that are not in |brands| yet.
+    fn alternative_brands(&self, brands: &[String]) -> Vec<String> {
+        let mut alternative_brands: Vec<String> = Vec::new();
+        for codec_choice in &self.alternative_codec_choices {
+            let brand = String::from(match self.compression_format(*codec_choice) {
+                Some(CompressionFormat::Avif) => "avif",
+                #[cfg(feature = "avm")]
+                Some(CompressionFormat::Avif2) => "av2f",
+                #[cfg(feature = "jpegxl")]
+                Some(CompressionFormat::JpegXl) => "hxlI",
+                _ => continue,
+            });
+            if !brands.contains(&brand) && !alternative_brands.contains(&brand) {
+                alternative_brands.push(brand);
+            }
+        }
+        alternative_brands
+    }
+
     pub(crate) fn write_avif_ftyp(&self, stream: &mut OStream) -> AvifResult<()> {
         let mut compatible_brands = vec![
             String::from("avif"),
@@ -146,6 +252,7 @@ impl Encoder {
         if self.items.iter().any(|x| x.is_tmap()) {
             compatible_brands.push(String::from("tmap"));
         }
+        compatible_brands.extend(self.alternative_brands(&compatible_brands));
         match self.image_metadata.depth {
             8 | 10 => match self.image_metadata.yuv_format {
                 PixelFormat::Yuv420 => compatible_brands.push(String::from("MA1B")),
@@ -189,6 +296,7 @@ impl Encoder {
         if self.items.iter().any(|x| x.is_tmap()) {
             compatible_brands.push(String::from("tmap"));
         }
+        compatible_brands.extend(self.alternative_brands(&compatible_brands));
         match self.image_metadata.depth {
             8 | 10 => match self.image_metadata.yuv_format {
                 PixelFormat::Yuv420 => compatible_brands.push(String::from("MA1B")),
@@ -219,7 +327,7 @@ impl Encoder {
         stream.start_box("ftyp")?;
         // No need to repeat the major_brand in the compatible_brands starting
         // with ISO/IEC 14496-12:2025/DAmd 1.
-        let (major_brand, compatible_brands) = if self.is_sequence() {
+        let (major_brand, mut compatible_brands) = if self.is_sequence() {
             (
                 String::from("hxlS"),
                 vec![
@@ -237,6 +345,8 @@ impl Encoder {
                 vec![String::from("mif1"), String::from("miaf")],
             )
         };
+        // The alternative renditions do not use JPEG XL.
+        compatible_brands.extend(self.alternative_brands(&compatible_brands));
         // unsigned int(32) major_brand;
         stream.write_string(&major_brand)?;
         // unsigned int(32) minor_version;
@@ -252,57 +362,65 @@ impl Encoder {
     }
 
     pub(crate) fn write_ftyp(&self, stream: &mut OStream) -> AvifResult<()> {
-        match self.settings.codec_choice.actual() {
-            CodecChoice::Aom => self.write_avif_ftyp(stream),
+        match self.compression_format(self.settings.codec_choice) {
+            Some(CompressionFormat::Avif) => self.write_avif_ftyp(stream),
             #[cfg(feature = "avm")]
-            CodecChoice::Avm => self.write_avif2_ftyp(stream),
+            Some(CompressionFormat::Avif2) => self.write_avif2_ftyp(stream),
             #[cfg(feature = "jpegxl")]
-            CodecChoice::Libjxl => self.write_jpegxl_ftyp(stream),
+            Some(CompressionFormat::JpegXl) => self.write_jpegxl_ftyp(stream),
             _ => unreachable!(),
         }
     }
 
-    pub(crate) fn write_iloc(stream: &mut OStream, items: &mut Vec<&mut Item>) -> AvifResult<()> {
+    pub(crate) fn write_iloc(
+        stream: &mut OStream,
+        items: &mut Vec<&mut Item>,
+        large_offsets: bool,
+    ) -> AvifResult<()> {
+        let extent_lengths: Vec<Vec<usize>> = items
+            .iter()
+            .map(|item| {
+                if item.extra_layer_count > 0 {
+                    item.samples[..=item.extra_layer_count as usize]
+                        .iter()
+                        .map(|sample| sample.data.len())
+                        .collect()
+                } else if item.samples.is_empty() {
+                    vec![item.metadata_payload.len()]
+                } else {
+                    vec![item.sample_size(item.iloc_sample_index)]
+                }
+            })
+            .collect();
+        let large_lengths = extent_lengths
+            .iter()
+            .flatten()
+            .any(|length| *length > u32::MAX as usize);
+        let offset_size: u8 = if large_offsets { 8 } else { 4 };
+        let length_size: u8 = if large_lengths { 8 } else { 4 };
         stream.start_full_box("iloc", (0, 0))?;
         // unsigned int(4) offset_size;
         // unsigned int(4) length_size;
-        stream.write_u8(0x44)?;
+        stream.write_u8((offset_size << 4) | length_size)?;
         // unsigned int(4) base_offset_size;
         // unsigned int(4) reserved;
         stream.write_u8(0)?;
         // unsigned int(16) item_count;
         stream.write_u16(u16_from_usize(items.len())?)?;
 
-        for item in items {
+        for (item, extent_lengths) in items.iter_mut().zip(extent_lengths) {
             // unsigned int(16) item_ID;
             stream.write_u16(item.id)?;
             // unsigned int(16) data_reference_index;
             stream.write_u16(0)?;
-
-            if item.extra_layer_count > 0 {
-                let layer_count = item.extra_layer_count as u16 + 1;
-                // unsigned int(16) extent_count;
-                stream.write_u16(layer_count)?;
-                for i in 0..layer_count as usize {
-                    item.mdat_offset_locations.push(stream.offset());
-                    // unsigned int(offset_size*8) extent_offset;
-                    stream.write_u32(0)?;
-                    // unsigned int(length_size*8) extent_length;
-                    stream.write_u32(u32_from_usize(item.samples[i].data.len())?)?;
-                }
-            } else {
-                // unsigned int(16) extent_count;
-                stream.write_u16(1)?;
+            // unsigned int(16) extent_count;
+            stream.write_u16(u16_from_usize(extent_lengths.len())?)?;
+            for extent_length in extent_lengths {
                 item.mdat_offset_locations.push(stream.offset());
                 // unsigned int(offset_size*8) extent_offset;
-                stream.write_u32(0)?;
-                let extent_length = if item.samples.is_empty() {
-                    u32_from_usize(item.metadata_payload.len())?
-                } else {
-                    u32_from_usize(item.samples[0].data.len())?
-                };
+                write_uxx(stream, 0, offset_size)?;
                 // unsigned int(length_size*8) extent_length;
-                stream.write_u32(extent_length)?;
+                write_uxx(stream, u64_from_usize(extent_length)?, length_size)?;
             }
         }
 
@@ -346,7 +464,7 @@ impl Encoder {
 
     pub(crate) fn write_iref(&self, stream: &mut OStream) -> AvifResult<()> {
         let mut box_started = false;
-        for item in &self.items {
+        for item in self.items.iter().filter(|item| !item.is_track_only) {
             let dimg_item_ids: Vec<_> = self
                 .items
                 .iter()
@@ -383,6 +501,20 @@ impl Encoder {
                 stream.write_u16(iref_to_id)?;
                 stream.finish_box()?;
             }
+            if let Some(thmb_to_id) = item.thmb_to_id {
+                if !box_started {
+                    stream.start_full_box("iref", (0, 0))?;
+                    box_started = true;
+                }
+                stream.start_box("thmb")?;
+                // unsigned int(16) from_item_ID;
+                stream.write_u16(item.id)?;
+                // unsigned int(16) reference_count;
+                stream.write_u16(1)?;
+                // unsigned int(16) to_item_ID;
+                stream.write_u16(thmb_to_id)?;
+                stream.finish_box()?;
+            }
         }
         if box_started {
             stream.finish_box()?;
@@ -420,11 +552,18 @@ impl Encoder {
         stream.start_box("ipco")?;
         let mut property_streams = Vec::new();
         for item in &mut self.items {
+            if item.is_track_only {
+                continue;
+            }
             let mut bit_depth_extension_metadata;
-            let item_metadata = if item.is_tmap() {
+            let item_metadata = if item.is_cover {
+                &self.cover_image_metadata
+            } else if item.is_tmap() {
                 &self.alt_image_metadata
             } else if item.category == Category::Gainmap {
                 &self.gainmap_image_metadata
+            } else if item.is_thumbnail {
+                &self.thumbnail_image_metadata
             } else {
                 match self.final_recipe.unwrap() {
                     Recipe::Auto => unreachable!(),
@@ -448,14 +587,31 @@ impl Encoder {
                             &bit_depth_extension_metadata
                         }
                     }
+                    recipe @ (Recipe::BitDepthExtension12b8bOverlap4b
+                    | Recipe::BitDepthExtension10b10bOverlap4b) => {
+                        if item.is_sato() {
+                            &self.image_metadata
+                        } else {
+                            let (base_depth, residual_depth) = recipe.residual_depths().unwrap();
+                            bit_depth_extension_metadata = self.image_metadata.shallow_clone();
+                            bit_depth_extension_metadata.depth =
+                                if item.is_sato_least_significant_input {
+                                    residual_depth
+                                } else {
+                                    base_depth
+                                };
+                            &bit_depth_extension_metadata
+                        }
+                    }
                 }
             };
+            let codec_choice = item.codec_choice.unwrap_or(self.settings.codec_choice);
             item.get_property_streams(
                 &self.image_metadata,
                 item_metadata,
                 &mut property_streams,
-                self.settings.must_write_extended_pixi(),
-                self.settings.codec_supports_native_alpha_channel(),
+                self.settings.force_write_extended_pixi || codec_choice.must_write_extended_pixi(),
+                codec_choice.supports_native_alpha_channel(),
             )?;
         }
         // Deduplicate the property streams.
@@ -557,14 +713,17 @@ impl Encoder {
     }
 
     pub(crate) fn write_track_meta(&mut self, stream: &mut OStream) -> AvifResult<()> {
-        let mut metadata_items: Vec<_> =
-            self.items.iter_mut().filter(|x| x.is_metadata()).collect();
+        let mut metadata_items: Vec<_> = self
+            .items
+            .iter_mut()
+            .filter(|x| x.is_metadata() && !x.is_cover)
+            .collect();
         if metadata_items.is_empty() {
             return Ok(());
         }
         stream.start_full_box("meta", (0, 0))?;
         write_hdlr(stream, "pict")?;
-        Self::write_iloc(stream, &mut metadata_items)?;
+        Self::write_iloc(stream, &mut metadata_items, self.large_offsets)?;
         Self::write_iinf(stream, &metadata_items)?;
         stream.finish_box()
     }
@@ -579,7 +738,7 @@ impl Encoder {
     ) -> AvifResult<()> {
         for index in 0..self.items.len() {
             let item = &self.items[index];
-            if item.samples.is_empty() {
+            if item.samples.is_empty() || item.is_cover {
                 continue;
             }
             stream.start_box("trak")?;
@@ -634,6 +793,7 @@ impl Encoder {
                         stream,
                         &self.image_metadata,
                         &self.duration_in_timescales,
+                        self.large_offsets,
                     )?;
                     stream.finish_box()?;
                 }
@@ -645,7 +805,11 @@ impl Encoder {
     }
 
     pub(crate) fn write_mdat(&self, stream: &mut OStream) -> AvifResult<()> {
-        stream.start_box("mdat")?;
+        if self.large_offsets {
+            stream.start_large_box("mdat")?;
+        } else {
+            stream.start_box("mdat")?;
+        }
         let mut layered_item_ids = [Vec::new(), Vec::new()];
         // Use multiple passes to pack the items in the following order:
         //   * Pass 0: metadata (Exif/XMP/gain map metadata)
@@ -684,10 +848,14 @@ impl Encoder {
                 }
 
                 let mut chunk_offset = stream.offset();
+                let mut iloc_extent_offset = None;
                 if !item.samples.is_empty() {
                     if item.samples.len() > 1 {
                         // If there is more than 1 sample, then we do not de-duplicate the chunks.
-                        for sample in &item.samples {
+                        for (index, sample) in item.samples.iter().enumerate() {
+                            if index == item.iloc_sample_index {
+                                iloc_extent_offset = Some(stream.offset());
+                            }
                             stream.write_slice(&sample.data)?;
                         }
                     } else {
@@ -701,10 +869,18 @@ impl Encoder {
                     // Empty item, ignore it.
                     continue;
                 }
-                for mdat_offset_location in &item.mdat_offset_locations {
-                    stream.write_u32_at_offset(
-                        u32_from_usize(chunk_offset)?,
+                for (index, mdat_offset_location) in item.mdat_offset_locations.iter().enumerate() {
+                    // The meta box is written before the moov box, so the first location is the
+                    // iloc extent, if any.
+                    let offset = match iloc_extent_offset {
+                        Some(iloc_extent_offset) if index == 0 => iloc_extent_offset,
+                        _ => chunk_offset,
+                    };
+                    write_offset_at(
+                        stream,
+                        u64_from_usize(offset)?,
                         *mdat_offset_location,
+                        self.large_offsets,
                     )?;
                 }
             }
@@ -729,9 +905,11 @@ impl Encoder {
 
                     let chunk_offset = stream.offset();
                     stream.write_slice(&item.samples[layer_index].data)?;
-                    stream.write_u32_at_offset(
-                        u32_from_usize(chunk_offset)?,
+                    write_offset_at(
+                        stream,
+                        u64_from_usize(chunk_offset)?,
                         item.mdat_offset_locations[layer_index],
+                        self.large_offsets,
                     )?;
                 }
                 layer_index += 1;
@@ -747,8 +925,12 @@ impl Encoder {
         stream.start_full_box("meta", (0, 0))?;
         write_hdlr(stream, "pict")?;
         write_pitm(stream, self.primary_item_id)?;
-        let mut items_ref: Vec<_> = self.items.iter_mut().collect();
-        Self::write_iloc(stream, &mut items_ref)?;
+        let mut items_ref: Vec<_> = self
+            .items
+            .iter_mut()
+            .filter(|item| !item.is_track_only)
+            .collect();
+        Self::write_iloc(stream, &mut items_ref, self.large_offsets)?;
         Self::write_iinf(stream, &items_ref)?;
         self.write_iref(stream)?;
         self.write_iprp(stream)?;
@@ -0,0 +1,302 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::encoder::*;
+
+fn for_each_sample(
+    image: &Image,
+    plane: Plane,
+    mut f: impl FnMut(u16) -> bool,
+) -> AvifResult<bool> {
+    for y in 0..u32_from_usize(image.height(plane))? {
+        let all = if image.depth == 8 {
+            image.row(plane, y)?.iter().all(|sample| f(*sample as u16))
+        } else {
+            image.row16(plane, y)?.iter().all(|sample| f(*sample))
+        };
+        if !all {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// Returns true if all the chroma samples of |image| are neutral, meaning that the samples only
+// carry luma information.
+fn has_neutral_chroma(image: &Image) -> AvifResult<bool> {
+    if image.yuv_format == PixelFormat::Yuv400
+        || !image.has_plane(Plane::U)
+        || image.matrix_coefficients == MatrixCoefficients::Identity
+    {
+        return Ok(false);
+    }
+    let neutral_value = 1u16 << (image.depth - 1);
+    for plane in [Plane::U, Plane::V] {
+        if !for_each_sample(image, plane, |sample| sample == neutral_value)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// Returns the number of least significant bits that are zero in all the samples of |planes|.
+fn zero_low_bit_count(image: &Image, planes: &[Plane]) -> AvifResult<u32> {
+    let mut bits = 0u16;
+    for plane in planes {
+        for_each_sample(image, *plane, |sample| {
+            bits |= sample;
+            true
+        })?;
+    }
+    Ok(bits.trailing_zeros())
+}
+
+// Returns a copy of the |planes| of |image| with |yuv_format| and |depth|, dropping the
+// least significant bits of the samples.
+fn convert(
+    image: &Image,
+    yuv_format: PixelFormat,
+    depth: u8,
+    planes: &[Plane],
+) -> AvifResult<Image> {
+    let mut converted = image.shallow_clone();
+    converted.yuv_format = yuv_format;
+    converted.depth = depth;
+    converted.quality_map = image.quality_map.clone();
+    converted.allocate_planes(Category::Color)?;
+    if planes.contains(&Plane::A) {
+        converted.allocate_planes(Category::Alpha)?;
+    }
+    let shift = image.depth - depth;
+    for plane in planes {
+        for y in 0..u32_from_usize(image.height(*plane))? {
+            if image.depth == 8 {
+                converted
+                    .row_mut(*plane, y)?
+                    .copy_from_slice(image.row(*plane, y)?);
+            } else if depth == 8 {
+                for (dst, src) in converted
+                    .row_mut(*plane, y)?
+                    .iter_mut()
+                    .zip(image.row16(*plane, y)?)
+                {
+                    *dst = (*src >> shift) as u8;
+                }
+            } else {
+                for (dst, src) in converted
+                    .row16_mut(*plane, y)?
+                    .iter_mut()
+                    .zip(image.row16(*plane, y)?)
+                {
+                    *dst = *src >> shift;
+                }
+            }
+        }
+    }
+    Ok(converted)
+}
+
+impl Encoder {
+    // Analyzes the cells of a single image and returns them with the optimizations enabled in
+    // Settings::input_optimizations applied, or None if there is nothing to change. The same
+    // optimizations are applied to all the cells so that they share the same format.
+    pub(crate) fn optimize_input(
+        &mut self,
+        cell_images: &[&Image],
+    ) -> AvifResult<Option<Vec<Image>>> {
+        let enabled = self.settings.input_optimizations;
+        let mut applied = InputOptimizations::default();
+        let first_image = cell_images[0];
+        if !cell_images.iter().all(|image| {
+            image.depth_valid()
+                && image.depth == first_image.depth
+                && image.yuv_format == first_image.yuv_format
+                && image.has_plane(Plane::Y)
+        }) {
+            // Invalid inputs are rejected by the caller.
+            return Ok(None);
+        }
+        let drop_alpha =
+            first_image.has_alpha() && cell_images.iter().all(|image| image.is_opaque());
+        // The alpha plane of an opaque single image is never encoded.
+        applied.drop_opaque_alpha = enabled.drop_opaque_alpha && drop_alpha;
+        if enabled.grayscale {
+            applied.grayscale = true;
+            for image in cell_images {
+                if !has_neutral_chroma(image)? {
+                    applied.grayscale = false;
+                    break;
+                }
+            }
+        }
+        let mut planes = if applied.grayscale { vec![Plane::Y] } else { YUV_PLANES.to_vec() };
+        if first_image.yuv_format == PixelFormat::Yuv400 {
+            planes = vec![Plane::Y];
+        }
+        if first_image.has_alpha() && !drop_alpha {
+            planes.push(Plane::A);
+        }
+        let mut depth = first_image.depth;
+        if enabled.reduce_depth
+            && first_image.depth > 8
+            && matches!(self.settings.recipe, Recipe::Auto | Recipe::None)
+        {
+            let mut zero_bit_count = u32::MAX;
+            for image in cell_images {
+                zero_bit_count = zero_bit_count.min(zero_low_bit_count(image, &planes)?);
+            }
+            // Use the lowest depth supported by AV1 that loses no information.
+            if let Some(reduced_depth) = [8, 10, 12].into_iter().find(|&d| {
+                d < first_image.depth && (first_image.depth - d) as u32 <= zero_bit_count
+            }) {
+                depth = reduced_depth;
+                applied.reduce_depth = true;
+            }
+        }
+        self.applied_input_optimizations = applied;
+        if !applied.grayscale && !applied.reduce_depth {
+            return Ok(None);
+        }
+        let yuv_format =
+            if applied.grayscale { PixelFormat::Yuv400 } else { first_image.yuv_format };
+        let mut optimized_images = Vec::with_capacity(cell_images.len());
+        for image in cell_images {
+            optimized_images.push(convert(image, yuv_format, depth, &planes)?);
+        }
+        let first_optimized_image = &mut optimized_images[0];
+        first_optimized_image.exif = first_image.exif.try_clone()?;
+        first_optimized_image.xmp = first_image.xmp.try_clone()?;
+        first_optimized_image.icc = first_image.icc.try_clone()?;
+        Ok(Some(optimized_images))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    fn image(depth: u8, yuv_format: PixelFormat, alpha: bool, sample: u16) -> AvifResult<Image> {
+        let mut image = Image {
+            width: 4,
+            height: 4,
+            depth,
+            yuv_format,
+            ..Default::default()
+        };
+        image.allocate_planes(Category::Color)?;
+        if alpha {
+            image.allocate_planes(Category::Alpha)?;
+        }
+        image.fill_plane_with_value(Plane::Y, sample)?;
+        Ok(image)
+    }
+
+    fn optimize(
+        images: &[&Image],
+        recipe: Recipe,
+    ) -> AvifResult<(InputOptimizations, Option<Vec<Image>>)> {
+        let mut encoder = Encoder::create_with_settings(&Settings {
+            recipe,
+            input_optimizations: InputOptimizations {
+                drop_opaque_alpha: true,
+                grayscale: true,
+                reduce_depth: true,
+            },
+            ..Default::default()
+        })?;
+        let optimized_images = encoder.optimize_input(images)?;
+        Ok((encoder.applied_input_optimizations(), optimized_images))
+    }
+
+    #[test_case(8, 0x12, 8 ; "8 bits")]
+    #[test_case(10, 0x120, 8 ; "10 to 8 bits")]
+    #[test_case(12, 0x124, 10 ; "12 to 10 bits")]
+    #[test_case(16, 0x1230, 12 ; "16 to 12 bits")]
+    #[test_case(16, 0x1200, 8 ; "16 to 8 bits")]
+    #[test_case(16, 0x1201, 16 ; "16 bits")]
+    fn reduce_depth(depth: u8, sample: u16, expected_depth: u8) -> AvifResult<()> {
+        let mut input = image(depth, PixelFormat::Yuv444, false, sample)?;
+        input.fill_plane_with_value(Plane::U, 0)?;
+        input.fill_plane_with_value(Plane::V, 0)?;
+        let (applied, optimized_images) = optimize(&[&input], Recipe::Auto)?;
+        assert_eq!(applied.reduce_depth, expected_depth != depth);
+        assert!(!applied.grayscale);
+        match optimized_images {
+            Some(optimized_images) => {
+                let optimized = &optimized_images[0];
+                assert_eq!(optimized.depth, expected_depth);
+                let expected_sample = sample >> (depth - expected_depth);
+                if expected_depth == 8 {
+                    assert_eq!(optimized.row(Plane::Y, 3)?[3] as u16, expected_sample);
+                } else {
+                    assert_eq!(optimized.row16(Plane::Y, 3)?[3], expected_sample);
+                }
+            }
+            None => assert_eq!(expected_depth, depth),
+        }
+        // Sample transform recipes need the input bit depth.
+        let (applied, _) = optimize(&[&input], Recipe::BitDepthExtension12b4b)?;
+        assert!(!applied.reduce_depth);
+        Ok(())
+    }
+
+    #[test_case(PixelFormat::Yuv420, 128, true ; "neutral")]
+    #[test_case(PixelFormat::Yuv444, 129, false ; "colored")]
+    #[test_case(PixelFormat::Yuv400, 128, false ; "already gray")]
+    fn grayscale(yuv_format: PixelFormat, chroma: u16, expected: bool) -> AvifResult<()> {
+        let mut cells = Vec::new();
+        for _ in 0..2 {
+            let mut cell = image(8, yuv_format, true, 100)?;
+            if yuv_format != PixelFormat::Yuv400 {
+                cell.fill_plane_with_value(Plane::U, 128)?;
+                cell.fill_plane_with_value(Plane::V, 128)?;
+            }
+            cells.push(cell);
+        }
+        if yuv_format != PixelFormat::Yuv400 {
+            cells[1].row_mut(Plane::V, 1)?[1] = chroma as u8;
+        }
+        cells[1].row_mut(Plane::A, 0)?[0] = 10;
+        let cell_refs: Vec<&Image> = cells.iter().collect();
+        let (applied, optimized_images) = optimize(&cell_refs, Recipe::Auto)?;
+        assert_eq!(applied.grayscale, expected);
+        assert!(!applied.drop_opaque_alpha);
+        assert_eq!(optimized_images.is_some(), expected);
+        if let Some(optimized_images) = optimized_images {
+            for (optimized, cell) in optimized_images.iter().zip(&cells) {
+                assert_eq!(optimized.yuv_format, PixelFormat::Yuv400);
+                assert!(!optimized.has_plane(Plane::U));
+                assert_eq!(optimized.row(Plane::Y, 2)?, cell.row(Plane::Y, 2)?);
+                assert_eq!(optimized.row(Plane::A, 0)?, cell.row(Plane::A, 0)?);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn opaque_alpha() -> AvifResult<()> {
+        // The opaque alpha plane does not prevent the depth reduction.
+        let mut input = image(16, PixelFormat::Yuv400, true, 0x4400)?;
+        input.exif = vec![1, 2];
+        let (applied, optimized_images) = optimize(&[&input], Recipe::Auto)?;
+        assert!(applied.drop_opaque_alpha);
+        assert!(applied.reduce_depth);
+        let optimized = &optimized_images.unwrap()[0];
+        assert_eq!(optimized.depth, 8);
+        assert!(!optimized.has_alpha());
+        assert_eq!(optimized.exif, input.exif);
+        Ok(())
+    }
+}
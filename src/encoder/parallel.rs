@@ -0,0 +1,317 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::codecs::EncoderConfig;
+use crate::encoder::item::Item;
+use crate::image::Image;
+use crate::internal_utils::*;
+use crate::utils::pixels::Pixels;
+use crate::*;
+
+pub(crate) enum EncodeInput<'a> {
+    Borrowed(&'a Image),
+    // Padded cell or bit depth extension image.
+    Owned(Box<Image>),
+}
+
+impl EncodeInput<'_> {
+    pub(crate) fn image(&self) -> &Image {
+        match self {
+            Self::Borrowed(image) => image,
+            Self::Owned(image) => image,
+        }
+    }
+}
+
+// The input and configuration of the codec of self.items[item_index].
+pub(crate) struct EncodeJob<'a> {
+    pub item_index: usize,
+    pub input: EncodeInput<'a>,
+    pub config: EncoderConfig,
+}
+
+pub(crate) fn encode_item(item: &mut Item, job: &EncodeJob) -> AvifResult<()> {
+    item.codec.unwrap_mut().encode_image(
+        job.input.image(),
+        item.category,
+        &job.config,
+        &mut item.samples,
+    )
+}
+
+// A job whose input image only has planes owned by the image (Pixels::Buffer and
+// Pixels::Buffer16) so that it can be run on another thread.
+struct ThreadJob<'a>(EncodeJob<'a>);
+
+// SAFETY: Image is neither Send nor Sync only because its planes may be PointerSlices into memory
+// owned by the caller. The planes of the input of a ThreadJob are Vecs (see ThreadJob::create()),
+// so the input can be moved to and read from another thread like any other owned data.
+unsafe impl Send for ThreadJob<'_> {}
+
+impl<'a> ThreadJob<'a> {
+    fn create(mut job: EncodeJob<'a>) -> AvifResult<Self> {
+        let image = job.input.image();
+        let has_pointer_planes = image
+            .planes
+            .iter()
+            .flatten()
+            .any(|pixels| matches!(pixels, Pixels::Pointer(_) | Pixels::Pointer16(_)));
+        if has_pointer_planes {
+            let mut copy = image.shallow_clone();
+            copy.copy_and_pad(image)?;
+            job.input = EncodeInput::Owned(Box::new(copy));
+        }
+        Ok(Self(job))
+    }
+}
+
+// Jobs run by a single worker thread.
+struct WorkerJobs<'a, 'b>(Vec<(&'a mut Item, ThreadJob<'b>)>);
+
+impl WorkerJobs<'_, '_> {
+    fn run(self) -> AvifResult<()> {
+        for (item, job) in self.0 {
+            encode_item(item, &job.0)?;
+        }
+        Ok(())
+    }
+}
+
+// Runs |jobs|, sorted by item index, on at most |max_threads| threads. Each item has its own codec
+// instance so the samples do not depend on the number of threads.
+pub(crate) fn encode_items_in_parallel(
+    items: &mut [Item],
+    jobs: Vec<EncodeJob>,
+    max_threads: u32,
+) -> AvifResult<()> {
+    let thread_count = jobs.len().min(usize_from_u32(max_threads.max(1))?);
+    if thread_count <= 1 {
+        for job in &jobs {
+            encode_item(&mut items[job.item_index], job)?;
+        }
+        return Ok(());
+    }
+    // Distribute the jobs in a round-robin fashion since cells usually take a similar time.
+    let mut workers: Vec<_> = (0..thread_count).map(|_| WorkerJobs(Vec::new())).collect();
+    let mut jobs = jobs.into_iter().peekable();
+    let mut job_count = 0;
+    for (item_index, item) in items.iter_mut().enumerate() {
+        if jobs.peek().is_some_and(|job| job.item_index == item_index) {
+            workers[job_count % thread_count]
+                .0
+                .push((item, ThreadJob::create(jobs.next().unwrap())?));
+            job_count += 1;
+        }
+    }
+    assert!(jobs.next().is_none());
+    let results: Vec<_> = std::thread::scope(|scope| {
+        let handles: Vec<_> = workers
+            .into_iter()
+            .map(|worker| scope.spawn(move || worker.run()))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|err| std::panic::resume_unwind(err))
+            })
+            .collect()
+    });
+    results.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::Sample;
+    use crate::parser::mp4box::CodecConfiguration;
+    use crate::utils::clap::CropRect;
+
+    // Outputs the first sample of the input image, the quality and the number of calls.
+    #[derive(Default)]
+    struct FakeCodec {
+        call_count: u8,
+    }
+
+    impl crate::codecs::Encoder for FakeCodec {
+        fn encode_image(
+            &mut self,
+            image: &Image,
+            _category: Category,
+            config: &EncoderConfig,
+            output_samples: &mut Vec<Sample>,
+        ) -> AvifResult<()> {
+            if config.quality < 0.0 {
+                return AvifError::unknown_error("");
+            }
+            self.call_count += 1;
+            let data = [
+                image.row(Plane::Y, 0)?[0],
+                config.quality as u8,
+                self.call_count,
+            ];
+            output_samples.push(Sample::create_from(&data, true)?);
+            Ok(())
+        }
+
+        fn finish(&mut self, _output_samples: &mut Vec<Sample>) -> AvifResult<()> {
+            Ok(())
+        }
+
+        fn get_codec_config(
+            &self,
+            _image: &Image,
+            _is_single_image: bool,
+            _is_lossless: bool,
+            _output_samples: &[Sample],
+        ) -> AvifResult<CodecConfiguration> {
+            AvifError::not_implemented()
+        }
+    }
+
+    fn encode(images: &[Image], max_threads: u32, frame_count: u8) -> AvifResult<Vec<Vec<u8>>> {
+        // Every other item has no codec, like derived items.
+        let mut items: Vec<Item> = (0..images.len() * 2)
+            .map(|index| Item {
+                codec: if index % 2 == 0 { Some(Box::<FakeCodec>::default()) } else { None },
+                ..Default::default()
+            })
+            .collect();
+        for _ in 0..frame_count {
+            let jobs = images
+                .iter()
+                .enumerate()
+                .map(|(index, image)| EncodeJob {
+                    item_index: index * 2,
+                    input: EncodeInput::Borrowed(image),
+                    config: EncoderConfig {
+                        quality: index as f32,
+                        ..Default::default()
+                    },
+                })
+                .collect();
+            encode_items_in_parallel(&mut items, jobs, max_threads)?;
+        }
+        Ok(items
+            .iter()
+            .map(|item| {
+                item.samples
+                    .iter()
+                    .flat_map(|sample| sample.data.clone())
+                    .collect()
+            })
+            .collect())
+    }
+
+    #[test]
+    fn deterministic_output() -> AvifResult<()> {
+        let mut images = Vec::new();
+        for index in 0..7 {
+            let mut image = Image {
+                width: 1,
+                height: 1,
+                depth: 8,
+                yuv_format: PixelFormat::Yuv400,
+                ..Default::default()
+            };
+            image.allocate_planes(Category::Color)?;
+            image.row_mut(Plane::Y, 0)?[0] = 100 + index;
+            images.push(image);
+        }
+        let expected = encode(&images, 1, 2)?;
+        assert_eq!(expected[0], vec![100, 0, 1, 100, 0, 2]);
+        assert!(expected[1].is_empty());
+        assert_eq!(expected[12], vec![106, 6, 1, 106, 6, 2]);
+        for max_threads in [0, 2, 3, 7, 16] {
+            assert_eq!(encode(&images, max_threads, 2)?, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pointer_planes() -> AvifResult<()> {
+        let mut image = Image {
+            width: 2,
+            height: 1,
+            depth: 8,
+            yuv_format: PixelFormat::Yuv400,
+            ..Default::default()
+        };
+        image.allocate_planes(Category::Color)?;
+        image.row_mut(Plane::Y, 0)?.copy_from_slice(&[100, 101]);
+        // The planes of the views point to the pixels of |image|.
+        let views = (0..2)
+            .map(|x| {
+                image.view(&CropRect {
+                    x,
+                    y: 0,
+                    width: 1,
+                    height: 1,
+                })
+            })
+            .collect::<AvifResult<Vec<_>>>()?;
+        let mut items: Vec<Item> = (0..2)
+            .map(|_| Item {
+                codec: Some(Box::<FakeCodec>::default()),
+                ..Default::default()
+            })
+            .collect();
+        let jobs = views
+            .iter()
+            .enumerate()
+            .map(|(index, view)| EncodeJob {
+                item_index: index,
+                input: EncodeInput::Borrowed(view),
+                config: EncoderConfig::default(),
+            })
+            .collect();
+        encode_items_in_parallel(&mut items, jobs, 2)?;
+        assert_eq!(items[0].samples[0].data, vec![100, 0, 1]);
+        assert_eq!(items[1].samples[0].data, vec![101, 0, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn error() -> AvifResult<()> {
+        let mut image = Image {
+            width: 1,
+            height: 1,
+            depth: 8,
+            yuv_format: PixelFormat::Yuv400,
+            ..Default::default()
+        };
+        image.allocate_planes(Category::Color)?;
+        for max_threads in [1, 4] {
+            let mut items: Vec<Item> = (0..4)
+                .map(|_| Item {
+                    codec: Some(Box::<FakeCodec>::default()),
+                    ..Default::default()
+                })
+                .collect();
+            let jobs = (0..4)
+                .map(|index| EncodeJob {
+                    item_index: index,
+                    input: EncodeInput::Borrowed(&image),
+                    config: EncoderConfig {
+                        quality: if index == 2 { -1.0 } else { 0.0 },
+                        ..Default::default()
+                    },
+                })
+                .collect();
+            assert!(encode_items_in_parallel(&mut items, jobs, max_threads).is_err());
+        }
+        Ok(())
+    }
+}
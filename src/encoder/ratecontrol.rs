@@ -0,0 +1,406 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::encoder::*;
+use crate::*;
+
+const MAX_QUALITY: u8 = 100;
+
+impl RateControl {
+    pub(crate) fn is_valid(&self) -> bool {
+        match *self {
+            Self::Quality => true,
+            Self::TargetSize {
+                target_size,
+                max_size,
+            } => target_size > 0 && max_size.unwrap_or(target_size) >= target_size,
+            Self::TargetMetric { target, .. } => target.is_finite(),
+        }
+    }
+}
+
+impl MutableSettings {
+    // Returns a copy of self with the color quality set to |quality| and the alpha and gain map
+    // qualities changed by the same ratio.
+    fn with_scaled_quality(&self, quality: f32) -> Self {
+        let scale = |value: f32| {
+            if self.quality > 0.0 {
+                (value * quality / self.quality).round().clamp(0.0, 100.0)
+            } else {
+                quality
+            }
+        };
+        Self {
+            quality,
+            quality_alpha: scale(self.quality_alpha),
+            quality_gainmap: scale(self.quality_gainmap),
+            ..*self
+        }
+    }
+}
+
+// Returns the quality in [0:100] for which |encoded_size| is the closest to |target_size| without
+// exceeding |max_size|, assuming |encoded_size| increases with the quality. Also returns the
+// corresponding size.
+fn search_quality_for_target_size(
+    target_size: usize,
+    max_size: Option<usize>,
+    mut encoded_size: impl FnMut(u8) -> AvifResult<usize>,
+) -> AvifResult<(u8, usize)> {
+    let mut sizes = [None; MAX_QUALITY as usize + 1];
+    let mut size_at = |quality: u8| -> AvifResult<usize> {
+        if let Some(size) = sizes[quality as usize] {
+            return Ok(size);
+        }
+        let size = encoded_size(quality)?;
+        sizes[quality as usize] = Some(size);
+        Ok(size)
+    };
+    // Binary search for the highest quality whose size does not exceed target_size.
+    let mut low = 0;
+    let mut high = MAX_QUALITY;
+    if size_at(low)? > target_size {
+        high = low;
+    }
+    while low < high {
+        let mid = (low + high).div_ceil(2);
+        if size_at(mid)? <= target_size {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    let mut quality = low;
+    let mut size = size_at(quality)?;
+    if size <= target_size && quality < MAX_QUALITY {
+        // The next quality may be closer to the target while being above it.
+        let next_size = size_at(quality + 1)?;
+        if next_size.abs_diff(target_size) < target_size - size
+            && next_size <= max_size.unwrap_or(usize::MAX)
+        {
+            quality += 1;
+            size = next_size;
+        }
+    }
+    if size > max_size.unwrap_or(usize::MAX) {
+        return AvifError::unknown_error(format!(
+            "the smallest encoded size {size} exceeds the maximum size"
+        ));
+    }
+    Ok((quality, size))
+}
+
+// Returns the lowest quality in [0:100] for which |metric_and_size| returns a metric of at least
+// |target|, assuming the metric increases with the quality. If no quality reaches the target, the
+// highest quality is returned. Also returns the corresponding metric and size.
+fn search_quality_for_target_metric(
+    target: f64,
+    mut metric_and_size: impl FnMut(u8) -> AvifResult<(f64, usize)>,
+) -> AvifResult<(u8, f64, usize)> {
+    let mut results = [None; MAX_QUALITY as usize + 1];
+    let mut result_at = |quality: u8| -> AvifResult<(f64, usize)> {
+        if let Some(result) = results[quality as usize] {
+            return Ok(result);
+        }
+        let result = metric_and_size(quality)?;
+        results[quality as usize] = Some(result);
+        Ok(result)
+    };
+    let mut low = 0;
+    let mut high = MAX_QUALITY;
+    while low < high {
+        let mid = (low + high) / 2;
+        if result_at(mid)?.0 >= target {
+            high = mid;
+        } else {
+            low = mid + 1;
+        }
+    }
+    let (metric, size) = result_at(low)?;
+    Ok((low, metric, size))
+}
+
+// Returns the lowest value of |metric| between each cell of |source_images| and the corresponding
+// region of |decoded_image|.
+fn cell_metric(
+    source_images: &[&Image],
+    grid_columns: u32,
+    decoded_image: &Image,
+    metric: Metric,
+    space: MetricSpace,
+) -> AvifResult<f64> {
+    let mut lowest = f64::INFINITY;
+    for (cell_index, source_image) in source_images.iter().enumerate() {
+        let cell_index = u32_from_usize(cell_index)?;
+        let rect = CropRect {
+            x: checked_mul!(cell_index % grid_columns, source_images[0].width)?,
+            y: checked_mul!(cell_index / grid_columns, source_images[0].height)?,
+            width: source_image.width,
+            height: source_image.height,
+        };
+        if checked_add!(rect.x, rect.width)? > decoded_image.width
+            || checked_add!(rect.y, rect.height)? > decoded_image.height
+        {
+            return AvifError::unknown_error("unexpected decoded image dimensions");
+        }
+        let decoded_cell = decoded_image.sub_image(&rect)?;
+        lowest = lowest.min(metric.compute(source_image, &decoded_cell, space)?);
+    }
+    Ok(lowest)
+}
+
+// Output of the codec of an item for one rate control attempt.
+struct EncodedItem {
+    samples: Vec<Sample>,
+    codec_configuration: Option<CodecConfiguration>,
+}
+
+impl Encoder {
+    // Moves the output of the codecs out of the items, in the order of the items.
+    fn take_encoded_items(&mut self) -> Vec<EncodedItem> {
+        self.items
+            .iter_mut()
+            .filter(|item| item.codec.is_some())
+            .map(|item| EncodedItem {
+                samples: std::mem::take(&mut item.samples),
+                codec_configuration: item.codec_configuration.take(),
+            })
+            .collect()
+    }
+
+    // Puts back the output returned by take_encoded_items() and discards the boxes written for
+    // it, so that finish() writes the file again.
+    fn restore_encoded_items(&mut self, encoded_items: Vec<EncodedItem>) {
+        let mut encoded_items = encoded_items.into_iter();
+        for item in &mut self.items {
+            if item.codec.is_some() {
+                let encoded_item = encoded_items.next().unwrap();
+                item.samples = encoded_item.samples;
+                item.codec_configuration = encoded_item.codec_configuration;
+            }
+            item.clear_written_boxes();
+        }
+    }
+
+    // Discards the output of the previous encoding attempt so that the same items can be encoded
+    // again.
+    fn reset_items(&mut self) -> AvifResult<()> {
+        for item in &mut self.items {
+            if item.codec.is_some() {
+                let (_, codec) = self
+                    .settings
+                    .codec_choice
+                    .get_item_type_and_encoder_codec(&self.codec_plugins)?;
+                item.codec = Some(codec);
+                item.samples.clear();
+                item.codec_configuration = None;
+            }
+            item.clear_written_boxes();
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn encode_items_with_rate_control(
+        &mut self,
+        cell_images: &[&Image],
+        gainmaps: Option<&[&GainMap]>,
+        thumbnail_images: &[Image],
+        final_recipe: Recipe,
+        is_single_image: bool,
+        frame_options: &FrameOptions,
+        duration: u64,
+    ) -> AvifResult<()> {
+        if self.settings.rate_control == RateControl::Quality {
+            return self.encode_items(
+                cell_images,
+                gainmaps,
+                thumbnail_images,
+                final_recipe,
+                is_single_image,
+                /*pad_cells=*/ true,
+                frame_options,
+                duration,
+            );
+        }
+        if !is_single_image || !self.duration_in_timescales.is_empty() {
+            return AvifError::not_implemented();
+        }
+        let base_settings = self.settings.mutable;
+        let mut attempt_count = 0;
+        // The samples and codec configurations of each item for every quality tried so far, so
+        // that the chosen attempt does not have to be encoded again.
+        let mut attempts: Vec<(u8, Vec<EncodedItem>)> = Vec::new();
+        let mut encode_at = |encoder: &mut Self, quality: u8| -> AvifResult<Vec<u8>> {
+            attempt_count += 1;
+            encoder.settings.mutable = base_settings.with_scaled_quality(quality as f32);
+            encoder.reset_items()?;
+            encoder.encode_items(
+                cell_images,
+                gainmaps,
+                thumbnail_images,
+                final_recipe,
+                is_single_image,
+                /*pad_cells=*/ true,
+                frame_options,
+                duration,
+            )?;
+            encoder.finish_codecs()?;
+            let output = encoder.write_output()?;
+            attempts.push((quality, encoder.take_encoded_items()));
+            Ok(output)
+        };
+        let (quality, metric, size) = match self.settings.rate_control {
+            RateControl::Quality => unreachable!(),
+            RateControl::TargetSize {
+                target_size,
+                max_size,
+            } => {
+                let (quality, size) =
+                    search_quality_for_target_size(target_size, max_size, |quality| {
+                        Ok(encode_at(self, quality)?.len())
+                    })?;
+                (quality, None, size)
+            }
+            RateControl::TargetMetric {
+                metric,
+                space,
+                target,
+            } => {
+                let grid_columns = self
+                    .items
+                    .iter()
+                    .find(|item| item.category == Category::Color && item.grid.is_some())
+                    .map_or(1, |item| item.grid.unwrap().columns);
+                let (quality, metric, size) =
+                    search_quality_for_target_metric(target, |quality| {
+                        let output = encode_at(self, quality)?;
+                        let size = output.len();
+                        let mut decoder = decoder::Decoder::default();
+                        decoder.settings.allow_sample_transform = true;
+                        decoder.set_io_vec(output);
+                        decoder.parse()?;
+                        decoder.next_image()?;
+                        let decoded_image = decoder
+                            .image()
+                            .ok_or(AvifError::UnknownError("no decoded image".into()))?;
+                        let value =
+                            cell_metric(cell_images, grid_columns, decoded_image, metric, space)?;
+                        Ok((value, size))
+                    })?;
+                (quality, Some(metric), size)
+            }
+        };
+
+        // Keep the output of the chosen attempt. The codecs are already finalized, so finish()
+        // only writes the file.
+        let chosen = attempts
+            .into_iter()
+            .rfind(|(attempt_quality, _)| *attempt_quality == quality)
+            .ok_or(AvifError::UnknownError(
+                "missing rate control attempt".into(),
+            ))?;
+        self.restore_encoded_items(chosen.1);
+        let chosen_settings = base_settings.with_scaled_quality(quality as f32);
+        self.settings.mutable = base_settings;
+        self.rate_control_result = Some(RateControlResult {
+            quality: chosen_settings.quality,
+            quality_alpha: chosen_settings.quality_alpha,
+            quality_gainmap: chosen_settings.quality_gainmap,
+            size,
+            attempt_count,
+            metric,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_case;
+
+    // Size grows quadratically with the quality, from 1000 to 101000 bytes.
+    fn encoded_size(quality: u8) -> AvifResult<usize> {
+        Ok(1000 + 10 * (quality as usize) * (quality as usize))
+    }
+
+    #[test_case(50000, None, 70, 50000 ; "exact")]
+    #[test_case(50100, None, 70, 50000 ; "below")]
+    #[test_case(51350, None, 71, 51410 ; "above is closer")]
+    #[test_case(51350, Some(51400), 70, 50000 ; "above is closer but too large")]
+    #[test_case(500, None, 0, 1000 ; "smaller than lowest quality")]
+    #[test_case(500, Some(1000), 0, 1000 ; "max size reached")]
+    #[test_case(200000, None, 100, 101000 ; "larger than highest quality")]
+    fn target_size(
+        target_size: usize,
+        max_size: Option<usize>,
+        expected_quality: u8,
+        expected_size: usize,
+    ) -> AvifResult<()> {
+        let mut attempt_count = 0;
+        let (quality, size) = search_quality_for_target_size(target_size, max_size, |quality| {
+            attempt_count += 1;
+            encoded_size(quality)
+        })?;
+        assert_eq!((quality, size), (expected_quality, expected_size));
+        // Binary search over 101 values plus one extra attempt.
+        assert!(attempt_count <= 9);
+        Ok(())
+    }
+
+    #[test]
+    fn max_size_too_small() {
+        assert!(search_quality_for_target_size(500, Some(999), encoded_size).is_err());
+    }
+
+    // PSNR grows linearly with the quality, from 20 to 45 dB.
+    fn metric_and_size(quality: u8) -> AvifResult<(f64, usize)> {
+        Ok((20.0 + quality as f64 / 4.0, encoded_size(quality)?))
+    }
+
+    #[test_case(20.0, 0, 20.0 ; "lowest quality")]
+    #[test_case(35.0, 60, 35.0 ; "exact")]
+    #[test_case(35.1, 61, 35.25 ; "above")]
+    #[test_case(50.0, 100, 45.0 ; "unreachable")]
+    fn target_metric(target: f64, expected_quality: u8, expected_metric: f64) -> AvifResult<()> {
+        let mut attempt_count = 0;
+        let (quality, metric, size) = search_quality_for_target_metric(target, |quality| {
+            attempt_count += 1;
+            metric_and_size(quality)
+        })?;
+        assert_eq!(quality, expected_quality);
+        assert_eq!(metric, expected_metric);
+        assert_eq!(size, encoded_size(quality)?);
+        assert!(attempt_count <= 8);
+        Ok(())
+    }
+
+    #[test]
+    fn scaled_quality() {
+        let settings = MutableSettings {
+            quality: 50.0,
+            quality_alpha: 100.0,
+            quality_gainmap: 25.0,
+            ..Default::default()
+        };
+        let scaled = settings.with_scaled_quality(30.0);
+        assert_eq!(scaled.quality, 30.0);
+        assert_eq!(scaled.quality_alpha, 60.0);
+        assert_eq!(scaled.quality_gainmap, 15.0);
+        let scaled = settings.with_scaled_quality(80.0);
+        assert_eq!(scaled.quality_alpha, 100.0);
+        assert_eq!(scaled.quality_gainmap, 40.0);
+    }
+}
@@ -0,0 +1,171 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::encoder::*;
+use crate::reformat::rgb::ChromaDownsampling;
+
+impl RgbPolicy {
+    // Returns the YUV image to encode for |rgb|. If |lossless| is true, the returned image can be
+    // converted back to |rgb| without loss once encoded losslessly.
+    pub(crate) fn convert(&self, rgb: &rgb::Image, lossless: bool) -> AvifResult<Image> {
+        let mut image = Image {
+            width: rgb.width,
+            height: rgb.height,
+            depth: self.depth.unwrap_or(rgb.depth),
+            yuv_format: self.yuv_format,
+            yuv_range: self.yuv_range,
+            matrix_coefficients: self.matrix_coefficients,
+            color_primaries: self.color_primaries,
+            transfer_characteristics: self.transfer_characteristics,
+            // Keep the alpha samples as is.
+            alpha_premultiplied: rgb.premultiply_alpha,
+            ..Default::default()
+        };
+        let mut sharp_yuv = self.sharp_yuv;
+        if lossless {
+            image.depth = rgb.depth;
+            image.yuv_range = YuvRange::Full;
+            sharp_yuv = false;
+            if rgb.format.is_gray() {
+                image.yuv_format = PixelFormat::Yuv400;
+                image.matrix_coefficients = MatrixCoefficients::Unspecified;
+            } else if self.lossless_mode == RgbLosslessMode::YcgcoR && matches!(rgb.depth, 8 | 10) {
+                image.yuv_format = PixelFormat::Yuv444;
+                image.matrix_coefficients = MatrixCoefficients::YcgcoRe;
+                image.depth = rgb.depth + 2;
+            } else {
+                image.yuv_format = PixelFormat::Yuv444;
+                image.matrix_coefficients = MatrixCoefficients::Identity;
+            }
+        }
+        if sharp_yuv {
+            rgb.convert_to_yuv_with_chroma_downsampling(&mut image, ChromaDownsampling::SharpYuv)?;
+        } else {
+            rgb.convert_to_yuv(&mut image)?;
+        }
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reformat::rgb::Format;
+    use test_case::test_matrix;
+
+    fn rgb_image(format: Format, depth: u8) -> AvifResult<rgb::Image> {
+        let mut rgb = rgb::Image {
+            width: 13,
+            height: 7,
+            depth,
+            format,
+            ..Default::default()
+        };
+        rgb.allocate()?;
+        let max_channel = rgb.max_channel() as u32;
+        for y in 0..rgb.height {
+            if depth == 8 {
+                for (i, sample) in rgb.row_mut(y)?.iter_mut().enumerate() {
+                    *sample = ((i as u32 * 37 + y * 101) % (max_channel + 1)) as u8;
+                }
+            } else {
+                for (i, sample) in rgb.row16_mut(y)?.iter_mut().enumerate() {
+                    *sample = ((i as u32 * 997 + y * 4099) % (max_channel + 1)) as u16;
+                }
+            }
+        }
+        Ok(rgb)
+    }
+
+    #[test_matrix(
+        [Format::Rgba, Format::Bgr, Format::Gray],
+        [8, 10, 12],
+        [RgbLosslessMode::Identity, RgbLosslessMode::YcgcoR]
+    )]
+    fn lossless(format: Format, depth: u8, lossless_mode: RgbLosslessMode) -> AvifResult<()> {
+        let rgb = rgb_image(format, depth)?;
+        let policy = RgbPolicy {
+            yuv_range: YuvRange::Limited,
+            depth: Some(8),
+            lossless_mode,
+            ..Default::default()
+        };
+        let image = policy.convert(&rgb, true)?;
+        assert_eq!(image.yuv_range, YuvRange::Full);
+        let ycgco = lossless_mode == RgbLosslessMode::YcgcoR && depth != 12;
+        let (expected_format, expected_matrix, expected_depth) = if format == Format::Gray {
+            (PixelFormat::Yuv400, MatrixCoefficients::Unspecified, depth)
+        } else if ycgco {
+            (PixelFormat::Yuv444, MatrixCoefficients::YcgcoRe, depth + 2)
+        } else {
+            (PixelFormat::Yuv444, MatrixCoefficients::Identity, depth)
+        };
+        assert_eq!(image.yuv_format, expected_format);
+        assert_eq!(image.matrix_coefficients, expected_matrix);
+        assert_eq!(image.depth, expected_depth);
+        assert_eq!(image.has_alpha(), format == Format::Rgba);
+
+        let mut decoded = rgb::Image::create_from_yuv(&image);
+        decoded.format = format;
+        decoded.depth = depth;
+        decoded.allocate()?;
+        decoded.convert_from_yuv(&image)?;
+        for y in 0..rgb.height {
+            if depth == 8 {
+                assert_eq!(decoded.row(y)?, rgb.row(y)?);
+            } else {
+                assert_eq!(decoded.row16(y)?, rgb.row16(y)?);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn lossy() -> AvifResult<()> {
+        let rgb = rgb_image(Format::Rgb, 8)?;
+        let policy = RgbPolicy {
+            depth: Some(10),
+            matrix_coefficients: MatrixCoefficients::Bt709,
+            yuv_range: YuvRange::Limited,
+            lossless_mode: RgbLosslessMode::YcgcoR,
+            ..Default::default()
+        };
+        let image = policy.convert(&rgb, false)?;
+        assert_eq!(image.yuv_format, PixelFormat::Yuv420);
+        assert_eq!(image.matrix_coefficients, MatrixCoefficients::Bt709);
+        assert_eq!(image.yuv_range, YuvRange::Limited);
+        assert_eq!(image.depth, 10);
+        assert_eq!((image.width, image.height), (13, 7));
+        assert!(!image.has_alpha());
+        Ok(())
+    }
+
+    #[test]
+    fn sharp_yuv() -> AvifResult<()> {
+        let rgb = rgb_image(Format::Rgba, 8)?;
+        let policy = RgbPolicy {
+            sharp_yuv: true,
+            ..Default::default()
+        };
+        let result = policy.convert(&rgb, false);
+        if cfg!(feature = "sharpyuv") {
+            assert_eq!(result?.yuv_format, PixelFormat::Yuv420);
+        } else {
+            assert_eq!(result.err(), Some(AvifError::NotImplemented));
+        }
+        // SharpYUV is not used for lossless conversions.
+        assert!(policy.convert(&rgb, true).is_ok());
+        Ok(())
+    }
+}
@@ -12,22 +12,63 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::codecs::DecoderConfig;
+use crate::decoder::CompressionFormat;
 use crate::encoder::*;
 use crate::internal_utils::sampletransform::*;
 use crate::*;
 
 impl Recipe {
-    pub(crate) fn self_or_auto_choose_depending_on(self, image: &Image) -> Recipe {
+    // The residual recipes are only chosen for single, non-layered images, the only ones they
+    // support.
+    pub(crate) fn self_or_auto_choose_depending_on(
+        self,
+        image: &Image,
+        is_single_image: bool,
+        extra_layer_count: u32,
+        encoder: &Encoder,
+    ) -> Recipe {
         match self {
             Recipe::Auto => match image.depth {
                 8 | 10 | 12 => Recipe::None,
+                16 if is_single_image
+                    && extra_layer_count == 0
+                    && encoder.settings.mutable.quality < 100.0
+                    && encoder.can_decode_base_image() =>
+                {
+                    Recipe::BitDepthExtension12b8bOverlap4b
+                }
                 16 => Recipe::BitDepthExtension12b4b,
                 // This is unsupported and will lead to an error later.
                 _ => Recipe::None,
             },
-            Recipe::None | Recipe::BitDepthExtension8b8b | Recipe::BitDepthExtension12b4b => self,
+            Recipe::None
+            | Recipe::BitDepthExtension8b8b
+            | Recipe::BitDepthExtension12b4b
+            | Recipe::BitDepthExtension12b8bOverlap4b
+            | Recipe::BitDepthExtension10b10bOverlap4b => self,
         }
     }
+
+    // Returns the depths of the base image and of the residual image for the recipes encoding the
+    // difference between the input image and the decoded base image.
+    pub(crate) fn residual_depths(self) -> Option<(u8, u8)> {
+        match self {
+            Recipe::BitDepthExtension12b8bOverlap4b => Some((12, 8)),
+            Recipe::BitDepthExtension10b10bOverlap4b => Some((10, 10)),
+            _ => None,
+        }
+    }
+}
+
+impl Encoder {
+    // Returns true if the base image of the residual recipes can be decoded at encoding.
+    fn can_decode_base_image(&self) -> bool {
+        self.compression_format(self.settings.codec_choice) == Some(CompressionFormat::Avif)
+            && CodecChoice::Auto
+                .get_decoder_codec(CompressionFormat::Avif, &[])
+                .is_some()
+    }
 }
 
 // Mapping used in the coding of Sample Transform metadata.
@@ -136,11 +177,38 @@ fn recipe_to_expression(recipe: Recipe) -> Result<SampleTransform, AvifError> {
                 ],
             )
         }
+        Recipe::BitDepthExtension12b8bOverlap4b | Recipe::BitDepthExtension10b10bOverlap4b => {
+            // reference_count is two: one lossy base image and one residual image.
+            //   (base_sample << shift) + hidden_sample - offset
+            // Note: base_sample is encoded lossily. hidden_sample is encoded losslessly.
+            let (base_depth, residual_depth) = recipe.residual_depths().unwrap();
+            SampleTransform::create_from(
+                // SampleTransformBitDepth::Signed32bits is necessary because the two input images
+                // once combined use 16-bit unsigned values, but intermediate results are stored in signed integers.
+                SampleTransformBitDepth::Signed32bits.to_bits(),
+                2, // num_inputs
+                vec![
+                    // The base image represents the most significant bits of the reconstructed,
+                    // bit-depth-extended output image, with some loss.
+                    SampleTransformToken::Constant(1 << (16 - base_depth)),
+                    SampleTransformToken::ImageItem(0),
+                    SampleTransformToken::BinaryOp(SampleTransformBinaryOp::Product),
+                    // The second image represents the difference between the input image and the
+                    // decoded base image, offset to be stored as unsigned values.
+                    SampleTransformToken::ImageItem(1),
+                    SampleTransformToken::BinaryOp(SampleTransformBinaryOp::Sum),
+                    SampleTransformToken::Constant(1 << (residual_depth - 1)),
+                    SampleTransformToken::BinaryOp(SampleTransformBinaryOp::Difference),
+                ],
+            )
+        }
     }
 }
 
-fn write_sato(recipe: Recipe) -> AvifResult<Vec<u8>> {
-    let expression = recipe_to_expression(recipe)?;
+fn write_sato(expression: &SampleTransform) -> AvifResult<Vec<u8>> {
+    if !matches!(expression.bit_depth, 8 | 16 | 32 | 64) || expression.tokens.len() > 255 {
+        return AvifError::invalid_argument();
+    }
     let bit_depth = SampleTransformBitDepth::from_bits(expression.bit_depth);
 
     let mut stream = OStream::default();
@@ -152,10 +220,13 @@ fn write_sato(recipe: Recipe) -> AvifResult<Vec<u8>> {
     for token in &expression.tokens {
         stream.write_u8(token.to_type())?; // unsigned int(8) token;
         if let SampleTransformToken::Constant(constant) = token {
-            let constant = i32::try_from(*constant).unwrap();
-            let bytes = &constant.to_be_bytes();
-            assert_eq!(bytes.len() * 8, bit_depth.to_bits().into());
-            stream.write_slice(bytes)?; // signed int(1<<(bit_depth+3)) constant;
+            // signed int(1<<(bit_depth+3)) constant;
+            let bytes = &constant.to_be_bytes()[8 - bit_depth.to_bits() as usize / 8..];
+            let min = -(1i128 << (bit_depth.to_bits() - 1));
+            if (*constant as i128) < min || (*constant as i128) >= -min {
+                return AvifError::invalid_argument();
+            }
+            stream.write_slice(bytes)?;
         }
     }
     Ok(stream.data)
@@ -180,7 +251,7 @@ impl Encoder {
             id: u16_from_usize(self.items.len() + 1)?,
             item_type: "sato".into(),
             category: Category::Color,
-            metadata_payload: write_sato(self.final_recipe.unwrap())?,
+            metadata_payload: write_sato(&recipe_to_expression(self.final_recipe.unwrap())?)?,
             hidden_image: false,
             ..Default::default()
         };
@@ -229,6 +300,97 @@ impl Encoder {
         Ok(())
     }
 
+    pub(crate) fn validate_sample_transform(
+        sample_transform: &SampleTransform,
+        inputs: &[&Image],
+        depth: u8,
+    ) -> AvifResult<()> {
+        if inputs.is_empty()
+            || inputs.len() > 32
+            || inputs.len() != sample_transform.num_inputs
+            || !matches!(depth, 8 | 10 | 12 | 16)
+        {
+            return AvifError::invalid_argument();
+        }
+        // Same checks as at decoding.
+        if sample_transform.is_valid().is_err() {
+            return AvifError::invalid_argument();
+        }
+        // Checks that the constants fit in the bit depth.
+        write_sato(sample_transform)?;
+        let first_image = inputs[0];
+        for image in inputs {
+            if !matches!(image.depth, 8 | 10 | 12)
+                || image.width != first_image.width
+                || image.height != first_image.height
+                || image.yuv_format != first_image.yuv_format
+                || !image.has_same_cicp(first_image)
+                || image.has_alpha() != first_image.has_alpha()
+                || image.alpha_premultiplied != first_image.alpha_premultiplied
+            {
+                return AvifError::invalid_argument();
+            }
+            if image.matrix_coefficients == MatrixCoefficients::Identity
+                && image.yuv_format != PixelFormat::Yuv444
+            {
+                return AvifError::invalid_argument();
+            }
+            if !image.has_plane(Plane::Y) {
+                return AvifError::no_content();
+            }
+        }
+        Ok(())
+    }
+
+    // Adds the primary 'sato' item and the hidden coded items of its inputs, in order, each with
+    // an auxiliary alpha item if the inputs have alpha.
+    pub(crate) fn add_sample_transform_items(
+        &mut self,
+        sample_transform: &SampleTransform,
+        inputs: &[&Image],
+    ) -> AvifResult<()> {
+        let item = Item {
+            id: u16_from_usize(self.items.len() + 1)?,
+            item_type: "sato".into(),
+            category: Category::Color,
+            metadata_payload: write_sato(sample_transform)?,
+            ..Default::default()
+        };
+        let sato_item_id = item.id;
+        self.items.push(item);
+        self.primary_item_id = sato_item_id;
+        let grid = Grid {
+            rows: 1,
+            columns: 1,
+            width: inputs[0].width,
+            height: inputs[0].height,
+        };
+        // The 'dimg' item references are written in item id order, which is the order of the
+        // inputs.
+        for (cell_index, image) in inputs.iter().enumerate() {
+            let color_item_id = self.add_items(&grid, Category::Color, /*hidden=*/ true)?;
+            let color_item = &mut self.items[color_item_id as usize - 1];
+            color_item.cell_index = cell_index;
+            color_item.dimg_from_id = Some(sato_item_id);
+            color_item.depth = Some(image.depth);
+            if self.alpha_present {
+                let alpha_item_id =
+                    self.add_items(&grid, Category::Alpha, /*hidden=*/ true)?;
+                let alpha_item = &mut self.items[alpha_item_id as usize - 1];
+                alpha_item.cell_index = cell_index;
+                alpha_item.depth = Some(image.depth);
+                alpha_item.iref_type = Some("auxl".into());
+                alpha_item.iref_to_id = Some(color_item_id);
+                if self.image_metadata.alpha_premultiplied {
+                    let color_item = &mut self.items[color_item_id as usize - 1];
+                    color_item.iref_type = Some("prem".into());
+                    color_item.iref_to_id = Some(alpha_item_id);
+                }
+            }
+        }
+        Ok(())
+    }
+
     pub(crate) fn create_bit_depth_extension_8b8b_image(
         full_depth_image: &Image,
         item: &Item,
@@ -350,6 +512,155 @@ impl Encoder {
     }
 }
 
+// Rounds the 16-bit samples of the planes of |category| of |full_depth_image| to |depth| bits
+// into |base|.
+fn round_to_base_depth(
+    full_depth_image: &Image,
+    category: Category,
+    base: &mut Image,
+) -> AvifResult<()> {
+    let shift = 16 - base.depth;
+    let max_channel = base.max_channel();
+    for plane in category.planes() {
+        if !full_depth_image.has_plane(*plane) {
+            continue;
+        }
+        for y in 0..u32_from_usize(full_depth_image.height(*plane))? {
+            for (dst, src) in base
+                .row16_mut(*plane, y)?
+                .iter_mut()
+                .zip(full_depth_image.row16(*plane, y)?)
+            {
+                *dst = ((*src as u32 + (1 << (shift - 1))) >> shift).min(max_channel as u32) as u16;
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Encoder {
+    // Decodes the samples produced for the base items of a residual recipe, once these items are
+    // encoded and their codecs finished. Returns one image per cell, with the dimensions of the
+    // encoded cells, which are padded to the dimensions of the first cell.
+    pub(crate) fn decode_base_items(
+        &self,
+        cell_images: &[&Image],
+        recipe: Recipe,
+    ) -> AvifResult<Vec<Image>> {
+        let (base_depth, _) = recipe.residual_depths().unwrap();
+        let mut decoded_base_images = Vec::with_capacity(cell_images.len());
+        for cell_index in 0..cell_images.len() {
+            // The decoded planes point to memory owned by the codecs, which are kept until the
+            // samples are copied.
+            let mut codecs = Vec::new();
+            let mut decoded = Image::default();
+            for category in [Category::Color, Category::Alpha] {
+                let item = match self.items.iter().find(|item| {
+                    item.codec.is_some()
+                        && item.category == category
+                        && item.cell_index == cell_index
+                        && !item.is_sato_least_significant_input
+                }) {
+                    Some(item) => item,
+                    None => continue,
+                };
+                let (sample, codec_config) = match (item.samples.first(), &item.codec_configuration)
+                {
+                    (Some(sample), Some(codec_config)) => (sample, codec_config),
+                    _ => return AvifError::unknown_error("base item was not encoded"),
+                };
+                let mut codec = CodecChoice::Auto
+                    .get_decoder_codec(CompressionFormat::Avif, &[])
+                    .ok_or(AvifError::NoCodecAvailable)?;
+                codec.initialize(&DecoderConfig {
+                    operating_point: 0,
+                    all_layers: false,
+                    width: cell_images[0].width,
+                    height: cell_images[0].height,
+                    depth: base_depth,
+                    max_threads: self.settings.threads,
+                    image_size_limit: None,
+                    max_input_size: sample.data.len(),
+                    codec_config: codec_config.clone(),
+                    category,
+                    android_mediacodec_output_color_format:
+                        AndroidMediaCodecOutputColorFormat::default(),
+                })?;
+                codec.get_next_image(
+                    &sample.data,
+                    /*spatial_id=*/ 0,
+                    &mut decoded,
+                    category,
+                    None,
+                    #[cfg(feature = "android_mediacodec")]
+                    true,
+                )?;
+                codecs.push(codec);
+            }
+            let mut decoded_base = decoded.shallow_clone();
+            decoded_base.copy_and_pad(&decoded)?;
+            decoded_base_images.push(decoded_base);
+        }
+        Ok(decoded_base_images)
+    }
+
+    pub(crate) fn create_residual_recipe_image(
+        full_depth_image: &Image,
+        decoded_base: &Image,
+        item: &Item,
+        recipe: Recipe,
+    ) -> AvifResult<Image> {
+        assert_eq!(full_depth_image.depth, 16);
+        let (base_depth, residual_depth) = recipe.residual_depths().unwrap();
+        if !item.is_sato_least_significant_input {
+            // Image containing the most significant bits of the 16-bit image, rounded.
+            let mut base = full_depth_image.shallow_clone();
+            base.depth = base_depth;
+            base.allocate_planes(item.category)?;
+            round_to_base_depth(full_depth_image, item.category, &mut base)?;
+            return Ok(base);
+        }
+        // Image containing the difference between the 16-bit image and the decoded base image.
+        let mut residual = full_depth_image.shallow_clone();
+        residual.depth = residual_depth;
+        residual.allocate_planes(item.category)?;
+        let shift = 16 - base_depth;
+        let offset = 1i32 << (residual_depth - 1);
+        let max_channel = residual.max_channel() as i32;
+        for plane in item.category.planes() {
+            if !residual.has_plane(*plane) {
+                continue;
+            }
+            let base_width = decoded_base.width(*plane);
+            let base_height = u32_from_usize(decoded_base.height(*plane))?;
+            for y in 0..u32_from_usize(residual.height(*plane))? {
+                // The padding of the grid cells is not part of the decoded image. Extend the
+                // edges instead.
+                let base_row = decoded_base.row16(*plane, y.min(base_height - 1))?;
+                let samples =
+                    full_depth_image
+                        .row16(*plane, y)?
+                        .iter()
+                        .enumerate()
+                        .map(|(x, sample)| {
+                            let base = base_row[x.min(base_width - 1)] as i32;
+                            (*sample as i32 - (base << shift) + offset).clamp(0, max_channel) as u16
+                        });
+                if residual_depth == 8 {
+                    for (dst, sample) in residual.row_mut(*plane, y)?.iter_mut().zip(samples) {
+                        *dst = sample as u8;
+                    }
+                } else {
+                    for (dst, sample) in residual.row16_mut(*plane, y)?.iter_mut().zip(samples) {
+                        *dst = sample;
+                    }
+                }
+            }
+        }
+        Ok(residual)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -358,4 +669,102 @@ mod tests {
     fn test_sample_transform_bit_depth(depth: SampleTransformBitDepth) {
         assert_eq!(depth, SampleTransformBitDepth::from_bits(depth.to_bits()));
     }
+
+    #[test_case(Recipe::BitDepthExtension12b8bOverlap4b, 0)]
+    #[test_case(Recipe::BitDepthExtension12b8bOverlap4b, 7)]
+    #[test_case(Recipe::BitDepthExtension12b8bOverlap4b, -7)]
+    #[test_case(Recipe::BitDepthExtension12b8bOverlap4b, 20)]
+    #[test_case(Recipe::BitDepthExtension10b10bOverlap4b, 0)]
+    #[test_case(Recipe::BitDepthExtension10b10bOverlap4b, 7)]
+    #[test_case(Recipe::BitDepthExtension10b10bOverlap4b, -20)]
+    fn residual(recipe: Recipe, base_error: i32) -> AvifResult<()> {
+        let mut image = Image {
+            width: 16,
+            height: 8,
+            depth: 16,
+            yuv_format: PixelFormat::Yuv444,
+            yuv_range: YuvRange::Full,
+            ..Default::default()
+        };
+        image.allocate_planes(Category::Color)?;
+        for plane in YUV_PLANES {
+            for y in 0..image.height {
+                for (x, sample) in image.row16_mut(plane, y)?.iter_mut().enumerate() {
+                    *sample = 0x1000 + ((x as u32 * 2311 + y * 7919) % 0xE000) as u16;
+                }
+            }
+        }
+        let mut item = Item {
+            category: Category::Color,
+            ..Default::default()
+        };
+        let no_base = Image::default();
+        let mut base = Encoder::create_residual_recipe_image(&image, &no_base, &item, recipe)?;
+        // Simulate the loss of the codec.
+        for plane in YUV_PLANES {
+            for y in 0..base.height {
+                for sample in base.row16_mut(plane, y)? {
+                    *sample = (*sample as i32 + base_error) as u16;
+                }
+            }
+        }
+        item.is_sato_least_significant_input = true;
+        let residual = Encoder::create_residual_recipe_image(&image, &base, &item, recipe)?;
+
+        let mut output = image.shallow_clone();
+        output.allocate_planes(Category::Color)?;
+        recipe_to_expression(recipe)?.apply_to_planes(
+            Category::Color,
+            &[base, residual],
+            &mut output,
+        )?;
+        let (base_depth, residual_depth) = recipe.residual_depths().unwrap();
+        let shift = 16 - base_depth;
+        // The residual covers the rounding and the codec loss up to its offset.
+        let max_error =
+            ((base_error.abs() << shift) + (1 << (shift - 1)) - (1 << (residual_depth - 1))).max(0);
+        for plane in YUV_PLANES {
+            for y in 0..image.height {
+                for (expected, actual) in image.row16(plane, y)?.iter().zip(output.row16(plane, y)?)
+                {
+                    assert!((*expected as i32 - *actual as i32).abs() <= max_error);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test_case(true, 0)]
+    #[test_case(false, 0)]
+    #[test_case(true, 1)]
+    fn auto_recipe(is_single_image: bool, extra_layer_count: u32) -> AvifResult<()> {
+        let encoder = Encoder::create_with_settings(&Settings {
+            recipe: Recipe::Auto,
+            mutable: MutableSettings {
+                quality: 50.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        })?;
+        let image = Image {
+            width: 16,
+            height: 8,
+            depth: 16,
+            yuv_format: PixelFormat::Yuv444,
+            ..Default::default()
+        };
+        let recipe = Recipe::Auto.self_or_auto_choose_depending_on(
+            &image,
+            is_single_image,
+            extra_layer_count,
+            &encoder,
+        );
+        // The residual recipes are not supported for image sequences and layered images.
+        if is_single_image && extra_layer_count == 0 && encoder.can_decode_base_image() {
+            assert_eq!(recipe, Recipe::BitDepthExtension12b8bOverlap4b);
+        } else {
+            assert_eq!(recipe, Recipe::BitDepthExtension12b4b);
+        }
+        Ok(())
+    }
 }
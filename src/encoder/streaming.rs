@@ -0,0 +1,340 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::decoder::Extent;
+use crate::encoder::*;
+use crate::internal_utils::stream::OStream;
+use crate::*;
+
+use std::io::SeekFrom;
+
+// Number of bytes reserved at the beginning of the output for the ftyp box, which depends on
+// whether the output is an image sequence and is thus only written by Encoder::finish(). The
+// ftyp box is followed by a free box filling the remaining reserved bytes. The last 8 reserved
+// bytes may instead be used by the mdat box header if its size needs 64 bits.
+const FTYP_RESERVED_SIZE: usize = 128;
+const BOX_HEADER_SIZE: usize = 8;
+
+pub(crate) struct StreamingOutput {
+    output: GenericOutput,
+    // Position of the mdat box header, if already written.
+    mdat_offset: Option<u64>,
+    // Position of the next byte to be written.
+    offset: u64,
+}
+
+impl StreamingOutput {
+    pub(crate) fn create(output: GenericOutput) -> Self {
+        Self {
+            output,
+            mdat_offset: None,
+            offset: 0,
+        }
+    }
+
+    // Writes |data| at the current position and returns that position.
+    fn write(&mut self, data: &[u8]) -> AvifResult<u64> {
+        let offset = self.offset;
+        self.output
+            .write_all(data)
+            .map_err(AvifError::map_io_error)?;
+        self.offset = checked_add!(self.offset, u64_from_usize(data.len())?)?;
+        Ok(offset)
+    }
+
+    fn write_at(&mut self, data: &[u8], offset: u64) -> AvifResult<()> {
+        self.output
+            .seek(SeekFrom::Start(offset))
+            .map_err(AvifError::map_io_error)?;
+        self.output
+            .write_all(data)
+            .map_err(AvifError::map_io_error)?;
+        self.output
+            .seek(SeekFrom::Start(self.offset))
+            .map_err(AvifError::map_io_error)?;
+        Ok(())
+    }
+
+    // Writes the bytes reserved for the ftyp box followed by the header of the mdat box, whose
+    // size is only known in finish_mdat().
+    fn start_mdat(&mut self) -> AvifResult<()> {
+        if self.mdat_offset.is_some() {
+            return Ok(());
+        }
+        self.output
+            .seek(SeekFrom::Start(0))
+            .map_err(AvifError::map_io_error)?;
+        let mut stream = OStream::default();
+        write_free(&mut stream, FTYP_RESERVED_SIZE)?;
+        self.write(&stream.data)?;
+        let mut stream = OStream::default();
+        stream.start_box("mdat")?;
+        self.mdat_offset = Some(self.write(&stream.data)?);
+        Ok(())
+    }
+
+    fn finish_mdat(&mut self) -> AvifResult<()> {
+        let mdat_offset = self.mdat_offset.unwrap();
+        let mdat_size = checked_sub!(self.offset, mdat_offset)?;
+        if mdat_size <= u32::MAX as u64 {
+            return self.write_at(&(mdat_size as u32).to_be_bytes(), mdat_offset);
+        }
+        // Move the start of the mdat box to make room for the 64-bit largesize.
+        let mdat_offset = checked_sub!(mdat_offset, BOX_HEADER_SIZE as u64)?;
+        let mut stream = OStream::default();
+        // unsigned int(32) size = 1;
+        stream.write_u32(1)?;
+        stream.write_str("mdat")?;
+        // unsigned int(64) largesize;
+        stream.write_u64(checked_add!(mdat_size, BOX_HEADER_SIZE as u64)?)?;
+        self.write_at(&stream.data, mdat_offset)?;
+        self.mdat_offset = Some(mdat_offset);
+        Ok(())
+    }
+
+    // Writes the ftyp box followed by a free box filling the bytes up to the mdat box.
+    fn write_ftyp(&mut self, ftyp: &[u8]) -> AvifResult<()> {
+        let reserved_size = usize_from_u64(self.mdat_offset.unwrap())?;
+        let mut stream = OStream::default();
+        stream.write_slice(ftyp)?;
+        if ftyp.len() != reserved_size {
+            if ftyp.len() + BOX_HEADER_SIZE > reserved_size {
+                return AvifError::unknown_error("ftyp box too large");
+            }
+            write_free(&mut stream, reserved_size - ftyp.len())?;
+        }
+        self.write_at(&stream.data, 0)
+    }
+
+    // Leaves a hole of |size| bytes in the mdat box.
+    #[cfg(test)]
+    fn skip(&mut self, size: u64) -> AvifResult<()> {
+        self.offset = checked_add!(self.offset, size)?;
+        self.output
+            .seek(SeekFrom::Start(self.offset))
+            .map_err(AvifError::map_io_error)?;
+        Ok(())
+    }
+}
+
+// Writes a free box of |size| bytes, header included.
+fn write_free(stream: &mut OStream, size: usize) -> AvifResult<()> {
+    stream.start_box("free")?;
+    stream.write_slice(&vec![0; size - BOX_HEADER_SIZE])?;
+    stream.finish_box()
+}
+
+impl Encoder {
+    // Writes the samples produced since the last call, if an output was set. The data of all
+    // samples but the first one of each item is released, as the first one is needed to build the
+    // codec configuration.
+    pub(crate) fn write_pending_samples(&mut self) -> AvifResult<()> {
+        let streaming_output = match &mut self.streaming_output {
+            Some(streaming_output) => streaming_output,
+            None => return Ok(()),
+        };
+        streaming_output.start_mdat()?;
+        for item in &mut self.items {
+            for index in item.written_samples.len()..item.samples.len() {
+                let sample = &mut item.samples[index];
+                let offset = streaming_output.write(&sample.data)?;
+                item.written_samples.push(Extent {
+                    offset,
+                    size: sample.data.len(),
+                });
+                if index > 0 {
+                    sample.data = Vec::new();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Completes the mdat box with the remaining item payloads, then writes the meta and moov boxes
+    // pointing to the already written data, and finally the ftyp box.
+    pub(crate) fn finish_streaming(&mut self) -> AvifResult<()> {
+        self.finish_codecs()?;
+        self.write_pending_samples()?;
+        let streaming_output = self.streaming_output.unwrap_mut();
+        for item in &mut self.items {
+            if item.samples.is_empty() && !item.metadata_payload.is_empty() {
+                let offset = streaming_output.write(&item.metadata_payload)?;
+                item.written_samples.push(Extent {
+                    offset,
+                    size: item.metadata_payload.len(),
+                });
+            }
+        }
+        streaming_output.finish_mdat()?;
+        self.large_offsets = streaming_output.offset > u32::MAX as u64;
+
+        let mut stream = OStream::default();
+        self.write_meta(&mut stream)?;
+        self.write_moov(
+            &mut stream,
+            self.settings.creation_time,
+            self.settings.modification_time,
+        )?;
+        for item in &self.items {
+            // Only the iloc extent offsets are left to be filled. Layered items are not supported,
+            // so there is at most one extent per item.
+            for (location, extent) in item.mdat_offset_locations.iter().zip(&item.written_samples) {
+                write_offset_at(&mut stream, extent.offset, *location, self.large_offsets)?;
+            }
+        }
+        let streaming_output = self.streaming_output.unwrap_mut();
+        streaming_output.write(&stream.data)?;
+
+        let mut stream = OStream::default();
+        self.write_ftyp(&mut stream)?;
+        let streaming_output = self.streaming_output.unwrap_mut();
+        streaming_output.write_ftyp(&stream.data)?;
+        streaming_output
+            .output
+            .flush()
+            .map_err(AvifError::map_io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "aom")]
+    use crate::encoder::tests::gradient_image;
+
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::io::Read;
+    use std::rc::Rc;
+
+    const FOUR_GIB: u64 = 1 << 32;
+
+    #[derive(Clone, Default)]
+    struct SharedOutput(Rc<RefCell<Cursor<Vec<u8>>>>);
+
+    impl std::io::Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl std::io::Seek for SharedOutput {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.0.borrow_mut().seek(pos)
+        }
+    }
+
+    #[test]
+    fn box_layout() -> AvifResult<()> {
+        let output = SharedOutput::default();
+        let mut streaming_output = StreamingOutput::create(Box::new(output.clone()));
+        streaming_output.start_mdat()?;
+        assert_eq!(streaming_output.write(&[1, 2, 3])?, 136);
+        assert_eq!(streaming_output.write(&[4, 5])?, 139);
+        streaming_output.finish_mdat()?;
+        assert_eq!(streaming_output.write(b"moov")?, 141);
+        let mut stream = OStream::default();
+        stream.start_box("ftyp")?;
+        stream.write_str("avif")?;
+        stream.finish_box()?;
+        streaming_output.write_ftyp(&stream.data)?;
+        assert!(streaming_output.write_ftyp(&[0; 121]).is_err());
+
+        let data = output.0.borrow().get_ref().clone();
+        assert_eq!(data.len(), 145);
+        assert_eq!(
+            &data[..12],
+            &[0, 0, 0, 12, b'f', b't', b'y', b'p', b'a', b'v', b'i', b'f']
+        );
+        assert_eq!(&data[12..20], &[0, 0, 0, 116, b'f', b'r', b'e', b'e']);
+        assert!(data[20..128].iter().all(|x| *x == 0));
+        assert_eq!(&data[128..136], &[0, 0, 0, 13, b'm', b'd', b'a', b't']);
+        assert_eq!(&data[136..141], &[1, 2, 3, 4, 5]);
+        assert_eq!(&data[141..], b"moov");
+        Ok(())
+    }
+
+    #[test]
+    fn large_mdat() -> AvifResult<()> {
+        let path = std::env::temp_dir().join("crabbyavif_streaming_large_mdat.bin");
+        let file = std::fs::File::create(&path).unwrap();
+        let mut streaming_output = StreamingOutput::create(Box::new(file));
+        streaming_output.start_mdat()?;
+        streaming_output.write(&[1, 2, 3])?;
+        // The file is sparse, so this does not use disk space.
+        streaming_output.skip(FOUR_GIB)?;
+        assert_eq!(streaming_output.write(&[4])?, 139 + FOUR_GIB);
+        streaming_output.finish_mdat()?;
+        let mut stream = OStream::default();
+        stream.start_box("ftyp")?;
+        stream.write_str("avif")?;
+        stream.finish_box()?;
+        streaming_output.write_ftyp(&stream.data)?;
+        streaming_output.output.flush().unwrap();
+        drop(streaming_output);
+
+        let mut file = std::fs::File::open(&path).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), 140 + FOUR_GIB);
+        let mut data = vec![0; 139];
+        file.read_exact(&mut data).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&data[4..8], b"ftyp");
+        assert_eq!(&data[12..20], &[0, 0, 0, 108, b'f', b'r', b'e', b'e']);
+        let mdat_size = (20 + FOUR_GIB).to_be_bytes();
+        assert_eq!(
+            &data[120..136],
+            &[&[0, 0, 0, 1], &b"mdat"[..], &mdat_size].concat()
+        );
+        assert_eq!(&data[136..], &[1, 2, 3]);
+        Ok(())
+    }
+
+    #[cfg(feature = "aom")]
+    #[test]
+    fn large_file() -> AvifResult<()> {
+        let path = std::env::temp_dir().join("crabbyavif_streaming_large_file.avif");
+        let image = gradient_image(64, 48)?;
+        let mut encoder = Encoder::create_with_settings(&Settings {
+            speed: Some(10),
+            ..Default::default()
+        })?;
+        encoder.set_output(Box::new(std::fs::File::create(&path).unwrap()))?;
+        encoder.add_image_for_sequence(&image, 1)?;
+        // Place the next samples past 4 GiB.
+        encoder.streaming_output.unwrap_mut().skip(FOUR_GIB)?;
+        encoder.add_image_for_sequence(&image, 1)?;
+        assert!(encoder.finish()?.is_empty());
+        drop(encoder);
+        assert!(std::fs::metadata(&path).unwrap().len() > FOUR_GIB);
+
+        let mut decoder = crate::decoder::Decoder::default();
+        decoder.set_io_file(&path.to_str().unwrap().to_string())?;
+        let res = decoder.parse().and_then(|_| {
+            assert_eq!(decoder.image_count(), 2);
+            if cfg!(feature = "dav1d") {
+                for _ in 0..2 {
+                    decoder.next_image()?;
+                }
+            }
+            Ok(())
+        });
+        std::fs::remove_file(&path).unwrap();
+        res
+    }
+}
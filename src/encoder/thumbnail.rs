@@ -0,0 +1,126 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::encoder::*;
+use crate::*;
+
+impl ThumbnailSettings {
+    pub(crate) fn is_valid(&self) -> bool {
+        self.width > 0
+            && self.height > 0
+            && (0.0..=100.0).contains(&self.quality)
+            && (0.0..=100.0).contains(&self.quality_alpha)
+    }
+}
+
+impl Encoder {
+    // Returns a downscaled copy of the visible region of |image| (after applying the clean
+    // aperture, if any). The alpha plane is only kept if |keep_alpha| is true.
+    fn create_thumbnail_image(
+        image: &Image,
+        thumbnail: &ThumbnailSettings,
+        keep_alpha: bool,
+    ) -> AvifResult<Image> {
+        let rect = match &image.clap {
+            Some(clap) => CropRect::create_from(clap, image.width, image.height, image.yuv_format)?,
+            None => CropRect {
+                x: 0,
+                y: 0,
+                width: image.width,
+                height: image.height,
+            },
+        };
+        // Image::scale() only scales the planes of the given category, so the color and alpha
+        // planes are scaled separately.
+        let mut thumbnail_image = image.sub_image(&rect)?;
+        thumbnail_image.clap = None;
+        thumbnail_image.scale(thumbnail.width, thumbnail.height, Category::Color)?;
+        if keep_alpha {
+            let mut alpha_image = image.sub_image(&rect)?;
+            alpha_image.scale(thumbnail.width, thumbnail.height, Category::Alpha)?;
+            let alpha = Plane::A.as_usize();
+            thumbnail_image.planes[alpha] = alpha_image.planes[alpha].take();
+            thumbnail_image.row_bytes[alpha] = alpha_image.row_bytes[alpha];
+        } else {
+            thumbnail_image.free_planes(&[Plane::A]);
+        }
+        Ok(thumbnail_image)
+    }
+
+    // Adds one color item (and one alpha item if needed) per thumbnail setting, all linked to the
+    // primary item. Returns the downscaled images to be encoded into these items, indexed by
+    // Item::cell_index.
+    pub(crate) fn add_thumbnail_items(&mut self, image: &Image) -> AvifResult<Vec<Image>> {
+        self.thumbnail_image_metadata = self.image_metadata.shallow_clone();
+        self.thumbnail_image_metadata.icc = self.image_metadata.icc.try_clone()?;
+        // The clean aperture is applied before downscaling.
+        self.thumbnail_image_metadata.clap = None;
+        let mut thumbnail_images = create_vec_exact(self.thumbnails.len())?;
+        for index in 0..self.thumbnails.len() {
+            let thumbnail = self.thumbnails[index];
+            thumbnail_images.push(Self::create_thumbnail_image(
+                image,
+                &thumbnail,
+                self.alpha_present,
+            )?);
+
+            let (item_type, codec) = self
+                .settings
+                .codec_choice
+                .get_item_type_and_encoder_codec(&self.codec_plugins)?;
+            let color_item = Item {
+                id: u16_from_usize(self.items.len() + 1)?,
+                item_type: item_type.into(),
+                infe_name: Category::Color.infe_name(),
+                cell_index: index,
+                category: Category::Color,
+                is_thumbnail: true,
+                thmb_to_id: Some(self.primary_item_id),
+                dimensions: Some((thumbnail.width, thumbnail.height)),
+                codec: Some(codec),
+                ..Default::default()
+            };
+            let color_item_id = color_item.id;
+            self.items.push(color_item);
+
+            if self.alpha_present && !self.settings.codec_supports_native_alpha_channel() {
+                let (item_type, codec) = self
+                    .settings
+                    .codec_choice
+                    .get_item_type_and_encoder_codec(&self.codec_plugins)?;
+                let alpha_item = Item {
+                    id: u16_from_usize(self.items.len() + 1)?,
+                    item_type: item_type.into(),
+                    infe_name: Category::Alpha.infe_name(),
+                    cell_index: index,
+                    category: Category::Alpha,
+                    is_thumbnail: true,
+                    iref_to_id: Some(color_item_id),
+                    iref_type: Some(String::from("auxl")),
+                    dimensions: Some((thumbnail.width, thumbnail.height)),
+                    codec: Some(codec),
+                    ..Default::default()
+                };
+                let alpha_item_id = alpha_item.id;
+                self.items.push(alpha_item);
+                if self.image_metadata.alpha_premultiplied {
+                    let color_item = &mut self.items[color_item_id as usize - 1];
+                    color_item.iref_type = Some(String::from("prem"));
+                    color_item.iref_to_id = Some(alpha_item_id);
+                }
+            }
+        }
+        Ok(thumbnail_images)
+    }
+}
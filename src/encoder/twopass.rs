@@ -0,0 +1,79 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::codecs::EncoderPass;
+use crate::encoder::*;
+
+// A frame of an image sequence kept until Encoder::finish() when encoding in two passes.
+pub(crate) struct TwoPassFrame {
+    image: Image,
+    duration: u64,
+    options: FrameOptions,
+}
+
+impl Encoder {
+    pub(crate) fn buffer_two_pass_frame(
+        &mut self,
+        image: &Image,
+        duration: u64,
+        frame_options: &FrameOptions,
+    ) -> AvifResult<()> {
+        let mut frame_image = image.shallow_clone();
+        frame_image.copy_and_pad(image)?;
+        frame_image.quality_map = image.quality_map.clone();
+        self.two_pass_frames.push(TwoPassFrame {
+            image: frame_image,
+            duration,
+            options: frame_options.clone(),
+        });
+        Ok(())
+    }
+
+    // Encodes the buffered frames once to collect statistics and once more to produce the
+    // samples, which are finalized by finish_codecs().
+    pub(crate) fn encode_two_pass_frames(&mut self) -> AvifResult<()> {
+        if self.two_pass_frames.is_empty() {
+            return Ok(());
+        }
+        let frames = std::mem::take(&mut self.two_pass_frames);
+        let final_recipe = self.final_recipe.unwrap();
+        for pass in [EncoderPass::First, EncoderPass::Last] {
+            self.pass = pass;
+            self.duration_in_timescales.clear();
+            for frame in &frames {
+                self.encode_items(
+                    &[&frame.image],
+                    None,
+                    &[],
+                    final_recipe,
+                    /*is_single_image=*/ false,
+                    /*pad_cells=*/ true,
+                    &frame.options,
+                    frame.duration,
+                )?;
+                self.duration_in_timescales.push(frame.duration);
+            }
+            if pass == EncoderPass::First {
+                for item in &mut self.items {
+                    if let Some(codec) = &mut item.codec {
+                        codec.finish(&mut item.samples)?;
+                        // The first pass does not produce any sample.
+                        item.samples.clear();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
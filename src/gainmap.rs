@@ -13,6 +13,7 @@
 // limitations under the License.
 
 use crate::image::YuvRange;
+use crate::internal_utils::TryClone;
 use crate::utils::*;
 use crate::*;
 
@@ -86,6 +87,24 @@ pub struct GainMap {
     pub alt_clli: ContentLightLevelInformation,
 }
 
+impl GainMap {
+    // Returns a copy of this gain map with its image replaced by |image|.
+    pub(crate) fn try_clone_with_image(&self, image: Image) -> AvifResult<GainMap> {
+        Ok(GainMap {
+            image,
+            metadata: self.metadata.clone(),
+            alt_icc: self.alt_icc.try_clone()?,
+            alt_color_primaries: self.alt_color_primaries,
+            alt_transfer_characteristics: self.alt_transfer_characteristics,
+            alt_matrix_coefficients: self.alt_matrix_coefficients,
+            alt_yuv_range: self.alt_yuv_range,
+            alt_plane_count: self.alt_plane_count,
+            alt_plane_depth: self.alt_plane_depth,
+            alt_clli: self.alt_clli,
+        })
+    }
+}
+
 impl PartialEq for GainMap {
     fn eq(&self, other: &Self) -> bool {
         self.metadata == other.metadata
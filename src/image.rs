@@ -19,6 +19,9 @@ use crate::utils::clap::*;
 use crate::utils::pixels::*;
 use crate::*;
 
+use std::marker::PhantomData;
+use std::ops::Deref;
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Plane {
     Y = 0,
@@ -64,6 +67,149 @@ pub enum YuvRange {
     Full = 1,
 }
 
+// The maximum number of distinct quality offsets in a QualityMap, including the implicit 0.
+/// cbindgen:ignore
+pub const MAX_QUALITY_MAP_OFFSET_COUNT: usize = 8;
+
+// A rectangle of an image encoded with a different quality, see QualityMap::Regions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QualityRegion {
+    pub rect: CropRect,
+    // Added to the quality of the image in |rect|, from -100 to 100 inclusive.
+    pub quality_offset: i32,
+}
+
+// Quality of the areas of an image relative to the quality given to the encoder, for example to
+// spend more bits on faces than on the background. Only used when encoding the color samples with
+// CodecChoice::Aom, and ignored by the other codecs (see encoder::Encoder::warnings()). There can
+// be at most MAX_QUALITY_MAP_OFFSET_COUNT distinct offsets.
+#[derive(Clone, Debug, PartialEq)]
+pub enum QualityMap {
+    // One quality offset from -100 to 100 inclusive per block of |block_size|x|block_size|
+    // samples, in raster order. The blocks on the right and bottom edges may be partial.
+    Blocks { block_size: u32, offsets: Vec<i32> },
+    // The samples outside of any region have an offset of 0. Where regions overlap, the last one
+    // applies.
+    Regions(Vec<QualityRegion>),
+}
+
+impl QualityMap {
+    pub(crate) fn is_valid(&self, width: u32, height: u32) -> bool {
+        let offsets_are_valid = match self {
+            Self::Blocks {
+                block_size,
+                offsets,
+            } => {
+                *block_size != 0
+                    && offsets.len() as u64
+                        == width.div_ceil(*block_size) as u64 * height.div_ceil(*block_size) as u64
+                    && offsets.iter().all(|offset| (-100..=100).contains(offset))
+            }
+            Self::Regions(regions) => regions.iter().all(|region| {
+                region.rect.width != 0
+                    && region.rect.height != 0
+                    && region.rect.x as u64 + region.rect.width as u64 <= width as u64
+                    && region.rect.y as u64 + region.rect.height as u64 <= height as u64
+                    && (-100..=100).contains(&region.quality_offset)
+            }),
+        };
+        offsets_are_valid && self.distinct_offsets().len() <= MAX_QUALITY_MAP_OFFSET_COUNT
+    }
+
+    // Returns the offsets used in the map, starting with 0.
+    pub(crate) fn distinct_offsets(&self) -> Vec<i32> {
+        let mut distinct_offsets = vec![0];
+        let mut add = |offset: i32| {
+            if !distinct_offsets.contains(&offset) {
+                distinct_offsets.push(offset);
+            }
+        };
+        match self {
+            Self::Blocks { offsets, .. } => offsets.iter().for_each(|offset| add(*offset)),
+            Self::Regions(regions) => regions.iter().for_each(|region| add(region.quality_offset)),
+        }
+        distinct_offsets
+    }
+
+    // Returns the quality offset of the sample at |x|,|y| in an image of |width| samples, or 0 if
+    // the sample is outside of the map.
+    pub(crate) fn offset_at(&self, x: u32, y: u32, width: u32) -> i32 {
+        match self {
+            Self::Blocks {
+                block_size,
+                offsets,
+            } => {
+                let columns = width.div_ceil(*block_size);
+                let column = x / block_size;
+                if column >= columns {
+                    return 0;
+                }
+                let index = (y / block_size) as usize * columns as usize + column as usize;
+                offsets.get(index).copied().unwrap_or(0)
+            }
+            Self::Regions(regions) => regions
+                .iter()
+                .rev()
+                .find(|region| {
+                    x >= region.rect.x
+                        && x - region.rect.x < region.rect.width
+                        && y >= region.rect.y
+                        && y - region.rect.y < region.rect.height
+                })
+                .map_or(0, |region| region.quality_offset),
+        }
+    }
+
+    // Returns the map of the |rect| area of an image of |width| samples, in the coordinates of
+    // |rect|. Blocks that are not aligned with |rect| take the offset at their center.
+    pub(crate) fn crop(&self, rect: &CropRect, width: u32) -> AvifResult<Self> {
+        match self {
+            Self::Blocks { block_size, .. } => {
+                let block_size = *block_size;
+                let columns = rect.width.div_ceil(block_size);
+                let rows = rect.height.div_ceil(block_size);
+                let mut offsets = create_vec_exact(usize_from_u32(checked_mul!(columns, rows)?)?)?;
+                for row in 0..rows {
+                    for column in 0..columns {
+                        let x = checked_add!(checked_mul!(column, block_size)?, block_size / 2)?
+                            .min(rect.width - 1);
+                        let y = checked_add!(checked_mul!(row, block_size)?, block_size / 2)?
+                            .min(rect.height - 1);
+                        offsets.push(self.offset_at(rect.x + x, rect.y + y, width));
+                    }
+                }
+                Ok(Self::Blocks {
+                    block_size,
+                    offsets,
+                })
+            }
+            Self::Regions(regions) => Ok(Self::Regions(
+                regions
+                    .iter()
+                    .filter_map(|region| {
+                        let x0 = region.rect.x.max(rect.x);
+                        let y0 = region.rect.y.max(rect.y);
+                        let x1 = (region.rect.x + region.rect.width).min(rect.x + rect.width);
+                        let y1 = (region.rect.y + region.rect.height).min(rect.y + rect.height);
+                        if x0 >= x1 || y0 >= y1 {
+                            return None;
+                        }
+                        Some(QualityRegion {
+                            rect: CropRect {
+                                x: x0 - rect.x,
+                                y: y0 - rect.y,
+                                width: x1 - x0,
+                                height: y1 - y0,
+                            },
+                            quality_offset: region.quality_offset,
+                        })
+                    })
+                    .collect(),
+            )),
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct Image {
     pub width: u32,
@@ -97,6 +243,36 @@ pub struct Image {
 
     pub image_sequence_track_present: bool,
     pub progressive_state: ProgressiveState,
+
+    // Only used by the encoder.
+    pub quality_map: Option<QualityMap>,
+}
+
+// A borrowed view into a rectangular region of an Image, created with Image::view(). It
+// dereferences to an Image so that it can be passed to Encoder::add_image() and the reformat
+// functions.
+pub struct ImageView<'a> {
+    image: Image,
+    phantom: PhantomData<&'a Image>,
+}
+
+impl Deref for ImageView<'_> {
+    type Target = Image;
+
+    fn deref(&self) -> &Image {
+        &self.image
+    }
+}
+
+impl ImageView<'_> {
+    // Copies the Exif, XMP and ICC data of |image| into the view. For example, this can be used on
+    // the first cell of a grid since its metadata is used for the whole encoded image.
+    pub fn with_metadata_from(mut self, image: &Image) -> AvifResult<Self> {
+        self.image.exif = image.exif.try_clone()?;
+        self.image.xmp = image.xmp.try_clone()?;
+        self.image.icc = image.icc.try_clone()?;
+        Ok(self)
+    }
 }
 
 pub struct PlaneData {
@@ -117,6 +293,7 @@ impl Image {
             exif: vec![],
             icc: vec![],
             xmp: vec![],
+            quality_map: None,
 
             // All other field values can be copied.
             ..*self
@@ -179,10 +356,11 @@ impl Image {
         match plane {
             Plane::Y | Plane::A => self.width as usize,
             Plane::U => match self.yuv_format {
-                PixelFormat::Yuv444
-                | PixelFormat::AndroidP010
-                | PixelFormat::AndroidNv12
-                | PixelFormat::AndroidNv21 => self.width as usize,
+                PixelFormat::Yuv444 => self.width as usize,
+                // The interleaved chroma plane holds two samples per pair of luma columns.
+                PixelFormat::AndroidP010 | PixelFormat::AndroidNv12 | PixelFormat::AndroidNv21 => {
+                    (self.width as usize).div_ceil(2) * 2
+                }
                 PixelFormat::Yuv420 | PixelFormat::Yuv422 => (self.width as usize).div_ceil(2),
                 PixelFormat::None | PixelFormat::Yuv400 => 0,
             },
@@ -265,62 +443,83 @@ impl Image {
             .slice16_mut(start, plane_data.width)
     }
 
+    // Returns an image with the same properties as self whose planes point to the pixel values of
+    // self within |rect|. |rect| must be valid for the dimensions and pixel format of self.
+    pub(crate) fn sub_image(&self, rect: &CropRect) -> AvifResult<Image> {
+        let mut image = self.shallow_clone();
+        image.width = rect.width;
+        image.height = rect.height;
+        for plane in ALL_PLANES {
+            if !self.has_plane(plane) {
+                continue;
+            }
+            let (x, y) = if plane == Plane::Y || plane == Plane::A {
+                (usize_from_u32(rect.x)?, rect.y)
+            } else {
+                (
+                    usize_from_u32(image.yuv_format.apply_chroma_shift_x(rect.x))?,
+                    image.yuv_format.apply_chroma_shift_y(rect.y),
+                )
+            };
+            let ptr = if image.depth == 8 {
+                let row = self.row(plane, y)?;
+                // SAFETY: rect is a valid rectangle that is guaranteed to be within the image
+                // bounds. So this pointer is pointing to a valid buffer.
+                unsafe { row.as_ptr().add(x) as *mut u8 }
+            } else {
+                let row = self.row16(plane, y)?;
+                // SAFETY: rect is a valid rectangle that is guaranteed to be within the image
+                // bounds. So this pointer is pointing to a valid buffer.
+                unsafe { row.as_ptr().add(x) as *mut u8 }
+            };
+            image.planes[plane.as_usize()] = Some(Pixels::from_raw_pointer_view(
+                ptr,
+                image.depth as _,
+                u32_from_usize(image.width(plane))?,
+                u32_from_usize(image.height(plane))?,
+                self.row_bytes[plane.as_usize()],
+            )?);
+            image.row_bytes[plane.as_usize()] = self.row_bytes[plane.as_usize()];
+        }
+        image.quality_map = match &self.quality_map {
+            Some(quality_map) => Some(quality_map.crop(rect, self.width)?),
+            None => None,
+        };
+        Ok(image)
+    }
+
     // Returns a view with the same image properties as self and pointing to
     // the pixel values of self. Copies all Exif, XMP and ICC data if any.
     #[cfg(feature = "cli")]
     pub fn cropped_image(&self) -> AvifResult<Image> {
         match self.clap {
             Some(clap) => {
-                match CropRect::create_from(&clap, self.width, self.height, self.yuv_format) {
-                    Ok(rect) => {
-                        let mut image = self.shallow_clone();
-                        image.width = rect.width;
-                        image.height = rect.height;
-                        for plane in ALL_PLANES {
-                            if self.planes[plane.as_usize()].is_none() {
-                                continue;
-                            }
-                            let (x, y) = if plane == Plane::Y || plane == Plane::A {
-                                (usize_from_u32(rect.x)?, rect.y)
-                            } else {
-                                (
-                                    usize_from_u32(image.yuv_format.apply_chroma_shift_x(rect.x))?,
-                                    image.yuv_format.apply_chroma_shift_y(rect.y),
-                                )
-                            };
-                            let ptr = if image.depth == 8 {
-                                let row = self.row(plane, y)?;
-                                // SAFETY: rect is a valid rectangle that is guaranteed to be
-                                // within the image bounds. So this pointer is pointing to a valid
-                                // buffer.
-                                unsafe { row.as_ptr().add(x) as *mut u8 }
-                            } else {
-                                let row = self.row16(plane, y)?;
-                                // SAFETY: rect is a valid rectangle that is guaranteed to be
-                                // within the image bounds. So this pointer is pointing to a valid
-                                // buffer.
-                                unsafe { row.as_ptr().add(x) as *mut u8 }
-                            };
-                            image.planes[plane.as_usize()] = Some(Pixels::from_raw_pointer(
-                                ptr,
-                                image.depth as _,
-                                u32_from_usize(image.height(plane))?,
-                                self.row_bytes[plane.as_usize()],
-                            )?);
-                            image.row_bytes[plane.as_usize()] = self.row_bytes[plane.as_usize()];
-                        }
-                        image.exif = self.exif.try_clone()?;
-                        image.xmp = self.xmp.try_clone()?;
-                        image.icc = self.icc.try_clone()?;
-                        Ok(image)
-                    }
-                    Err(e) => Err(e),
-                }
+                let rect = CropRect::create_from(&clap, self.width, self.height, self.yuv_format)?;
+                let mut image = self.sub_image(&rect)?;
+                image.exif = self.exif.try_clone()?;
+                image.xmp = self.xmp.try_clone()?;
+                image.icc = self.icc.try_clone()?;
+                Ok(image)
             }
             None => Err(AvifError::InvalidArgument),
         }
     }
 
+    // Returns a view of the |rect| region of self without copying any pixels. The clean aperture
+    // of self is not carried over to the view. Exif, XMP and ICC data are not copied (see
+    // ImageView::with_metadata_from()).
+    pub fn view(&self, rect: &CropRect) -> AvifResult<ImageView<'_>> {
+        if !rect.is_valid(self.width, self.height, self.yuv_format) {
+            return AvifError::invalid_argument();
+        }
+        let mut image = self.sub_image(rect)?;
+        image.clap = None;
+        Ok(ImageView {
+            image,
+            phantom: PhantomData,
+        })
+    }
+
     #[cfg(feature = "libyuv")]
     pub(crate) fn plane_ptrs(&self) -> [*const u8; 4] {
         ALL_PLANES.map(|x| {
@@ -365,7 +564,7 @@ impl Image {
         }))
     }
 
-    #[cfg(any(feature = "dav1d", feature = "avm"))]
+    #[cfg(any(feature = "dav1d", feature = "avm", feature = "encoder"))]
     pub(crate) fn free_planes(&mut self, planes: &[Plane]) {
         for plane in planes {
             let plane = plane.as_usize();
@@ -506,6 +705,40 @@ impl Image {
         Ok(())
     }
 
+    // Sets every sample of |plane| (if allocated) to the value returned by |f| for its (x, y)
+    // position.
+    #[cfg(test)]
+    pub(crate) fn fill_plane_with(&mut self, plane: Plane, f: impl Fn(usize, u32) -> u16) {
+        let Some(plane_data) = self.plane_data(plane) else {
+            return;
+        };
+        for y in 0..plane_data.height {
+            for x in 0..plane_data.width as usize {
+                if self.depth == 8 {
+                    self.row_mut(plane, y).unwrap()[x] = f(x, y) as u8;
+                } else {
+                    self.row16_mut(plane, y).unwrap()[x] = f(x, y);
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn fill_planes_with(&mut self, f: impl Fn(Plane, usize, u32) -> u16) {
+        for plane in ALL_PLANES {
+            self.fill_plane_with(plane, |x, y| f(plane, x, y));
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn sample(&self, plane: Plane, x: usize, y: u32) -> u16 {
+        if self.depth == 8 {
+            self.row(plane, y).unwrap()[x] as u16
+        } else {
+            self.row16(plane, y).unwrap()[x]
+        }
+    }
+
     #[cfg(feature = "png")]
     pub(crate) fn remove_trailing_null_from_xmp(&mut self) {
         if self.xmp.len() >= 2
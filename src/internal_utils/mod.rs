@@ -56,6 +56,8 @@ conversion_function!(isize_from_usize, isize, usize);
 #[cfg(feature = "android_mediacodec")]
 conversion_function!(i32_from_usize, i32, usize);
 conversion_function!(i32_from_i64, i32, i64);
+#[cfg(feature = "aom")]
+conversion_function!(i64_from_u64, i64, u64);
 
 macro_rules! clamp_function {
     ($func:ident, $type:ty) => {
@@ -426,7 +426,7 @@ impl SampleTransform {
         Ok(())
     }
 
-    fn is_valid(&self) -> AvifResult<()> {
+    pub(crate) fn is_valid(&self) -> AvifResult<()> {
         let mut stack_size: i32 = 0;
         for token in &self.tokens {
             match token {
@@ -376,8 +376,9 @@ pub struct OStreamImpl<const LITTLE_ENDIAN: bool> {
     // already written in the last byte of self.data.
     num_bits: u8,
     // The positions in self.data where are written the 4-byte sizes of the
-    // boxes that were started but not yet finished.
-    box_marker_offsets: Vec<usize>,
+    // boxes that were started but not yet finished, and whether these boxes
+    // have a 64-bit largesize.
+    box_marker_offsets: Vec<(usize, bool)>,
 }
 
 #[cfg(feature = "encoder")]
@@ -494,6 +495,18 @@ impl<const LITTLE_ENDIAN: bool> OStreamImpl<LITTLE_ENDIAN> {
         Ok(())
     }
 
+    pub(crate) fn write_u64_at_offset(&mut self, value: u64, offset: usize) -> AvifResult<()> {
+        assert_eq!(self.num_bits, 0);
+        let range = offset..offset + 8;
+        check_slice_range(self.data.len(), &range)?;
+        self.data[range].copy_from_slice(&if LITTLE_ENDIAN {
+            value.to_le_bytes()
+        } else {
+            value.to_be_bytes()
+        });
+        Ok(())
+    }
+
     pub(crate) fn write_u64(&mut self, value: u64) -> AvifResult<()> {
         assert_eq!(self.num_bits, 0);
         self.try_reserve(8)?;
@@ -570,7 +583,11 @@ impl<const LITTLE_ENDIAN: bool> OStreamImpl<LITTLE_ENDIAN> {
         self.write_u32(value.1)
     }
 
-    fn write_i32(&mut self, value: i32) -> AvifResult<()> {
+    pub(crate) fn write_i16(&mut self, value: i16) -> AvifResult<()> {
+        self.write_u16(value as u16)
+    }
+
+    pub(crate) fn write_i32(&mut self, value: i32) -> AvifResult<()> {
         self.write_u32(value as u32)
     }
 
@@ -585,7 +602,7 @@ impl<const LITTLE_ENDIAN: bool> OStreamImpl<LITTLE_ENDIAN> {
         version_and_flags: Option<(u8, u32)>,
     ) -> AvifResult<()> {
         assert_eq!(self.num_bits, 0);
-        self.box_marker_offsets.push(self.offset());
+        self.box_marker_offsets.push((self.offset(), false));
         // 4 bytes for size to be filled out later.
         self.write_u32(0)?;
         self.write_str(box_type)?;
@@ -600,6 +617,17 @@ impl<const LITTLE_ENDIAN: bool> OStreamImpl<LITTLE_ENDIAN> {
         self.start_box_impl(box_type, None)
     }
 
+    // Starts a box whose size is written as a 64-bit largesize.
+    pub(crate) fn start_large_box(&mut self, box_type: &str) -> AvifResult<()> {
+        assert_eq!(self.num_bits, 0);
+        self.box_marker_offsets.push((self.offset(), true));
+        // unsigned int(32) size = 1;
+        self.write_u32(1)?;
+        self.write_str(box_type)?;
+        // 8 bytes for largesize to be filled out later.
+        self.write_u64(0)
+    }
+
     pub(crate) fn start_full_box(
         &mut self,
         box_type: &str,
@@ -610,12 +638,15 @@ impl<const LITTLE_ENDIAN: bool> OStreamImpl<LITTLE_ENDIAN> {
 
     pub(crate) fn finish_box(&mut self) -> AvifResult<()> {
         assert_eq!(self.num_bits, 0);
-        let offset = self
+        let (offset, is_large) = self
             .box_marker_offsets
             .pop()
             .ok_or(AvifError::UnknownError("".into()))?;
-        let box_size = u32_from_usize(checked_sub!(self.offset(), offset)?)?;
-        self.write_u32_at_offset(box_size, offset)?;
+        let box_size = checked_sub!(self.offset(), offset)?;
+        if is_large {
+            return self.write_u64_at_offset(u64_from_usize(box_size)?, offset + 8);
+        }
+        self.write_u32_at_offset(u32_from_usize(box_size)?, offset)?;
         Ok(())
     }
 }
@@ -731,6 +762,24 @@ mod tests {
         assert!(stream.finish_box().is_err());
     }
 
+    #[cfg(feature = "encoder")]
+    #[test]
+    fn write_large_box() -> AvifResult<()> {
+        let mut stream = OStream::default();
+        stream.start_large_box("mdat")?;
+        stream.write_u8(20)?;
+        stream.finish_box()?;
+        assert_eq!(
+            stream.data,
+            vec![0, 0, 0, 1, b'm', b'd', b'a', b't', 0, 0, 0, 0, 0, 0, 0, 17, 20]
+        );
+        let mut stream = IStream::create(&stream.data);
+        assert_eq!(stream.read_u32()?, 1);
+        assert_eq!(stream.read_string(4)?, "mdat");
+        assert_eq!(stream.read_u64()?, 17);
+        Ok(())
+    }
+
     #[cfg(feature = "encoder")]
     #[test]
     fn write() {
@@ -28,6 +28,7 @@ pub mod decoder;
 pub mod encoder;
 pub mod gainmap;
 pub mod image;
+pub mod plugin;
 pub mod reformat;
 pub mod utils;
 
@@ -67,6 +68,7 @@ pub enum CodecChoice {
     Libgav1, // AVIF (AV1-HEIF) decoder.
     #[cfg(feature = "jpegxl")]
     Libjxl, // JPEG XL-HEIF encoder and decoder. WARNING: experimental.
+    Plugin(&'static str), // Registered in the settings with this name, see plugin.rs.
 }
 
 #[repr(C)]
@@ -93,9 +95,11 @@ pub enum PixelFormat {
     Yuv422 = 2,
     Yuv420 = 3, // Also used for alpha items when 4:0:0 is not supported by the codec.
     Yuv400 = 4,
-    // The following formats are not found in the AV1 spec. They are formats that are supported by
-    // Android platform. They are intended to be pass-through formats that are used only by the
-    // Android MediaCodec wrapper. All internal functions will treat them as opaque.
+    // The following formats are not found in the AV1 spec. They are semi-planar 4:2:0 formats
+    // where the chroma samples are interleaved into a single plane (stored as Plane::U). They are
+    // produced by the Android MediaCodec wrapper or by converting a decoded Yuv420 image with
+    // Image::convert_to_semi_planar(). AndroidP010 stores 10-bit samples in the most significant
+    // bits of 16-bit values.
     AndroidP010 = 5,
     AndroidNv12 = 6,
     AndroidNv21 = 7,
@@ -106,6 +110,13 @@ impl PixelFormat {
         *self == Self::Yuv400
     }
 
+    pub fn is_semi_planar(&self) -> bool {
+        matches!(
+            self,
+            Self::AndroidP010 | Self::AndroidNv12 | Self::AndroidNv21
+        )
+    }
+
     pub fn plane_count(&self) -> usize {
         match self {
             PixelFormat::None
@@ -120,7 +131,7 @@ impl PixelFormat {
     pub fn chroma_shift_x(&self) -> (u32, u32) {
         match self {
             Self::Yuv422 | Self::Yuv420 => (1, 0),
-            Self::AndroidP010 | Self::AndroidNv12 => (1, 1),
+            Self::AndroidP010 | Self::AndroidNv12 | Self::AndroidNv21 => (1, 1),
             _ => (0, 0),
         }
     }
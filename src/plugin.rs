@@ -0,0 +1,325 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Codecs implemented outside of this crate. A plugin is registered with a factory in
+// decoder::Settings::codec_plugins or with encoder::Encoder::add_codec_plugin(), and selected with
+// CodecChoice::Plugin(name). With CodecChoice::Auto, the first registered decoder plugin supporting
+// the compression format is used if no built-in decoder is available.
+
+use crate::codecs::DecoderConfig;
+use crate::decoder::item::Item;
+use crate::decoder::CompressionFormat;
+use crate::decoder::GridImageHelper;
+use crate::image::Image;
+use crate::*;
+
+#[cfg(feature = "encoder")]
+use crate::codecs::EncoderConfig;
+#[cfg(feature = "encoder")]
+use crate::encoder::Sample;
+
+pub use crate::parser::mp4box::Av1CodecConfiguration;
+pub use crate::parser::mp4box::CodecConfiguration;
+pub use crate::parser::mp4box::HevcCodecConfiguration;
+
+use std::fmt;
+use std::sync::Arc;
+
+// Parameters of a decoder instance, see DecoderPlugin::initialize().
+#[derive(Clone, Debug)]
+pub struct DecoderPluginConfig {
+    pub codec_config: CodecConfiguration,
+    pub category: Category,
+    // Dimensions and bit depth of the coded images, as signaled in the container.
+    pub width: u32,
+    pub height: u32,
+    pub depth: u8,
+    pub operating_point: u8,
+    // If true, all the spatial layers are output, otherwise only the highest one.
+    pub all_layers: bool,
+    pub max_threads: u32,
+    // Size in bytes of the largest payload given to the decoder.
+    pub max_input_size: usize,
+}
+
+// The destination of the cells decoded by DecoderPlugin::decode_grid().
+pub struct GridOutput<'a, 'b>(&'a mut GridImageHelper<'b>);
+
+impl GridOutput<'_, '_> {
+    pub fn category(&self) -> Category {
+        self.0.category
+    }
+
+    // Returns true once all the cells were added.
+    pub fn is_complete(&self) -> bool {
+        self.0.is_grid_complete().unwrap_or(true)
+    }
+
+    // Copies the next decoded cell, in raster order, into the grid image.
+    pub fn add_cell(&mut self, cell: &mut Image) -> AvifResult<()> {
+        self.0.copy_from_cell_image(cell)
+    }
+}
+
+pub trait DecoderPlugin {
+    // Called once before any payload is decoded.
+    fn initialize(&mut self, config: &DecoderPluginConfig) -> AvifResult<()>;
+    // Decodes |payload| into |image|, allocating its planes. For Category::Alpha, the decoded
+    // samples go into the Plane::A plane.
+    fn decode(
+        &mut self,
+        payload: &[u8],
+        spatial_id: u8,
+        image: &mut Image,
+        category: Category,
+    ) -> AvifResult<()>;
+    // Decodes the |payloads| of all the cells of a grid and adds them to |output|. By default,
+    // each cell is decoded with decode().
+    fn decode_grid(
+        &mut self,
+        payloads: &[Vec<u8>],
+        spatial_id: u8,
+        output: &mut GridOutput,
+    ) -> AvifResult<()> {
+        for payload in payloads {
+            let mut cell = Image::default();
+            self.decode(payload, spatial_id, &mut cell, output.category())?;
+            output.add_cell(&mut cell)?;
+        }
+        Ok(())
+    }
+}
+
+pub type DecoderPluginCreator = Arc<dyn Fn() -> Box<dyn DecoderPlugin> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct DecoderPluginFactory {
+    // Selects the plugin with CodecChoice::Plugin(name).
+    pub name: &'static str,
+    pub compression_formats: Vec<CompressionFormat>,
+    // Called for each decoder instance, there may be one per grid cell.
+    pub create: DecoderPluginCreator,
+}
+
+impl fmt::Debug for DecoderPluginFactory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DecoderPluginFactory")
+            .field("name", &self.name)
+            .field("compression_formats", &self.compression_formats)
+            .finish()
+    }
+}
+
+// Returns the decoder plugin to use for |compression_format|, if any.
+pub(crate) fn find_decoder_plugin(
+    codec_choice: CodecChoice,
+    compression_format: CompressionFormat,
+    plugins: &[DecoderPluginFactory],
+) -> Option<crate::decoder::Codec> {
+    let plugin = plugins.iter().find(|plugin| {
+        plugin.compression_formats.contains(&compression_format)
+            && match codec_choice {
+                CodecChoice::Auto => true,
+                CodecChoice::Plugin(name) => plugin.name == name,
+                _ => false,
+            }
+    })?;
+    Some(Box::new(PluginDecoder {
+        name: plugin.name,
+        plugin: (plugin.create)(),
+    }))
+}
+
+struct PluginDecoder {
+    name: &'static str,
+    plugin: Box<dyn DecoderPlugin>,
+}
+
+impl crate::codecs::Decoder for PluginDecoder {
+    fn codec(&self) -> CodecChoice {
+        CodecChoice::Plugin(self.name)
+    }
+
+    fn initialize(&mut self, config: &DecoderConfig) -> AvifResult<()> {
+        self.plugin.initialize(&DecoderPluginConfig {
+            codec_config: config.codec_config.clone(),
+            category: config.category,
+            width: config.width,
+            height: config.height,
+            depth: config.depth,
+            operating_point: config.operating_point,
+            all_layers: config.all_layers,
+            max_threads: config.max_threads,
+            max_input_size: config.max_input_size,
+        })
+    }
+
+    fn get_next_image(
+        &mut self,
+        payload: &[u8],
+        spatial_id: u8,
+        image: &mut Image,
+        category: Category,
+        _item: Option<&Item>,
+        #[cfg(feature = "android_mediacodec")] _signal_eos: bool,
+    ) -> AvifResult<()> {
+        self.plugin.decode(payload, spatial_id, image, category)
+    }
+
+    fn get_next_image_grid(
+        &mut self,
+        payloads: &[Vec<u8>],
+        spatial_id: u8,
+        grid_image_helper: &mut GridImageHelper,
+    ) -> AvifResult<()> {
+        self.plugin
+            .decode_grid(payloads, spatial_id, &mut GridOutput(grid_image_helper))
+    }
+}
+
+// Parameters of the encoding of a single image, see EncoderPlugin::encode_image().
+#[cfg(feature = "encoder")]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EncoderPluginConfig {
+    // From 0 (maximum distortion) to 100 (lossless) inclusive.
+    pub quality: f32,
+    pub speed: Option<u32>,
+    pub threads: u32,
+    pub tile_rows_log2: i32,
+    pub tile_columns_log2: i32,
+    // If true, this is the only image encoded by this instance.
+    pub is_single_image: bool,
+    // If true, a sample must be output for each image before encode_image() returns.
+    pub disable_lagged_output: bool,
+    pub force_keyframe: bool,
+    // Number of images encoded by this instance after the first one, each being a spatial layer.
+    pub extra_layer_count: u32,
+    // Set with encoder::Encoder::set_codec_specific_option() for this category or for all.
+    pub codec_specific_options: Vec<(String, String)>,
+}
+
+// An encoder producing samples of EncoderPluginFactory::compression_format.
+#[cfg(feature = "encoder")]
+pub trait EncoderPlugin: Send {
+    // Encodes |image| and appends the produced samples, if any, to |output_samples|. For
+    // Category::Alpha, the samples to encode are in the Plane::A plane of |image|. Each
+    // instance only encodes images of a single category.
+    fn encode_image(
+        &mut self,
+        image: &Image,
+        category: Category,
+        config: &EncoderPluginConfig,
+        output_samples: &mut Vec<Sample>,
+    ) -> AvifResult<()>;
+    // Appends the remaining samples to |output_samples|. No image is given afterwards.
+    fn finish(&mut self, output_samples: &mut Vec<Sample>) -> AvifResult<()>;
+    // Returns the configuration property of the encoded samples, usually parsed from the sequence
+    // header of the first sample.
+    fn get_codec_config(
+        &self,
+        image: &Image,
+        is_single_image: bool,
+        is_lossless: bool,
+        output_samples: &[Sample],
+    ) -> AvifResult<CodecConfiguration>;
+}
+
+#[cfg(feature = "encoder")]
+pub type EncoderPluginCreator = Arc<dyn Fn() -> Box<dyn EncoderPlugin> + Send + Sync>;
+
+#[cfg(feature = "encoder")]
+#[derive(Clone)]
+pub struct EncoderPluginFactory {
+    // Selects the plugin with CodecChoice::Plugin(name).
+    pub name: &'static str,
+    // Format of the produced samples, which decides the item type. CompressionFormat::Heic is not
+    // supported by the encoder.
+    pub compression_format: CompressionFormat,
+    // Called for each encoder instance, there may be one per grid cell and category.
+    pub create: EncoderPluginCreator,
+}
+
+#[cfg(feature = "encoder")]
+impl fmt::Debug for EncoderPluginFactory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncoderPluginFactory")
+            .field("name", &self.name)
+            .field("compression_format", &self.compression_format)
+            .finish()
+    }
+}
+
+// Returns the compression format and a new instance of the encoder plugin named |name|.
+#[cfg(feature = "encoder")]
+pub(crate) fn find_encoder_plugin(
+    name: &str,
+    plugins: &[EncoderPluginFactory],
+) -> AvifResult<(CompressionFormat, crate::encoder::Codec)> {
+    match plugins.iter().find(|plugin| plugin.name == name) {
+        Some(plugin) => Ok((
+            plugin.compression_format,
+            Box::new(PluginEncoder((plugin.create)())),
+        )),
+        None => AvifError::no_codec_available(),
+    }
+}
+
+#[cfg(feature = "encoder")]
+struct PluginEncoder(Box<dyn EncoderPlugin>);
+
+#[cfg(feature = "encoder")]
+impl crate::codecs::Encoder for PluginEncoder {
+    fn encode_image(
+        &mut self,
+        image: &Image,
+        category: Category,
+        config: &EncoderConfig,
+        output_samples: &mut Vec<Sample>,
+    ) -> AvifResult<()> {
+        let config = EncoderPluginConfig {
+            quality: config.quality,
+            speed: config.speed,
+            threads: config.threads,
+            tile_rows_log2: config.tile_rows_log2,
+            tile_columns_log2: config.tile_columns_log2,
+            is_single_image: config.is_single_image,
+            disable_lagged_output: config.disable_lagged_output,
+            force_keyframe: config.force_keyframe,
+            extra_layer_count: config.extra_layer_count,
+            codec_specific_options: config.codec_specific_options(category),
+        };
+        self.0
+            .encode_image(image, category, &config, output_samples)
+    }
+
+    fn finish(&mut self, output_samples: &mut Vec<Sample>) -> AvifResult<()> {
+        self.0.finish(output_samples)
+    }
+
+    fn get_codec_config(
+        &self,
+        image: &Image,
+        is_single_image: bool,
+        is_lossless: bool,
+        output_samples: &[Sample],
+    ) -> AvifResult<CodecConfiguration> {
+        match self
+            .0
+            .get_codec_config(image, is_single_image, is_lossless, output_samples)?
+        {
+            config @ CodecConfiguration::Av1(_) => Ok(config),
+            _ => AvifError::not_implemented(),
+        }
+    }
+}
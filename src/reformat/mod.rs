@@ -21,8 +21,11 @@ pub mod sharpyuv;
 
 pub mod alpha;
 pub mod coeffs;
+pub mod packed;
 pub mod rgb;
 pub mod rgb_impl;
+pub mod semi_planar;
+pub mod transform;
 
 // If libyuv is not present, add placeholder functions so that the library will build successfully
 // without it.
@@ -0,0 +1,673 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::rgb;
+use super::rgb::*;
+use super::rgb_impl::Quantizer;
+
+use crate::image;
+use crate::image::Plane;
+use crate::internal_utils::*;
+use crate::*;
+
+use std::cmp::min;
+
+// The green, blue and red planes of Format::GbrPlanar map to these channels of Format::Rgb.
+const GBR_PLANAR_CHANNELS: [usize; 3] = [1, 2, 0];
+
+fn split_u32(value: u32) -> [u16; 2] {
+    [value as u16, (value >> 16) as u16]
+}
+
+fn join_u32(values: &[u16]) -> u32 {
+    (values[0] as u32) | ((values[1] as u32) << 16)
+}
+
+fn alpha10_to_alpha2(alpha: u16) -> u32 {
+    (min(alpha, 1023) as u32 * 3 + 511) / 1023
+}
+
+fn alpha2_to_alpha10(alpha: u32) -> u16 {
+    (alpha * 341) as u16
+}
+
+fn rescale(value: u16, src_depth: u8, dst_depth: u8) -> u16 {
+    if src_depth == dst_depth {
+        return value;
+    }
+    let src_max = (1u32 << src_depth) - 1;
+    let dst_max = (1u32 << dst_depth) - 1;
+    ((min(value as u32, src_max) * dst_max + src_max / 2) / src_max) as u16
+}
+
+impl rgb::Image {
+    fn intermediate_format_and_depth(&self) -> (Format, u8) {
+        match self.format {
+            Format::Ar30 | Format::Ab30 => (Format::Rgba, 10),
+            Format::RgbaF32 => (Format::Rgba, 16),
+            Format::GbrPlanar => (Format::Rgb, self.depth),
+            _ => (Format::Rgb, 8),
+        }
+    }
+
+    // Creates an image in one of the natively supported formats that holds the same samples as
+    // this image. Only valid for formats where Format::requires_repacking() is true.
+    pub(crate) fn create_intermediate(&self) -> AvifResult<rgb::Image> {
+        let (format, depth) = self.intermediate_format_and_depth();
+        let mut intermediate = rgb::Image {
+            width: self.width,
+            height: self.height,
+            depth,
+            format,
+            chroma_upsampling: self.chroma_upsampling,
+            chroma_downsampling: self.chroma_downsampling,
+            premultiply_alpha: self.premultiply_alpha,
+            is_float: false,
+            dithering: self.dithering,
+            max_threads: self.max_threads,
+            pixels: None,
+            row_bytes: 0,
+        };
+        intermediate.allocate()?;
+        Ok(intermediate)
+    }
+
+    fn plane_row_index(&self, plane: usize, y: u32) -> AvifResult<u32> {
+        checked_add!(checked_mul!(plane as u32, self.height)?, y)
+    }
+
+    // Packs the samples of |src| (created using create_intermediate()) into this image.
+    pub(crate) fn pack_from(&mut self, src: &rgb::Image) -> AvifResult<()> {
+        if src.width != self.width || src.height != self.height {
+            return AvifError::invalid_argument();
+        }
+        let width = usize_from_u32(self.width)?;
+        for y in 0..self.height {
+            match self.format {
+                Format::Ar30 | Format::Ab30 => {
+                    let is_ar30 = self.format == Format::Ar30;
+                    let src_row = src.row16(y)?;
+                    let dst_row = self.row16_mut(y)?;
+                    for x in 0..width {
+                        let rgba = &src_row[x * 4..x * 4 + 4];
+                        let (low, high) =
+                            if is_ar30 { (rgba[2], rgba[0]) } else { (rgba[0], rgba[2]) };
+                        let pixel = (alpha10_to_alpha2(rgba[3]) << 30)
+                            | ((high as u32) << 20)
+                            | ((rgba[1] as u32) << 10)
+                            | (low as u32);
+                        dst_row[x * 2..x * 2 + 2].copy_from_slice(&split_u32(pixel));
+                    }
+                }
+                Format::RgbaF32 => {
+                    let src_row = src.row16(y)?;
+                    let dst_row = self.row16_mut(y)?;
+                    for i in 0..width * 4 {
+                        let value = (src_row[i] as f32 / 65535.0).to_bits();
+                        dst_row[i * 2..i * 2 + 2].copy_from_slice(&split_u32(value));
+                    }
+                }
+                Format::GbrPlanar => {
+                    for (plane, channel) in GBR_PLANAR_CHANNELS.iter().enumerate() {
+                        let dst_y = self.plane_row_index(plane, y)?;
+                        if self.depth == 8 {
+                            let src_row = src.row(y)?;
+                            let dst_row = self.row_mut(dst_y)?;
+                            for x in 0..width {
+                                dst_row[x] = src_row[x * 3 + channel];
+                            }
+                        } else {
+                            let src_row = src.row16(y)?;
+                            let dst_row = self.row16_mut(dst_y)?;
+                            for x in 0..width {
+                                dst_row[x] = src_row[x * 3 + channel];
+                            }
+                        }
+                    }
+                }
+                Format::Bgr565 => {
+                    let src_row = src.row(y)?;
+                    let dst_row = self.row_mut(y)?;
+                    for x in 0..width {
+                        let rgb = &src_row[x * 3..x * 3 + 3];
+                        let pixel = ((rgb[2] as u16 >> 3) << 11)
+                            | ((rgb[1] as u16 >> 2) << 5)
+                            | (rgb[0] as u16 >> 3);
+                        dst_row[x * 2..x * 2 + 2].copy_from_slice(&pixel.to_le_bytes());
+                    }
+                }
+                _ => return AvifError::not_implemented(),
+            }
+        }
+        Ok(())
+    }
+
+    // Unpacks the samples of this image into |dst| (created using create_intermediate()).
+    pub(crate) fn unpack_to(&self, dst: &mut rgb::Image) -> AvifResult<()> {
+        if dst.width != self.width || dst.height != self.height {
+            return AvifError::invalid_argument();
+        }
+        let width = usize_from_u32(self.width)?;
+        for y in 0..self.height {
+            match self.format {
+                Format::Ar30 | Format::Ab30 => {
+                    let is_ar30 = self.format == Format::Ar30;
+                    let src_row = self.row16(y)?;
+                    let dst_row = dst.row16_mut(y)?;
+                    for x in 0..width {
+                        let pixel = join_u32(&src_row[x * 2..x * 2 + 2]);
+                        let low = (pixel & 0x3ff) as u16;
+                        let high = ((pixel >> 20) & 0x3ff) as u16;
+                        let (r, b) = if is_ar30 { (high, low) } else { (low, high) };
+                        dst_row[x * 4..x * 4 + 4].copy_from_slice(&[
+                            r,
+                            ((pixel >> 10) & 0x3ff) as u16,
+                            b,
+                            alpha2_to_alpha10(pixel >> 30),
+                        ]);
+                    }
+                }
+                Format::RgbaF32 => {
+                    let src_row = self.row16(y)?;
+                    let dst_row = dst.row16_mut(y)?;
+                    for i in 0..width * 4 {
+                        let value = f32::from_bits(join_u32(&src_row[i * 2..i * 2 + 2]));
+                        dst_row[i] = (0.5 + value.clamp(0.0, 1.0) * 65535.0) as u16;
+                    }
+                }
+                Format::GbrPlanar => {
+                    for (plane, channel) in GBR_PLANAR_CHANNELS.iter().enumerate() {
+                        let src_y = self.plane_row_index(plane, y)?;
+                        if self.depth == 8 {
+                            let src_row = self.row(src_y)?;
+                            let dst_row = dst.row_mut(y)?;
+                            for x in 0..width {
+                                dst_row[x * 3 + channel] = src_row[x];
+                            }
+                        } else {
+                            let src_row = self.row16(src_y)?;
+                            let dst_row = dst.row16_mut(y)?;
+                            for x in 0..width {
+                                dst_row[x * 3 + channel] = src_row[x];
+                            }
+                        }
+                    }
+                }
+                Format::Bgr565 => {
+                    let src_row = self.row(y)?;
+                    let dst_row = dst.row_mut(y)?;
+                    for x in 0..width {
+                        let pixel = u16::from_le_bytes([src_row[x * 2], src_row[x * 2 + 1]]);
+                        let r = (pixel & 0x1f) as u8;
+                        let g = ((pixel >> 5) & 0x3f) as u8;
+                        let b = (pixel >> 11) as u8;
+                        dst_row[x * 3..x * 3 + 3].copy_from_slice(&[
+                            (r << 3) | (r >> 2),
+                            (g << 2) | (g >> 4),
+                            (b << 3) | (b >> 2),
+                        ]);
+                    }
+                }
+                _ => return AvifError::not_implemented(),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn packed_yuv_depth(format: Format) -> u8 {
+    match format {
+        Format::Y410 => 10,
+        Format::Y416 => 16,
+        _ => 8,
+    }
+}
+
+fn validate_packed_yuv(rgb: &rgb::Image, image: &image::Image) -> AvifResult<()> {
+    if rgb.width != image.width
+        || rgb.height != image.height
+        || rgb.width == 0
+        || rgb.height == 0
+        || !rgb.depth_valid()
+        || !image.depth_valid()
+    {
+        return AvifError::reformat_failed();
+    }
+    if !matches!(
+        image.yuv_format,
+        PixelFormat::Yuv444 | PixelFormat::Yuv422 | PixelFormat::Yuv420 | PixelFormat::Yuv400
+    ) {
+        return AvifError::not_implemented();
+    }
+    Ok(())
+}
+
+// Returns the Y, U, V and A samples of |image| upsampled to 4:4:4 (using the nearest neighbor) and
+// scaled to |depth|.
+fn yuva444_from_image(image: &image::Image, depth: u8) -> AvifResult<[Vec<u16>; 4]> {
+    let width = usize_from_u32(image.width)?;
+    let size = checked_mul!(width, usize_from_u32(image.height)?)?;
+    let neutral_chroma = 1u16 << (depth - 1);
+    let mut planes: [Vec<u16>; 4] = [
+        create_vec_exact(size)?,
+        create_vec_exact(size)?,
+        create_vec_exact(size)?,
+        create_vec_exact(size)?,
+    ];
+    let (shift_x, shift_y) = (
+        image.yuv_format.chroma_shift_x().0,
+        image.yuv_format.chroma_shift_y(),
+    );
+    for plane in image::ALL_PLANES {
+        let samples = &mut planes[plane.as_usize()];
+        let plane_present = image.has_plane(plane)
+            && (plane != Plane::A || image.has_alpha())
+            && (plane == Plane::Y || plane == Plane::A || image.yuv_format != PixelFormat::Yuv400);
+        if !plane_present {
+            let default_value =
+                if plane == Plane::A { ((1u32 << depth) - 1) as u16 } else { neutral_chroma };
+            samples.resize(size, default_value);
+            continue;
+        }
+        let is_chroma = matches!(plane, Plane::U | Plane::V);
+        for y in 0..image.height {
+            let src_y = if is_chroma { y >> shift_y } else { y };
+            for x in 0..width {
+                let src_x = if is_chroma { x >> shift_x } else { x };
+                let value = if image.depth == 8 {
+                    image.row(plane, src_y)?[src_x] as u16
+                } else {
+                    image.row16(plane, src_y)?[src_x]
+                };
+                samples.push(rescale(value, image.depth, depth));
+            }
+        }
+    }
+    Ok(planes)
+}
+
+// Writes the 4:4:4 |planes| (of |depth|) into |image|, averaging the chroma samples if |image| is
+// subsampled.
+fn yuva444_to_image(
+    planes: &[Vec<u16>; 4],
+    depth: u8,
+    write_alpha: bool,
+    image: &mut image::Image,
+) -> AvifResult<()> {
+    let width = usize_from_u32(image.width)?;
+    let height = usize_from_u32(image.height)?;
+    let (shift_x, shift_y) = (
+        image.yuv_format.chroma_shift_x().0,
+        image.yuv_format.chroma_shift_y(),
+    );
+    for plane in image::ALL_PLANES {
+        if !image.has_plane(plane) || (plane == Plane::A && !write_alpha) {
+            continue;
+        }
+        let samples = &planes[plane.as_usize()];
+        let is_chroma = matches!(plane, Plane::U | Plane::V);
+        let plane_width = image.width(plane);
+        for plane_y in 0..image.height(plane) {
+            for plane_x in 0..plane_width {
+                let value = if is_chroma {
+                    let x_range = (plane_x << shift_x)..min((plane_x + 1) << shift_x, width);
+                    let y_range = (plane_y << shift_y)..min((plane_y + 1) << shift_y, height);
+                    let count = (x_range.len() * y_range.len()) as u32;
+                    let mut sum = 0u32;
+                    for y in y_range {
+                        for x in x_range.clone() {
+                            sum += samples[y * width + x] as u32;
+                        }
+                    }
+                    ((sum + count / 2) / count) as u16
+                } else {
+                    samples[plane_y * width + plane_x]
+                };
+                let value = rescale(value, depth, image.depth);
+                if image.depth == 8 {
+                    image.row_mut(plane, plane_y as u32)?[plane_x] = value as u8;
+                } else {
+                    image.row16_mut(plane, plane_y as u32)?[plane_x] = value;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Quantizes the 4:4:4 |planes| of |width| from |src_depth| to |dst_depth| using |dithering|. The
+// alpha plane is only rescaled.
+fn dither_yuva444(
+    planes: &mut [Vec<u16>; 4],
+    width: u32,
+    src_depth: u8,
+    dst_depth: u8,
+    dithering: Dithering,
+) -> AvifResult<()> {
+    let row_length = usize_from_u32(width)?;
+    let mut quantized_row: Vec<u16> = create_vec_exact(row_length)?;
+    quantized_row.resize(row_length, 0);
+    for (plane, samples) in image::ALL_PLANES.iter().zip(planes.iter_mut()) {
+        if *plane == Plane::A {
+            for sample in samples.iter_mut() {
+                *sample = rescale(*sample, src_depth, dst_depth);
+            }
+            continue;
+        }
+        let mut quantizer = Quantizer::create(dithering, width, src_depth, &[dst_depth], None)?;
+        for (y, row) in samples.chunks_exact_mut(row_length).enumerate() {
+            quantizer.quantize_row(y as u32, row, &mut quantized_row);
+            row.copy_from_slice(&quantized_row);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn yuv_to_packed_yuv(image: &image::Image, rgb: &mut rgb::Image) -> AvifResult<()> {
+    validate_packed_yuv(rgb, image)?;
+    let depth = packed_yuv_depth(rgb.format);
+    let [y_samples, u_samples, v_samples, a_samples] =
+        if rgb.dithering != Dithering::None && image.depth > depth {
+            let mut planes = yuva444_from_image(image, image.depth)?;
+            dither_yuva444(&mut planes, rgb.width, image.depth, depth, rgb.dithering)?;
+            planes
+        } else {
+            yuva444_from_image(image, depth)?
+        };
+    let width = usize_from_u32(rgb.width)?;
+    for y in 0..usize_from_u32(rgb.height)? {
+        let i = y * width;
+        match rgb.format {
+            Format::Y410 => {
+                let dst_row = rgb.row16_mut(y as u32)?;
+                for x in 0..width {
+                    let pixel = (alpha10_to_alpha2(a_samples[i + x]) << 30)
+                        | ((v_samples[i + x] as u32) << 20)
+                        | ((y_samples[i + x] as u32) << 10)
+                        | (u_samples[i + x] as u32);
+                    dst_row[x * 2..x * 2 + 2].copy_from_slice(&split_u32(pixel));
+                }
+            }
+            Format::Y416 => {
+                let dst_row = rgb.row16_mut(y as u32)?;
+                for x in 0..width {
+                    dst_row[x * 4..x * 4 + 4].copy_from_slice(&[
+                        u_samples[i + x],
+                        y_samples[i + x],
+                        v_samples[i + x],
+                        a_samples[i + x],
+                    ]);
+                }
+            }
+            Format::Yuy2 => {
+                let dst_row = rgb.row_mut(y as u32)?;
+                for x0 in (0..width).step_by(2) {
+                    // For odd widths, the last pixel is duplicated.
+                    let x1 = min(x0 + 1, width - 1);
+                    let average = |samples: &Vec<u16>| {
+                        (samples[i + x0] as u32 + samples[i + x1] as u32).div_ceil(2) as u8
+                    };
+                    dst_row[x0 * 2..x0 * 2 + 4].copy_from_slice(&[
+                        y_samples[i + x0] as u8,
+                        average(&u_samples),
+                        y_samples[i + x1] as u8,
+                        average(&v_samples),
+                    ]);
+                }
+            }
+            _ => return AvifError::not_implemented(),
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn packed_yuv_to_yuv(rgb: &rgb::Image, image: &mut image::Image) -> AvifResult<()> {
+    validate_packed_yuv(rgb, image)?;
+    let depth = packed_yuv_depth(rgb.format);
+    let width = usize_from_u32(rgb.width)?;
+    let size = checked_mul!(width, usize_from_u32(rgb.height)?)?;
+    let mut planes: [Vec<u16>; 4] = [
+        create_vec_exact(size)?,
+        create_vec_exact(size)?,
+        create_vec_exact(size)?,
+        create_vec_exact(size)?,
+    ];
+    for y in 0..rgb.height {
+        match rgb.format {
+            Format::Y410 => {
+                let src_row = rgb.row16(y)?;
+                for x in 0..width {
+                    let pixel = join_u32(&src_row[x * 2..x * 2 + 2]);
+                    planes[0].push(((pixel >> 10) & 0x3ff) as u16);
+                    planes[1].push((pixel & 0x3ff) as u16);
+                    planes[2].push(((pixel >> 20) & 0x3ff) as u16);
+                    planes[3].push(alpha2_to_alpha10(pixel >> 30));
+                }
+            }
+            Format::Y416 => {
+                let src_row = rgb.row16(y)?;
+                for x in 0..width {
+                    let uyva = &src_row[x * 4..x * 4 + 4];
+                    planes[0].push(uyva[1]);
+                    planes[1].push(uyva[0]);
+                    planes[2].push(uyva[2]);
+                    planes[3].push(uyva[3]);
+                }
+            }
+            Format::Yuy2 => {
+                let src_row = rgb.row(y)?;
+                for x in 0..width {
+                    let pair = &src_row[(x / 2) * 4..(x / 2) * 4 + 4];
+                    planes[0].push(pair[(x % 2) * 2] as u16);
+                    planes[1].push(pair[1] as u16);
+                    planes[2].push(pair[3] as u16);
+                    planes[3].push(255);
+                }
+            }
+            _ => return AvifError::not_implemented(),
+        }
+    }
+    image.allocate_planes(Category::Color)?;
+    let write_alpha = rgb.has_alpha();
+    if write_alpha {
+        image.allocate_planes(Category::Alpha)?;
+    }
+    yuva444_to_image(&planes, depth, write_alpha, image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::image::YuvRange;
+    use crate::utils::pixels::*;
+
+    use test_case::test_case;
+
+    const WIDTH: u32 = 5;
+    const HEIGHT: u32 = 3;
+
+    fn create_yuv(
+        depth: u8,
+        yuv_format: PixelFormat,
+        matrix: MatrixCoefficients,
+        alpha: bool,
+    ) -> image::Image {
+        let mut image = image::Image {
+            width: WIDTH,
+            height: HEIGHT,
+            depth,
+            yuv_format,
+            yuv_range: YuvRange::Full,
+            matrix_coefficients: matrix,
+            ..Default::default()
+        };
+        image.allocate_planes(Category::Color).unwrap();
+        if alpha {
+            image.allocate_planes(Category::Alpha).unwrap();
+        }
+        let max_channel = image.max_channel() as u32;
+        image.fill_planes_with(|plane, x, y| {
+            if plane == Plane::A {
+                // Only use values that can be represented with 2 bits.
+                (((x as u32 + y) % 4) * max_channel / 3) as u16
+            } else {
+                ((x as u32 * 37 + y * 101 + plane.as_usize() as u32 * 13) % (max_channel + 1))
+                    as u16
+            }
+        });
+        image
+    }
+
+    fn max_difference(image1: &image::Image, image2: &image::Image, plane: Plane) -> u16 {
+        let mut max_difference = 0;
+        for y in 0..image1.height(plane) as u32 {
+            for x in 0..image1.width(plane) {
+                let difference = image1
+                    .sample(plane, x, y)
+                    .abs_diff(image2.sample(plane, x, y));
+                max_difference = std::cmp::max(max_difference, difference);
+            }
+        }
+        max_difference
+    }
+
+    #[test_case(Format::Ar30, 10, 0)]
+    #[test_case(Format::Ab30, 10, 0)]
+    #[test_case(Format::RgbaF32, 32, 0)]
+    #[test_case(Format::GbrPlanar, 10, 0)]
+    #[test_case(Format::Bgr565, 8, 33)]
+    fn rgb_round_trip(format: Format, depth: u8, tolerance: u16) -> AvifResult<()> {
+        let yuv = create_yuv(
+            10,
+            PixelFormat::Yuv444,
+            MatrixCoefficients::Identity,
+            format.has_alpha(),
+        );
+        let mut rgb = rgb::Image::create_from_yuv(&yuv);
+        rgb.format = format;
+        rgb.depth = depth;
+        rgb.is_float = format == Format::RgbaF32;
+        rgb.allocate()?;
+        rgb.convert_from_yuv(&yuv)?;
+
+        let mut yuv2 = image::Image {
+            width: yuv.width,
+            height: yuv.height,
+            depth: yuv.depth,
+            yuv_format: yuv.yuv_format,
+            yuv_range: yuv.yuv_range,
+            matrix_coefficients: yuv.matrix_coefficients,
+            ..Default::default()
+        };
+        rgb.convert_to_yuv(&mut yuv2)?;
+        for plane in image::YUV_PLANES {
+            assert!(max_difference(&yuv, &yuv2, plane) <= tolerance);
+        }
+        if format.has_alpha() {
+            assert_eq!(max_difference(&yuv, &yuv2, Plane::A), 0);
+        } else {
+            assert!(!yuv2.has_plane(Plane::A));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pack_layouts() -> AvifResult<()> {
+        let src = rgb::Image {
+            width: 1,
+            height: 1,
+            depth: 10,
+            format: Format::Rgba,
+            pixels: Some(Pixels::Buffer16(vec![1, 2, 3, 1023])),
+            row_bytes: 8,
+            ..Default::default()
+        };
+        for (format, expected) in [
+            (Format::Ar30, (3 << 30) | (1 << 20) | (2 << 10) | 3),
+            (Format::Ab30, (3 << 30) | (3 << 20) | (2 << 10) | 1),
+        ] {
+            let mut rgb = rgb::Image {
+                width: 1,
+                height: 1,
+                depth: 10,
+                format,
+                ..Default::default()
+            };
+            rgb.allocate()?;
+            rgb.pack_from(&src)?;
+            assert_eq!(join_u32(rgb.row16(0)?), expected);
+        }
+
+        let src = rgb::Image {
+            width: 1,
+            height: 1,
+            depth: 8,
+            format: Format::Rgb,
+            pixels: Some(Pixels::Buffer(vec![255, 0, 8])),
+            row_bytes: 3,
+            ..Default::default()
+        };
+        let mut rgb = rgb::Image {
+            width: 1,
+            height: 1,
+            depth: 8,
+            format: Format::Bgr565,
+            ..Default::default()
+        };
+        rgb.allocate()?;
+        rgb.pack_from(&src)?;
+        assert_eq!(rgb.row(0)?, &[0x1f, 0x08]);
+        Ok(())
+    }
+
+    #[test_case(Format::Y410, 10, PixelFormat::Yuv444)]
+    #[test_case(Format::Y416, 16, PixelFormat::Yuv444)]
+    #[test_case(Format::Y416, 16, PixelFormat::Yuv420)]
+    #[test_case(Format::Yuy2, 8, PixelFormat::Yuv422)]
+    #[test_case(Format::Yuy2, 8, PixelFormat::Yuv420)]
+    #[test_case(Format::Yuy2, 8, PixelFormat::Yuv400)]
+    fn packed_yuv_round_trip(format: Format, depth: u8, yuv_format: PixelFormat) -> AvifResult<()> {
+        let yuv_depth = if depth == 8 { 8 } else { 10 };
+        let yuv = create_yuv(
+            yuv_depth,
+            yuv_format,
+            MatrixCoefficients::Bt601,
+            format.has_alpha(),
+        );
+        let mut rgb = rgb::Image::create_from_yuv(&yuv);
+        rgb.format = format;
+        rgb.depth = depth;
+        rgb.allocate()?;
+        rgb.convert_from_yuv(&yuv)?;
+
+        let mut yuv2 = image::Image {
+            width: yuv.width,
+            height: yuv.height,
+            depth: yuv.depth,
+            yuv_format: yuv.yuv_format,
+            ..Default::default()
+        };
+        rgb.convert_to_yuv(&mut yuv2)?;
+        for plane in image::YUV_PLANES {
+            if yuv.has_plane(plane) {
+                assert_eq!(max_difference(&yuv, &yuv2, plane), 0);
+            }
+        }
+        if format.has_alpha() {
+            assert_eq!(max_difference(&yuv, &yuv2, Plane::A), 0);
+        }
+        Ok(())
+    }
+}
@@ -14,9 +14,11 @@
 
 use super::coeffs::*;
 use super::libyuv;
+use super::packed;
 use super::rgb_impl;
 use super::sharpyuv;
 
+use crate::checked_add;
 use crate::checked_mul;
 use crate::image::Plane;
 use crate::image::YuvRange;
@@ -43,6 +45,14 @@ pub enum Format {
     Gray,
     GrayA,
     AGray,
+    Ar30, // 2:10:10:10 packed into a little-endian u32 with B in the least significant bits.
+    Ab30, // 2:10:10:10 packed into a little-endian u32 with R in the least significant bits.
+    RgbaF32,
+    GbrPlanar, // G, B and R planes stored one after the other.
+    Bgr565,
+    Y410, // 10-bit 4:4:4 YUV with 2-bit alpha packed into a little-endian u32 (U, Y, V, A).
+    Y416, // 16-bit 4:4:4 YUVA stored as U, Y, V, A.
+    Yuy2, // 8-bit 4:2:2 YUV stored as Y0, U, Y1, V.
 }
 
 impl Format {
@@ -57,6 +67,15 @@ impl Format {
             Format::Rgb565 | Format::Rgba1010102 | Format::Gray => [0; 4],
             Format::GrayA => [0, 0, 0, 1],
             Format::AGray => [1, 0, 0, 0],
+            Format::RgbaF32 => [0, 1, 2, 3],
+            // For planar formats, these are the plane indices.
+            Format::GbrPlanar => [2, 0, 1, 0],
+            Format::Ar30
+            | Format::Ab30
+            | Format::Bgr565
+            | Format::Y410
+            | Format::Y416
+            | Format::Yuy2 => [0; 4],
         }
     }
 
@@ -79,7 +98,13 @@ impl Format {
     pub fn has_alpha(&self) -> bool {
         !matches!(
             self,
-            Format::Rgb | Format::Bgr | Format::Rgb565 | Format::Gray
+            Format::Rgb
+                | Format::Bgr
+                | Format::Rgb565
+                | Format::Gray
+                | Format::GbrPlanar
+                | Format::Bgr565
+                | Format::Yuy2
         )
     }
 
@@ -90,13 +115,27 @@ impl Format {
             Format::Rgb565 | Format::GrayA | Format::AGray => 2,
             Format::Rgba1010102 => 0, // This is never used.
             Format::Gray => 1,
+            Format::RgbaF32 | Format::Y416 => 4,
+            Format::GbrPlanar => 3,
+            Format::Bgr565 | Format::Yuy2 => 2,
+            Format::Ar30 | Format::Ab30 | Format::Y410 => 0, // This is never used.
         }
     }
 
+    // For planar formats, this is the size of one sample in a single plane.
     pub fn pixel_size(&self, depth: u32) -> u32 {
         match self {
-            Format::Rgb565 => 2,
-            Format::Rgba1010102 => 4,
+            Format::Rgb565 | Format::Bgr565 | Format::Yuy2 => 2,
+            Format::Rgba1010102 | Format::Ar30 | Format::Ab30 | Format::Y410 => 4,
+            Format::RgbaF32 => 16,
+            Format::Y416 => 8,
+            Format::GbrPlanar => {
+                if depth > 8 {
+                    2
+                } else {
+                    1
+                }
+            }
             _ => self.channel_count() * if depth > 8 { 2 } else { 1 },
         }
     }
@@ -104,6 +143,27 @@ impl Format {
     pub(crate) fn is_gray(&self) -> bool {
         matches!(self, Format::Gray | Format::GrayA | Format::AGray)
     }
+
+    pub fn plane_count(&self) -> u32 {
+        match self {
+            Format::GbrPlanar => 3,
+            _ => 1,
+        }
+    }
+
+    // Packed YUV formats hold YUV samples and are not color converted.
+    pub fn is_packed_yuv(&self) -> bool {
+        matches!(self, Format::Y410 | Format::Y416 | Format::Yuy2)
+    }
+
+    // These formats are converted through an intermediate image in one of the other formats. See
+    // packed.rs.
+    pub(crate) fn requires_repacking(&self) -> bool {
+        matches!(
+            self,
+            Format::Ar30 | Format::Ab30 | Format::RgbaF32 | Format::GbrPlanar | Format::Bgr565
+        )
+    }
 }
 
 #[repr(C)]
@@ -140,6 +200,17 @@ pub enum ChromaDownsampling {
     SharpYuv,
 }
 
+// Used only when the RGB image has a lower bit depth than the YUV image it is converted from (the
+// 565 formats are always dithered since their channels have fewer than 8 bits).
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Dithering {
+    #[default]
+    None,
+    Ordered, // 8x8 Bayer matrix.
+    FloydSteinberg,
+}
+
 #[derive(Default)]
 pub struct Image {
     pub width: u32,
@@ -150,6 +221,7 @@ pub struct Image {
     pub chroma_downsampling: ChromaDownsampling,
     pub premultiply_alpha: bool,
     pub is_float: bool,
+    pub dithering: Dithering,
     pub max_threads: i32,
     pub pixels: Option<Pixels>,
     pub row_bytes: u32,
@@ -190,7 +262,7 @@ impl From<&crate::image::Image> for Mode {
 
 impl Image {
     pub fn max_channel(&self) -> u16 {
-        ((1i32 << self.depth) - 1) as u16
+        ((1i32 << std::cmp::min(self.depth, 16)) - 1) as u16
     }
 
     pub(crate) fn max_channel_f(&self) -> f32 {
@@ -209,6 +281,7 @@ impl Image {
             chroma_downsampling: ChromaDownsampling::Automatic,
             premultiply_alpha: false,
             is_float: false,
+            dithering: Dithering::None,
             max_threads: 1,
             pixels: None,
             row_bytes: 0,
@@ -268,14 +341,31 @@ impl Image {
             .slice16_mut(checked_mul!(row, self.row_bytes / 2)?, self.row_bytes / 2)
     }
 
+    pub(crate) fn min_row_bytes(&self) -> AvifResult<u32> {
+        // Yuy2 stores two horizontally adjacent pixels together. So the width has to be even.
+        let width = if self.format == Format::Yuy2 {
+            checked_add!(self.width, self.width & 1)?
+        } else {
+            self.width
+        };
+        checked_mul!(width, self.pixel_size())
+    }
+
+    // Number of rows in the pixel buffer. For planar formats, the planes are stored one after the
+    // other and the rows of plane p start at row p * height.
+    pub(crate) fn row_count(&self) -> AvifResult<u32> {
+        checked_mul!(self.height, self.format.plane_count())
+    }
+
     pub fn allocate(&mut self) -> AvifResult<()> {
-        let row_bytes = checked_mul!(self.width, self.pixel_size())?;
+        let row_bytes = self.min_row_bytes()?;
+        let row_count = self.row_count()?;
         if self.channel_size() == 1 {
-            let buffer_size: usize = usize_from_u32(checked_mul!(row_bytes, self.height)?)?;
+            let buffer_size: usize = usize_from_u32(checked_mul!(row_bytes, row_count)?)?;
             let buffer: Vec<u8> = vec![0; buffer_size];
             self.pixels = Some(Pixels::Buffer(buffer));
         } else {
-            let buffer_size: usize = usize_from_u32(checked_mul!(row_bytes / 2, self.height)?)?;
+            let buffer_size: usize = usize_from_u32(checked_mul!(row_bytes / 2, row_count)?)?;
             let buffer: Vec<u16> = vec![0; buffer_size];
             self.pixels = Some(Pixels::Buffer16(buffer));
         }
@@ -285,8 +375,15 @@ impl Image {
 
     pub(crate) fn depth_valid(&self) -> bool {
         match (self.format, self.is_float, self.depth) {
-            (Format::Rgb565, false, 8) => true,
-            (Format::Rgb565, _, _) => false,
+            (Format::Rgb565 | Format::Bgr565 | Format::Yuy2, false, 8) => true,
+            (Format::Rgb565 | Format::Bgr565 | Format::Yuy2, _, _) => false,
+            (Format::Ar30 | Format::Ab30 | Format::Y410, false, 10) => true,
+            (Format::Ar30 | Format::Ab30 | Format::Y410, _, _) => false,
+            (Format::Y416, false, 16) => true,
+            (Format::Y416, _, _) => false,
+            (Format::RgbaF32, true, 32) => true, // IEEE 754 single-precision binary32
+            (Format::RgbaF32, _, _) => false,
+            (Format::GbrPlanar, true, _) => false,
             (_, true, 16) => true, // IEEE 754 half-precision binary16
             (_, false, 8 | 10 | 12 | 16) => true,
             _ => false,
@@ -301,8 +398,19 @@ impl Image {
             | Format::Abgr
             | Format::Rgba1010102
             | Format::GrayA
-            | Format::AGray => true,
-            Format::Rgb | Format::Bgr | Format::Rgb565 | Format::Gray => false,
+            | Format::AGray
+            | Format::Ar30
+            | Format::Ab30
+            | Format::RgbaF32
+            | Format::Y410
+            | Format::Y416 => true,
+            Format::Rgb
+            | Format::Bgr
+            | Format::Rgb565
+            | Format::Gray
+            | Format::GbrPlanar
+            | Format::Bgr565
+            | Format::Yuy2 => false,
         }
     }
 
@@ -310,6 +418,7 @@ impl Image {
         match self.depth {
             8 => 1,
             10 | 12 | 16 => 2,
+            32 => 4,
             _ => panic!(),
         }
     }
@@ -326,6 +435,14 @@ impl Image {
             Format::Rgba1010102 => 4,
             Format::Gray => self.channel_size(),
             Format::GrayA | Format::AGray => self.channel_size() * 2,
+            Format::Ar30
+            | Format::Ab30
+            | Format::RgbaF32
+            | Format::GbrPlanar
+            | Format::Bgr565
+            | Format::Y410
+            | Format::Y416
+            | Format::Yuy2 => self.format.pixel_size(self.depth as u32),
         }
     }
 
@@ -347,10 +464,85 @@ impl Image {
         Ok(())
     }
 
+    // Returns the depth of the RGB samples that the conversion of |image| produces before they are
+    // quantized to the depth of this image.
+    fn dithering_source_depth(image: &crate::image::Image) -> u8 {
+        match image.matrix_coefficients {
+            // YCgCo-R converts losslessly into RGB samples of a fixed depth.
+            MatrixCoefficients::YcgcoRe => image.depth - 2,
+            MatrixCoefficients::YcgcoRo => image.depth - 1,
+            _ => 16,
+        }
+    }
+
+    // Returns the format of the image passed to rgb_impl::dither() when dithering into this
+    // image.
+    pub(crate) fn dithering_source_format(&self) -> Format {
+        match self.format {
+            Format::Rgb565 | Format::Bgr565 => Format::Rgb,
+            Format::Rgba1010102 => Format::Rgba,
+            _ => self.format,
+        }
+    }
+
+    fn dithering_required(&self, image: &crate::image::Image) -> bool {
+        if self.dithering == Dithering::None || self.is_float {
+            return false;
+        }
+        match self.format {
+            // The 5 and 6-bit channels are always shallower than the YUV samples.
+            Format::Rgb565 | Format::Bgr565 => true,
+            // These formats are dithered while converting into their intermediate image.
+            Format::Ar30 | Format::Ab30 | Format::RgbaF32 | Format::GbrPlanar => false,
+            _ => match image.matrix_coefficients {
+                MatrixCoefficients::YcgcoRe | MatrixCoefficients::YcgcoRo => {
+                    Self::dithering_source_depth(image) > self.depth
+                }
+                _ => image.depth > self.depth,
+            },
+        }
+    }
+
+    fn convert_from_yuv_with_dithering(&mut self, image: &crate::image::Image) -> AvifResult<()> {
+        // Convert into an intermediate image of a higher depth first and then quantize it to the
+        // requested depth.
+        let mut intermediate = Image {
+            width: self.width,
+            height: self.height,
+            depth: Self::dithering_source_depth(image),
+            format: self.dithering_source_format(),
+            chroma_upsampling: self.chroma_upsampling,
+            chroma_downsampling: self.chroma_downsampling,
+            premultiply_alpha: self.premultiply_alpha,
+            is_float: false,
+            dithering: Dithering::None,
+            max_threads: self.max_threads,
+            pixels: None,
+            row_bytes: 0,
+        };
+        if !intermediate.depth_valid() {
+            return AvifError::not_implemented();
+        }
+        intermediate.allocate()?;
+        intermediate.convert_from_yuv(image)?;
+        rgb_impl::dither(&intermediate, self)
+    }
+
     pub fn convert_from_yuv(&mut self, image: &crate::image::Image) -> AvifResult<()> {
         if !image.has_plane(Plane::Y) || !image.depth_valid() || !self.depth_valid() {
             return AvifError::reformat_failed();
         }
+        if self.format.is_packed_yuv() {
+            return packed::yuv_to_packed_yuv(image, self);
+        }
+        if self.dithering_required(image) {
+            return self.convert_from_yuv_with_dithering(image);
+        }
+        if self.format.requires_repacking() {
+            let mut intermediate = self.create_intermediate()?;
+            intermediate.convert_from_yuv(image)?;
+            return self.pack_from(&intermediate);
+        }
         if matches!(
             image.matrix_coefficients,
             MatrixCoefficients::Reserved
@@ -414,10 +606,14 @@ impl Image {
                 converted_with_libyuv = true;
             }
         }
-        if image.yuv_format == PixelFormat::AndroidNv21 || self.format == Format::Rgba1010102 {
+        if !converted_with_libyuv && image.yuv_format.is_semi_planar() {
+            // The built-in conversions only handle planar images.
+            return self.convert_from_yuv(&image.convert_to_planar()?);
+        }
+        if self.format == Format::Rgba1010102 {
             // These conversions are only supported via libyuv.
             if converted_with_libyuv {
-                if image.has_alpha() && matches!(self.format, Format::Rgba1010102) {
+                if image.has_alpha() {
                     // If the source image has an alpha channel, scale them to 2 bits and fill it
                     // into the rgb image. Otherwise, libyuv writes them as opaque by default.
                     self.import_alpha_from(image)?;
@@ -462,6 +658,24 @@ impl Image {
     }
 
     pub fn convert_to_yuv(&self, image: &mut crate::image::Image) -> AvifResult<()> {
+        self.convert_to_yuv_with_chroma_downsampling(image, self.chroma_downsampling)
+    }
+
+    // Same as convert_to_yuv() but with |chroma_downsampling| instead of self.chroma_downsampling.
+    pub(crate) fn convert_to_yuv_with_chroma_downsampling(
+        &self,
+        image: &mut crate::image::Image,
+        chroma_downsampling: ChromaDownsampling,
+    ) -> AvifResult<()> {
+        if self.format.is_packed_yuv() {
+            return packed::packed_yuv_to_yuv(self, image);
+        }
+        if self.format.requires_repacking() {
+            let mut intermediate = self.create_intermediate()?;
+            self.unpack_to(&mut intermediate)?;
+            return intermediate
+                .convert_to_yuv_with_chroma_downsampling(image, chroma_downsampling);
+        }
         if self.format == Format::Rgb565 || self.is_float {
             return AvifError::not_implemented();
         }
@@ -481,7 +695,7 @@ impl Image {
             rgb_impl::rgb_gray_to_yuv(self, image)?;
         } else {
             let mut conversion_complete = false;
-            if self.chroma_downsampling == ChromaDownsampling::SharpYuv {
+            if chroma_downsampling == ChromaDownsampling::SharpYuv {
                 sharpyuv::rgb_to_yuv(self, image)?;
                 conversion_complete = true;
             } else if alpha_multiply_mode == AlphaMultiplyMode::NoOp {
@@ -505,7 +719,13 @@ impl Image {
         if self.format == format {
             return Ok(self);
         }
-        if self.format == Format::Rgb565 || format == Format::Rgb565 {
+        if self.format == Format::Rgb565
+            || format == Format::Rgb565
+            || self.format.requires_repacking()
+            || format.requires_repacking()
+            || self.format.is_packed_yuv()
+            || format.is_packed_yuv()
+        {
             return AvifError::not_implemented();
         }
 
@@ -724,6 +944,121 @@ mod tests {
         Ok(())
     }
 
+    #[test_matrix(
+        [Format::Gray, Format::Rgb, Format::Rgba],
+        [Dithering::None, Dithering::Ordered, Dithering::FloydSteinberg]
+    )]
+    fn dithering(format: Format, dithering: Dithering) -> AvifResult<()> {
+        const SIZE: u32 = 16;
+        const Y_VALUE: u16 = 514;
+        let mut image = crate::image::Image {
+            width: SIZE,
+            height: SIZE,
+            depth: 10,
+            yuv_format: PixelFormat::Yuv400,
+            yuv_range: YuvRange::Full,
+            ..crate::image::Image::default()
+        };
+        image.allocate_planes(Category::Color)?;
+        for y in 0..SIZE {
+            image.row16_mut(Plane::Y, y)?.fill(Y_VALUE);
+        }
+        let mut rgb = Image::create_from_yuv(&image);
+        rgb.format = format;
+        rgb.depth = 8;
+        rgb.dithering = dithering;
+        rgb.allocate()?;
+        rgb.convert_from_yuv(&image)?;
+
+        let expected_mean = Y_VALUE as f64 * 255.0 / 1023.0;
+        let channel_count = rgb.channel_count() as usize;
+        let mut sum = 0u64;
+        let mut values = Vec::new();
+        for y in 0..SIZE {
+            for pixel in rgb.row(y)?.chunks_exact(channel_count) {
+                sum += pixel[format.r_offset()] as u64;
+                values.push(pixel[format.r_offset()]);
+                if format.has_alpha() {
+                    assert_eq!(pixel[format.alpha_offset()], 255);
+                }
+            }
+        }
+        let mean = sum as f64 / (SIZE * SIZE) as f64;
+        if dithering == Dithering::None {
+            assert!(values.iter().all(|x| *x == 128));
+        } else {
+            assert!(values.iter().all(|x| *x == 128 || *x == 129));
+            assert!(
+                (mean - expected_mean).abs() < 0.02,
+                "{mean} vs {expected_mean}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test_case(Format::Rgb565, 8, 8, MatrixCoefficients::Bt601, 100)]
+    #[test_case(Format::Bgr565, 10, 8, MatrixCoefficients::Bt601, 514)]
+    #[test_case(Format::Rgba1010102, 12, 10, MatrixCoefficients::Bt601, 2051)]
+    #[test_case(Format::Yuy2, 10, 8, MatrixCoefficients::Bt601, 514)]
+    #[test_case(Format::Y410, 12, 10, MatrixCoefficients::Bt601, 2051)]
+    #[test_case(Format::Rgba, 12, 8, MatrixCoefficients::YcgcoRe, 513)]
+    fn dithering_packed_formats(
+        format: Format,
+        yuv_depth: u8,
+        rgb_depth: u8,
+        matrix_coefficients: MatrixCoefficients,
+        y_value: u16,
+    ) -> AvifResult<()> {
+        const SIZE: u32 = 16;
+        let mut image = crate::image::Image {
+            width: SIZE,
+            height: SIZE,
+            depth: yuv_depth,
+            yuv_format: PixelFormat::Yuv444,
+            yuv_range: YuvRange::Full,
+            matrix_coefficients,
+            ..crate::image::Image::default()
+        };
+        image.allocate_planes(Category::Color)?;
+        let neutral_chroma = 1u16 << (yuv_depth - 1);
+        for y in 0..SIZE {
+            for (plane, value) in [
+                (Plane::Y, y_value),
+                (Plane::U, neutral_chroma),
+                (Plane::V, neutral_chroma),
+            ] {
+                if yuv_depth == 8 {
+                    image.row_mut(plane, y)?.fill(value as u8);
+                } else {
+                    image.row16_mut(plane, y)?.fill(value);
+                }
+            }
+        }
+        for dithering in [Dithering::Ordered, Dithering::FloydSteinberg] {
+            let mut rgb = Image::create_from_yuv(&image);
+            rgb.format = format;
+            rgb.depth = rgb_depth;
+            rgb.dithering = dithering;
+            rgb.allocate()?;
+            rgb.convert_from_yuv(&image)?;
+            // The samples fall between two quantization levels so dithering must mix both.
+            let mut pixels: Vec<Vec<u16>> = Vec::new();
+            for y in 0..SIZE {
+                let row: Vec<u16> = if rgb.depth == 8 {
+                    rgb.row(y)?.iter().map(|x| *x as u16).collect()
+                } else {
+                    rgb.row16(y)?.to_vec()
+                };
+                let pixel_size = row.len() / SIZE as usize;
+                pixels.extend(row.chunks_exact(pixel_size).map(|x| x.to_vec()));
+            }
+            pixels.sort();
+            pixels.dedup();
+            assert!(pixels.len() > 1, "{format:?} {dithering:?}");
+        }
+        Ok(())
+    }
+
     #[test_case(Format::Rgba, &[0, 1, 2, 3])]
     #[test_case(Format::Abgr, &[3, 2, 1, 0])]
     #[test_case(Format::Rgb, &[0, 1, 2])]
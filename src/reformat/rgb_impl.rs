@@ -1002,6 +1002,191 @@ pub(crate) fn yuv_to_rgb_any(
     }
 }
 
+const BAYER_MATRIX_8X8: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+// Quantizes rows of samples to a lower depth, one row at a time and from top to bottom, using a
+// dithering mode. Each channel has its own destination depth. The alpha channel is never
+// dithered.
+pub(crate) struct Quantizer {
+    dithering: Dithering,
+    channel_count: usize,
+    alpha_channel: Option<usize>,
+    scales: Vec<f32>,
+    max_values: Vec<f32>,
+    // Floyd-Steinberg error buffers for the current and the next row. They have one pixel of
+    // padding on either side so that the error can be diffused without bounds checks.
+    errors: [Vec<f32>; 2],
+}
+
+impl Quantizer {
+    pub(crate) fn create(
+        dithering: Dithering,
+        width: u32,
+        src_depth: u8,
+        dst_depths: &[u8],
+        alpha_channel: Option<usize>,
+    ) -> AvifResult<Self> {
+        if dst_depths.is_empty() || dst_depths.iter().any(|depth| *depth >= src_depth) {
+            return AvifError::reformat_failed();
+        }
+        let channel_count = dst_depths.len();
+        let src_max_value = ((1u32 << src_depth) - 1) as f32;
+        let max_values: Vec<f32> = dst_depths
+            .iter()
+            .map(|depth| ((1u32 << depth) - 1) as f32)
+            .collect();
+        let error_row_length =
+            checked_mul!(checked_add!(usize_from_u32(width)?, 2)?, channel_count)?;
+        let mut errors = [
+            create_vec_exact::<f32>(error_row_length)?,
+            create_vec_exact::<f32>(error_row_length)?,
+        ];
+        errors[0].resize(error_row_length, 0.0);
+        errors[1].resize(error_row_length, 0.0);
+        Ok(Self {
+            dithering,
+            channel_count,
+            alpha_channel,
+            scales: max_values
+                .iter()
+                .map(|max_value| max_value / src_max_value)
+                .collect(),
+            max_values,
+            errors,
+        })
+    }
+
+    // Quantizes |src_row| (the row |y| of the source) into |dst_row|. Both rows must hold the
+    // same number of samples.
+    pub(crate) fn quantize_row(&mut self, y: u32, src_row: &[u16], dst_row: &mut [u16]) {
+        let channel_count = self.channel_count;
+        let (current_errors, next_errors) = self.errors.split_at_mut(1);
+        let current_errors = &mut current_errors[0];
+        let next_errors = &mut next_errors[0];
+        next_errors.fill(0.0);
+        for (i, (src_value, dst_value)) in src_row.iter().zip(dst_row.iter_mut()).enumerate() {
+            let c = i % channel_count;
+            let value = *src_value as f32 * self.scales[c];
+            let max_value = self.max_values[c];
+            let quantized = if self.alpha_channel == Some(c) {
+                (value + 0.5).floor()
+            } else {
+                match self.dithering {
+                    Dithering::None => (value + 0.5).floor(),
+                    Dithering::Ordered => {
+                        let x = i / channel_count;
+                        let threshold =
+                            (BAYER_MATRIX_8X8[(y % 8) as usize][x % 8] as f32 + 0.5) / 64.0;
+                        (value + threshold).floor()
+                    }
+                    Dithering::FloydSteinberg => {
+                        let e = i + channel_count;
+                        let target = value + current_errors[e];
+                        let quantized = (target + 0.5).floor().clamp(0.0, max_value);
+                        let error = target - quantized;
+                        current_errors[e + channel_count] += error * 7.0 / 16.0;
+                        next_errors[e - channel_count] += error * 3.0 / 16.0;
+                        next_errors[e] += error * 5.0 / 16.0;
+                        next_errors[e + channel_count] += error / 16.0;
+                        quantized
+                    }
+                }
+            };
+            *dst_value = quantized.clamp(0.0, max_value) as u16;
+        }
+        self.errors.swap(0, 1);
+    }
+}
+
+// Splits a pixel of Format::Rgba1010102 into the two u16 values it is stored as. See
+// alpha_index_in_rgba_1010102.
+fn split_rgba1010102(pixel: u32) -> [u16; 2] {
+    if cfg!(target_endian = "little") {
+        [pixel as u16, (pixel >> 16) as u16]
+    } else {
+        [(pixel >> 16) as u16, pixel as u16]
+    }
+}
+
+// Quantizes |src| into |dst| using the dithering mode of |dst|. |src| must have the same
+// dimensions as |dst| and a higher depth than the channels of |dst|. Its format must be the one
+// returned by rgb::Image::dithering_source_format() for |dst|.
+pub(crate) fn dither(src: &rgb::Image, dst: &mut rgb::Image) -> AvifResult<()> {
+    let channel_depths = match dst.format {
+        Format::Rgb565 | Format::Bgr565 => vec![5, 6, 5],
+        Format::Rgba1010102 => vec![10, 10, 10, 2],
+        _ => vec![dst.depth; src.channel_count() as usize],
+    };
+    if src.format != dst.dithering_source_format()
+        || src.width != dst.width
+        || src.height != dst.height
+    {
+        return AvifError::reformat_failed();
+    }
+    let alpha_channel = if src.has_alpha() { Some(src.format.alpha_offset()) } else { None };
+    let mut quantizer = Quantizer::create(
+        dst.dithering,
+        src.width,
+        src.depth,
+        &channel_depths,
+        alpha_channel,
+    )?;
+    let width = usize_from_u32(src.width)?;
+    let row_length = checked_mul!(width, channel_depths.len())?;
+    let mut src_row: Vec<u16> = create_vec_exact(row_length)?;
+    src_row.resize(row_length, 0);
+    let mut quantized_row: Vec<u16> = create_vec_exact(row_length)?;
+    quantized_row.resize(row_length, 0);
+    for y in 0..src.height {
+        if src.depth == 8 {
+            for (value, src_value) in src_row.iter_mut().zip(src.row(y)?.iter()) {
+                *value = *src_value as u16;
+            }
+        } else {
+            src_row.copy_from_slice(&src.row16(y)?[..row_length]);
+        }
+        quantizer.quantize_row(y, &src_row, &mut quantized_row);
+        match dst.format {
+            Format::Rgb565 | Format::Bgr565 => {
+                let is_rgb565 = dst.format == Format::Rgb565;
+                let dst_row = dst.row_mut(y)?;
+                for (x, rgb) in quantized_row.chunks_exact(3).enumerate() {
+                    let (high, low) = if is_rgb565 { (rgb[0], rgb[2]) } else { (rgb[2], rgb[0]) };
+                    let pixel = (high << 11) | (rgb[1] << 5) | low;
+                    dst_row[x * 2..x * 2 + 2].copy_from_slice(&pixel.to_le_bytes());
+                }
+            }
+            Format::Rgba1010102 => {
+                let dst_row = dst.row16_mut(y)?;
+                for (x, rgba) in quantized_row.chunks_exact(4).enumerate() {
+                    let pixel = ((rgba[3] as u32) << 30)
+                        | ((rgba[2] as u32) << 20)
+                        | ((rgba[1] as u32) << 10)
+                        | (rgba[0] as u32);
+                    dst_row[x * 2..x * 2 + 2].copy_from_slice(&split_rgba1010102(pixel));
+                }
+            }
+            _ if dst.depth == 8 => {
+                let dst_row = &mut dst.row_mut(y)?[..row_length];
+                for (dst_value, value) in dst_row.iter_mut().zip(quantized_row.iter()) {
+                    *dst_value = *value as u8;
+                }
+            }
+            _ => dst.row16_mut(y)?[..row_length].copy_from_slice(&quantized_row),
+        }
+    }
+    Ok(())
+}
+
 #[derive(Debug, Default, Copy, Clone)]
 struct YUVBlock(f32, f32, f32);
 
@@ -0,0 +1,348 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::image;
+use crate::image::Plane;
+use crate::internal_utils::*;
+use crate::*;
+
+// AndroidP010 stores 10-bit samples in the most significant bits of 16-bit values.
+const P010_SHIFT: u32 = 6;
+
+fn to_p010(value: u16) -> u16 {
+    value << P010_SHIFT
+}
+
+fn from_p010(value: u16) -> u16 {
+    value >> P010_SHIFT
+}
+
+// The alpha plane is not part of P010. Replicate the most significant bits into the low bits so
+// that opaque pixels map to the maximum 16-bit value.
+fn alpha_to_p010(value: u16) -> u16 {
+    (value << P010_SHIFT) | (value >> (10 - P010_SHIFT))
+}
+
+// Returns the indices of the U and V samples within each interleaved chroma pair.
+fn uv_indices(yuv_format: PixelFormat) -> (usize, usize) {
+    if yuv_format == PixelFormat::AndroidNv21 {
+        (1, 0)
+    } else {
+        (0, 1)
+    }
+}
+
+impl image::Image {
+    // Returns a copy of this Yuv420 (or Yuv400) image in the semi-planar |yuv_format|, which must
+    // be one of AndroidNv12, AndroidNv21 (both 8-bit) or AndroidP010 (10-bit). Yuv400 images get
+    // neutral chroma. Exif, XMP and ICC metadata are not copied.
+    pub fn convert_to_semi_planar(&self, yuv_format: PixelFormat) -> AvifResult<image::Image> {
+        if !yuv_format.is_semi_planar() || !self.has_plane(Plane::Y) {
+            return AvifError::invalid_argument();
+        }
+        let is_p010 = yuv_format == PixelFormat::AndroidP010;
+        if self.depth != if is_p010 { 10 } else { 8 }
+            || !matches!(self.yuv_format, PixelFormat::Yuv420 | PixelFormat::Yuv400)
+        {
+            return AvifError::not_implemented();
+        }
+        let mut image = self.shallow_clone();
+        image.yuv_format = yuv_format;
+        image.depth = if is_p010 { 16 } else { 8 };
+        image.allocate_planes(Category::Color)?;
+        if self.has_alpha() {
+            image.allocate_planes(Category::Alpha)?;
+        }
+        let has_chroma = self.yuv_format == PixelFormat::Yuv420;
+        let chroma_height = u32_from_usize(image.height(Plane::U))?;
+        let (u_index, v_index) = uv_indices(yuv_format);
+        if is_p010 {
+            for y in 0..self.height {
+                let src_row = self.row16(Plane::Y, y)?;
+                let dst_row = image.row16_mut(Plane::Y, y)?;
+                for (dst, src) in dst_row.iter_mut().zip(src_row) {
+                    *dst = to_p010(*src);
+                }
+            }
+            for y in 0..chroma_height {
+                let dst_row = image.row16_mut(Plane::U, y)?;
+                if !has_chroma {
+                    dst_row.fill(to_p010(512));
+                    continue;
+                }
+                let u_row = self.row16(Plane::U, y)?;
+                let v_row = self.row16(Plane::V, y)?;
+                for (x, dst) in dst_row.chunks_exact_mut(2).enumerate() {
+                    dst[u_index] = to_p010(u_row[x]);
+                    dst[v_index] = to_p010(v_row[x]);
+                }
+            }
+            if image.has_alpha() {
+                for y in 0..self.height {
+                    let src_row = self.row16(Plane::A, y)?;
+                    let dst_row = image.row16_mut(Plane::A, y)?;
+                    for (dst, src) in dst_row.iter_mut().zip(src_row) {
+                        *dst = alpha_to_p010(*src);
+                    }
+                }
+            }
+        } else {
+            for y in 0..self.height {
+                image
+                    .row_mut(Plane::Y, y)?
+                    .copy_from_slice(self.row(Plane::Y, y)?);
+            }
+            for y in 0..chroma_height {
+                let dst_row = image.row_mut(Plane::U, y)?;
+                if !has_chroma {
+                    dst_row.fill(128);
+                    continue;
+                }
+                let u_row = self.row(Plane::U, y)?;
+                let v_row = self.row(Plane::V, y)?;
+                for (x, dst) in dst_row.chunks_exact_mut(2).enumerate() {
+                    dst[u_index] = u_row[x];
+                    dst[v_index] = v_row[x];
+                }
+            }
+            if image.has_alpha() {
+                for y in 0..self.height {
+                    image
+                        .row_mut(Plane::A, y)?
+                        .copy_from_slice(self.row(Plane::A, y)?);
+                }
+            }
+        }
+        Ok(image)
+    }
+
+    // Returns a copy of this semi-planar image as a Yuv420 image with separate U and V planes.
+    // AndroidP010 images are converted to 10-bit. Exif, XMP and ICC metadata are not copied.
+    pub fn convert_to_planar(&self) -> AvifResult<image::Image> {
+        if !self.yuv_format.is_semi_planar()
+            || !self.has_plane(Plane::Y)
+            || !self.has_plane(Plane::U)
+        {
+            return AvifError::invalid_argument();
+        }
+        let is_p010 = self.yuv_format == PixelFormat::AndroidP010;
+        if self.depth != if is_p010 { 16 } else { 8 } {
+            return AvifError::invalid_argument();
+        }
+        let mut image = self.shallow_clone();
+        image.yuv_format = PixelFormat::Yuv420;
+        image.depth = if is_p010 { 10 } else { 8 };
+        image.allocate_planes(Category::Color)?;
+        if self.has_alpha() {
+            image.allocate_planes(Category::Alpha)?;
+        }
+        let chroma_height = u32_from_usize(self.height(Plane::U))?;
+        let (u_index, v_index) = uv_indices(self.yuv_format);
+        if is_p010 {
+            for y in 0..self.height {
+                let src_row = self.row16(Plane::Y, y)?;
+                let dst_row = image.row16_mut(Plane::Y, y)?;
+                for (dst, src) in dst_row.iter_mut().zip(src_row) {
+                    *dst = from_p010(*src);
+                }
+            }
+            for y in 0..chroma_height {
+                let src_row = self.row16(Plane::U, y)?;
+                for (plane, index) in [(Plane::U, u_index), (Plane::V, v_index)] {
+                    let dst_row = image.row16_mut(plane, y)?;
+                    for (dst, src) in dst_row.iter_mut().zip(src_row.chunks_exact(2)) {
+                        *dst = from_p010(src[index]);
+                    }
+                }
+            }
+            if image.has_alpha() {
+                for y in 0..self.height {
+                    let src_row = self.row16(Plane::A, y)?;
+                    let dst_row = image.row16_mut(Plane::A, y)?;
+                    for (dst, src) in dst_row.iter_mut().zip(src_row) {
+                        *dst = from_p010(*src);
+                    }
+                }
+            }
+        } else {
+            for y in 0..self.height {
+                image
+                    .row_mut(Plane::Y, y)?
+                    .copy_from_slice(self.row(Plane::Y, y)?);
+            }
+            for y in 0..chroma_height {
+                let src_row = self.row(Plane::U, y)?;
+                for (plane, index) in [(Plane::U, u_index), (Plane::V, v_index)] {
+                    let dst_row = image.row_mut(plane, y)?;
+                    for (dst, src) in dst_row.iter_mut().zip(src_row.chunks_exact(2)) {
+                        *dst = src[index];
+                    }
+                }
+            }
+            if image.has_alpha() {
+                for y in 0..self.height {
+                    image
+                        .row_mut(Plane::A, y)?
+                        .copy_from_slice(self.row(Plane::A, y)?);
+                }
+            }
+        }
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reformat::rgb;
+
+    use test_case::test_matrix;
+
+    fn create_image(width: u32, height: u32, depth: u8, yuv_format: PixelFormat) -> image::Image {
+        let mut image = image::Image {
+            width,
+            height,
+            depth,
+            yuv_format,
+            ..Default::default()
+        };
+        image.allocate_planes(Category::Color).unwrap();
+        image.allocate_planes(Category::Alpha).unwrap();
+        let max_channel = image.max_channel() as u32;
+        image.fill_planes_with(|plane, x, y| {
+            ((x as u32 * 7 + y * 31 + plane.as_usize() as u32 * 13) % (max_channel + 1)) as u16
+        });
+        image
+    }
+
+    #[test_matrix(
+        [PixelFormat::AndroidNv12, PixelFormat::AndroidNv21, PixelFormat::AndroidP010],
+        [(1, 1), (4, 4), (7, 5), (16, 3)]
+    )]
+    fn round_trip(yuv_format: PixelFormat, (width, height): (u32, u32)) {
+        let depth = if yuv_format == PixelFormat::AndroidP010 { 10 } else { 8 };
+        let image = create_image(width, height, depth, PixelFormat::Yuv420);
+        let semi_planar = image.convert_to_semi_planar(yuv_format).unwrap();
+        assert_eq!(semi_planar.yuv_format, yuv_format);
+        assert!(!semi_planar.has_plane(Plane::V));
+        let chroma_data = semi_planar.plane_data(Plane::U).unwrap();
+        assert_eq!(chroma_data.width, width.div_ceil(2) * 2);
+        assert_eq!(chroma_data.height, height.div_ceil(2));
+
+        let (u_index, v_index) = uv_indices(yuv_format);
+        let shift = if yuv_format == PixelFormat::AndroidP010 { P010_SHIFT } else { 0 };
+        for y in 0..chroma_data.height {
+            for x in 0..width.div_ceil(2) as usize {
+                assert_eq!(
+                    semi_planar.sample(Plane::U, x * 2 + u_index, y),
+                    image.sample(Plane::U, x, y) << shift
+                );
+                assert_eq!(
+                    semi_planar.sample(Plane::U, x * 2 + v_index, y),
+                    image.sample(Plane::V, x, y) << shift
+                );
+            }
+        }
+
+        let planar = semi_planar.convert_to_planar().unwrap();
+        assert!(planar.has_same_properties_and_cicp(&image));
+        for plane in image::ALL_PLANES {
+            let plane_data = image.plane_data(plane).unwrap();
+            for y in 0..plane_data.height {
+                for x in 0..plane_data.width as usize {
+                    assert_eq!(planar.sample(plane, x, y), image.sample(plane, x, y));
+                }
+            }
+        }
+    }
+
+    #[test_matrix(
+        [PixelFormat::AndroidNv12, PixelFormat::AndroidNv21, PixelFormat::AndroidP010],
+        [(1, 1, 2), (4, 3, 4), (7, 5, 8)]
+    )]
+    fn plane_dimensions(yuv_format: PixelFormat, (width, height, chroma_width): (u32, u32, usize)) {
+        let image = image::Image {
+            width,
+            height,
+            yuv_format,
+            ..Default::default()
+        };
+        assert_eq!(image.width(Plane::Y), width as usize);
+        // The interleaved chroma plane is rounded up to a whole number of UV pairs, which is what
+        // MediaCodec and libyuv use for odd widths.
+        assert_eq!(image.width(Plane::U), chroma_width);
+        assert_eq!(image.height(Plane::U), height.div_ceil(2) as usize);
+        assert_eq!(image.width(Plane::V), 0);
+        assert_eq!(image.height(Plane::V), 0);
+    }
+
+    #[test]
+    fn p010_alpha_is_opaque() {
+        let mut image = create_image(3, 3, 10, PixelFormat::Yuv420);
+        image.fill_plane_with_value(Plane::A, 1023).unwrap();
+        let semi_planar = image
+            .convert_to_semi_planar(PixelFormat::AndroidP010)
+            .unwrap();
+        assert_eq!(semi_planar.row16(Plane::A, 0).unwrap()[0], u16::MAX);
+    }
+
+    #[test]
+    fn monochrome() {
+        let image = create_image(5, 3, 8, PixelFormat::Yuv400);
+        let semi_planar = image
+            .convert_to_semi_planar(PixelFormat::AndroidNv12)
+            .unwrap();
+        for y in 0..2 {
+            assert!(semi_planar
+                .row(Plane::U, y)
+                .unwrap()
+                .iter()
+                .all(|x| *x == 128));
+        }
+    }
+
+    #[test_matrix([PixelFormat::AndroidNv12, PixelFormat::AndroidP010])]
+    fn convert_to_rgb(yuv_format: PixelFormat) {
+        let depth = if yuv_format == PixelFormat::AndroidP010 { 10 } else { 8 };
+        let image = create_image(7, 5, depth, PixelFormat::Yuv420);
+        let semi_planar = image.convert_to_semi_planar(yuv_format).unwrap();
+        let mut expected = rgb::Image::create_from_yuv(&image);
+        expected.allocate().unwrap();
+        expected.convert_from_yuv(&image).unwrap();
+        let mut actual = rgb::Image::create_from_yuv(&image);
+        actual.allocate().unwrap();
+        actual.convert_from_yuv(&semi_planar).unwrap();
+        for y in 0..image.height {
+            if depth == 8 {
+                assert_eq!(expected.row(y).unwrap(), actual.row(y).unwrap());
+            } else {
+                assert_eq!(expected.row16(y).unwrap(), actual.row16(y).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_input() {
+        let image = create_image(4, 4, 8, PixelFormat::Yuv444);
+        assert!(image
+            .convert_to_semi_planar(PixelFormat::AndroidNv12)
+            .is_err());
+        let image = create_image(4, 4, 8, PixelFormat::Yuv420);
+        assert!(image.convert_to_semi_planar(PixelFormat::Yuv420).is_err());
+        assert!(image
+            .convert_to_semi_planar(PixelFormat::AndroidP010)
+            .is_err());
+        assert!(image.convert_to_planar().is_err());
+    }
+}
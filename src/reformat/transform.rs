@@ -0,0 +1,483 @@
+// Copyright 2024 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use super::rgb;
+use super::rgb::Format;
+
+use crate::image;
+use crate::image::Plane;
+use crate::internal_utils::*;
+use crate::utils::clap::*;
+use crate::*;
+
+#[derive(Clone, Copy)]
+struct Transform {
+    // Anti-clockwise rotation in units of 90 degrees.
+    angle: u8,
+    // 0: top and bottom parts are exchanged. 1: left and right parts are exchanged.
+    axis: Option<u8>,
+}
+
+impl Transform {
+    fn create(irot_angle: Option<u8>, imir_axis: Option<u8>) -> AvifResult<Self> {
+        if irot_angle.is_some_and(|x| x > 3) || imir_axis.is_some_and(|x| x > 1) {
+            return AvifError::invalid_argument();
+        }
+        Ok(Self {
+            angle: irot_angle.unwrap_or(0),
+            axis: imir_axis,
+        })
+    }
+
+    fn swaps_dimensions(&self) -> bool {
+        self.angle % 2 == 1
+    }
+
+    fn output_dimensions(&self, width: usize, height: usize) -> (usize, usize) {
+        if self.swaps_dimensions() {
+            (height, width)
+        } else {
+            (width, height)
+        }
+    }
+
+    // Returns the position in the |width|x|height| input that ends up at (x, y) in the output.
+    fn source_position(&self, x: usize, y: usize, width: usize, height: usize) -> (usize, usize) {
+        let (rotated_width, rotated_height) = self.output_dimensions(width, height);
+        // The mirror is applied after the rotation, so undo it first.
+        let (x, y) = match self.axis {
+            Some(0) => (x, rotated_height - 1 - y),
+            Some(1) => (rotated_width - 1 - x, y),
+            _ => (x, y),
+        };
+        match self.angle {
+            1 => (width - 1 - y, x),
+            2 => (width - 1 - x, height - 1 - y),
+            3 => (y, height - 1 - x),
+            _ => (x, y),
+        }
+    }
+
+    // Fills row |y| of the output. |src_rows| are the rows of the input region which starts at
+    // column |x_offset| and is |width| pixels wide. Each pixel is made of |units| samples.
+    fn fill_row<T: Copy>(
+        &self,
+        src_rows: &[&[T]],
+        x_offset: usize,
+        width: usize,
+        units: usize,
+        y: usize,
+        dst_row: &mut [T],
+    ) {
+        let height = src_rows.len();
+        let (dst_width, _) = self.output_dimensions(width, height);
+        for x in 0..dst_width {
+            let (src_x, src_y) = self.source_position(x, y, width, height);
+            let src_start = (x_offset + src_x) * units;
+            dst_row[x * units..(x + 1) * units]
+                .copy_from_slice(&src_rows[src_y][src_start..src_start + units]);
+        }
+    }
+}
+
+impl image::Image {
+    fn upsample_422_to_444(&self) -> AvifResult<image::Image> {
+        let mut image = self.shallow_clone();
+        image.yuv_format = PixelFormat::Yuv444;
+        image.allocate_planes(Category::Color)?;
+        if self.has_alpha() {
+            image.allocate_planes(Category::Alpha)?;
+        }
+        for plane in image::ALL_PLANES {
+            if !self.has_plane(plane) {
+                continue;
+            }
+            let subsampled = plane == Plane::U || plane == Plane::V;
+            for y in 0..self.height {
+                if self.depth == 8 {
+                    let src_row = self.row(plane, y)?;
+                    let dst_row = image.row_mut(plane, y)?;
+                    for (x, dst) in dst_row.iter_mut().enumerate() {
+                        *dst = src_row[if subsampled { x / 2 } else { x }];
+                    }
+                } else {
+                    let src_row = self.row16(plane, y)?;
+                    let dst_row = image.row16_mut(plane, y)?;
+                    for (x, dst) in dst_row.iter_mut().enumerate() {
+                        *dst = src_row[if subsampled { x / 2 } else { x }];
+                    }
+                }
+            }
+        }
+        Ok(image)
+    }
+
+    // Returns a copy of this image with the clean aperture, rotation and mirror transformative
+    // properties applied in that order, as required by the HEIF specification. The returned image
+    // has no transformative properties. Yuv422 images rotated by 90 or 270 degrees are returned as
+    // Yuv444 since the rotated chroma planes cannot be represented as 4:2:2.
+    pub fn transformed_image(&self) -> AvifResult<image::Image> {
+        self.transformed_image_with(self.clap.as_ref(), self.irot_angle, self.imir_axis)
+    }
+
+    // Same as transformed_image() but with the given transformative properties instead of the ones
+    // of this image. Used for gain maps, which use the properties of the base image (with the clean
+    // aperture scaled to the dimensions of the gain map).
+    pub fn transformed_image_with(
+        &self,
+        clap: Option<&CleanAperture>,
+        irot_angle: Option<u8>,
+        imir_axis: Option<u8>,
+    ) -> AvifResult<image::Image> {
+        let transform = Transform::create(irot_angle, imir_axis)?;
+        let upsampled;
+        let src = if self.yuv_format == PixelFormat::Yuv422 && transform.swaps_dimensions() {
+            upsampled = self.upsample_422_to_444()?;
+            &upsampled
+        } else {
+            self
+        };
+        let rect = match clap {
+            Some(clap) => CropRect::create_from(clap, src.width, src.height, src.yuv_format)?,
+            None => CropRect {
+                x: 0,
+                y: 0,
+                width: src.width,
+                height: src.height,
+            },
+        };
+        let mut cropped = src.shallow_clone();
+        cropped.width = rect.width;
+        cropped.height = rect.height;
+        let mut image = cropped.shallow_clone();
+        if transform.swaps_dimensions() {
+            image.width = cropped.height;
+            image.height = cropped.width;
+        }
+        if (transform.angle != 0 || transform.axis.is_some())
+            && !matches!(src.yuv_format, PixelFormat::Yuv444 | PixelFormat::Yuv400)
+        {
+            // The chroma sample position is not preserved by the rotation and mirror.
+            image.chroma_sample_position = ChromaSamplePosition::Unknown;
+        }
+        image.clap = None;
+        image.irot_angle = None;
+        image.imir_axis = None;
+        image.allocate_planes(Category::Color)?;
+        if src.has_alpha() {
+            image.allocate_planes(Category::Alpha)?;
+        }
+        for plane in image::ALL_PLANES {
+            if !src.has_plane(plane) || !image.has_plane(plane) {
+                continue;
+            }
+            let (x_offset, y_offset) = if plane == Plane::Y || plane == Plane::A {
+                (rect.x, rect.y)
+            } else {
+                (
+                    rect.x >> src.yuv_format.chroma_shift_x().0,
+                    src.yuv_format.apply_chroma_shift_y(rect.y),
+                )
+            };
+            // The interleaved chroma plane of semi-planar formats has two samples per pixel.
+            let units = if src.yuv_format.is_semi_planar() && plane == Plane::U { 2 } else { 1 };
+            let width = cropped.width(plane) / units;
+            let height = u32_from_usize(cropped.height(plane))?;
+            let rows = y_offset..checked_add!(y_offset, height)?;
+            let x_offset = usize_from_u32(x_offset)?;
+            let dst_height = u32_from_usize(image.height(plane))?;
+            if src.depth == 8 {
+                let src_rows: Vec<&[u8]> =
+                    rows.map(|y| src.row(plane, y)).collect::<AvifResult<_>>()?;
+                for y in 0..dst_height {
+                    let dst_row = image.row_mut(plane, y)?;
+                    transform.fill_row(&src_rows, x_offset, width, units, y as usize, dst_row);
+                }
+            } else {
+                let src_rows: Vec<&[u16]> = rows
+                    .map(|y| src.row16(plane, y))
+                    .collect::<AvifResult<_>>()?;
+                for y in 0..dst_height {
+                    let dst_row = image.row16_mut(plane, y)?;
+                    transform.fill_row(&src_rows, x_offset, width, units, y as usize, dst_row);
+                }
+            }
+        }
+        image.exif = self.exif.try_clone()?;
+        image.xmp = self.xmp.try_clone()?;
+        image.icc = self.icc.try_clone()?;
+        Ok(image)
+    }
+}
+
+impl rgb::Image {
+    // Returns a copy of this image with the given transformative properties applied in the order
+    // required by the HEIF specification (clean aperture, then rotation, then mirror). These are
+    // typically the clap, irot_angle and imir_axis of the image::Image this image was converted
+    // from.
+    pub fn transformed_image(
+        &self,
+        clap: Option<&CleanAperture>,
+        irot_angle: Option<u8>,
+        imir_axis: Option<u8>,
+    ) -> AvifResult<rgb::Image> {
+        if self.format == Format::Yuy2 {
+            return AvifError::not_implemented();
+        }
+        let transform = Transform::create(irot_angle, imir_axis)?;
+        let rect = match clap {
+            Some(clap) => {
+                CropRect::create_from(clap, self.width, self.height, PixelFormat::Yuv444)?
+            }
+            None => CropRect {
+                x: 0,
+                y: 0,
+                width: self.width,
+                height: self.height,
+            },
+        };
+        let (width, height) =
+            transform.output_dimensions(usize_from_u32(rect.width)?, usize_from_u32(rect.height)?);
+        let mut image = rgb::Image {
+            width: u32_from_usize(width)?,
+            height: u32_from_usize(height)?,
+            pixels: None,
+            row_bytes: 0,
+            ..*self
+        };
+        image.allocate()?;
+        let sample_size = if self.channel_size() == 1 { 1 } else { 2 };
+        let units = usize_from_u32(self.pixel_size() / sample_size)?;
+        let x_offset = usize_from_u32(rect.x)?;
+        let src_width = usize_from_u32(rect.width)?;
+        for plane in 0..self.format.plane_count() {
+            let src_start = checked_add!(checked_mul!(plane, self.height)?, rect.y)?;
+            let rows = src_start..checked_add!(src_start, rect.height)?;
+            let dst_start = checked_mul!(plane, image.height)?;
+            if sample_size == 1 {
+                let src_rows: Vec<&[u8]> = rows.map(|y| self.row(y)).collect::<AvifResult<_>>()?;
+                for y in 0..image.height {
+                    let dst_row = image.row_mut(checked_add!(dst_start, y)?)?;
+                    transform.fill_row(&src_rows, x_offset, src_width, units, y as usize, dst_row);
+                }
+            } else {
+                let src_rows: Vec<&[u16]> =
+                    rows.map(|y| self.row16(y)).collect::<AvifResult<_>>()?;
+                for y in 0..image.height {
+                    let dst_row = image.row16_mut(checked_add!(dst_start, y)?)?;
+                    transform.fill_row(&src_rows, x_offset, src_width, units, y as usize, dst_row);
+                }
+            }
+        }
+        Ok(image)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_case::test_case;
+    use test_case::test_matrix;
+
+    // Returns the value stored at (x, y) in a test image before any transformation.
+    fn value(plane: Plane, x: usize, y: usize, max: u16) -> u16 {
+        ((plane.as_usize() * 1000 + y * 31 + x * 7) % (max as usize + 1)) as u16
+    }
+
+    fn create_image(
+        width: u32,
+        height: u32,
+        depth: u8,
+        yuv_format: PixelFormat,
+        irot_angle: Option<u8>,
+        imir_axis: Option<u8>,
+    ) -> image::Image {
+        let mut image = image::Image {
+            width,
+            height,
+            depth,
+            yuv_format,
+            irot_angle,
+            imir_axis,
+            ..Default::default()
+        };
+        image.allocate_planes(Category::Color).unwrap();
+        image.allocate_planes(Category::Alpha).unwrap();
+        let max = image.max_channel();
+        image.fill_planes_with(|plane, x, y| value(plane, x, y as usize, max));
+        image
+    }
+
+    // Corners of a 3x2 image (a b c / d e f) after each transformation, in the order top-left,
+    // top-right, bottom-left and bottom-right. Matches the Exif orientation mappings.
+    #[test_case(None, None, 3, 2, ['a', 'c', 'd', 'f']; "identity")]
+    #[test_case(Some(1), None, 2, 3, ['c', 'f', 'a', 'd']; "rotate 90 anti-clockwise")]
+    #[test_case(Some(2), None, 3, 2, ['f', 'd', 'c', 'a']; "rotate 180")]
+    #[test_case(Some(3), None, 2, 3, ['d', 'a', 'f', 'c']; "rotate 270 anti-clockwise")]
+    #[test_case(None, Some(0), 3, 2, ['d', 'f', 'a', 'c']; "mirror top bottom")]
+    #[test_case(None, Some(1), 3, 2, ['c', 'a', 'f', 'd']; "mirror left right")]
+    #[test_case(Some(1), Some(0), 2, 3, ['a', 'd', 'c', 'f']; "transpose")]
+    #[test_case(Some(3), Some(0), 2, 3, ['f', 'c', 'd', 'a']; "transverse")]
+    fn orientation(
+        irot_angle: Option<u8>,
+        imir_axis: Option<u8>,
+        width: u32,
+        height: u32,
+        corners: [char; 4],
+    ) {
+        let mut image = create_image(3, 2, 8, PixelFormat::Yuv444, irot_angle, imir_axis);
+        for (i, c) in "abcdef".chars().enumerate() {
+            image.row_mut(Plane::Y, i as u32 / 3).unwrap()[i % 3] = c as u8;
+        }
+        let transformed = image.transformed_image().unwrap();
+        assert_eq!(transformed.width, width);
+        assert_eq!(transformed.height, height);
+        assert!(transformed.irot_angle.is_none() && transformed.imir_axis.is_none());
+        let actual = [
+            transformed.row(Plane::Y, 0).unwrap()[0],
+            transformed.row(Plane::Y, 0).unwrap()[width as usize - 1],
+            transformed.row(Plane::Y, height - 1).unwrap()[0],
+            transformed.row(Plane::Y, height - 1).unwrap()[width as usize - 1],
+        ];
+        assert_eq!(actual.map(|x| x as char), corners);
+    }
+
+    #[test_matrix(
+        [8, 10, 12, 16],
+        [PixelFormat::Yuv444, PixelFormat::Yuv422, PixelFormat::Yuv420, PixelFormat::Yuv400],
+        [0, 1, 2, 3],
+        [None, Some(0), Some(1)]
+    )]
+    fn round_trip(depth: u8, yuv_format: PixelFormat, angle: u8, axis: Option<u8>) {
+        let image = create_image(6, 4, depth, yuv_format, Some(angle), axis);
+        let mut transformed = image.transformed_image().unwrap();
+        if angle % 2 == 1 {
+            assert_eq!((transformed.width, transformed.height), (4, 6));
+        }
+        // Undo the transformation: mirror first, then rotate back.
+        transformed.imir_axis = axis;
+        let mut mirrored = transformed.transformed_image().unwrap();
+        mirrored.irot_angle = Some((4 - angle) % 4);
+        let restored = mirrored.transformed_image().unwrap();
+        assert_eq!((restored.width, restored.height), (6, 4));
+        let upsampled = yuv_format == PixelFormat::Yuv422 && angle % 2 == 1;
+        for plane in image::ALL_PLANES {
+            let Some(plane_data) = restored.plane_data(plane) else {
+                continue;
+            };
+            for y in 0..plane_data.height {
+                for x in 0..plane_data.width as usize {
+                    let src_x =
+                        if upsampled && matches!(plane, Plane::U | Plane::V) { x / 2 } else { x };
+                    assert_eq!(restored.sample(plane, x, y), image.sample(plane, src_x, y));
+                }
+            }
+        }
+    }
+
+    #[test_matrix([PixelFormat::AndroidNv12, PixelFormat::AndroidNv21], [0, 1, 2, 3])]
+    fn semi_planar(yuv_format: PixelFormat, angle: u8) {
+        let image = create_image(6, 4, 8, PixelFormat::Yuv420, Some(angle), None);
+        let expected = image.transformed_image().unwrap();
+        let semi_planar = image.convert_to_semi_planar(yuv_format).unwrap();
+        let actual = semi_planar.transformed_image().unwrap();
+        assert_eq!(actual.yuv_format, yuv_format);
+        let actual = actual.convert_to_planar().unwrap();
+        for plane in image::YUV_PLANES {
+            let plane_data = expected.plane_data(plane).unwrap();
+            for y in 0..plane_data.height {
+                assert_eq!(
+                    expected.row(plane, y).unwrap(),
+                    actual.row(plane, y).unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn clap_before_rotation() {
+        let mut image = create_image(8, 6, 8, PixelFormat::Yuv420, Some(1), None);
+        // Crop the 4x2 region starting at (2, 2).
+        image.clap = Some(
+            CleanAperture::create_from(
+                &CropRect {
+                    x: 2,
+                    y: 2,
+                    width: 4,
+                    height: 2,
+                },
+                8,
+                6,
+                PixelFormat::Yuv420,
+            )
+            .unwrap(),
+        );
+        let transformed = image.transformed_image().unwrap();
+        assert!(transformed.clap.is_none());
+        assert_eq!((transformed.width, transformed.height), (2, 4));
+        // The top-right corner of the cropped region is now the top-left corner.
+        assert_eq!(
+            transformed.sample(Plane::Y, 0, 0),
+            value(Plane::Y, 5, 2, 255)
+        );
+        assert_eq!(
+            transformed.sample(Plane::U, 0, 0),
+            value(Plane::U, 2, 1, 255)
+        );
+    }
+
+    #[test_matrix(
+        [Format::Rgba, Format::Rgb, Format::Rgb565, Format::GbrPlanar, Format::Rgba1010102],
+        [0, 1, 2, 3],
+        [None, Some(0), Some(1)]
+    )]
+    fn rgb_formats(format: Format, angle: u8, axis: Option<u8>) {
+        let depth = match format {
+            Format::Rgba1010102 => 10,
+            Format::GbrPlanar => 12,
+            _ => 8,
+        };
+        let image = create_image(6, 4, depth, PixelFormat::Yuv444, Some(angle), axis);
+        let mut rgb = rgb::Image::create_from_yuv(&image);
+        rgb.format = format;
+        rgb.allocate().unwrap();
+        let row_count = rgb.row_count().unwrap();
+        for y in 0..row_count {
+            if rgb.channel_size() == 1 {
+                for (x, value) in rgb.row_mut(y).unwrap().iter_mut().enumerate() {
+                    *value = (x + y as usize * 3) as u8;
+                }
+            } else {
+                for (x, value) in rgb.row16_mut(y).unwrap().iter_mut().enumerate() {
+                    *value = (x + y as usize * 3) as u16;
+                }
+            }
+        }
+        let transformed = rgb.transformed_image(None, Some(angle), axis).unwrap();
+        let restored = transformed
+            .transformed_image(None, None, axis)
+            .unwrap()
+            .transformed_image(None, Some((4 - angle) % 4), None)
+            .unwrap();
+        assert_eq!((restored.width, restored.height), (6, 4));
+        for y in 0..row_count {
+            if rgb.channel_size() == 1 {
+                assert_eq!(rgb.row(y).unwrap(), restored.row(y).unwrap());
+            } else {
+                assert_eq!(rgb.row16(y).unwrap(), restored.row16(y).unwrap());
+            }
+        }
+    }
+}
@@ -85,7 +85,10 @@ impl CropRect {
             return false;
         }
         match pixel_format {
-            PixelFormat::Yuv420 => self.x % 2 == 0 && self.y % 2 == 0,
+            PixelFormat::Yuv420
+            | PixelFormat::AndroidP010
+            | PixelFormat::AndroidNv12
+            | PixelFormat::AndroidNv21 => self.x % 2 == 0 && self.y % 2 == 0,
             PixelFormat::Yuv422 => self.x % 2 == 0,
             _ => true,
         }
@@ -135,6 +138,53 @@ impl CropRect {
             AvifError::unknown_error("")
         }
     }
+
+    // Returns the smallest rectangle of an image of |scaled_width|x|scaled_height| that covers the
+    // same region as this rectangle does in an image of |image_width|x|image_height|. The offsets
+    // are rounded down to even values when required by |pixel_format|.
+    pub(crate) fn scaled(
+        &self,
+        image_width: u32,
+        image_height: u32,
+        scaled_width: u32,
+        scaled_height: u32,
+        pixel_format: PixelFormat,
+    ) -> AvifResult<Self> {
+        if !self.is_valid(image_width, image_height, PixelFormat::Yuv444) {
+            return AvifError::invalid_argument();
+        }
+        let scale_down =
+            |value: u32, from: u32, to: u32| (value as u64 * to as u64 / from as u64) as u32;
+        let scale_up = |value: u32, from: u32, to: u32| {
+            (value as u64 * to as u64).div_ceil(from as u64) as u32
+        };
+        let mut x = scale_down(self.x, image_width, scaled_width);
+        let mut y = scale_down(self.y, image_height, scaled_height);
+        let right = scale_up(self.x + self.width, image_width, scaled_width);
+        let bottom = scale_up(self.y + self.height, image_height, scaled_height);
+        match pixel_format {
+            PixelFormat::Yuv420
+            | PixelFormat::AndroidP010
+            | PixelFormat::AndroidNv12
+            | PixelFormat::AndroidNv21 => {
+                x &= !1;
+                y &= !1;
+            }
+            PixelFormat::Yuv422 => x &= !1,
+            _ => {}
+        }
+        let rect = CropRect {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        };
+        if rect.is_valid(scaled_width, scaled_height, pixel_format) {
+            Ok(rect)
+        } else {
+            AvifError::invalid_argument()
+        }
+    }
 }
 
 #[cfg(test)]
@@ -232,6 +282,41 @@ mod tests {
         }
     }
 
+    #[test_case::test_case(120, 160, 120, 160, PixelFormat::Yuv420, (12, 14, 96, 132), (12, 14, 96, 132))]
+    #[test_case::test_case(120, 160, 60, 80, PixelFormat::Yuv444, (12, 14, 96, 132), (6, 7, 48, 66))]
+    #[test_case::test_case(120, 160, 60, 80, PixelFormat::Yuv420, (12, 14, 96, 132), (6, 6, 48, 67))]
+    #[test_case::test_case(120, 160, 30, 40, PixelFormat::Yuv422, (13, 15, 95, 131), (2, 3, 25, 34))]
+    #[test_case::test_case(100, 100, 33, 33, PixelFormat::Yuv444, (0, 0, 99, 99), (0, 0, 33, 33))]
+    fn scaled_rect(
+        image_width: u32,
+        image_height: u32,
+        scaled_width: u32,
+        scaled_height: u32,
+        pixel_format: PixelFormat,
+        rect: (u32, u32, u32, u32),
+        expected_rect: (u32, u32, u32, u32),
+    ) {
+        let rect = CropRect {
+            x: rect.0,
+            y: rect.1,
+            width: rect.2,
+            height: rect.3,
+        };
+        let scaled = rect
+            .scaled(
+                image_width,
+                image_height,
+                scaled_width,
+                scaled_height,
+                pixel_format,
+            )
+            .unwrap();
+        assert_eq!(
+            (scaled.x, scaled.y, scaled.width, scaled.height),
+            expected_rect
+        );
+    }
+
     #[allow(clippy::zero_prefixed_literal)]
     #[test_case::test_matrix(0usize..20)]
     fn rect_to_clap(index: usize) {
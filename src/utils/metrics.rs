@@ -0,0 +1,883 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::image::*;
+use crate::internal_utils::*;
+use crate::reformat::rgb;
+use crate::*;
+
+// PSNR value returned for identical images.
+pub const MAX_PSNR: f64 = 99.0;
+
+// Distortion metrics comparing a distorted image to a reference image.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Metric {
+    // Peak signal-to-noise ratio in dB, in [0:MAX_PSNR].
+    #[default]
+    Psnr,
+    // Structural similarity index, in [-1:1] (1 for identical images).
+    Ssim,
+    // Multi-scale structural similarity index, in [0:1] (1 for identical images).
+    MsSsim,
+}
+
+// The sample values the metrics are computed on.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MetricSpace {
+    // The samples as stored: Y, U and V for Image, R, G and B for rgb::Image. These are usually
+    // gamma-encoded.
+    #[default]
+    Gamma,
+    // Linear R, G and B values obtained by converting the images to RGB and applying the inverse
+    // of the transfer function signaled in their CICP. rgb::Image has no CICP, so the sRGB
+    // transfer function is assumed.
+    Linear,
+}
+
+// How the alpha channel is taken into account.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum AlphaMode {
+    // The alpha channel is not compared.
+    #[default]
+    Ignore,
+    // The alpha channel is compared as an additional channel. An image without alpha is
+    // considered opaque.
+    Separate,
+    // Same as Separate, but the color channels are multiplied by the alpha values before being
+    // compared, so that differences in transparent areas do not count. Requires R, G and B
+    // channels, so it is not supported in MetricSpace::Gamma for Image.
+    Premultiply,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CompareOptions {
+    pub space: MetricSpace,
+    pub alpha: AlphaMode,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChannelType {
+    Y,
+    U,
+    V,
+    R,
+    G,
+    B,
+    A,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Metrics {
+    pub psnr: f64,
+    pub ssim: f64,
+    pub ms_ssim: f64,
+    // Largest absolute difference between two samples, in units of the samples of the reference
+    // image (values in [0:1] in MetricSpace::Linear).
+    pub max_abs_error: f64,
+    // Average of the differences (distorted minus reference) between two samples, in the same
+    // units as max_abs_error. Non-zero values reveal a bias such as a brightness shift.
+    pub mean_error: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelMetrics {
+    pub channel: ChannelType,
+    pub metrics: Metrics,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Comparison {
+    pub channels: Vec<ChannelMetrics>,
+    // PSNR over the samples of all channels. The other metrics are averaged over all channels,
+    // weighted by their sample count, except max_abs_error which is the largest of all channels.
+    pub overall: Metrics,
+}
+
+// One channel of an image, with samples normalized to [0:1].
+struct Channel {
+    channel_type: ChannelType,
+    width: usize,
+    height: usize,
+    // Value of a sample of 1.0 in the units reported by Metrics.
+    max_value: f64,
+    samples: Vec<f32>,
+}
+
+impl Channel {
+    fn create(
+        channel_type: ChannelType,
+        width: usize,
+        height: usize,
+        max_value: f64,
+    ) -> AvifResult<Self> {
+        let mut samples = create_vec_exact(checked_mul!(width, height)?)?;
+        samples.resize(width * height, 0.0);
+        Ok(Self {
+            channel_type,
+            width,
+            height,
+            max_value,
+            samples,
+        })
+    }
+
+    fn opaque(&self) -> AvifResult<Self> {
+        let mut alpha = Self::create(ChannelType::A, self.width, self.height, self.max_value)?;
+        alpha.samples.fill(1.0);
+        Ok(alpha)
+    }
+
+    fn alpha(channels: &[Channel]) -> Option<&Channel> {
+        channels
+            .iter()
+            .find(|channel| channel.channel_type == ChannelType::A)
+    }
+}
+
+fn yuv_channels(image: &Image, alpha: AlphaMode) -> AvifResult<Vec<Channel>> {
+    let planes: &[Plane] = match alpha {
+        AlphaMode::Ignore => &YUV_PLANES,
+        AlphaMode::Separate => &ALL_PLANES,
+        AlphaMode::Premultiply => return AvifError::not_implemented(),
+    };
+    let max_channel = image.max_channel() as f32;
+    let mut channels = Vec::new();
+    for plane in planes {
+        let plane_data = match image.plane_data(*plane) {
+            Some(plane_data) => plane_data,
+            None => continue,
+        };
+        let channel_type = match plane {
+            Plane::Y => ChannelType::Y,
+            Plane::U => ChannelType::U,
+            Plane::V => ChannelType::V,
+            Plane::A => ChannelType::A,
+        };
+        let width = usize_from_u32(plane_data.width)?;
+        let height = usize_from_u32(plane_data.height)?;
+        let mut channel = Channel::create(channel_type, width, height, max_channel as f64)?;
+        for y in 0..plane_data.height {
+            let samples = &mut channel.samples[usize_from_u32(y)? * width..][..width];
+            if image.depth == 8 {
+                for (sample, value) in samples.iter_mut().zip(image.row(*plane, y)?) {
+                    *sample = *value as f32 / max_channel;
+                }
+            } else {
+                for (sample, value) in samples.iter_mut().zip(image.row16(*plane, y)?) {
+                    *sample = *value as f32 / max_channel;
+                }
+            }
+        }
+        channels.push(channel);
+    }
+    Ok(channels)
+}
+
+// Inverse of the Rec. ITU-R BT.2100 HLG OETF, in [0:1].
+fn hlg_to_linear(value: f32) -> f32 {
+    const A: f32 = 0.17883277;
+    const B: f32 = 0.28466892;
+    const C: f32 = 0.5599107;
+    if value <= 0.5 {
+        value * value / 3.0
+    } else {
+        (((value - C) / A).exp() + B) / 12.0
+    }
+}
+
+// SMPTE ST 2084 EOTF, normalized so that 10000 cd/m2 maps to 1.
+fn pq_to_linear(value: f32) -> f32 {
+    const M1: f32 = 2610.0 / 16384.0;
+    const M2: f32 = 2523.0 / 4096.0 * 128.0;
+    const C1: f32 = 3424.0 / 4096.0;
+    const C2: f32 = 2413.0 / 4096.0 * 32.0;
+    const C3: f32 = 2392.0 / 4096.0 * 32.0;
+    let power = value.powf(1.0 / M2);
+    ((power - C1).max(0.0) / (C2 - C3 * power)).powf(1.0 / M1)
+}
+
+fn linearizer(transfer_characteristics: TransferCharacteristics) -> AvifResult<fn(f32) -> f32> {
+    Ok(match transfer_characteristics {
+        // Unspecified is assumed to be sRGB, like most image viewers do.
+        TransferCharacteristics::Srgb | TransferCharacteristics::Unspecified => |value| {
+            if value <= 0.04045 {
+                value / 12.92
+            } else {
+                ((value + 0.055) / 1.055).powf(2.4)
+            }
+        },
+        TransferCharacteristics::Bt709
+        | TransferCharacteristics::Bt601
+        | TransferCharacteristics::Bt2020_10bit
+        | TransferCharacteristics::Bt2020_12bit => |value| {
+            if value < 0.081 {
+                value / 4.5
+            } else {
+                ((value + 0.099) / 1.099).powf(1.0 / 0.45)
+            }
+        },
+        TransferCharacteristics::Bt470m => |value| value.powf(2.2),
+        TransferCharacteristics::Bt470bg => |value| value.powf(2.8),
+        TransferCharacteristics::Linear => |value| value,
+        TransferCharacteristics::Pq => pq_to_linear,
+        TransferCharacteristics::Hlg => hlg_to_linear,
+        _ => return AvifError::not_implemented(),
+    })
+}
+
+fn rgb_channels(
+    rgb: &rgb::Image,
+    to_linear: Option<fn(f32) -> f32>,
+    alpha: AlphaMode,
+) -> AvifResult<Vec<Channel>> {
+    if rgb.is_float
+        || !matches!(
+            rgb.format,
+            rgb::Format::Rgb
+                | rgb::Format::Rgba
+                | rgb::Format::Argb
+                | rgb::Format::Bgr
+                | rgb::Format::Bgra
+                | rgb::Format::Abgr
+        )
+    {
+        return AvifError::not_implemented();
+    }
+    let max_channel = rgb.max_channel_f();
+    let max_value = if to_linear.is_some() { 1.0 } else { max_channel as f64 };
+    let width = usize_from_u32(rgb.width)?;
+    let height = usize_from_u32(rgb.height)?;
+    let has_alpha = rgb.has_alpha() && alpha != AlphaMode::Ignore;
+    let mut channel_types = vec![ChannelType::R, ChannelType::G, ChannelType::B];
+    if has_alpha {
+        channel_types.push(ChannelType::A);
+    }
+    let mut channels = Vec::new();
+    for channel_type in channel_types {
+        channels.push(Channel::create(channel_type, width, height, max_value)?);
+    }
+    let offsets = rgb.format.offsets();
+    let channel_count = rgb.channel_count() as usize;
+    let mut pixel = [0.0f32; 4];
+    for y in 0..rgb.height {
+        let row16;
+        let row8;
+        let row_value: &dyn Fn(usize) -> f32 = if rgb.depth == 8 {
+            row8 = rgb.row(y)?;
+            &|i| row8[i] as f32
+        } else {
+            row16 = rgb.row16(y)?;
+            &|i| row16[i] as f32
+        };
+        let offset = usize_from_u32(y)? * width;
+        for x in 0..width {
+            for (c, value) in pixel.iter_mut().enumerate() {
+                *value = if c == 3 && !rgb.has_alpha() {
+                    1.0
+                } else {
+                    row_value(x * channel_count + offsets[c]) / max_channel
+                };
+            }
+            let a = pixel[3];
+            if to_linear.is_some() || alpha == AlphaMode::Premultiply {
+                if rgb.premultiply_alpha && a > 0.0 {
+                    pixel[..3]
+                        .iter_mut()
+                        .for_each(|value| *value = (*value / a).min(1.0));
+                }
+                if let Some(to_linear) = to_linear {
+                    pixel[..3]
+                        .iter_mut()
+                        .for_each(|value| *value = to_linear(*value));
+                }
+                if alpha == AlphaMode::Premultiply {
+                    pixel[..3].iter_mut().for_each(|value| *value *= a);
+                }
+            }
+            for (c, channel) in channels.iter_mut().enumerate() {
+                channel.samples[offset + x] = pixel[c];
+            }
+        }
+    }
+    Ok(channels)
+}
+
+fn linear_rgb_channels(image: &Image, alpha: AlphaMode) -> AvifResult<Vec<Channel>> {
+    let to_linear = linearizer(image.transfer_characteristics)?;
+    let mut rgb = rgb::Image::create_from_yuv(image);
+    rgb.depth = 16;
+    rgb.format = if image.has_alpha() && alpha != AlphaMode::Ignore {
+        rgb::Format::Rgba
+    } else {
+        rgb::Format::Rgb
+    };
+    rgb.allocate()?;
+    rgb.convert_from_yuv(image)?;
+    rgb_channels(&rgb, Some(to_linear), alpha)
+}
+
+// Returns the metrics of each pair of channels.
+fn compare_channels(
+    mut channels1: Vec<Channel>,
+    mut channels2: Vec<Channel>,
+    metrics: &[Metric],
+) -> AvifResult<Comparison> {
+    // An image without alpha is considered opaque.
+    if let (Some(alpha), None) = (Channel::alpha(&channels1), Channel::alpha(&channels2)) {
+        channels2.push(alpha.opaque()?);
+    }
+    if let (None, Some(alpha)) = (Channel::alpha(&channels1), Channel::alpha(&channels2)) {
+        channels1.push(alpha.opaque()?);
+    }
+    if channels1.is_empty()
+        || channels1.len() != channels2.len()
+        || channels1.iter().zip(&channels2).any(|(c1, c2)| {
+            c1.channel_type != c2.channel_type || c1.width != c2.width || c1.height != c2.height
+        })
+    {
+        return AvifError::invalid_argument();
+    }
+    let mut comparison = Comparison::default();
+    let mut squared_error_sum = 0.0;
+    let mut sample_count = 0;
+    for (channel1, channel2) in channels1.iter().zip(&channels2) {
+        let mut channel_squared_error_sum = 0.0;
+        let mut error_sum = 0.0;
+        let mut max_abs_error = 0.0f64;
+        for (sample1, sample2) in channel1.samples.iter().zip(&channel2.samples) {
+            let diff = (*sample2 - *sample1) as f64;
+            channel_squared_error_sum += diff * diff;
+            error_sum += diff;
+            max_abs_error = max_abs_error.max(diff.abs());
+        }
+        let count = channel1.samples.len();
+        let mut channel_metrics = Metrics {
+            psnr: psnr_from_mse(channel_squared_error_sum / count as f64),
+            max_abs_error: max_abs_error * channel1.max_value,
+            mean_error: error_sum / count as f64 * channel1.max_value,
+            ..Default::default()
+        };
+        if metrics.contains(&Metric::Ssim) {
+            channel_metrics.ssim = channel_ssim(channel1, channel2)?;
+        }
+        if metrics.contains(&Metric::MsSsim) {
+            channel_metrics.ms_ssim = channel_ms_ssim(channel1, channel2)?;
+        }
+        comparison.channels.push(ChannelMetrics {
+            channel: channel1.channel_type,
+            metrics: channel_metrics,
+        });
+
+        squared_error_sum += channel_squared_error_sum;
+        sample_count += count;
+        let overall = &mut comparison.overall;
+        overall.ssim += channel_metrics.ssim * count as f64;
+        overall.ms_ssim += channel_metrics.ms_ssim * count as f64;
+        overall.max_abs_error = overall.max_abs_error.max(channel_metrics.max_abs_error);
+        overall.mean_error += channel_metrics.mean_error * count as f64;
+    }
+    let overall = &mut comparison.overall;
+    overall.psnr = psnr_from_mse(squared_error_sum / sample_count as f64);
+    overall.ssim /= sample_count as f64;
+    overall.ms_ssim /= sample_count as f64;
+    overall.mean_error /= sample_count as f64;
+    Ok(comparison)
+}
+
+fn compare_impl(
+    image1: &Image,
+    image2: &Image,
+    options: &CompareOptions,
+    metrics: &[Metric],
+) -> AvifResult<Comparison> {
+    if image1.width != image2.width || image1.height != image2.height {
+        return AvifError::invalid_argument();
+    }
+    let channels = |image| match options.space {
+        MetricSpace::Gamma => yuv_channels(image, options.alpha),
+        MetricSpace::Linear => linear_rgb_channels(image, options.alpha),
+    };
+    compare_channels(channels(image1)?, channels(image2)?, metrics)
+}
+
+// Compares the distorted |image2| to the reference |image1|. The images must have the same
+// dimensions and, in MetricSpace::Gamma, the same pixel format. They may have different depths.
+pub fn compare(image1: &Image, image2: &Image, options: &CompareOptions) -> AvifResult<Comparison> {
+    compare_impl(
+        image1,
+        image2,
+        options,
+        &[Metric::Psnr, Metric::Ssim, Metric::MsSsim],
+    )
+}
+
+// Same as compare() for RGB images. Only the integer formats with R, G and B channels (and
+// optionally alpha) are supported.
+pub fn compare_rgb(
+    image1: &rgb::Image,
+    image2: &rgb::Image,
+    options: &CompareOptions,
+) -> AvifResult<Comparison> {
+    if image1.width != image2.width || image1.height != image2.height {
+        return AvifError::invalid_argument();
+    }
+    let to_linear = match options.space {
+        MetricSpace::Gamma => None,
+        MetricSpace::Linear => Some(linearizer(TransferCharacteristics::Srgb)?),
+    };
+    compare_channels(
+        rgb_channels(image1, to_linear, options.alpha)?,
+        rgb_channels(image2, to_linear, options.alpha)?,
+        &[Metric::Psnr, Metric::Ssim, Metric::MsSsim],
+    )
+}
+
+fn psnr_from_mse(mse: f64) -> f64 {
+    if mse <= 0.0 {
+        MAX_PSNR
+    } else {
+        (-10.0 * mse.log10()).min(MAX_PSNR)
+    }
+}
+
+// Returns the PSNR of |image2| compared to |image1|, computed over all the samples of all the
+// color channels in the given |space|. See compare() for the requirements on the images.
+pub fn psnr(image1: &Image, image2: &Image, space: MetricSpace) -> AvifResult<f64> {
+    let options = CompareOptions {
+        space,
+        ..Default::default()
+    };
+    Ok(compare_impl(image1, image2, &options, &[Metric::Psnr])?
+        .overall
+        .psnr)
+}
+
+// Standard deviation of the Gaussian weighting window used by SSIM.
+const SSIM_SIGMA: f64 = 1.5;
+const SSIM_RADIUS: usize = 5;
+
+fn gaussian_kernel() -> [f64; 2 * SSIM_RADIUS + 1] {
+    let mut kernel = [0.0; 2 * SSIM_RADIUS + 1];
+    for (i, weight) in kernel.iter_mut().enumerate() {
+        let d = i as f64 - SSIM_RADIUS as f64;
+        *weight = (-d * d / (2.0 * SSIM_SIGMA * SSIM_SIGMA)).exp();
+    }
+    let sum: f64 = kernel.iter().sum();
+    kernel.iter_mut().for_each(|weight| *weight /= sum);
+    kernel
+}
+
+// Returns the Gaussian-weighted local average of |value| around each sample. Samples outside the
+// channel are replaced by the closest edge sample.
+fn blur(
+    width: usize,
+    height: usize,
+    kernel: &[f64],
+    value: impl Fn(usize, usize) -> f64,
+) -> AvifResult<Vec<f64>> {
+    let clamp = |v: isize, max: usize| v.clamp(0, max as isize - 1) as usize;
+    let sample_count = checked_mul!(width, height)?;
+    let mut horizontal = create_vec_exact(sample_count)?;
+    horizontal.resize(sample_count, 0.0);
+    for y in 0..height {
+        for x in 0..width {
+            horizontal[y * width + x] = kernel
+                .iter()
+                .enumerate()
+                .map(|(i, w)| {
+                    w * value(
+                        clamp(x as isize + i as isize - SSIM_RADIUS as isize, width),
+                        y,
+                    )
+                })
+                .sum();
+        }
+    }
+    let mut blurred = create_vec_exact(sample_count)?;
+    blurred.resize(sample_count, 0.0);
+    for y in 0..height {
+        for x in 0..width {
+            blurred[y * width + x] = kernel
+                .iter()
+                .enumerate()
+                .map(|(i, w)| {
+                    let y = clamp(y as isize + i as isize - SSIM_RADIUS as isize, height);
+                    w * horizontal[y * width + x]
+                })
+                .sum();
+        }
+    }
+    Ok(blurred)
+}
+
+// Returns the mean SSIM and the mean contrast-structure term of SSIM of two sets of samples of
+// the same |width| and |height|.
+fn ssim_terms(
+    width: usize,
+    height: usize,
+    samples1: &[f64],
+    samples2: &[f64],
+) -> AvifResult<(f64, f64)> {
+    const C1: f64 = 0.01 * 0.01;
+    const C2: f64 = 0.03 * 0.03;
+    let kernel = gaussian_kernel();
+    let s1 = |x, y| samples1[y * width + x];
+    let s2 = |x, y| samples2[y * width + x];
+    let mu1 = blur(width, height, &kernel, s1)?;
+    let mu2 = blur(width, height, &kernel, s2)?;
+    let sq1 = blur(width, height, &kernel, |x, y| s1(x, y) * s1(x, y))?;
+    let sq2 = blur(width, height, &kernel, |x, y| s2(x, y) * s2(x, y))?;
+    let cross = blur(width, height, &kernel, |x, y| s1(x, y) * s2(x, y))?;
+    let mut ssim_sum = 0.0;
+    let mut cs_sum = 0.0;
+    for i in 0..width * height {
+        let sigma1_sq = sq1[i] - mu1[i] * mu1[i];
+        let sigma2_sq = sq2[i] - mu2[i] * mu2[i];
+        let sigma12 = cross[i] - mu1[i] * mu2[i];
+        let cs = (2.0 * sigma12 + C2) / (sigma1_sq + sigma2_sq + C2);
+        cs_sum += cs;
+        ssim_sum += cs * (2.0 * mu1[i] * mu2[i] + C1) / (mu1[i] * mu1[i] + mu2[i] * mu2[i] + C1);
+    }
+    let count = (width * height) as f64;
+    Ok((ssim_sum / count, cs_sum / count))
+}
+
+fn channel_samples(channel: &Channel) -> AvifResult<Vec<f64>> {
+    let mut samples = create_vec_exact(channel.samples.len())?;
+    samples.extend(channel.samples.iter().map(|sample| *sample as f64));
+    Ok(samples)
+}
+
+// Returns the mean SSIM of two channels of the same dimensions.
+fn channel_ssim(channel1: &Channel, channel2: &Channel) -> AvifResult<f64> {
+    Ok(ssim_terms(
+        channel1.width,
+        channel1.height,
+        &channel_samples(channel1)?,
+        &channel_samples(channel2)?,
+    )?
+    .0)
+}
+
+// Returns a copy of |samples| downscaled by two in each dimension by averaging 2x2 blocks.
+fn downscale(width: usize, height: usize, samples: &[f64]) -> AvifResult<Vec<f64>> {
+    let (half_width, half_height) = (width / 2, height / 2);
+    let mut downscaled = create_vec_exact(checked_mul!(half_width, half_height)?)?;
+    for y in 0..half_height {
+        for x in 0..half_width {
+            let top = 2 * y * width + 2 * x;
+            let bottom = top + width;
+            downscaled.push(
+                (samples[top] + samples[top + 1] + samples[bottom] + samples[bottom + 1]) / 4.0,
+            );
+        }
+    }
+    Ok(downscaled)
+}
+
+// Returns the MS-SSIM of two channels of the same dimensions, as defined in "Multiscale
+// structural similarity for image quality assessment" by Wang, Simoncelli and Bovik. Scales whose
+// dimensions are smaller than the SSIM window are skipped and the weights of the remaining scales
+// are renormalized.
+fn channel_ms_ssim(channel1: &Channel, channel2: &Channel) -> AvifResult<f64> {
+    const WEIGHTS: [f64; 5] = [0.0448, 0.2856, 0.3001, 0.2363, 0.1333];
+    const MIN_DIMENSION: usize = 2 * SSIM_RADIUS + 1;
+    let (mut width, mut height) = (channel1.width, channel1.height);
+    let mut scale_count = 1;
+    while scale_count < WEIGHTS.len()
+        && (width >> scale_count).min(height >> scale_count) >= MIN_DIMENSION
+    {
+        scale_count += 1;
+    }
+    let weight_sum: f64 = WEIGHTS[..scale_count].iter().sum();
+    let mut samples1 = channel_samples(channel1)?;
+    let mut samples2 = channel_samples(channel2)?;
+    let mut ms_ssim = 1.0;
+    for (scale, weight) in WEIGHTS[..scale_count].iter().enumerate() {
+        let (ssim, cs) = ssim_terms(width, height, &samples1, &samples2)?;
+        let term = if scale + 1 == scale_count { ssim } else { cs };
+        ms_ssim *= term.max(0.0).powf(weight / weight_sum);
+        if scale + 1 < scale_count {
+            samples1 = downscale(width, height, &samples1)?;
+            samples2 = downscale(width, height, &samples2)?;
+            (width, height) = (width / 2, height / 2);
+        }
+    }
+    Ok(ms_ssim)
+}
+
+// Returns the SSIM of |image2| compared to |image1|. The SSIM of each color channel in the given
+// |space| is computed with an 11x11 Gaussian window (sigma 1.5) and the results are averaged,
+// weighted by the number of samples in each channel. See compare() for the requirements on the
+// images.
+pub fn ssim(image1: &Image, image2: &Image, space: MetricSpace) -> AvifResult<f64> {
+    let options = CompareOptions {
+        space,
+        ..Default::default()
+    };
+    Ok(compare_impl(image1, image2, &options, &[Metric::Ssim])?
+        .overall
+        .ssim)
+}
+
+// Same as ssim() for MS-SSIM.
+pub fn ms_ssim(image1: &Image, image2: &Image, space: MetricSpace) -> AvifResult<f64> {
+    let options = CompareOptions {
+        space,
+        ..Default::default()
+    };
+    Ok(compare_impl(image1, image2, &options, &[Metric::MsSsim])?
+        .overall
+        .ms_ssim)
+}
+
+impl Metric {
+    // Returns the value of this metric for |image2| compared to |image1|. Higher is better.
+    pub fn compute(&self, image1: &Image, image2: &Image, space: MetricSpace) -> AvifResult<f64> {
+        match self {
+            Self::Psnr => psnr(image1, image2, space),
+            Self::Ssim => ssim(image1, image2, space),
+            Self::MsSsim => ms_ssim(image1, image2, space),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_case::test_matrix;
+
+    fn create_image(depth: u8, yuv_format: PixelFormat, offset: u16) -> AvifResult<Image> {
+        let mut image = Image {
+            width: 48,
+            height: 44,
+            depth,
+            yuv_format,
+            yuv_range: YuvRange::Full,
+            ..Default::default()
+        };
+        image.allocate_planes(Category::Color)?;
+        let max_channel = image.max_channel();
+        image.fill_planes_with(|_, x, y| {
+            // A gradient with some texture.
+            ((x as u32 * 7 + y * 5 + (x as u32 ^ y) % 3) % 64) as u16 * (max_channel / 64) + offset
+        });
+        Ok(image)
+    }
+
+    #[test_matrix(
+        [8, 10, 12],
+        [PixelFormat::Yuv444, PixelFormat::Yuv420, PixelFormat::Yuv400],
+        [MetricSpace::Gamma, MetricSpace::Linear]
+    )]
+    fn identical(depth: u8, yuv_format: PixelFormat, space: MetricSpace) -> AvifResult<()> {
+        let image = create_image(depth, yuv_format, 0)?;
+        assert_eq!(psnr(&image, &image, space)?, MAX_PSNR);
+        assert!((ssim(&image, &image, space)? - 1.0).abs() < 1e-9);
+        assert!((ms_ssim(&image, &image, space)? - 1.0).abs() < 1e-9);
+        Ok(())
+    }
+
+    #[test_matrix(
+        [8, 10, 12],
+        [MetricSpace::Gamma, MetricSpace::Linear]
+    )]
+    fn distortion_lowers_metrics(depth: u8, space: MetricSpace) -> AvifResult<()> {
+        let image = create_image(depth, PixelFormat::Yuv444, 0)?;
+        let step = 1 << (depth - 8);
+        let slightly_different = create_image(depth, PixelFormat::Yuv444, step)?;
+        let very_different = create_image(depth, PixelFormat::Yuv444, 16 * step)?;
+        for metric in [Metric::Psnr, Metric::Ssim, Metric::MsSsim] {
+            let slight = metric.compute(&image, &slightly_different, space)?;
+            let strong = metric.compute(&image, &very_different, space)?;
+            assert!(slight < metric.compute(&image, &image, space)?);
+            assert!(strong < slight, "{metric:?}: {strong} >= {slight}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn psnr_value() -> AvifResult<()> {
+        // An offset of 1 on every 8-bit sample gives an MSE of 1/255^2.
+        let image1 = create_image(8, PixelFormat::Yuv420, 0)?;
+        let image2 = create_image(8, PixelFormat::Yuv420, 1)?;
+        let expected = 20.0 * 255f64.log10();
+        assert!((psnr(&image1, &image2, MetricSpace::Gamma)? - expected).abs() < 1e-3);
+        Ok(())
+    }
+
+    #[test_matrix([8, 10, 12])]
+    fn errors(depth: u8) -> AvifResult<()> {
+        let image1 = create_image(depth, PixelFormat::Yuv420, 0)?;
+        let mut image2 = create_image(depth, PixelFormat::Yuv420, 2)?;
+        // Decrease one sample of the V plane instead.
+        if depth == 8 {
+            image2.row_mut(Plane::V, 3)?[5] -= 7;
+        } else {
+            image2.row16_mut(Plane::V, 3)?[5] -= 7;
+        }
+        let comparison = compare(&image1, &image2, &CompareOptions::default())?;
+        let channel_types: Vec<_> = comparison.channels.iter().map(|c| c.channel).collect();
+        assert_eq!(
+            channel_types,
+            [ChannelType::Y, ChannelType::U, ChannelType::V]
+        );
+        let y = comparison.channels[0].metrics;
+        assert!((y.max_abs_error - 2.0).abs() < 1e-3);
+        assert!((y.mean_error - 2.0).abs() < 1e-3);
+        let v = comparison.channels[2].metrics;
+        assert!((v.max_abs_error - 5.0).abs() < 1e-3);
+        assert!((v.mean_error - (2.0 - 7.0 / (24.0 * 22.0))).abs() < 1e-3);
+        assert!((comparison.overall.max_abs_error - 5.0).abs() < 1e-3);
+        assert!(comparison.overall.mean_error < 2.0);
+        assert!(comparison.overall.psnr < MAX_PSNR);
+        assert!(comparison.overall.ssim < 1.0 && comparison.overall.ms_ssim < 1.0);
+        Ok(())
+    }
+
+    fn set_alpha(image: &mut Image, alpha: impl Fn(u32, usize) -> u16) -> AvifResult<()> {
+        image.allocate_planes(Category::Alpha)?;
+        image.fill_plane_with(Plane::A, |x, y| alpha(y, x));
+        Ok(())
+    }
+
+    #[test_matrix([8, 10])]
+    fn alpha(depth: u8) -> AvifResult<()> {
+        let image1 = create_image(depth, PixelFormat::Yuv444, 0)?;
+        let mut image2 = create_image(depth, PixelFormat::Yuv444, 0)?;
+        let max_channel = image2.max_channel();
+        set_alpha(&mut image2, |_, _| max_channel)?;
+        // An opaque alpha plane is the same as no alpha plane.
+        for alpha in [AlphaMode::Ignore, AlphaMode::Separate] {
+            let options = CompareOptions {
+                alpha,
+                ..Default::default()
+            };
+            let comparison = compare(&image1, &image2, &options)?;
+            assert_eq!(
+                comparison.channels.len(),
+                if alpha == AlphaMode::Ignore { 3 } else { 4 }
+            );
+            assert_eq!(comparison.overall.psnr, MAX_PSNR);
+        }
+        let gamma_premultiply = CompareOptions {
+            alpha: AlphaMode::Premultiply,
+            ..Default::default()
+        };
+        assert!(compare(&image1, &image2, &gamma_premultiply).is_err());
+
+        // Differences in transparent areas only count when premultiplying.
+        let mut image1 = create_image(depth, PixelFormat::Yuv444, 0)?;
+        set_alpha(&mut image1, |_, x| if x < 24 { 0 } else { max_channel })?;
+        set_alpha(&mut image2, |_, x| if x < 24 { 0 } else { max_channel })?;
+        for y in 0..image2.height {
+            if depth == 8 {
+                image2.row_mut(Plane::Y, y)?[..24].fill(0);
+            } else {
+                image2.row16_mut(Plane::Y, y)?[..24].fill(0);
+            }
+        }
+        for (alpha, identical) in [(AlphaMode::Separate, false), (AlphaMode::Premultiply, true)] {
+            let options = CompareOptions {
+                space: MetricSpace::Linear,
+                alpha,
+            };
+            let comparison = compare(&image1, &image2, &options)?;
+            assert_eq!(comparison.channels[3].channel, ChannelType::A);
+            assert_eq!(comparison.channels[3].metrics.psnr, MAX_PSNR);
+            assert_eq!(comparison.overall.psnr == MAX_PSNR, identical);
+        }
+        Ok(())
+    }
+
+    #[test_matrix(
+        [8, 10, 12, 16],
+        [rgb::Format::Rgb, rgb::Format::Rgba, rgb::Format::Bgra],
+        [MetricSpace::Gamma, MetricSpace::Linear]
+    )]
+    fn rgb_images(depth: u8, format: rgb::Format, space: MetricSpace) -> AvifResult<()> {
+        let create = |offset: u16| -> AvifResult<rgb::Image> {
+            let mut rgb = rgb::Image {
+                width: 32,
+                height: 30,
+                depth,
+                format,
+                ..Default::default()
+            };
+            rgb.allocate()?;
+            let max_channel = rgb.max_channel();
+            let values_per_row = (rgb.width * rgb.channel_count()) as usize;
+            for y in 0..rgb.height {
+                for i in 0..values_per_row {
+                    let value = (i as u16 * 3 + y as u16 * 5) % (max_channel / 2) + offset;
+                    if depth == 8 {
+                        rgb.row_mut(y)?[i] = value as u8;
+                    } else {
+                        rgb.row16_mut(y)?[i] = value;
+                    }
+                }
+            }
+            Ok(rgb)
+        };
+        let step = 1 << (depth - 8);
+        let image1 = create(0)?;
+        let image2 = create(step)?;
+        let options = CompareOptions {
+            space,
+            alpha: AlphaMode::Separate,
+        };
+        let comparison = compare_rgb(&image1, &image1, &options)?;
+        assert_eq!(comparison.overall.psnr, MAX_PSNR);
+        assert_eq!(
+            comparison.channels.len(),
+            if format == rgb::Format::Rgb { 3 } else { 4 }
+        );
+        let comparison = compare_rgb(&image1, &image2, &options)?;
+        assert!(comparison.overall.psnr < MAX_PSNR);
+        assert!(comparison.overall.mean_error > 0.0);
+        if space == MetricSpace::Gamma {
+            assert!((comparison.overall.max_abs_error - step as f64).abs() < 1e-3);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn incompatible_images() -> AvifResult<()> {
+        let image = create_image(8, PixelFormat::Yuv444, 0)?;
+        let other_format = create_image(8, PixelFormat::Yuv420, 0)?;
+        assert!(psnr(&image, &other_format, MetricSpace::Gamma).is_err());
+        let mut other_size = create_image(8, PixelFormat::Yuv444, 0)?;
+        other_size.width -= 2;
+        assert!(ssim(&image, &other_size, MetricSpace::Gamma).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn transfer_functions() {
+        for transfer_characteristics in [
+            TransferCharacteristics::Srgb,
+            TransferCharacteristics::Bt709,
+            TransferCharacteristics::Bt470m,
+            TransferCharacteristics::Linear,
+            TransferCharacteristics::Pq,
+            TransferCharacteristics::Hlg,
+        ] {
+            let to_linear = linearizer(transfer_characteristics).unwrap();
+            assert!(to_linear(0.0).abs() < 1e-6);
+            assert!((to_linear(1.0) - 1.0).abs() < 1e-3);
+            assert!(to_linear(0.25) < to_linear(0.5));
+        }
+        assert!(linearizer(TransferCharacteristics::Log100).is_err());
+    }
+}
@@ -17,6 +17,7 @@ use crate::*;
 
 pub mod clap;
 pub mod error;
+pub mod metrics;
 pub mod pixels;
 pub mod reader;
 pub mod writer;
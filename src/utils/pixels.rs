@@ -52,6 +52,31 @@ impl Pixels {
         }
     }
 
+    // Same as from_raw_pointer() but only requires the last row to contain |width| samples instead
+    // of |row_bytes| bytes. Used for views into a rectangular region of another buffer.
+    pub(crate) fn from_raw_pointer_view(
+        ptr: *mut u8,
+        depth: u32,
+        width: u32,
+        height: u32,
+        mut row_bytes: u32,
+    ) -> AvifResult<Self> {
+        if depth > 8 {
+            row_bytes /= 2;
+        }
+        let size = usize_from_u32(checked_add!(
+            checked_mul!(checked_sub!(height, 1)?, row_bytes)?,
+            width
+        )?)?;
+        if depth > 8 {
+            Ok(Pixels::Pointer16(unsafe {
+                PointerSlice::create(ptr as *mut u16, size)?
+            }))
+        } else {
+            Ok(Pixels::Pointer(unsafe { PointerSlice::create(ptr, size)? }))
+        }
+    }
+
     pub fn size(&self) -> usize {
         match self {
             Pixels::Pointer(_) => 0,
@@ -25,6 +25,7 @@ use std::fs::File;
 #[derive(Default)]
 pub struct JpegWriter {
     pub quality: Option<u8>,
+    pub dithering: rgb::Dithering,
 }
 
 impl Writer for JpegWriter {
@@ -32,6 +33,7 @@ impl Writer for JpegWriter {
         let mut rgb = rgb::Image::create_from_yuv(image);
         rgb.depth = 8;
         rgb.format = rgb::Format::Rgb;
+        rgb.dithering = self.dithering;
         rgb.allocate()?;
         rgb.convert_from_yuv(image)?;
 
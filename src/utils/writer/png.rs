@@ -29,6 +29,7 @@ use libpng_sys::bindings::*;
 pub struct PngWriter {
     pub depth: Option<u8>,
     pub compression_level: Option<i32>,
+    pub dithering: rgb::Dithering,
 }
 
 struct PngWriterNative {
@@ -118,6 +119,7 @@ impl Writer for PngWriter {
             color_type = PNG_COLOR_TYPE_GRAY;
         } else {
             rgb.depth = rgb_depth;
+            rgb.dithering = self.dithering;
             match (image.yuv_format, image.alpha_present) {
                 (PixelFormat::Yuv400, true) => {
                     color_type = PNG_COLOR_TYPE_GRAY_ALPHA;
@@ -1651,3 +1651,104 @@ fn b_497926602() -> AvifResult<()> {
     assert!(!matches!(res, Err(AvifError::WaitingOnIo)));
     Ok(())
 }
+
+#[test_case("sofa_grid1x5_420.avif", PixelFormat::AndroidNv12)]
+#[test_case("sofa_grid1x5_420.avif", PixelFormat::AndroidNv21)]
+fn semi_planar_output(filename: &str, yuv_format: PixelFormat) {
+    let mut planar_decoder = get_decoder(filename);
+    assert!(planar_decoder.parse().is_ok());
+    assert_eq!(
+        planar_decoder.image().unwrap().yuv_format,
+        PixelFormat::Yuv420
+    );
+    if !HAS_DECODER {
+        return;
+    }
+    assert!(planar_decoder.next_image().is_ok());
+    let planar = planar_decoder.image().unwrap();
+    assert_eq!(planar.depth, 8);
+
+    let mut decoder = get_decoder(filename);
+    decoder.settings.semi_planar_output_format = Some(yuv_format);
+    assert!(decoder.parse().is_ok());
+    assert!(decoder.next_image().is_ok());
+    let image = decoder.image().unwrap();
+    assert_eq!(image.yuv_format, yuv_format);
+    assert_eq!((image.width, image.height), (planar.width, planar.height));
+    assert!(!image.has_plane(Plane::V));
+    for y in 0..planar.height {
+        assert_eq!(
+            image.row(Plane::Y, y).unwrap()[..planar.width as usize],
+            planar.row(Plane::Y, y).unwrap()[..planar.width as usize]
+        );
+    }
+    // Nv12 interleaves the chroma samples as UVUV... and Nv21 as VUVU...
+    let (first, second) = if yuv_format == PixelFormat::AndroidNv12 {
+        (Plane::U, Plane::V)
+    } else {
+        (Plane::V, Plane::U)
+    };
+    for y in 0..planar.height.div_ceil(2) {
+        let chroma = image.row(Plane::U, y).unwrap();
+        for x in 0..planar.width.div_ceil(2) as usize {
+            assert_eq!(chroma[2 * x], planar.row(first, y).unwrap()[x]);
+            assert_eq!(chroma[2 * x + 1], planar.row(second, y).unwrap()[x]);
+        }
+    }
+}
+
+#[test]
+fn semi_planar_output_invalid_format() {
+    let mut decoder = get_decoder("paris_icc_exif_xmp.avif");
+    decoder.settings.semi_planar_output_format = Some(PixelFormat::Yuv444);
+    assert_eq!(decoder.parse(), Err(AvifError::InvalidArgument));
+}
+
+#[test]
+fn apply_transformations() {
+    let mut decoder = get_decoder("gainmap_oriented.avif");
+    decoder.settings.image_content_to_decode = ImageContentType::All;
+    assert!(decoder.parse().is_ok());
+    assert!(decoder.gainmap_present());
+    let (width, height) = (
+        decoder.image().unwrap().width,
+        decoder.image().unwrap().height,
+    );
+    let (gainmap_width, gainmap_height) = (
+        decoder.gainmap().image.width,
+        decoder.gainmap().image.height,
+    );
+    if !HAS_DECODER {
+        return;
+    }
+    assert!(decoder.next_image().is_ok());
+    let expected = decoder.image().unwrap().transformed_image().unwrap();
+    // The gain map shares the transformative properties of the base image.
+    let base = decoder.image().unwrap();
+    let expected_gainmap = decoder
+        .gainmap()
+        .image
+        .transformed_image_with(base.clap.as_ref(), base.irot_angle, base.imir_axis)
+        .unwrap();
+
+    let mut decoder = get_decoder("gainmap_oriented.avif");
+    decoder.settings.image_content_to_decode = ImageContentType::All;
+    decoder.settings.apply_transformations = true;
+    assert!(decoder.parse().is_ok());
+    assert!(decoder.next_image().is_ok());
+    let image = decoder.image().unwrap();
+    // irot_angle is 1, so the dimensions are swapped.
+    assert_eq!((image.width, image.height), (height, width));
+    assert_eq!(image.irot_angle, None);
+    assert_eq!(image.imir_axis, None);
+    assert!(are_images_equal(image, &expected).unwrap());
+    let gainmap = &decoder.gainmap().image;
+    assert_eq!(
+        (gainmap.width, gainmap.height),
+        (gainmap_height, gainmap_width)
+    );
+    assert_eq!(gainmap.clap, None);
+    assert_eq!(gainmap.irot_angle, None);
+    assert_eq!(gainmap.imir_axis, None);
+    assert!(are_images_equal(gainmap, &expected_gainmap).unwrap());
+}
@@ -0,0 +1,567 @@
+// Copyright 2025 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(feature = "encoder")]
+
+use crabby_avif::decoder::CompressionFormat;
+use crabby_avif::decoder::ImageContentType;
+use crabby_avif::encoder::Sample;
+use crabby_avif::gainmap::*;
+use crabby_avif::image::*;
+use crabby_avif::plugin::*;
+use crabby_avif::utils::clap::*;
+use crabby_avif::utils::*;
+use crabby_avif::*;
+
+mod utils;
+use utils::*;
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use test_case::test_matrix;
+
+type RecordedOptions = Arc<Mutex<Vec<Vec<(String, String)>>>>;
+
+// Stores the 8-bit samples of the planes of the category as is (or inverted if |invert| is
+// true), after the dimensions. The codec specific options of each encoded image are appended to
+// |options|.
+#[derive(Default)]
+struct RawEncoder {
+    category: Option<Category>,
+    pending_samples: Vec<Sample>,
+    invert: bool,
+    options: RecordedOptions,
+}
+
+fn planes(category: Category) -> &'static [Plane] {
+    match category {
+        Category::Alpha => &[Plane::A],
+        _ => &[Plane::Y, Plane::U, Plane::V],
+    }
+}
+
+impl EncoderPlugin for RawEncoder {
+    fn encode_image(
+        &mut self,
+        image: &Image,
+        category: Category,
+        config: &EncoderPluginConfig,
+        output_samples: &mut Vec<Sample>,
+    ) -> AvifResult<()> {
+        if image.depth != 8 || image.yuv_format != PixelFormat::Yuv444 {
+            return Err(AvifError::NotImplemented);
+        }
+        self.category = Some(category);
+        let mut options = config.codec_specific_options.clone();
+        options.sort();
+        self.options.lock().unwrap().push(options);
+        let mut data = Vec::new();
+        data.extend_from_slice(&image.width.to_be_bytes());
+        data.extend_from_slice(&image.height.to_be_bytes());
+        for plane in planes(category) {
+            for y in 0..image.height {
+                let row = image.row(*plane, y)?;
+                if self.invert {
+                    data.extend(row.iter().map(|sample| 255 - sample));
+                } else {
+                    data.extend_from_slice(row);
+                }
+            }
+        }
+        let sample = Sample { data, sync: true };
+        if config.disable_lagged_output || config.is_single_image {
+            output_samples.push(sample);
+        } else {
+            self.pending_samples.push(sample);
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, output_samples: &mut Vec<Sample>) -> AvifResult<()> {
+        output_samples.append(&mut self.pending_samples);
+        Ok(())
+    }
+
+    fn get_codec_config(
+        &self,
+        _image: &Image,
+        _is_single_image: bool,
+        _is_lossless: bool,
+        _output_samples: &[Sample],
+    ) -> AvifResult<CodecConfiguration> {
+        let is_alpha = self.category == Some(Category::Alpha);
+        Ok(CodecConfiguration::Av1(Av1CodecConfiguration {
+            seq_profile: if is_alpha { 0 } else { 1 },
+            monochrome: is_alpha,
+            chroma_subsampling_x: if is_alpha { 1 } else { 0 },
+            chroma_subsampling_y: if is_alpha { 1 } else { 0 },
+            ..Default::default()
+        }))
+    }
+}
+
+// Registers RawEncoder instances returned by |create| as the plugin |name|.
+fn raw_encoder_plugin(
+    name: &'static str,
+    create: impl Fn() -> RawEncoder + Send + Sync + 'static,
+) -> EncoderPluginFactory {
+    EncoderPluginFactory {
+        name,
+        compression_format: CompressionFormat::Avif,
+        create: Arc::new(move || Box::new(create())),
+    }
+}
+
+#[derive(Default)]
+struct RawDecoder {
+    config: Option<DecoderPluginConfig>,
+}
+
+impl DecoderPlugin for RawDecoder {
+    fn initialize(&mut self, config: &DecoderPluginConfig) -> AvifResult<()> {
+        if !matches!(config.codec_config, CodecConfiguration::Av1(_)) {
+            return Err(AvifError::NotImplemented);
+        }
+        self.config = Some(config.clone());
+        Ok(())
+    }
+
+    fn decode(
+        &mut self,
+        payload: &[u8],
+        _spatial_id: u8,
+        image: &mut Image,
+        category: Category,
+    ) -> AvifResult<()> {
+        assert_eq!(self.config.as_ref().unwrap().category, category);
+        image.width = u32::from_be_bytes(payload[0..4].try_into().unwrap());
+        image.height = u32::from_be_bytes(payload[4..8].try_into().unwrap());
+        image.depth = 8;
+        image.yuv_format = PixelFormat::Yuv444;
+        image.allocate_planes(category)?;
+        let mut data = payload[8..].chunks_exact(image.width as usize);
+        for plane in planes(category) {
+            for y in 0..image.height {
+                image
+                    .row_mut(*plane, y)?
+                    .copy_from_slice(data.next().unwrap());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test_matrix([false, true], [1, 3])]
+fn raw_plugins(alpha: bool, frame_count: usize) -> AvifResult<()> {
+    let image = generate_gradient_image(30, 20, 8, PixelFormat::Yuv444, YuvRange::Full, alpha)?;
+    let settings = encoder::Settings {
+        codec_choice: CodecChoice::Plugin("raw"),
+        ..Default::default()
+    };
+    let mut encoder = encoder::Encoder::create_with_settings(&settings)?;
+    encoder.add_codec_plugin(raw_encoder_plugin("raw", RawEncoder::default))?;
+    if frame_count == 1 {
+        encoder.add_image(&image)?;
+    } else {
+        for _ in 0..frame_count {
+            encoder.add_image_for_sequence(&image, 1)?;
+        }
+    }
+    let edata = encoder.finish()?;
+
+    let instance_count = Arc::new(AtomicUsize::new(0));
+    let counter = instance_count.clone();
+    let mut decoder = decoder::Decoder::default();
+    decoder.settings.codec_plugins = vec![DecoderPluginFactory {
+        name: "raw",
+        compression_formats: vec![CompressionFormat::Avif],
+        create: Arc::new(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+            Box::<RawDecoder>::default()
+        }),
+    }];
+    decoder.set_io_vec(edata);
+    decoder.parse()?;
+    assert_eq!(decoder.image_count(), frame_count as u32);
+    for _ in 0..frame_count {
+        decoder.next_image()?;
+        assert!(are_images_equal(decoder.image().unwrap(), &image)?);
+    }
+    if !HAS_DECODER {
+        // CodecChoice::Auto falls back to the plugin.
+        assert!(instance_count.load(Ordering::Relaxed) > 0);
+    }
+    Ok(())
+}
+
+#[test]
+fn frame_codec_specific_options() -> AvifResult<()> {
+    let image = generate_gradient_image(30, 20, 8, PixelFormat::Yuv444, YuvRange::Full, false)?;
+    let options = RecordedOptions::default();
+    let encoder_options = options.clone();
+    let settings = encoder::Settings {
+        codec_choice: CodecChoice::Plugin("raw"),
+        ..Default::default()
+    };
+    let mut encoder = encoder::Encoder::create_with_settings(&settings)?;
+    encoder.add_codec_plugin(raw_encoder_plugin("raw", move || RawEncoder {
+        options: encoder_options.clone(),
+        ..Default::default()
+    }))?;
+    encoder.set_codec_specific_option(None, "key".into(), "value".into());
+    for index in 0..3 {
+        let mut frame_options = encoder::FrameOptions::default();
+        if index == 1 {
+            frame_options
+                .codec_specific_options
+                .insert((None, "key".into()), "frame_value".into());
+        }
+        encoder.add_image_for_sequence_with_options(&image, 1, &frame_options)?;
+    }
+    encoder.finish()?;
+
+    let option = |value: &str| vec![("key".to_string(), value.to_string())];
+    // The frame after the one that replaced the option does not inherit it.
+    assert_eq!(
+        *options.lock().unwrap(),
+        vec![option("value"), option("frame_value"), option("value")]
+    );
+    Ok(())
+}
+
+#[test]
+fn raw_plugin_grid() -> AvifResult<()> {
+    let cells: Vec<Image> = (0..4)
+        .map(|_| generate_gradient_image(64, 64, 8, PixelFormat::Yuv444, YuvRange::Full, false))
+        .collect::<AvifResult<_>>()?;
+    let cell_refs: Vec<&Image> = cells.iter().collect();
+    let settings = encoder::Settings {
+        codec_choice: CodecChoice::Plugin("raw"),
+        ..Default::default()
+    };
+    let mut encoder = encoder::Encoder::create_with_settings(&settings)?;
+    encoder.add_codec_plugin(raw_encoder_plugin("raw", RawEncoder::default))?;
+    encoder.add_image_grid(2, 2, &cell_refs)?;
+    let edata = encoder.finish()?;
+
+    let mut decoder = decoder::Decoder::default();
+    decoder.settings.codec_choice = CodecChoice::Plugin("raw");
+    decoder.settings.codec_plugins = vec![DecoderPluginFactory {
+        name: "raw",
+        compression_formats: vec![CompressionFormat::Avif],
+        create: Arc::new(|| Box::<RawDecoder>::default()),
+    }];
+    decoder.set_io_vec(edata);
+    decoder.parse()?;
+    decoder.next_image()?;
+    let image = decoder.image().unwrap();
+    assert_eq!((image.width, image.height), (128, 128));
+    let expected = merge_cells_into_grid_image(2, 2, &cell_refs)?;
+    assert!(are_images_equal(image, &expected)?);
+    Ok(())
+}
+
+// Outputs a uniform 8-bit 4:2:0 image of the dimensions signaled in the container for each HEVC
+// payload.
+#[derive(Default)]
+struct UniformHevcDecoder {
+    config: Option<DecoderPluginConfig>,
+}
+
+impl DecoderPlugin for UniformHevcDecoder {
+    fn initialize(&mut self, config: &DecoderPluginConfig) -> AvifResult<()> {
+        if !matches!(config.codec_config, CodecConfiguration::Hevc(_)) {
+            return Err(AvifError::NotImplemented);
+        }
+        self.config = Some(config.clone());
+        Ok(())
+    }
+
+    fn decode(
+        &mut self,
+        payload: &[u8],
+        _spatial_id: u8,
+        image: &mut Image,
+        category: Category,
+    ) -> AvifResult<()> {
+        assert!(!payload.is_empty());
+        let config = self.config.as_ref().unwrap();
+        image.width = config.width;
+        image.height = config.height;
+        image.depth = 8;
+        image.yuv_format = PixelFormat::Yuv420;
+        image.allocate_planes(category)?;
+        for plane in planes(category) {
+            for y in 0..image.height(*plane) as u32 {
+                image.row_mut(*plane, y)?.fill(128);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn heic_decoder_plugin() -> AvifResult<()> {
+    let instance_count = Arc::new(AtomicUsize::new(0));
+    let counter = instance_count.clone();
+    let mut decoder = get_decoder("heic/blue.heic");
+    decoder.settings.codec_choice = CodecChoice::Plugin("hevc");
+    decoder.settings.codec_plugins = vec![DecoderPluginFactory {
+        name: "hevc",
+        compression_formats: vec![CompressionFormat::Heic],
+        create: Arc::new(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+            Box::<UniformHevcDecoder>::default()
+        }),
+    }];
+    decoder.settings.strictness = decoder::Strictness::None;
+    let res = decoder.parse();
+    if !cfg!(feature = "heic") {
+        // HEIC files are not parsed.
+        assert!(res.is_err());
+        return Ok(());
+    }
+    res?;
+    assert_eq!(decoder.compression_format(), CompressionFormat::Heic);
+    decoder.next_image()?;
+    assert!(instance_count.load(Ordering::Relaxed) > 0);
+    let image = decoder.image().unwrap();
+    assert_eq!((image.width, image.height), (320, 240));
+    assert_eq!(image.row(Plane::Y, 0)?[0], 128);
+    Ok(())
+}
+
+#[test]
+fn encoder_plugin_compression_format() -> AvifResult<()> {
+    let image = generate_gradient_image(16, 16, 8, PixelFormat::Yuv444, YuvRange::Full, false)?;
+    let settings = encoder::Settings {
+        codec_choice: CodecChoice::Plugin("raw"),
+        ..Default::default()
+    };
+    let mut encoder = encoder::Encoder::create_with_settings(&settings)?;
+    encoder.add_codec_plugin(EncoderPluginFactory {
+        compression_format: CompressionFormat::Heic,
+        ..raw_encoder_plugin("raw", RawEncoder::default)
+    })?;
+    // The names must be unique.
+    assert_eq!(
+        encoder.add_codec_plugin(raw_encoder_plugin("raw", RawEncoder::default)),
+        Err(AvifError::InvalidArgument)
+    );
+    // HEIC files cannot be written.
+    assert_eq!(encoder.add_image(&image), Err(AvifError::NotImplemented));
+    Ok(())
+}
+
+#[test]
+fn unregistered_plugin() -> AvifResult<()> {
+    let image = generate_gradient_image(16, 16, 8, PixelFormat::Yuv444, YuvRange::Full, false)?;
+    let settings = encoder::Settings {
+        codec_choice: CodecChoice::Plugin("raw"),
+        ..Default::default()
+    };
+    let mut encoder = encoder::Encoder::create_with_settings(&settings)?;
+    assert_eq!(encoder.add_image(&image), Err(AvifError::NoCodecAvailable));
+    Ok(())
+}
+
+#[test]
+fn quality_map_is_ignored() -> AvifResult<()> {
+    let mut image = generate_gradient_image(16, 16, 8, PixelFormat::Yuv444, YuvRange::Full, false)?;
+    image.quality_map = Some(QualityMap::Blocks {
+        block_size: 8,
+        offsets: vec![0, 10, 20, 30],
+    });
+    let settings = encoder::Settings {
+        codec_choice: CodecChoice::Plugin("raw"),
+        ..Default::default()
+    };
+    let mut encoder = encoder::Encoder::create_with_settings(&settings)?;
+    encoder.add_codec_plugin(raw_encoder_plugin("raw", RawEncoder::default))?;
+    encoder.add_image(&image)?;
+    assert_eq!(encoder.warnings().len(), 1);
+    assert!(!encoder.finish()?.is_empty());
+    Ok(())
+}
+
+#[test_matrix([false, true])]
+fn alternative_codec(alpha: bool) -> AvifResult<()> {
+    let image = generate_gradient_image(30, 20, 8, PixelFormat::Yuv444, YuvRange::Full, alpha)?;
+    let settings = encoder::Settings {
+        codec_choice: CodecChoice::Plugin("raw"),
+        ..Default::default()
+    };
+    let mut encoder = encoder::Encoder::create_with_settings(&settings)?;
+    encoder.add_codec_plugin(raw_encoder_plugin("raw", RawEncoder::default))?;
+    encoder.add_codec_plugin(raw_encoder_plugin("inverted", || RawEncoder {
+        invert: true,
+        ..Default::default()
+    }))?;
+    encoder.add_alternative_codec(CodecChoice::Plugin("inverted"))?;
+    encoder.add_image(&image)?;
+    let edata = encoder.finish()?;
+
+    let mut decoder = decoder::Decoder::default();
+    decoder.settings.codec_choice = CodecChoice::Plugin("raw");
+    decoder.settings.codec_plugins = vec![DecoderPluginFactory {
+        name: "raw",
+        compression_formats: vec![CompressionFormat::Avif],
+        create: Arc::new(|| Box::<RawDecoder>::default()),
+    }];
+    decoder.set_io_vec(edata);
+    decoder.parse()?;
+    assert_eq!(decoder.image_count(), 1);
+    decoder.next_image()?;
+    // The first supported rendition of the 'altr' group is the alternative one.
+    let mut expected =
+        generate_gradient_image(30, 20, 8, PixelFormat::Yuv444, YuvRange::Full, alpha)?;
+    for plane in ALL_PLANES {
+        if !expected.has_plane(plane) {
+            continue;
+        }
+        for y in 0..expected.height {
+            for sample in expected.row_mut(plane, y)? {
+                *sample = 255 - *sample;
+            }
+        }
+    }
+    assert!(are_images_equal(decoder.image().unwrap(), &expected)?);
+    Ok(())
+}
+
+#[test]
+fn rate_control_keeps_chosen_attempt() -> AvifResult<()> {
+    let image = generate_gradient_image(30, 20, 8, PixelFormat::Yuv444, YuvRange::Full, false)?;
+    let instance_count = Arc::new(AtomicUsize::new(0));
+    let counter = instance_count.clone();
+    let settings = encoder::Settings {
+        codec_choice: CodecChoice::Plugin("raw"),
+        rate_control: encoder::RateControl::TargetSize {
+            target_size: usize::MAX,
+            max_size: None,
+        },
+        mutable: encoder::MutableSettings {
+            quality: 50.0,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut encoder = encoder::Encoder::create_with_settings(&settings)?;
+    encoder.add_codec_plugin(raw_encoder_plugin("raw", move || {
+        counter.fetch_add(1, Ordering::Relaxed);
+        RawEncoder::default()
+    }))?;
+    encoder.add_image(&image)?;
+    let result = encoder.rate_control_result().unwrap();
+    let edata = encoder.finish()?;
+    // The raw encoder ignores the quality, so the highest one is chosen.
+    assert_eq!(result.quality, 100.0);
+    assert_eq!(result.size, edata.len());
+    // One codec per attempt plus the one created with the item. The chosen attempt is not
+    // encoded again.
+    assert_eq!(
+        instance_count.load(Ordering::Relaxed),
+        result.attempt_count as usize + 1
+    );
+
+    let mut decoder = decoder::Decoder::default();
+    decoder.settings.codec_choice = CodecChoice::Plugin("raw");
+    decoder.settings.codec_plugins = vec![DecoderPluginFactory {
+        name: "raw",
+        compression_formats: vec![CompressionFormat::Avif],
+        create: Arc::new(|| Box::<RawDecoder>::default()),
+    }];
+    decoder.set_io_vec(edata);
+    decoder.parse()?;
+    decoder.next_image()?;
+    assert!(are_images_equal(decoder.image().unwrap(), &image)?);
+    Ok(())
+}
+
+#[test]
+fn apply_transformations_to_smaller_gainmap() -> AvifResult<()> {
+    let mut image = generate_gradient_image(40, 20, 8, PixelFormat::Yuv444, YuvRange::Full, false)?;
+    let rect = CropRect {
+        x: 10,
+        y: 4,
+        width: 20,
+        height: 12,
+    };
+    image.clap = Some(CleanAperture::create_from(
+        &rect,
+        image.width,
+        image.height,
+        image.yuv_format,
+    )?);
+    image.irot_angle = Some(1);
+    let mut gainmap = GainMap {
+        image: generate_gradient_image(20, 10, 8, PixelFormat::Yuv444, YuvRange::Full, false)?,
+        ..Default::default()
+    };
+    for c in 0..3 {
+        gainmap.metadata.min[c] = Fraction(0, 1);
+        gainmap.metadata.max[c] = Fraction(1, 1);
+        gainmap.metadata.gamma[c] = UFraction(1, 1);
+        gainmap.metadata.base_offset[c] = Fraction(1, 64);
+        gainmap.metadata.alternate_offset[c] = Fraction(1, 64);
+    }
+    gainmap.metadata.base_hdr_headroom = UFraction(0, 1);
+    gainmap.metadata.alternate_hdr_headroom = UFraction(1, 1);
+    let settings = encoder::Settings {
+        codec_choice: CodecChoice::Plugin("raw"),
+        ..Default::default()
+    };
+    let mut encoder = encoder::Encoder::create_with_settings(&settings)?;
+    encoder.add_codec_plugin(raw_encoder_plugin("raw", RawEncoder::default))?;
+    encoder.add_image_gainmap(&image, &gainmap)?;
+    let edata = encoder.finish()?;
+
+    let mut decoder = decoder::Decoder::default();
+    decoder.settings.codec_choice = CodecChoice::Plugin("raw");
+    decoder.settings.codec_plugins = vec![DecoderPluginFactory {
+        name: "raw",
+        compression_formats: vec![CompressionFormat::Avif],
+        create: Arc::new(|| Box::<RawDecoder>::default()),
+    }];
+    decoder.settings.image_content_to_decode = ImageContentType::All;
+    decoder.settings.apply_transformations = true;
+    decoder.set_io_vec(edata);
+    decoder.parse()?;
+    decoder.next_image()?;
+    let decoded = decoder.image().unwrap();
+    assert_eq!((decoded.width, decoded.height), (12, 20));
+    // The gain map has half the resolution of the image, so is the clean aperture.
+    let gainmap_rect = CropRect {
+        x: 5,
+        y: 2,
+        width: 10,
+        height: 6,
+    };
+    let gainmap_clap = CleanAperture::create_from(
+        &gainmap_rect,
+        gainmap.image.width,
+        gainmap.image.height,
+        gainmap.image.yuv_format,
+    )?;
+    let expected_gainmap =
+        gainmap
+            .image
+            .transformed_image_with(Some(&gainmap_clap), image.irot_angle, None)?;
+    let decoded_gainmap = &decoder.gainmap().image;
+    assert_eq!((decoded_gainmap.width, decoded_gainmap.height), (6, 10));
+    assert!(are_images_equal(decoded_gainmap, &expected_gainmap)?);
+    Ok(())
+}
@@ -106,3 +106,164 @@ fn recipe_auto(
     assert!(psnr(decoder.image().unwrap(), &input_image)? >= 30.0);
     Ok(())
 }
+
+#[test_matrix(
+    [Recipe::BitDepthExtension12b8bOverlap4b, Recipe::BitDepthExtension10b10bOverlap4b],
+    [false, true]
+)]
+fn residual_sample_transform_roundtrip(recipe: Recipe, alpha: bool) -> AvifResult<()> {
+    // The residual is computed from the decoded base image.
+    if !HAS_ENCODER || !HAS_DECODER {
+        return Ok(());
+    }
+    let image = generate_gradient_image(64, 64, 16, PixelFormat::Yuv444, YuvRange::Full, alpha)?;
+    let settings = encoder::Settings {
+        speed: Some(10),
+        mutable: encoder::MutableSettings {
+            quality: 80.0,
+            quality_alpha: 80.0,
+            ..Default::default()
+        },
+        recipe,
+        ..Default::default()
+    };
+    let mut encoder = encoder::Encoder::create_with_settings(&settings)?;
+    encoder.add_image(&image)?;
+    let edata = encoder.finish()?;
+
+    let mut decoder = decoder::Decoder::default();
+    decoder.set_io_vec(edata.clone());
+    decoder.settings.allow_sample_transform = true;
+    decoder.parse()?;
+    decoder.next_image()?;
+    let decoded_image = decoder.image().unwrap();
+    assert_eq!(decoded_image.depth, 16);
+    // The residual corrects the codec loss of the base image within its range.
+    let mut max_error = 0;
+    for plane in ALL_PLANES {
+        if !image.has_plane(plane) {
+            continue;
+        }
+        for y in 0..image.height(plane) as u32 {
+            for (expected, actual) in image
+                .row16(plane, y)?
+                .iter()
+                .zip(decoded_image.row16(plane, y)?)
+            {
+                max_error = max_error.max((*expected as i32 - *actual as i32).abs());
+            }
+        }
+    }
+    assert!(max_error <= 256, "max_error: {max_error}");
+    assert!(psnr(decoded_image, &image)? >= 60.0);
+
+    // The base image can be decoded alone.
+    let mut decoder = decoder::Decoder::default();
+    decoder.set_io_vec(edata);
+    decoder.parse()?;
+    decoder.next_image()?;
+    let base_image = decoder.image().unwrap();
+    assert_eq!(
+        base_image.depth,
+        if recipe == Recipe::BitDepthExtension12b8bOverlap4b { 12 } else { 10 }
+    );
+    assert_eq!(base_image.alpha_present, alpha);
+    Ok(())
+}
+
+fn difference_transform() -> encoder::SampleTransform {
+    // 128 + input0 - input1
+    encoder::SampleTransform {
+        bit_depth: 32,
+        num_inputs: 2,
+        tokens: vec![
+            encoder::SampleTransformToken::Constant(128),
+            encoder::SampleTransformToken::ImageItem(0),
+            encoder::SampleTransformToken::BinaryOp(encoder::SampleTransformBinaryOp::Sum),
+            encoder::SampleTransformToken::ImageItem(1),
+            encoder::SampleTransformToken::BinaryOp(encoder::SampleTransformBinaryOp::Difference),
+        ],
+    }
+}
+
+#[test_matrix([false, true])]
+fn user_sample_transform(alpha: bool) -> AvifResult<()> {
+    if !HAS_ENCODER {
+        return Ok(());
+    }
+    let image = generate_gradient_image(32, 16, 8, PixelFormat::Yuv444, YuvRange::Full, alpha)?;
+    let mut reference =
+        generate_gradient_image(32, 16, 8, PixelFormat::Yuv444, YuvRange::Full, alpha)?;
+    for plane in ALL_PLANES {
+        if reference.has_plane(plane) {
+            fill_plane(&mut reference, plane, 100)?;
+        }
+    }
+    let settings = encoder::Settings {
+        speed: Some(10),
+        mutable: encoder::MutableSettings {
+            quality: 100.0,
+            quality_alpha: 100.0,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut encoder = encoder::Encoder::create_with_settings(&settings)?;
+    encoder.add_sample_transform_image(&difference_transform(), &[&image, &reference], 8)?;
+    let edata = encoder.finish()?;
+
+    if !HAS_DECODER {
+        return Ok(());
+    }
+    let mut decoder = decoder::Decoder::default();
+    decoder.set_io_vec(edata);
+    decoder.settings.allow_sample_transform = true;
+    decoder.parse()?;
+    decoder.next_image()?;
+    let decoded_image = decoder.image().unwrap();
+    assert_eq!(decoded_image.alpha_present, alpha);
+    for plane in ALL_PLANES {
+        if !image.has_plane(plane) {
+            continue;
+        }
+        for y in 0..image.height(plane) as u32 {
+            for (expected, actual) in image
+                .row(plane, y)?
+                .iter()
+                .zip(decoded_image.row(plane, y)?)
+            {
+                let expected = (128 + *expected as i32 - 100).clamp(0, 255);
+                assert_eq!(*actual as i32, expected);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn invalid_user_sample_transform() -> AvifResult<()> {
+    let image = generate_gradient_image(32, 16, 8, PixelFormat::Yuv444, YuvRange::Full, false)?;
+    let smaller_image =
+        generate_gradient_image(16, 16, 8, PixelFormat::Yuv444, YuvRange::Full, false)?;
+    let mut wrong_input_count = difference_transform();
+    wrong_input_count.num_inputs = 3;
+    let mut missing_operand = difference_transform();
+    missing_operand.tokens.remove(0);
+    let mut constant_too_large = difference_transform();
+    constant_too_large.bit_depth = 8;
+    constant_too_large.tokens[0] = encoder::SampleTransformToken::Constant(128);
+    for (sample_transform, inputs, depth) in [
+        (difference_transform(), [&image, &image], 9),
+        (difference_transform(), [&image, &smaller_image], 8),
+        (wrong_input_count, [&image, &image], 8),
+        (missing_operand, [&image, &image], 8),
+        (constant_too_large, [&image, &image], 8),
+    ] {
+        let mut encoder = encoder::Encoder::create_with_settings(&encoder::Settings::default())?;
+        assert_eq!(
+            encoder.add_sample_transform_image(&sample_transform, &inputs, depth),
+            Err(AvifError::InvalidArgument)
+        );
+    }
+    Ok(())
+}
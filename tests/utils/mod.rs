@@ -205,11 +205,11 @@ pub fn fill_plane(image: &mut Image, plane: Plane, value: u16) -> AvifResult<()>
     let plane_data = image.plane_data(plane).ok_or(AvifError::NoContent)?;
     for y in 0..plane_data.height {
         if image.depth == 8 {
-            for pixel in image.row_mut(Plane::A, y)? {
+            for pixel in image.row_mut(plane, y)? {
                 *pixel = value as u8;
             }
         } else {
-            for pixel in image.row16_mut(Plane::A, y)? {
+            for pixel in image.row16_mut(plane, y)? {
                 *pixel = value;
             }
         }